@@ -0,0 +1,66 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+
+#![no_main]
+
+use std::path::{Component, Path, PathBuf};
+
+use libfuzzer_sys::fuzz_target;
+
+// Only the parsers and member checks are fuzzed
+#[allow(dead_code)]
+#[path = "../../src/archive.rs"]
+mod archive;
+
+/// Resolves `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fuzz_target!(|data: &[u8]| {
+    let input = String::from_utf8_lossy(data);
+    let root = Path::new("/extract/root");
+
+    let mut names: Vec<String> = input.lines().map(String::from).collect();
+    names.extend(archive::parse_7z_listing(&input).into_iter().map(|(n, _)| n));
+    names.extend(archive::parse_unrar_listing(&input).into_iter().map(|(n, _)| n));
+    names.extend(archive::parse_unzip_listing(&input).into_iter().map(|(n, _)| n));
+
+    for name in names {
+        let _ = archive::unzip_pattern(&name);
+        if archive::is_safe_member(&name) {
+            let joined = normalize(&root.join(name.replace('\\', "/")));
+            assert!(joined.starts_with(root), "{name:?} escapes to {joined:?}");
+        }
+    }
+});
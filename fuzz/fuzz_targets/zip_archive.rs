@@ -0,0 +1,53 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+#![no_main]
+
+use std::fs;
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+
+// Only opening archives and reading members are fuzzed, not extraction
+#[allow(dead_code)]
+#[path = "../../src/hash.rs"]
+mod hash;
+#[path = "../../src/inflate.rs"]
+mod inflate;
+#[allow(dead_code)]
+#[path = "../../src/zip.rs"]
+mod zip;
+
+fuzz_target!(|data: &[u8]| {
+    // The same bytes are tried as a raw deflate stream, which must fail
+    // cleanly rather than panic
+    let _ = inflate::inflate(data, &mut io::sink());
+
+    let path = std::env::temp_dir().join(format!("reflac-fuzz-{}.zip", std::process::id()));
+    fs::write(&path, data).unwrap();
+    if let Ok(archive) = zip::Archive::open(&path) {
+        for member in &archive.members {
+            let _ = archive.read(member, &mut io::sink());
+        }
+    }
+    let _ = fs::remove_file(&path);
+});
@@ -0,0 +1,306 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! `reflac ab`: compares the output of a track with its source, sample by
+//! sample or by ear.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use crate::config::Config;
+use crate::{ReflacError, Result, decoder, flac, provenance};
+
+pub struct Options {
+    pub album_dir: PathBuf,
+    pub track: String,
+    pub source: Option<PathBuf>,
+    pub play: bool,
+    pub config: Option<PathBuf>,
+}
+
+/// Outcome of comparing two decoded streams.
+#[derive(Debug, PartialEq)]
+pub struct Comparison {
+    /// Length of the source, in samples per channel
+    pub source_samples: u64,
+    /// Length of the output, in samples per channel
+    pub output_samples: u64,
+    /// Samples, over the common length, that differ in any channel
+    pub differing: u64,
+    /// First differing sample and its channel
+    pub first: Option<(u64, usize)>,
+}
+
+impl Comparison {
+    pub fn is_identical(&self) -> bool {
+        self.differing == 0 && self.source_samples == self.output_samples
+    }
+}
+
+/// Reads until `buf` is full or the stream ends.
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Compares two raw streams of interleaved samples of `sample_bytes` bytes
+/// each, `channels` to a frame.
+pub fn compare(
+    mut source: impl Read,
+    mut output: impl Read,
+    channels: usize,
+    sample_bytes: usize,
+) -> io::Result<Comparison> {
+    let frame = channels * sample_bytes;
+    let mut a = vec![0; frame * 4096];
+    let mut b = vec![0; frame * 4096];
+    let mut cmp = Comparison {
+        source_samples: 0,
+        output_samples: 0,
+        differing: 0,
+        first: None,
+    };
+    loop {
+        let a_len = fill(&mut source, &mut a)?;
+        let b_len = fill(&mut output, &mut b)?;
+        let common = a_len.min(b_len) / frame;
+        for (i, (fa, fb)) in a[..common * frame]
+            .chunks(frame)
+            .zip(b[..common * frame].chunks(frame))
+            .enumerate()
+        {
+            if fa != fb {
+                if cmp.first.is_none() {
+                    let channel = fa
+                        .chunks(sample_bytes)
+                        .zip(fb.chunks(sample_bytes))
+                        .position(|(sa, sb)| sa != sb)
+                        .unwrap();
+                    cmp.first = Some((cmp.source_samples + i as u64, channel));
+                }
+                cmp.differing += 1;
+            }
+        }
+        cmp.source_samples += (a_len / frame) as u64;
+        cmp.output_samples += (b_len / frame) as u64;
+        if a_len < a.len() || b_len < b.len() {
+            // One stream ended, count what is left of the other
+            let mut rest = Vec::new();
+            if a_len < a.len() {
+                cmp.output_samples += (output.read_to_end(&mut rest)? / frame) as u64;
+            } else {
+                cmp.source_samples += (source.read_to_end(&mut rest)? / frame) as u64;
+            }
+            return Ok(cmp);
+        }
+    }
+}
+
+/// Formats a sample position as minutes, seconds and milliseconds.
+fn timestamp(sample: u64, sample_rate: u32) -> String {
+    let ms = sample * 1000 / sample_rate as u64;
+    format!("{}:{:02}.{:03}", ms / 60000, ms / 1000 % 60, ms % 1000)
+}
+
+/// Locates the source file of a track recorded in the provenance of an
+/// album. Sources inside archives cannot be found this way.
+fn find_source(base_dir: &Path, input: &str, name: &str) -> Option<PathBuf> {
+    let input = base_dir.join(input);
+    if input.is_file() {
+        return (input.file_name()? == name).then_some(input);
+    }
+    let mut dirs = vec![input];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).ok()?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.file_name().is_some_and(|n| n == name) {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// Decodes both files and prints where they differ. The source is decoded
+/// in-process where the encoder was fed that way, the output by `flac`.
+fn verify(source: &Path, output: &Path) -> Result<bool> {
+    if !source.exists() {
+        return Err(ReflacError::PathDoesNotExist(source.to_path_buf()));
+    }
+    if !flac::is_flac(source) {
+        return Err(ReflacError::NotAFlacSource(source.to_path_buf()));
+    }
+    let a = flac::read_metadata(source)?.stream;
+    let b = flac::read_metadata(output)?.stream;
+    if (a.sample_rate, a.channels, a.bits_per_sample)
+        != (b.sample_rate, b.channels, b.bits_per_sample)
+    {
+        println!(
+            "Formats differ: {} Hz, {} channels, {} bits against {} Hz, {} channels, {} bits",
+            a.sample_rate,
+            a.channels,
+            a.bits_per_sample,
+            b.sample_rate,
+            b.channels,
+            b.bits_per_sample
+        );
+        return Ok(false);
+    }
+    let mut b_child = flac::decode_raw(output)?;
+    let channels = a.channels as usize;
+    let sample_bytes = (a.bits_per_sample as usize).div_ceil(8);
+    let cmp = if a.bits_per_sample % 8 == 0 {
+        let decoder = decoder::Decoder::new(File::open(source)?)
+            .map_err(|err| ReflacError::DecodeFailed(source.display().to_string(), err))?;
+        let (reader, mut writer) = io::pipe()?;
+        let feeder = thread::spawn(move || decoder.decode(&mut writer));
+        let cmp = compare(
+            reader,
+            b_child.stdout.take().unwrap(),
+            channels,
+            sample_bytes,
+        )?;
+        feeder
+            .join()
+            .unwrap()
+            .map_err(|err| ReflacError::DecodeFailed(source.display().to_string(), err))?;
+        cmp
+    } else {
+        let mut a_child = flac::decode_raw(source)?;
+        let cmp = compare(
+            a_child.stdout.take().unwrap(),
+            b_child.stdout.take().unwrap(),
+            channels,
+            sample_bytes,
+        )?;
+        flac::wait_decoder(&mut a_child)?;
+        cmp
+    };
+    flac::wait_decoder(&mut b_child)?;
+
+    if cmp.is_identical() {
+        println!("Identical: {} samples", cmp.source_samples);
+        return Ok(true);
+    }
+    if let Some((sample, channel)) = cmp.first {
+        println!(
+            "First difference: sample {sample} ({}), channel {}",
+            timestamp(sample, a.sample_rate),
+            channel + 1
+        );
+    }
+    println!("Differing samples: {}", cmp.differing);
+    println!(
+        "Length: {} samples in the source, {} in the output",
+        cmp.source_samples, cmp.output_samples
+    );
+    Ok(false)
+}
+
+/// Starts the configured player with the source and the output.
+fn play(player: &str, source: &Path, output: &Path) -> Result<()> {
+    let mut words = player.split_whitespace();
+    let Some(program) = words.next() else {
+        return Err(ReflacError::NoPlayer);
+    };
+    let status = Command::new(program)
+        .args(words)
+        .arg(source)
+        .arg(output)
+        .status()?;
+    if !status.success() {
+        return Err(ReflacError::Subprocess {
+            command: "PLAYER",
+            status: status.code(),
+            stderr: String::new(),
+        });
+    }
+    Ok(())
+}
+
+pub fn run(options: &Options) -> Result<()> {
+    let (base_dir, record) = provenance::find_track(&options.album_dir, &options.track)?;
+    let source = match &options.source {
+        Some(path) => path.clone(),
+        None => find_source(&base_dir, &record.input, &record.source)
+            .ok_or_else(|| ReflacError::MissingSource(options.track.clone()))?,
+    };
+    info!("Source: {}", source.display());
+    info!("Output: {}", record.output.display());
+    if options.play {
+        let config = Config::load(options.config.as_deref())?;
+        return play(
+            config.player.as_deref().ok_or(ReflacError::NoPlayer)?,
+            &source,
+            &record.output,
+        );
+    }
+    if verify(&source, &record.output)? {
+        Ok(())
+    } else {
+        Err(ReflacError::OutputDiffers(options.track.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_streams() {
+        let data: Vec<u8> = (0..=255).cycle().take(4 * 10000).collect();
+        let cmp = compare(&data[..], &data[..], 2, 2).unwrap();
+        assert!(cmp.is_identical());
+        assert_eq!(cmp.source_samples, 10000);
+    }
+
+    #[test]
+    fn first_difference_and_lengths() {
+        let a: Vec<u8> = (0..=255).cycle().take(4 * 20000).collect();
+        let mut b = a[..4 * 19000].to_vec();
+        b[4 * 17000 + 2] ^= 1;
+        b[4 * 18000] ^= 1;
+        let cmp = compare(&a[..], &b[..], 2, 2).unwrap();
+        assert_eq!(cmp.first, Some((17000, 1)));
+        assert_eq!(cmp.differing, 2);
+        assert_eq!((cmp.source_samples, cmp.output_samples), (20000, 19000));
+        assert!(!cmp.is_identical());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(44100 * 61 + 22050, 44100), "1:01.500");
+    }
+}
@@ -0,0 +1,223 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Interpretation of archive listings and member names. Everything in here
+//! handles untrusted input and depends on nothing but `std` and `regex`, so
+//! the fuzz targets in `fuzz/` can include it directly.
+
+use std::path::Path;
+use std::sync::LazyLock;
+
+/// Whether an archive member stays inside the extraction directory, i.e. is
+/// neither absolute nor contains `..` components.
+pub fn is_safe_member(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let absolute = name.starts_with(['/', '\\'])
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':');
+    !absolute && !name.split(['/', '\\']).any(|c| c == "..")
+}
+
+/// File extensions of the input archives reflac extracts. Disc images
+/// (ISO9660 and UDF) are read with `7z`, since `7za` lacks those formats.
+pub const EXTENSIONS: &[&str] = &["zip", "rar", "7z", "iso"];
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e))
+}
+
+/// Tools that decompress single files, by extension.
+const DECOMPRESSORS: &[(&str, &str)] = &[
+    ("gz", "gzip"),
+    ("bz2", "bzip2"),
+    ("xz", "xz"),
+    ("zst", "zstd"),
+];
+
+/// The tool that decompresses a compressed audio file such as
+/// `01.flac.xz` or `01.wav.gz`.
+pub fn decompressor(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    let (stem, ext) = name.rsplit_once('.')?;
+    let (_, inner) = stem.rsplit_once('.')?;
+    if !["flac", "wav", "aif", "aiff"].contains(&inner) {
+        return None;
+    }
+    DECOMPRESSORS
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, tool)| *tool)
+}
+
+/// Member names from the output of `7za l -slt` (or `7z l -slt`), and
+/// whether each is a symbolic link.
+pub fn parse_7z_listing(listing: &str) -> Vec<(String, bool)> {
+    let mut members: Vec<(String, bool)> = Vec::new();
+    for line in listing
+        .lines()
+        .skip_while(|line| !line.starts_with("----------"))
+    {
+        if let Some(name) = line.strip_prefix("Path = ") {
+            members.push((name.to_string(), false));
+        } else if let Some((_, link)) = members.last_mut() {
+            // Unix modes are listed after the DOS attributes, e.g. "A_ lrwxrwxrwx"
+            if let Some(attributes) = line.strip_prefix("Attributes = ") {
+                *link |= attributes
+                    .split_whitespace()
+                    .any(|a| a.len() == 10 && a.starts_with('l'));
+            } else if let Some(target) = line.strip_prefix("Symbolic Link = ") {
+                *link |= !target.is_empty();
+            }
+        }
+    }
+    members
+}
+
+/// Member names from the output of `unrar lt`, and whether each is a link.
+pub fn parse_unrar_listing(listing: &str) -> Vec<(String, bool)> {
+    let mut members: Vec<(String, bool)> = Vec::new();
+    for line in listing.lines().map(str::trim_start) {
+        if let Some(name) = line.strip_prefix("Name: ") {
+            members.push((name.to_string(), false));
+        } else if let Some(kind) = line.strip_prefix("Type: ")
+            && let Some((_, link)) = members.last_mut()
+        {
+            let kind = kind.to_lowercase();
+            *link = kind.contains("link") || kind.contains("junction");
+        }
+    }
+    members
+}
+
+/// Member names and sizes from the output of `unzip -l`.
+pub fn parse_unzip_listing(listing: &str) -> Vec<(String, u64)> {
+    static LISTING_RE: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r"^\s*(\d+)\s+\S+\s+\S+\s+(.+)$").unwrap());
+    listing
+        .lines()
+        .filter_map(|line| LISTING_RE.captures(line))
+        .filter_map(|caps| Some((caps[2].to_string(), caps[1].parse().ok()?)))
+        .collect()
+}
+
+/// Escapes a member name for use as an `unzip` pattern, which would
+/// otherwise treat `[`, `*` and `?` as wildcards.
+pub fn unzip_pattern(member: &str) -> String {
+    member
+        .chars()
+        .map(|c| match c {
+            '[' | '*' | '?' => format!("[{c}]"),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_decompressors_for_compressed_audio() {
+        assert_eq!(decompressor(Path::new("a/01.flac.xz")), Some("xz"));
+        assert_eq!(decompressor(Path::new("01 Intro.WAV.gz")), Some("gzip"));
+        assert_eq!(decompressor(Path::new("01.flac")), None);
+        assert_eq!(decompressor(Path::new("album.tar.zst")), None);
+        assert_eq!(decompressor(Path::new("01.flac.rar")), None);
+    }
+
+    #[test]
+    fn unsafe_members() {
+        assert!(is_safe_member("Album/01.flac"));
+        assert!(is_safe_member("..."));
+        assert!(!is_safe_member("../01.flac"));
+        assert!(!is_safe_member("/etc/passwd"));
+        assert!(!is_safe_member("C:\\Windows"));
+        assert!(!is_safe_member("Album\\..\\..\\01.flac"));
+    }
+
+    #[test]
+    fn unzip_listing() {
+        let listing = "Archive:  in.zip
+  Length      Date    Time    Name
+---------  ---------- -----   ----
+        1  2026-10-14 17:16   Album/01 One.flac
+ 99999999999999999999999  2026-10-14 17:16   huge.flac
+---------                     -------
+        1                     1 file
+";
+        assert_eq!(
+            parse_unzip_listing(listing),
+            [(String::from("Album/01 One.flac"), 1)]
+        );
+    }
+
+    #[test]
+    fn seven_zip_listing() {
+        let listing =
+            "Path = in.7z\nType = 7z\n----------\nPath = a/01.flac\nSize = 1\n\nPath = a\n";
+        assert_eq!(
+            parse_7z_listing(listing),
+            [
+                (String::from("a/01.flac"), false),
+                (String::from("a"), false)
+            ]
+        );
+        let listing = "----------\nPath = a/01.flac\nAttributes = A_ -rw-r--r--\n\n\
+                       Path = a/02.flac\nAttributes = A_ lrwxrwxrwx\n";
+        assert_eq!(
+            parse_7z_listing(listing),
+            [
+                (String::from("a/01.flac"), false),
+                (String::from("a/02.flac"), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn unrar_listing() {
+        let listing = "
+Archive: in.rar
+Details: RAR 5
+
+        Name: a/01.flac
+        Type: File
+        Size: 1
+
+        Name: a/02.flac
+        Type: Symbolic link
+      Target: /etc/passwd
+";
+        assert_eq!(
+            parse_unrar_listing(listing),
+            [
+                (String::from("a/01.flac"), false),
+                (String::from("a/02.flac"), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(unzip_pattern("[Disc 1] *?.flac"), "[[]Disc 1] [*][?].flac");
+    }
+}
@@ -0,0 +1,387 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::{ReflacError, Result, flac, run_command};
+
+/// FLAC files of an album directory, or the file itself.
+fn files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.exists() {
+        return Err(ReflacError::PathDoesNotExist(path.to_path_buf()));
+    }
+    let files = if path.is_dir() {
+        flac::album_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    if files.is_empty() {
+        return Err(ReflacError::NoFlacFilesFound(path.to_path_buf()));
+    }
+    Ok(files)
+}
+
+/// Writes the front cover (or first picture) of a file or album to `out`,
+/// which defaults to `cover.jpg` or `cover.png` depending on the picture.
+pub fn extract(path: &Path, out: Option<&Path>) -> Result<()> {
+    for file in files(path)? {
+        let meta = flac::read_metadata(&file)?;
+        let Some(picture) = meta.cover() else {
+            continue;
+        };
+        let out = out.map(Path::to_path_buf).unwrap_or_else(|| {
+            PathBuf::from(match picture.mime.as_str() {
+                "image/png" => "cover.png",
+                "image/gif" => "cover.gif",
+                "image/webp" => "cover.webp",
+                _ => "cover.jpg",
+            })
+        });
+        info!("{} → {}", file.display(), out.display());
+        fs::write(&out, &picture.data)?;
+        println!("{}", out.display());
+        return Ok(());
+    }
+    Err(ReflacError::NoPictureFound(path.to_path_buf()))
+}
+
+/// Replaces the pictures of every file of an album with `image` as front
+/// cover.
+pub fn set(path: &Path, image: &Path) -> Result<()> {
+    if !image.is_file() {
+        return Err(ReflacError::PathDoesNotExist(image.to_path_buf()));
+    }
+    for file in files(path)? {
+        info!("{}", file.display());
+        run_command(
+            Command::new("metaflac")
+                .arg("--remove")
+                .arg("--block-type=PICTURE")
+                .arg(format!("--import-picture-from={}", image.to_str().unwrap()))
+                .arg(&file)
+                .stdout(Stdio::null()),
+            "metaflac",
+        )?;
+    }
+    Ok(())
+}
+
+/// Pixels of the longer side of covers rendered from booklets.
+const BOOKLET_COVER_SIZE: u32 = 1200;
+
+/// Largest picture a FLAC metadata block can hold (24-bit length, minus the
+/// picture header).
+pub const MAX_PICTURE_BYTES: u64 = (1 << 24) - 1 - 64;
+
+/// An image that could serve as the cover.
+pub struct Candidate {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+}
+
+impl Candidate {
+    /// e.g. `front.jpg (1400×1400, 512 KiB)`
+    pub fn describe(&self, root: &Path) -> String {
+        format!(
+            "{} ({}×{}, {} KiB)",
+            self.path.strip_prefix(root).unwrap_or(&self.path).display(),
+            self.width,
+            self.height,
+            self.bytes.div_ceil(1024)
+        )
+    }
+
+    /// 2 for front covers, 1 for other covers and 0 for anything else
+    /// (back, booklet scans, ...).
+    fn name_rank(&self) -> u8 {
+        let name = self
+            .path
+            .file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().to_lowercase());
+        if name.contains("front") {
+            2
+        } else if name.contains("cover") || name.contains("folder") {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Whether the sides differ by no more than 5%.
+    fn is_square(&self) -> bool {
+        let (long, short) = (self.width.max(self.height), self.width.min(self.height));
+        long as u64 * 100 <= short as u64 * 105
+    }
+}
+
+fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") || data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(data.get(16..20)?.try_into().unwrap());
+    let height = u32::from_be_bytes(data.get(20..24)?.try_into().unwrap());
+    Some((width, height))
+}
+
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut i = 2;
+    while i + 9 <= data.len() {
+        if data[i] != 0xff {
+            return None;
+        }
+        let marker = data[i + 1];
+        if marker == 0xff {
+            // Fill byte
+            i += 1;
+            continue;
+        }
+        // Start of frame, except DHT, JPG and DAC which share the range
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let height = u16::from_be_bytes([data[i + 5], data[i + 6]]);
+            let width = u16::from_be_bytes([data[i + 7], data[i + 8]]);
+            return Some((width as u32, height as u32));
+        }
+        i += 2 + u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+    }
+    None
+}
+
+/// Width and height of a PNG or JPEG image.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    png_dimensions(data).or_else(|| jpeg_dimensions(data))
+}
+
+/// Files in `dir` and its direct subdirectories (scans, artwork) with one
+/// of the given extensions, sorted.
+fn files_with_extension(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                paths.push(entry?.path());
+            }
+        } else {
+            paths.push(path);
+        }
+    }
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| extensions.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+    });
+    paths.sort();
+    Ok(paths)
+}
+
+/// Images in `dir` and its direct subdirectories (scans, artwork).
+pub fn candidates(dir: &Path) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+    for path in files_with_extension(dir, &["jpg", "jpeg", "png"])? {
+        if !path.is_file() {
+            continue;
+        }
+        let data = fs::read(&path)?;
+        if let Some((width, height)) = dimensions(&data) {
+            candidates.push(Candidate {
+                path,
+                width,
+                height,
+                bytes: data.len() as u64,
+            });
+        }
+    }
+    Ok(candidates)
+}
+
+/// The best cover: one that fits under `max_bytes`, named front or cover,
+/// square and of the highest resolution, in that order of importance.
+pub fn best(candidates: &[Candidate], max_bytes: u64) -> Option<&Candidate> {
+    candidates.iter().max_by_key(|c| {
+        (
+            c.bytes <= max_bytes,
+            c.name_rank(),
+            c.is_square(),
+            c.width as u64 * c.height as u64,
+        )
+    })
+}
+
+/// A PDF booklet in `dir` or its direct subdirectories, preferring one
+/// named booklet, for releases that ship their artwork only that way.
+pub fn booklet(dir: &Path) -> Result<Option<PathBuf>> {
+    let pdfs = files_with_extension(dir, &["pdf"])?;
+    let named = pdfs.iter().find(|path| {
+        path.file_name()
+            .is_some_and(|name| name.to_string_lossy().to_lowercase().contains("booklet"))
+    });
+    Ok(named.or(pdfs.first()).cloned())
+}
+
+/// Renders the first page of a PDF booklet into a JPEG in `out_dir` with
+/// poppler's `pdftoppm`.
+pub fn render_booklet(pdf: &Path, out_dir: &Path) -> Result<PathBuf> {
+    let prefix = out_dir.join("booklet");
+    let rendered = run_command(
+        Command::new("pdftoppm")
+            .args(["-jpeg", "-f", "1", "-l", "1", "-singlefile"])
+            .args(["-scale-to", &BOOKLET_COVER_SIZE.to_string()])
+            .arg(pdf)
+            .arg(&prefix)
+            .stdout(Stdio::null()),
+        "pdftoppm",
+    );
+    match rendered {
+        Err(ReflacError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            Err(ReflacError::MissingProgram(
+                "pdftoppm",
+                "covers are rendered from PDF booklets with it (poppler-utils)",
+            ))
+        }
+        result => result.map(|()| prefix.with_extension("jpg")),
+    }
+}
+
+/// Chooses the cover among the images of an input directory, asking on the
+/// terminal if `interactive` and there is a choice to make.
+pub fn choose(dir: &Path, max_bytes: u64, interactive: bool) -> Result<PathBuf> {
+    let candidates = candidates(dir)?;
+    let Some(best) = best(&candidates, max_bytes) else {
+        return Err(ReflacError::NoPictureFound(dir.to_path_buf()));
+    };
+    let mut chosen = best;
+    if interactive && candidates.len() > 1 {
+        let default = candidates.iter().position(|c| c.path == best.path).unwrap();
+        for (i, candidate) in candidates.iter().enumerate() {
+            let mark = if i == default { '*' } else { ' ' };
+            eprintln!("{mark} {}) {}", i + 1, candidate.describe(dir));
+        }
+        loop {
+            eprint!("Cover [{}]: ", default + 1);
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            match answer.trim() {
+                "" => break,
+                n => match n.parse::<usize>() {
+                    Ok(n) if (1..=candidates.len()).contains(&n) => {
+                        chosen = &candidates[n - 1];
+                        break;
+                    }
+                    _ => continue,
+                },
+            }
+        }
+    }
+    if chosen.bytes > max_bytes {
+        warning!(
+            cover: "Cover {} is larger than {max_bytes} bytes",
+            chosen.describe(dir)
+        );
+    }
+    info!("  Cover: {}", chosen.describe(dir));
+    Ok(chosen.path.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data
+    }
+
+    fn candidate(name: &str, width: u32, height: u32, bytes: u64) -> Candidate {
+        Candidate {
+            path: PathBuf::from(name),
+            width,
+            height,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn image_dimensions() {
+        assert_eq!(dimensions(&png(600, 500)), Some((600, 500)));
+        // SOI, an APP0 segment, then SOF0 with 8 bits, 300 high, 400 wide
+        let jpeg = [
+            0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, 0xff, 0xc0, 0x00, 0x11, 0x08, 0x01,
+            0x2c, 0x01, 0x90, 0x03,
+        ];
+        assert_eq!(dimensions(&jpeg), Some((400, 300)));
+        assert_eq!(dimensions(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn best_candidate() {
+        let candidates = [
+            candidate("scans/booklet-01.png", 4800, 4800, 40 << 20),
+            candidate("back.jpg", 1400, 1400, 500 << 10),
+            candidate("cover.png", 1000, 1000, 2 << 20),
+            candidate("front-wide.jpg", 1600, 1200, 800 << 10),
+            candidate("front.jpg", 1200, 1200, 600 << 10),
+            candidate("front-small.jpg", 500, 500, 100 << 10),
+        ];
+        // Front covers first, square ones among them, then the largest
+        assert_eq!(
+            best(&candidates, MAX_PICTURE_BYTES).unwrap().path,
+            PathBuf::from("front.jpg")
+        );
+        assert_eq!(
+            best(&candidates[..3], MAX_PICTURE_BYTES).unwrap().path,
+            PathBuf::from("cover.png")
+        );
+        // The scan is too large, the back cover is the best of the rest
+        assert_eq!(
+            best(&candidates[..2], 1 << 20).unwrap().path,
+            PathBuf::from("back.jpg")
+        );
+        assert!(best(&[], MAX_PICTURE_BYTES).is_none());
+    }
+
+    #[test]
+    fn booklets_named_so_are_preferred() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        assert_eq!(booklet(dir.path()).unwrap(), None);
+        fs::write(dir.path().join("Liner Notes.PDF"), b"").unwrap();
+        assert_eq!(
+            booklet(dir.path()).unwrap(),
+            Some(dir.path().join("Liner Notes.PDF"))
+        );
+        fs::create_dir(dir.path().join("Scans")).unwrap();
+        fs::write(dir.path().join("Scans/Booklet.pdf"), b"").unwrap();
+        assert_eq!(
+            booklet(dir.path()).unwrap(),
+            Some(dir.path().join("Scans/Booklet.pdf"))
+        );
+    }
+}
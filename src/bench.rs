@@ -0,0 +1,107 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::{ReflacError, Result, TempDir, run_command};
+
+/// Presets compared by `reflac bench`, from fastest to the settings used for
+/// recompressing.
+const SETTINGS: &[(&str, &[&str])] = &[
+    ("-5", &["-5"]),
+    ("-8", &["-8"]),
+    ("-8e", &["-8", "--exhaustive-model-search"]),
+    ("-8p", &["-8", "--qlp-coeff-precision-search"]),
+    ("-8ep", crate::ENCODER_SETTINGS),
+];
+
+/// Returns whether the installed `flac` can encode with several threads,
+/// which was added in version 1.5.
+pub fn supports_threads() -> bool {
+    crate::flac::encoder_version().is_some_and(|version| version >= (1, 5))
+}
+
+fn encode(wav: &Path, out: &Path, args: &[&str], threads: usize) -> Result<Duration> {
+    let mut cmd = Command::new("flac");
+    cmd.args(args);
+    if threads > 1 {
+        cmd.arg(format!("--threads={threads}"));
+    }
+    let start = Instant::now();
+    run_command(
+        cmd.arg("--force")
+            .arg("--silent")
+            .arg(format!("--output-name={}", out.to_str().unwrap()))
+            .arg(wav)
+            .stdout(Stdio::null()),
+        "flac",
+    )?;
+    Ok(start.elapsed())
+}
+
+/// Encodes a sample with every preset and thread count and prints the
+/// resulting sizes and encoding times.
+pub fn run(path: &Path, threads: &[usize]) -> Result<()> {
+    if !path.is_file() {
+        return Err(ReflacError::PathDoesNotExist(path.to_path_buf()));
+    }
+    let tmp_dir = TempDir::new("reflac-bench")?;
+    let wav = tmp_dir.path().join("sample.wav");
+    info!("Decoding sample ...");
+    run_command(
+        Command::new("flac")
+            .arg("--decode")
+            .arg("--silent")
+            .arg(format!("--output-name={}", wav.to_str().unwrap()))
+            .arg(path)
+            .stdout(Stdio::null()),
+        "flac",
+    )?;
+    let wav_size = fs::metadata(&wav)?.len();
+    let source_size = fs::metadata(path)?.len();
+
+    info!("Encoding ...");
+    println!(
+        "  {:<6} {:>7} {:>12} {:>7} {:>9}",
+        "PRESET", "THREADS", "SIZE", "RATIO", "TIME"
+    );
+    let out = tmp_dir.path().join("sample.flac");
+    for (name, args) in SETTINGS {
+        for &n in threads {
+            let time = encode(&wav, &out, args, n)?;
+            let size = fs::metadata(&out)?.len();
+            println!(
+                "  {:<6} {:>7} {:>12} {:>6.2}% {:>8.2}s",
+                name,
+                n,
+                size,
+                size as f64 * 100.0 / wav_size as f64,
+                time.as_secs_f64()
+            );
+        }
+    }
+    println!("Source: {source_size} bytes, decoded: {wav_size} bytes");
+    Ok(())
+}
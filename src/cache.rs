@@ -0,0 +1,293 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Cache of extracted archives, so rerunning reflac on the same input while
+//! fixing a TRACKINFO file does not extract it again.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::{ReflacError, Result, create_private_dir, create_unique, hash};
+
+/// Size the cache is trimmed to unless configured otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 20 << 30;
+
+/// Prefix of extractions still in progress.
+const PARTIAL_PREFIX: &str = ".partial-";
+
+/// Shared locks on the entries this run uses, held until it exits so that
+/// concurrent runs do not evict them.
+static IN_USE: Mutex<Vec<File>> = Mutex::new(Vec::new());
+
+#[derive(Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// `512M`, `20G` or a plain number of bytes.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (number, shift) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 20),
+        b'G' | b'g' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Total size of the files below `path`.
+fn tree_size(path: &Path) -> Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += tree_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Locks the cache entry `entry` through an `flock` on `entry.lock`, shared
+/// by runs using it or exclusive for evicting it. Returns `None` if an
+/// exclusive lock is refused because the entry is in use.
+fn lock_entry(entry: &Path, exclusive: bool) -> Result<Option<File>> {
+    let path = entry.with_extension("lock");
+    let operation = match exclusive {
+        true => libc::LOCK_EX | libc::LOCK_NB,
+        false => libc::LOCK_SH,
+    };
+    loop {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| ReflacError::CreateFileFailed(path.clone(), err))?;
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+        // An evicting run may have removed the file between our open and
+        // flock, see `AlbumLock`
+        let held = file.metadata()?;
+        match fs::metadata(&path) {
+            Ok(current) if current.ino() == held.ino() && current.dev() == held.dev() => {
+                return Ok(Some(file));
+            }
+            _ => continue,
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+
+    /// `$XDG_CACHE_HOME/reflac`, `~/.cache/reflac` or, without a home
+    /// directory, `reflac-cache` in the temporary directory.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+            PathBuf::from(dir).join("reflac")
+        } else if let Some(home) = env::var_os("HOME") {
+            PathBuf::from(home).join(".cache/reflac")
+        } else {
+            env::temp_dir().join("reflac-cache")
+        }
+    }
+
+    fn archives(&self) -> PathBuf {
+        self.dir.join("archives")
+    }
+
+    /// The extracted tree of `archive`, produced by `extract` into an empty
+    /// directory unless an earlier run left it in the cache.
+    pub fn archive<F>(&self, archive: &Path, extract: F) -> Result<PathBuf>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let archives = self.archives();
+        fs::create_dir_all(&archives)
+            .map_err(|err| ReflacError::CreateDirFailed(archives.clone(), err))?;
+        let entry = archives.join(hash::hex(&hash::sha256_file(archive)?));
+        let lock = lock_entry(&entry, false)?.unwrap();
+        IN_USE.lock().unwrap_or_else(|e| e.into_inner()).push(lock);
+        if entry.is_dir() {
+            info!("  Using cached extraction of \"{}\"", archive.display());
+            // Recently used entries are evicted last
+            File::open(&entry)?.set_modified(SystemTime::now())?;
+            return Ok(entry);
+        }
+        // Extracted next to the entry and renamed, so an interrupted run
+        // leaves no half-extracted entry behind
+        let (partial, ()) = create_unique(&archives, PARTIAL_PREFIX, "", create_private_dir)
+            .map_err(|err| ReflacError::CreateDirFailed(archives.clone(), err))?;
+        if let Err(err) = extract(&partial).and_then(|()| Ok(fs::rename(&partial, &entry)?)) {
+            let _ = fs::remove_dir_all(&partial);
+            // A concurrent run extracted the same archive first
+            if !entry.is_dir() {
+                return Err(err);
+            }
+        }
+        self.evict(&entry)?;
+        Ok(entry)
+    }
+
+    /// Removes the least recently used entries, other than `keep` and
+    /// those other runs are using or still extracting, until the cache fits
+    /// its maximum size.
+    fn evict(&self, keep: &Path) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(self.archives())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(PARTIAL_PREFIX) || name.ends_with(".lock") {
+                continue;
+            }
+            let path = entry.path();
+            let size = tree_size(&path)?;
+            total += size;
+            if path != keep {
+                entries.push((fs::metadata(&path)?.modified()?, size, path));
+            }
+        }
+        entries.sort();
+        for (_, size, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            let Some(_lock) = lock_entry(&path, true)? else {
+                continue;
+            };
+            info!("  Evicting cached extraction {}", path.display());
+            fs::remove_dir_all(&path)?;
+            // Removed while still locked, so no other run can be holding it
+            fs::remove_file(path.with_extension("lock"))?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Removes all cached extractions and returns the bytes freed.
+    pub fn clean(&self) -> Result<u64> {
+        let archives = self.archives();
+        if !archives.exists() {
+            return Ok(0);
+        }
+        let freed = tree_size(&archives)?;
+        fs::remove_dir_all(&archives)?;
+        Ok(freed)
+    }
+}
+
+/// `reflac cache clean`
+pub fn run_clean(cache: &Cache) -> Result<()> {
+    let freed = cache.clean()?;
+    info!(
+        "Removed {} MiB from {}",
+        freed.div_ceil(1 << 20),
+        cache.dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("20g"), Some(20 << 30));
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("99999999999G"), None);
+    }
+
+    #[test]
+    fn extractions_are_reused_and_evicted() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let cache = Cache::new(dir.path().join("cache"), 10);
+        let extractions = Cell::new(0);
+        let extract = |out: &Path| {
+            extractions.set(extractions.get() + 1);
+            fs::write(out.join("track.flac"), b"12345678")?;
+            Ok(())
+        };
+        let [first, second, third] = ["first", "second", "third"].map(|name| {
+            let path = dir.path().join(format!("{name}.zip"));
+            fs::write(&path, name).unwrap();
+            path
+        });
+
+        let entry = cache.archive(&first, extract).unwrap();
+        assert!(entry.join("track.flac").is_file());
+        assert_eq!(cache.archive(&first, extract).unwrap(), entry);
+        assert_eq!(extractions.get(), 1);
+        // Two entries exceed the 10 bytes, but this run still uses the first
+        let kept = cache.archive(&second, extract).unwrap();
+        assert_eq!(extractions.get(), 2);
+        assert!(entry.exists());
+
+        // Once no run uses them, the older ones go; other runs' extractions
+        // in progress are left alone
+        IN_USE.lock().unwrap().clear();
+        let partial = cache.archives().join(".partial-other");
+        fs::create_dir(&partial).unwrap();
+        fs::write(partial.join("track.flac"), b"12345678").unwrap();
+        cache.archive(&third, extract).unwrap();
+        assert!(!entry.exists());
+        assert!(!kept.exists());
+        assert!(partial.exists());
+        fs::remove_dir_all(&partial).unwrap();
+
+        // Failed extractions leave nothing behind
+        assert!(
+            cache
+                .archive(&first, |_| Err(ReflacError::UnknownArchiveType(
+                    String::new()
+                )))
+                .is_err()
+        );
+        let entries = fs::read_dir(cache.archives()).unwrap().flatten();
+        let names: Vec<_> = entries
+            .map(|e| e.file_name().into_string().unwrap())
+            .filter(|name| !name.ends_with(".lock"))
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert_eq!(cache.clean().unwrap(), 8);
+        assert!(!cache.archives().exists());
+    }
+}
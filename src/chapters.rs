@@ -0,0 +1,266 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Joins the tracks of a disc into a single FLAC file with chapter marks,
+//! for audiobooks and other spoken-word releases.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::flac::{self, StreamInfo};
+use crate::{ReflacError, Result, TempDir, encoder_comments, run_command};
+
+/// Samples per CD frame (1/75 s)
+const CD_FRAME: u64 = 588;
+
+/// Comments that describe a single track, or how it was encoded, and not the
+/// joined file.
+const TRACK_FIELDS: &[&str] = &[
+    "TITLE",
+    "TRACKNUMBER",
+    "TRACKTOTAL",
+    "ENCODER",
+    "ENCODERSETTINGS",
+];
+
+/// A chapter starting `offset` samples into the joined file.
+pub struct Chapter {
+    pub offset: u64,
+    pub title: Option<String>,
+}
+
+/// `HH:MM:SS.mmm`, as used by CHAPTERxxx comments.
+fn chapter_time(offset: u64, rate: u32) -> String {
+    let ms = offset * 1000 / rate as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// Chapter marks as Vorbis comments, understood by most players of
+/// spoken-word FLAC files.
+pub fn chapter_comments(chapters: &[Chapter], rate: u32) -> Vec<(String, String)> {
+    let mut comments = Vec::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let key = format!("CHAPTER{:03}", i + 1);
+        comments.push((key.clone(), chapter_time(chapter.offset, rate)));
+        if let Some(ref title) = chapter.title {
+            comments.push((format!("{key}NAME"), title.clone()));
+        }
+    }
+    comments
+}
+
+/// A cue sheet for the CUESHEET block, or `None` unless the audio is CD-DA
+/// with every chapter on a CD frame boundary, which flac requires.
+pub fn cue_sheet(chapters: &[Chapter], stream: &StreamInfo) -> Option<String> {
+    let cd_da = stream.sample_rate == 44100 && stream.channels == 2 && stream.bits_per_sample == 16;
+    if !cd_da || chapters.iter().any(|c| c.offset % CD_FRAME != 0) {
+        return None;
+    }
+    let mut cue = String::from("FILE \"joined.wav\" WAVE\n");
+    for (i, chapter) in chapters.iter().enumerate() {
+        let frames = chapter.offset / CD_FRAME;
+        cue.push_str(&format!("  TRACK {:02} AUDIO\n", i + 1));
+        if let Some(ref title) = chapter.title {
+            cue.push_str(&format!("    TITLE \"{}\"\n", title.replace('"', "'")));
+        }
+        cue.push_str(&format!(
+            "    INDEX 01 {:02}:{:02}:{:02}\n",
+            frames / 75 / 60,
+            frames / 75 % 60,
+            frames % 75
+        ));
+    }
+    Some(cue)
+}
+
+/// Shared comments of all tracks, without the ones describing a single
+/// track.
+fn common_comments(metas: &[flac::Metadata]) -> Vec<(String, String)> {
+    metas[0]
+        .comments
+        .iter()
+        .filter(|c| !TRACK_FIELDS.contains(&c.0.as_str()))
+        .filter(|c| metas[1..].iter().all(|m| m.comments.contains(c)))
+        .cloned()
+        .collect()
+}
+
+/// Joins encoded tracks, in order, into `out_path` and removes them. The
+/// joined file keeps the comments the tracks share, is titled after the
+/// album and marks every track as a chapter.
+pub fn join(
+    tracks: &[PathBuf],
+    out_path: &Path,
+    cover: Option<&Path>,
+    settings: &[String],
+    work_dir: &TempDir,
+) -> Result<()> {
+    let metas = tracks
+        .iter()
+        .map(flac::read_metadata)
+        .collect::<Result<Vec<_>>>()?;
+    let stream = metas[0].stream;
+    let mut chapters = Vec::new();
+    let mut offset = 0;
+    for (path, meta) in tracks.iter().zip(&metas) {
+        let same_format = meta.stream.sample_rate == stream.sample_rate
+            && meta.stream.channels == stream.channels
+            && meta.stream.bits_per_sample == stream.bits_per_sample;
+        if !same_format || meta.stream.total_samples == 0 {
+            return Err(ReflacError::IncompatibleTracks(path.clone()));
+        }
+        chapters.push(Chapter {
+            offset,
+            title: meta.first("TITLE").map(String::from),
+        });
+        offset += meta.stream.total_samples;
+    }
+
+    // Raw samples of all tracks, one after another
+    let (raw_path, mut raw) = work_dir.unique_subfile("raw")?;
+    for path in tracks {
+        let (part_path, _) = work_dir.unique_subfile("raw")?;
+        run_command(
+            Command::new("flac")
+                .args(["--silent", "--decode", "--force", "--force-raw-format"])
+                .args(["--endian=little", "--sign=signed"])
+                .arg(format!("--output-name={}", part_path.to_str().unwrap()))
+                .arg(path)
+                .stdout(Stdio::null()),
+            "flac",
+        )?;
+        io::copy(&mut File::open(&part_path)?, &mut raw)?;
+        fs::remove_file(&part_path)?;
+    }
+    drop(raw);
+
+    let mut args = settings.to_vec();
+    args.extend([
+        String::from("--force-raw-format"),
+        String::from("--endian=little"),
+        String::from("--sign=signed"),
+        format!("--channels={}", stream.channels),
+        format!("--bps={}", stream.bits_per_sample),
+        format!("--sample-rate={}", stream.sample_rate),
+    ]);
+    if !stream.is_subset() {
+        args.push(String::from("--lax"));
+    }
+    if let Some(cue) = cue_sheet(&chapters, &stream) {
+        let (cue_path, _) = work_dir.unique_subfile("cue")?;
+        fs::write(&cue_path, cue)?;
+        args.push(format!("--cuesheet={}", cue_path.to_str().unwrap()));
+    } else {
+        info!("  Chapters are not on CD frames, writing chapter tags only");
+    }
+    if let Some(path) = cover {
+        args.push(format!("--picture={}", path.to_str().unwrap()));
+    }
+    args.push(format!("--output-name={}", out_path.to_str().unwrap()));
+    run_command(
+        Command::new("flac")
+            .arg("--silent")
+            .args(args)
+            .arg(&raw_path)
+            .stdout(Stdio::null()),
+        "flac",
+    )?;
+    fs::remove_file(&raw_path)?;
+
+    let mut comments = common_comments(&metas);
+    if let Some(album) = metas[0].first("ALBUM") {
+        comments.insert(0, (String::from("TITLE"), album.to_string()));
+    }
+    comments.extend(encoder_comments(settings, !stream.is_subset()));
+    comments.extend(chapter_comments(&chapters, stream.sample_rate));
+    flac::write_comments(out_path, &comments)?;
+
+    for path in tracks {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CD_DA: StreamInfo = StreamInfo {
+        sample_rate: 44100,
+        channels: 2,
+        bits_per_sample: 16,
+        total_samples: 0,
+        md5: [0; 16],
+    };
+
+    fn chapters(offsets: &[u64]) -> Vec<Chapter> {
+        offsets
+            .iter()
+            .enumerate()
+            .map(|(i, &offset)| Chapter {
+                offset,
+                title: Some(format!("Chapter \"{}\"", i + 1)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chapter_tags() {
+        let comments = chapter_comments(&chapters(&[0, 44100 * 3723 + 22050]), 44100);
+        assert_eq!(
+            comments,
+            [
+                (String::from("CHAPTER001"), String::from("00:00:00.000")),
+                (
+                    String::from("CHAPTER001NAME"),
+                    String::from("Chapter \"1\"")
+                ),
+                (String::from("CHAPTER002"), String::from("01:02:03.500")),
+                (
+                    String::from("CHAPTER002NAME"),
+                    String::from("Chapter \"2\"")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn cue_sheets_need_cd_frames() {
+        let cue = cue_sheet(&chapters(&[0, 588 * (75 * 61 + 3)]), &CD_DA).unwrap();
+        assert!(cue.contains("  TRACK 02 AUDIO\n    TITLE \"Chapter '2'\"\n"));
+        assert!(cue.contains("    INDEX 01 01:01:03\n"));
+        assert!(cue_sheet(&chapters(&[0, 1000]), &CD_DA).is_none());
+        let hi_res = StreamInfo {
+            sample_rate: 96000,
+            ..CD_DA
+        };
+        assert!(cue_sheet(&chapters(&[0]), &hi_res).is_none());
+    }
+}
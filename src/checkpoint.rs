@@ -0,0 +1,152 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! How far a run got after encoding, so `--resume` can finish an album
+//! whose run died during ReplayGain or later instead of encoding it again,
+//! and without recording it twice.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{ReflacError, Result};
+
+/// Name of the file inside the album directory; it is removed once the run
+/// has finished.
+pub const FILE_NAME: &str = ".reflac-checkpoint";
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Stage {
+    /// All tracks encoded, tagged, checked and joined
+    Encoded,
+    /// ReplayGain added
+    Gain,
+    /// Provenance written
+    Recorded,
+    /// Completed TRACKINFO written; only the checkpoint is left to remove
+    Finalized,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Checkpoint {
+    pub stage: Stage,
+    /// SHA-256 of the TRACKINFO file, which fixes the tracks and their order
+    pub trackinfo_sha256: String,
+    /// Output of every track, in order, with its concealed frames
+    pub tracks: Vec<(PathBuf, Option<u64>)>,
+}
+
+impl Checkpoint {
+    /// The checkpoint left in `album_path`, if any.
+    pub fn load(album_path: &Path) -> Result<Option<Self>> {
+        let text = match fs::read_to_string(album_path.join(FILE_NAME)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut stage = None;
+        let mut trackinfo_sha256 = None;
+        let mut tracks = Vec::new();
+        let invalid = || ReflacError::InvalidCheckpoint(album_path.join(FILE_NAME));
+        for line in text.lines() {
+            // File names may contain tabs
+            match line.splitn(3, '\t').collect::<Vec<_>>()[..] {
+                ["stage", "encoded"] => stage = Some(Stage::Encoded),
+                ["stage", "gain"] => stage = Some(Stage::Gain),
+                ["stage", "recorded"] => stage = Some(Stage::Recorded),
+                ["stage", "finalized"] => stage = Some(Stage::Finalized),
+                ["trackinfo", hash] => trackinfo_sha256 = Some(hash.to_string()),
+                ["track", frames, path] => {
+                    let frames = match frames {
+                        "" => None,
+                        n => Some(n.parse().map_err(|_| invalid())?),
+                    };
+                    tracks.push((PathBuf::from(path), frames));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        match (stage, trackinfo_sha256) {
+            (Some(stage), Some(trackinfo_sha256)) => Ok(Some(Self {
+                stage,
+                trackinfo_sha256,
+                tracks,
+            })),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Writes the checkpoint into `album_path`, replacing the previous one
+    /// in one step.
+    pub fn save(&self, album_path: &Path) -> Result<()> {
+        let stage = match self.stage {
+            Stage::Encoded => "encoded",
+            Stage::Gain => "gain",
+            Stage::Recorded => "recorded",
+            Stage::Finalized => "finalized",
+        };
+        let mut text = format!("stage\t{stage}\ntrackinfo\t{}\n", self.trackinfo_sha256);
+        for (path, frames) in &self.tracks {
+            let frames = frames.map(|n| n.to_string()).unwrap_or_default();
+            text.push_str(&format!("track\t{frames}\t{}\n", path.display()));
+        }
+        let partial = album_path.join(format!("{FILE_NAME}.partial"));
+        fs::write(&partial, text)?;
+        fs::rename(partial, album_path.join(FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Removes the checkpoint of a finished run.
+    pub fn remove(album_path: &Path) -> Result<()> {
+        match fs::remove_file(album_path.join(FILE_NAME)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        assert_eq!(Checkpoint::load(dir.path()).unwrap(), None);
+        let checkpoint = Checkpoint {
+            stage: Stage::Recorded,
+            trackinfo_sha256: String::from("ab12"),
+            tracks: vec![
+                (dir.path().join("01. One.flac"), None),
+                (dir.path().join("02. Two\twice.flac"), Some(3)),
+            ],
+        };
+        checkpoint.save(dir.path()).unwrap();
+        assert_eq!(Checkpoint::load(dir.path()).unwrap(), Some(checkpoint));
+        Checkpoint::remove(dir.path()).unwrap();
+        Checkpoint::remove(dir.path()).unwrap();
+        assert!(!dir.path().join(FILE_NAME).exists());
+
+        fs::write(dir.path().join(FILE_NAME), "stage\tencoded\n").unwrap();
+        assert!(Checkpoint::load(dir.path()).is_err());
+    }
+}
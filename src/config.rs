@@ -0,0 +1,444 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::cache;
+use crate::jobs::{self, TimeoutPolicy};
+use crate::normalize::{FeatTarget, Typography};
+use crate::quality::{self, Criterion};
+use crate::sandbox::Sandbox;
+use crate::{Collisions, DiscLayout, Naming, ReflacError, Result};
+
+/// How ReplayGain is computed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GainMode {
+    /// Album gain over all discs
+    Album,
+    /// Album gain per disc
+    Disc,
+    Off,
+}
+
+impl FromStr for GainMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "album" => Ok(GainMode::Album),
+            "disc" => Ok(GainMode::Disc),
+            "off" => Ok(GainMode::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Settings an album selects with `PROFILE=` in its TRACKINFO file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub encoder_settings: Option<Vec<String>>,
+    pub cover_max_size: Option<u64>,
+    pub replay_gain: Option<GainMode>,
+}
+
+pub struct Config {
+    pub genres: Vec<String>,
+    pub genre_aliases: HashMap<String, String>,
+    pub typography: Option<Typography>,
+    pub feat: Option<FeatTarget>,
+    pub feat_separator: Option<String>,
+    pub file_template: Option<String>,
+    pub disc_template: Option<String>,
+    pub naming: Option<Naming>,
+    pub on_collision: Option<Collisions>,
+    pub disc_layout: Option<DiscLayout>,
+    pub temp_dir: Option<PathBuf>,
+    /// Default OUTPUT_DIR
+    pub output_root: Option<PathBuf>,
+    pub sandbox: Option<Sandbox>,
+    pub keyring: Option<PathBuf>,
+    pub player: Option<String>,
+    /// Commands of tag providers by name
+    pub providers: HashMap<String, String>,
+    pub source_policy: Option<Vec<Criterion>>,
+    pub cache: bool,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_max_size: Option<u64>,
+    pub quota: Option<u64>,
+    /// SHA-256 of images never to embed (placeholders)
+    pub cover_blocklist: Vec<String>,
+    pub profiles: HashMap<String, Profile>,
+    pub timeout: Option<Option<Duration>>,
+    pub tool_timeouts: HashMap<String, Duration>,
+    pub stall_timeout: Option<Option<Duration>>,
+    pub on_timeout: Option<TimeoutPolicy>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<Duration>,
+    pub adaptive: bool,
+    pub max_track_time: Option<Duration>,
+    pub max_tag_length: Option<usize>,
+    /// Limits of single fields, overriding `max_tag_length`
+    pub field_max_lengths: HashMap<String, usize>,
+    /// File the configuration was read from
+    pub path: Option<PathBuf>,
+    /// Its settings, without comments, for the environment manifest
+    pub lines: Vec<String>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            genres: Vec::new(),
+            genre_aliases: HashMap::new(),
+            typography: None,
+            feat: None,
+            feat_separator: None,
+            file_template: None,
+            disc_template: None,
+            naming: None,
+            on_collision: None,
+            disc_layout: None,
+            temp_dir: None,
+            output_root: None,
+            sandbox: None,
+            keyring: None,
+            player: None,
+            providers: HashMap::new(),
+            source_policy: None,
+            cache: false,
+            cache_dir: None,
+            cache_max_size: None,
+            quota: None,
+            cover_blocklist: Vec::new(),
+            profiles: HashMap::new(),
+            timeout: None,
+            tool_timeouts: HashMap::new(),
+            stall_timeout: None,
+            on_timeout: None,
+            retries: None,
+            retry_delay: None,
+            adaptive: false,
+            max_track_time: None,
+            max_tag_length: None,
+            field_max_lengths: HashMap::new(),
+            path: None,
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+            Some(PathBuf::from(dir).join("reflac").join("config"))
+        } else {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/reflac/config"))
+        }
+    }
+
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        if let Some(path) = path {
+            if !path.exists() {
+                return Err(ReflacError::PathDoesNotExist(path.to_path_buf()));
+            }
+            Self::parse(path)
+        } else if let Some(path) = Self::default_path().filter(|p| p.exists()) {
+            Self::parse(path)
+        } else {
+            Ok(Self::new())
+        }
+    }
+
+    fn parse<P: AsRef<Path>>(path: P) -> Result<Self> {
+        static LINE_RE: LazyLock<regex::Regex> =
+            LazyLock::new(|| regex::Regex::new(r"^([A-Z_]+)(?:\[([^\]]*)\])?=(.*)$").unwrap());
+
+        let mut config = Self::new();
+        config.path = Some(path.as_ref().to_path_buf());
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            config.lines.push(line.clone());
+            let Some(caps) = LINE_RE.captures(line.as_str()) else {
+                return Err(ReflacError::InvalidConfig(line));
+            };
+            let value = caps[3].trim().to_string();
+            match (&caps[1], caps.get(2).map(|m| m.as_str().trim())) {
+                ("GENRE", None) => config.genres.push(value),
+                ("GENRE", Some(alias)) => {
+                    config.genre_aliases.insert(alias.to_lowercase(), value);
+                }
+                ("TYPOGRAPHY", None) => match value.parse() {
+                    Ok(style) => config.typography = Some(style),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("FEAT", None) => match value.parse() {
+                    Ok(target) => config.feat = Some(target),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("FEAT_SEPARATOR", None) => config.feat_separator = Some(value),
+                ("FILE_TEMPLATE", None) => config.file_template = Some(value),
+                ("DISC_TEMPLATE", None) => config.disc_template = Some(value),
+                ("NAMING", None) => match value.parse() {
+                    Ok(naming) => config.naming = Some(naming),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("DISC_LAYOUT", None) => match value.parse() {
+                    Ok(layout) => config.disc_layout = Some(layout),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("ON_COLLISION", None) => match value.parse() {
+                    Ok(policy) => config.on_collision = Some(policy),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("TEMP_DIR", None) => config.temp_dir = Some(PathBuf::from(value)),
+                ("OUTPUT_ROOT", None) => config.output_root = Some(PathBuf::from(value)),
+                ("KEYRING", None) => config.keyring = Some(PathBuf::from(value)),
+                ("PLAYER", None) => config.player = Some(value),
+                ("PROVIDER", Some(name)) if !value.is_empty() => {
+                    config.providers.insert(name.to_string(), value);
+                }
+                ("SOURCE_POLICY", None) => match quality::parse_policy(&value) {
+                    Some(policy) => config.source_policy = Some(policy),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("CACHE", None) => match value.as_str() {
+                    "yes" => config.cache = true,
+                    "no" => config.cache = false,
+                    _ => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("CACHE_DIR", None) => config.cache_dir = Some(PathBuf::from(value)),
+                ("CACHE_MAX_SIZE", None) => match cache::parse_size(&value) {
+                    Some(size) => config.cache_max_size = Some(size),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("QUOTA", None) => match cache::parse_size(&value) {
+                    Some(size) => config.quota = Some(size),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("COVER_BLOCKLIST", None)
+                    if value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()) =>
+                {
+                    config.cover_blocklist.push(value.to_ascii_lowercase());
+                }
+                ("ENCODER_SETTINGS", Some(name)) if !value.is_empty() => {
+                    config.profile(name).encoder_settings =
+                        Some(value.split_whitespace().map(String::from).collect());
+                }
+                ("COVER_MAX_SIZE", Some(name)) => match cache::parse_size(&value) {
+                    Some(size) => config.profile(name).cover_max_size = Some(size),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("REPLAYGAIN", Some(name)) => match value.parse() {
+                    Ok(mode) => config.profile(name).replay_gain = Some(mode),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("SANDBOX", None) => match value.parse() {
+                    Ok(sandbox) => config.sandbox = Some(sandbox),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("TIMEOUT", tool) => match (jobs::parse_seconds(&value), tool) {
+                    (Some(timeout), None) => config.timeout = Some(timeout),
+                    (Some(Some(timeout)), Some(tool)) => {
+                        config.tool_timeouts.insert(tool.to_string(), timeout);
+                    }
+                    _ => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("STALL_TIMEOUT", None) => match jobs::parse_seconds(&value) {
+                    Some(timeout) => config.stall_timeout = Some(timeout),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("ON_TIMEOUT", None) => match value.parse() {
+                    Ok(policy) => config.on_timeout = Some(policy),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("RETRIES", None) => match value.parse() {
+                    Ok(retries) => config.retries = Some(retries),
+                    Err(_) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("RETRY_DELAY", None) => match jobs::parse_seconds(&value) {
+                    Some(delay) => config.retry_delay = Some(delay.unwrap_or_default()),
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("ADAPTIVE", None) => match value.as_str() {
+                    "yes" => config.adaptive = true,
+                    "no" => config.adaptive = false,
+                    _ => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("MAX_TRACK_TIME", None) => match jobs::parse_seconds(&value) {
+                    Some(budget) => config.max_track_time = budget,
+                    None => return Err(ReflacError::InvalidConfig(line)),
+                },
+                ("MAX_TAG_LENGTH", field) => match (value.parse(), field) {
+                    (Ok(limit), None) => config.max_tag_length = Some(limit),
+                    (Ok(limit), Some(field)) => {
+                        config
+                            .field_max_lengths
+                            .insert(field.to_ascii_uppercase(), limit);
+                    }
+                    (Err(_), _) => return Err(ReflacError::InvalidConfig(line)),
+                },
+                _ => return Err(ReflacError::InvalidConfig(line)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn profile(&mut self, name: &str) -> &mut Profile {
+        self.profiles.entry(name.to_string()).or_default()
+    }
+
+    /// Maps a genre onto the configured vocabulary. Returns the canonical
+    /// spelling and whether the genre is known.
+    pub fn normalize_genre(&self, genre: &str) -> (String, bool) {
+        let key = genre.to_lowercase();
+        if let Some(mapped) = self.genre_aliases.get(&key) {
+            return (mapped.clone(), true);
+        }
+        if let Some(known) = self
+            .genres
+            .iter()
+            .chain(self.genre_aliases.values())
+            .find(|g| g.to_lowercase() == key)
+        {
+            return (known.clone(), true);
+        }
+        (genre.to_string(), self.genres.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Result<Config> {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("config");
+        std::fs::write(&path, config).unwrap();
+        Config::parse(&path)
+    }
+
+    #[test]
+    fn genres_and_aliases() {
+        let config =
+            parse("# Genres\nGENRE=Post-Rock\nGENRE[Alt Rock]=Alternative Rock\n").unwrap();
+        assert_eq!(
+            config.normalize_genre("post-rock"),
+            (String::from("Post-Rock"), true)
+        );
+        assert_eq!(
+            config.normalize_genre("ALT ROCK"),
+            (String::from("Alternative Rock"), true)
+        );
+        assert_eq!(
+            config.normalize_genre("Jazz"),
+            (String::from("Jazz"), false)
+        );
+    }
+
+    #[test]
+    fn unknown_genres_without_vocabulary() {
+        let config = parse("GENRE[Alt Rock]=Alternative Rock\n").unwrap();
+        assert_eq!(config.normalize_genre("Jazz"), (String::from("Jazz"), true));
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = parse("TIMEOUT=60\nTIMEOUT[unrar]=600\nSTALL_TIMEOUT=0\n").unwrap();
+        assert_eq!(config.timeout, Some(Some(Duration::from_secs(60))));
+        assert_eq!(
+            config.tool_timeouts.get("unrar"),
+            Some(&Duration::from_secs(600))
+        );
+        assert_eq!(config.stall_timeout, Some(None));
+        let config = parse("RETRIES=2\nRETRY_DELAY=0\n").unwrap();
+        assert_eq!(config.retries, Some(2));
+        assert_eq!(config.retry_delay, Some(Duration::ZERO));
+        assert!(parse("TIMEOUT[unrar]=0\n").is_err());
+        assert!(parse("ON_TIMEOUT=later\n").is_err());
+    }
+
+    #[test]
+    fn cache_settings() {
+        let config = parse("CACHE=yes\nCACHE_DIR=/var/cache/reflac\nCACHE_MAX_SIZE=50G\n").unwrap();
+        assert!(config.cache);
+        assert_eq!(config.cache_dir, Some(PathBuf::from("/var/cache/reflac")));
+        assert_eq!(config.cache_max_size, Some(50 << 30));
+        assert!(parse("CACHE=maybe\n").is_err());
+    }
+
+    #[test]
+    fn source_policy() {
+        let config = parse("SOURCE_POLICY=bits, lossless\n").unwrap();
+        assert_eq!(
+            config.source_policy,
+            Some(vec![Criterion::Bits, Criterion::Lossless])
+        );
+        assert!(parse("SOURCE_POLICY=loudest\n").is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let config = parse(
+            "ENCODER_SETTINGS[fast]=-5\nREPLAYGAIN[fast]=off\n\
+             COVER_MAX_SIZE[portable]=512K\nREPLAYGAIN[portable]=disc\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.profiles["fast"],
+            Profile {
+                encoder_settings: Some(vec![String::from("-5")]),
+                cover_max_size: None,
+                replay_gain: Some(GainMode::Off),
+            }
+        );
+        assert_eq!(config.profiles["portable"].cover_max_size, Some(512 << 10));
+        assert_eq!(
+            config.profiles["portable"].replay_gain,
+            Some(GainMode::Disc)
+        );
+        assert!(parse("REPLAYGAIN[fast]=track\n").is_err());
+        assert!(parse("ENCODER_SETTINGS[fast]=\n").is_err());
+    }
+
+    #[test]
+    fn tag_lengths() {
+        let config = parse("MAX_TAG_LENGTH=1000\nMAX_TAG_LENGTH[comment]=0\n").unwrap();
+        assert_eq!(config.max_tag_length, Some(1000));
+        assert_eq!(config.field_max_lengths.get("COMMENT"), Some(&0));
+        assert!(parse("MAX_TAG_LENGTH=long\n").is_err());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(parse("TYPOGRAPHY=fancy\n").is_err());
+        assert!(parse("UNKNOWN=1\n").is_err());
+        assert!(parse("not a setting\n").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Human progress and log messages go to stderr; stdout only receives
+//! results (output paths, reports, exported files) so it can be piped.
+
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// `--color`: whether messages are colored.
+static COLOR: AtomicBool = AtomicBool::new(false);
+/// `--quiet`: only warnings and errors are shown.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+pub enum ColorChoice {
+    Auto,
+    Never,
+    Always,
+}
+
+impl FromStr for ColorChoice {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "never" => Ok(ColorChoice::Never),
+            "always" => Ok(ColorChoice::Always),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether progress output should be shown, i.e. stderr is a terminal.
+pub fn is_tty() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+pub fn set_color(choice: ColorChoice) {
+    let color = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            is_tty()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && std::env::var("TERM").is_ok_and(|t| t != "dumb")
+        }
+    };
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// A message label such as "ERROR", colored if enabled.
+pub fn label(name: &str) -> String {
+    if !COLOR.load(Ordering::Relaxed) {
+        return name.to_string();
+    }
+    let code = match name {
+        "ERROR" => "1;31",
+        "WARNING" => "33",
+        _ => "1",
+    };
+    format!("\x1b[{code}m{name}\x1b[0m")
+}
+
+/// Makes file names and tag values safe to print: control characters and
+/// bidirectional overrides, which can garble or spoof terminal output, are
+/// replaced by escape sequences.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\u{061c}'
+            | '\u{200e}'
+            | '\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2066}'..='\u{2069}' => escaped.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A warning printed during the run, kept for the report and the summary.
+#[derive(Clone)]
+pub struct Warning {
+    pub category: &'static str,
+    pub message: String,
+}
+
+static WARNINGS: Mutex<Vec<Warning>> = Mutex::new(Vec::new());
+
+pub fn record(category: &'static str, message: String) {
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    warnings.push(Warning { category, message });
+}
+
+pub fn warnings() -> Vec<Warning> {
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Warning counts per category, most frequent first, e.g. "3 trimmed,
+/// 1 fallback".
+pub fn summary(warnings: &[Warning]) -> String {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for warning in warnings {
+        match counts.iter_mut().find(|(c, _)| *c == warning.category) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((warning.category, 1)),
+        }
+    }
+    counts.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+    counts
+        .iter()
+        .map(|(category, n)| format!("{n} {category}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats a message for stderr. Every line is escaped on its own, so a
+/// multi-line message stays readable; continuation lines are indented so
+/// they cannot pass for messages of their own. A track message starts with
+/// "  #N ".
+pub fn format(label: Option<&str>, track: Option<&str>, message: &str) -> String {
+    let mut text = String::new();
+    for (i, line) in message.split('\n').enumerate() {
+        if i > 0 {
+            text.push_str("    ");
+        } else if let Some(label) = label {
+            text.push_str(label);
+            text.push_str(": ");
+        }
+        match track {
+            Some(track) if i == 0 => {
+                text.push_str("  ");
+                text.push_str(&escape(&for_track(track, line)));
+            }
+            _ => text.push_str(&escape(line)),
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// "#3 → …" or "#3: …".
+pub fn for_track(track: &str, message: &str) -> String {
+    let separator = if message.starts_with(':') { "" } else { " " };
+    format!("#{track}{separator}{message}")
+}
+
+/// The one place messages reach stderr. The whole message is written with a
+/// single call while stderr is locked, so lines from concurrent jobs never
+/// interleave, and above the progress bars if they are shown. Unlabeled
+/// messages are dropped with `--quiet`.
+pub fn report(label: Option<&str>, track: Option<&str>, message: &str) {
+    if label.is_none() && is_quiet() {
+        return;
+    }
+    let text = format(label.map(self::label).as_deref(), track, message);
+    crate::progress::print(&text);
+}
+
+/// Prints a progress or log message to stderr, optionally for a track:
+/// `info!(track = tag.id(); "→ \"{name}\"")`.
+macro_rules! info {
+    () => {
+        $crate::console::report(None, None, "")
+    };
+    (track = $track:expr; $($arg:tt)*) => {
+        $crate::console::report(None, Some(&$track.to_string()), &format!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::console::report(None, None, &format!($($arg)*))
+    };
+}
+
+/// Prints a warning and records it under a category (`other` if none is
+/// given): `warning!(trimmed: "Line {line} trimmed!")`.
+macro_rules! warning {
+    ($category:ident: track = $track:expr; $($arg:tt)*) => {{
+        let track = $track.to_string();
+        let message = format!($($arg)*);
+        $crate::console::report(Some("WARNING"), Some(&track), &message);
+        $crate::console::record(
+            stringify!($category),
+            $crate::console::for_track(&track, &message),
+        );
+    }};
+    ($category:ident: $($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::console::report(Some("WARNING"), None, &message);
+        $crate::console::record(stringify!($category), message);
+    }};
+    ($($arg:tt)*) => {
+        warning!(other: $($arg)*)
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::console::report(Some("ERROR"), None, &format!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_are_summarized_by_category() {
+        let warning = |category| Warning {
+            category,
+            message: String::new(),
+        };
+        let warnings = [warning("fallback"), warning("trimmed"), warning("trimmed")];
+        assert_eq!(summary(&warnings), "2 trimmed, 1 fallback");
+    }
+
+    #[test]
+    fn escapes_control_and_bidi_characters() {
+        assert_eq!(escape("a\nb\tc"), "a\\nb\\tc");
+        assert_eq!(escape("Evil\u{202e}gpj.exe"), "Evil\\u{202e}gpj.exe");
+        assert_eq!(escape("\u{1b}[31m"), "\\u{001b}[31m");
+        assert_eq!(escape("春 – “ok”"), "春 – “ok”");
+    }
+
+    #[test]
+    fn messages_are_formatted_line_by_line() {
+        assert_eq!(format(None, None, "Done"), "Done\n");
+        assert_eq!(format(None, Some("3"), "→ \"a\tb\""), "  #3 → \"a\\tb\"\n");
+        assert_eq!(format(None, Some("3"), ": retrying"), "  #3: retrying\n");
+        assert_eq!(
+            format(Some("ERROR"), None, "flac failed\nline 1\nline 2"),
+            "ERROR: flac failed\n    line 1\n    line 2\n"
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+/// A track of a cue sheet.
+#[derive(Debug, PartialEq)]
+pub struct Track {
+    pub number: usize,
+    /// The audio file the track is in, as written in the sheet
+    pub file: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start (`INDEX 01`) in CD frames of 1/75 s
+    pub start: u64,
+}
+
+/// Splits a cue sheet line into its words; quoted words may contain spaces.
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            words.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Parses an `mm:ss:ff` time into CD frames. Times beyond a week are
+/// refused, which keeps sample positions from overflowing.
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || minutes >= 7 * 24 * 60 || seconds >= 60 || frames >= 75 {
+        return None;
+    }
+    Some((minutes * 60 + seconds) * 75 + frames)
+}
+
+/// Parses the tracks of a cue sheet. `REM` lines, flags, ISRCs and pregaps
+/// are ignored: a track starts at its `INDEX 01`, so a pregap stays at the
+/// end of the track before it.
+pub fn parse(text: &str) -> Result<Vec<Track>, String> {
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut file = None;
+    let mut started = false;
+    for (i, line) in text.lines().enumerate() {
+        let invalid = |reason: &str| format!("line {}: {reason}", i + 1);
+        let words = words(line.trim_start_matches('\u{feff}'));
+        let Some(keyword) = words.first() else {
+            continue;
+        };
+        match (keyword.to_uppercase().as_str(), &words[1..]) {
+            ("FILE", [name, ..]) => file = Some(name.clone()),
+            ("TRACK", [number, ..]) => {
+                let Some(ref file) = file else {
+                    return Err(invalid("TRACK before FILE"));
+                };
+                let number = number
+                    .parse()
+                    .map_err(|_| invalid("invalid track number"))?;
+                if tracks.last().is_some_and(|t| t.number >= number) {
+                    return Err(invalid("track numbers must increase"));
+                }
+                if tracks.last().is_some_and(|_| !started) {
+                    return Err(invalid("previous track has no INDEX 01"));
+                }
+                tracks.push(Track {
+                    number,
+                    file: file.clone(),
+                    title: None,
+                    performer: None,
+                    start: 0,
+                });
+                started = false;
+            }
+            ("INDEX", [number, time]) => {
+                let Some(track) = tracks.last_mut() else {
+                    return Err(invalid("INDEX outside of a track"));
+                };
+                let start = parse_time(time).ok_or_else(|| invalid("invalid time"))?;
+                if number.parse::<u32>() == Ok(1) {
+                    track.start = start;
+                    started = true;
+                }
+            }
+            ("TITLE", [title]) => {
+                if let Some(track) = tracks.last_mut() {
+                    track.title = Some(title.clone());
+                }
+            }
+            ("PERFORMER", [performer]) => {
+                if let Some(track) = tracks.last_mut() {
+                    track.performer = Some(performer.clone());
+                }
+            }
+            ("FILE" | "TRACK" | "INDEX", _) => return Err(invalid("missing value")),
+            _ => (),
+        }
+    }
+    if tracks.is_empty() {
+        return Err(String::from("no tracks"));
+    }
+    if !started {
+        return Err(String::from("last track has no INDEX 01"));
+    }
+    for pair in tracks.windows(2) {
+        if pair[0].file == pair[1].file && pair[1].start <= pair[0].start {
+            return Err(format!(
+                "track {} starts before track {}",
+                pair[1].number, pair[0].number
+            ));
+        }
+    }
+    Ok(tracks)
+}
+
+/// The first sample of a track and the first one after it (`None` for the
+/// end of the file), at `sample_rate`.
+pub fn sample_range(tracks: &[Track], index: usize, sample_rate: u64) -> (u64, Option<u64>) {
+    let to_samples = |frames: u64| frames * sample_rate / 75;
+    let track = &tracks[index];
+    let end = tracks
+        .get(index + 1)
+        .filter(|next| next.file == track.file)
+        .map(|next| to_samples(next.start));
+    (to_samples(track.start), end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Rock\n\
+        PERFORMER \"Artist\"\n\
+        TITLE \"Album\"\n\
+        FILE \"Album Image.flac\" WAVE\n\
+        \x20 TRACK 01 AUDIO\n\
+        \x20   TITLE \"One\"\n\
+        \x20   INDEX 01 00:00:00\n\
+        \x20 TRACK 02 AUDIO\n\
+        \x20   TITLE \"Two: \"\n\
+        \x20   PERFORMER \"Guest\"\n\
+        \x20   INDEX 00 03:10:00\n\
+        \x20   INDEX 01 03:12:37\n";
+
+    #[test]
+    fn parses_tracks() {
+        let tracks = parse(SHEET).unwrap();
+        assert_eq!(
+            tracks[1],
+            Track {
+                number: 2,
+                file: String::from("Album Image.flac"),
+                title: Some(String::from("Two: ")),
+                performer: Some(String::from("Guest")),
+                start: (3 * 60 + 12) * 75 + 37,
+            }
+        );
+        assert_eq!(tracks[0].title.as_deref(), Some("One"));
+        assert_eq!(tracks[0].performer, None);
+        assert_eq!(sample_range(&tracks, 0, 44100), (0, Some(8_488_956)));
+        assert_eq!(sample_range(&tracks, 1, 44100), (8_488_956, None));
+    }
+
+    #[test]
+    fn rejects_broken_sheets() {
+        assert_eq!(
+            parse("TRACK 01 AUDIO\n").unwrap_err(),
+            "line 1: TRACK before FILE"
+        );
+        assert_eq!(
+            parse("FILE a.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 00:61:00\n").unwrap_err(),
+            "line 3: invalid time"
+        );
+        assert_eq!(
+            parse("FILE a.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 99999999999999999:00:00\n")
+                .unwrap_err(),
+            "line 3: invalid time"
+        );
+        assert_eq!(
+            parse("FILE a.flac WAVE\nTRACK 01 AUDIO\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n")
+                .unwrap_err(),
+            "line 3: previous track has no INDEX 01"
+        );
+        assert_eq!(parse("REM nothing\n").unwrap_err(), "no tracks");
+    }
+}
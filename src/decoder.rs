@@ -0,0 +1,764 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! A decoder for FLAC audio frames, so sources reach the encoder without a
+//! `flac --decode` process per track. Every frame is checked against its
+//! CRCs and STREAMINFO, and the decoded samples against the recorded MD5.
+
+use std::io::{self, BufReader, Read, Write};
+
+use crate::flac::{self, StreamInfo};
+use crate::hash::Md5;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid FLAC data: {reason}"),
+    )
+}
+
+const CRC8: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 0x80 != 0 {
+                (c << 1) ^ 0x07
+            } else {
+                c << 1
+            };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+const CRC16: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 0x8000 != 0 {
+                (c << 1) ^ 0x8005
+            } else {
+                c << 1
+            };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// Reads a stream most significant bit first, as FLAC packs it. Bytes are
+/// only fetched when needed, so the CRCs of a frame cover exactly its bytes.
+struct Bits<R> {
+    input: BufReader<R>,
+    /// Bits not taken yet, aligned to the top
+    bits: u64,
+    count: u32,
+    crc8: u8,
+    crc16: u16,
+}
+
+impl<R: Read> Bits<R> {
+    fn pull(&mut self) -> io::Result<()> {
+        let mut byte = [0];
+        match self.input.read_exact(&mut byte) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid("unexpected end"));
+            }
+            result => result?,
+        }
+        let b = byte[0];
+        self.crc8 = CRC8[(self.crc8 ^ b) as usize];
+        self.crc16 = (self.crc16 << 8) ^ CRC16[((self.crc16 >> 8) as u8 ^ b) as usize];
+        self.bits |= (b as u64) << (56 - self.count);
+        self.count += 8;
+        Ok(())
+    }
+
+    /// The next `n` bits (at most 56) as an unsigned number.
+    fn take(&mut self, n: u32) -> io::Result<u64> {
+        if n == 0 {
+            return Ok(0);
+        }
+        while self.count < n {
+            self.pull()?;
+        }
+        let value = self.bits >> (64 - n);
+        self.bits <<= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// The next `n` bits as a two's complement number.
+    fn signed(&mut self, n: u32) -> io::Result<i64> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let value = self.take(n)?;
+        Ok(((value << (64 - n)) as i64) >> (64 - n))
+    }
+
+    /// The number of zero bits before the next one bit.
+    fn unary(&mut self) -> io::Result<u64> {
+        let mut zeros = 0;
+        loop {
+            if self.count > 0 && self.bits != 0 {
+                let n = self.bits.leading_zeros();
+                self.bits <<= n + 1;
+                self.count -= n + 1;
+                return Ok(zeros + n as u64);
+            }
+            zeros += self.count as u64;
+            self.bits = 0;
+            self.count = 0;
+            if zeros > u32::MAX as u64 {
+                return Err(invalid("residual out of range"));
+            }
+            self.pull()?;
+        }
+    }
+
+    /// Drops the bits left of the current byte; frames end at a byte
+    /// boundary.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    /// Whether the input has ended at a byte boundary.
+    fn at_end(&mut self) -> io::Result<bool> {
+        Ok(self.count == 0 && io::BufRead::fill_buf(&mut self.input)?.is_empty())
+    }
+
+    fn start_frame(&mut self) {
+        self.crc8 = 0;
+        self.crc16 = 0;
+    }
+}
+
+/// The samples of a subframe: predicted from the first `order` samples with
+/// the rest read as residuals.
+fn residual<R: Read>(bits: &mut Bits<R>, samples: &mut [i64], order: usize) -> io::Result<()> {
+    let param_bits = match bits.take(2)? {
+        0 => 4,
+        1 => 5,
+        _ => return Err(invalid("reserved residual coding")),
+    };
+    let escape = (1 << param_bits) - 1;
+    let partition_order = bits.take(4)?;
+    let partition_len = samples.len() >> partition_order;
+    if partition_len << partition_order != samples.len() || partition_len < order {
+        return Err(invalid("bad partition order"));
+    }
+    let mut i = order;
+    for partition in 0..1 << partition_order {
+        let end = (partition + 1) * partition_len;
+        let param = bits.take(param_bits)? as u32;
+        if param == escape {
+            let raw = bits.take(5)? as u32;
+            for sample in &mut samples[i..end] {
+                *sample = bits.signed(raw)?;
+            }
+        } else {
+            for sample in &mut samples[i..end] {
+                let value = (bits.unary()? << param) | bits.take(param)?;
+                *sample = (value >> 1) as i64 ^ -((value & 1) as i64);
+            }
+        }
+        i = end;
+    }
+    Ok(())
+}
+
+/// Decodes a subframe of `bps` bits per sample into `samples`.
+fn subframe<R: Read>(bits: &mut Bits<R>, samples: &mut [i64], bps: u32) -> io::Result<()> {
+    let header = bits.take(8)?;
+    if header & 0x80 != 0 {
+        return Err(invalid("bad subframe header"));
+    }
+    let wasted = match header & 1 {
+        0 => 0,
+        _ => bits.unary()? as u32 + 1,
+    };
+    if wasted >= bps {
+        return Err(invalid("too many wasted bits"));
+    }
+    let bps = bps - wasted;
+    match (header >> 1) & 0x3f {
+        0 => samples.fill(bits.signed(bps)?),
+        1 => {
+            for sample in samples.iter_mut() {
+                *sample = bits.signed(bps)?;
+            }
+        }
+        kind @ 8..=12 => {
+            let order = kind as usize - 8;
+            if order > samples.len() {
+                return Err(invalid("predictor order exceeds block size"));
+            }
+            for sample in &mut samples[..order] {
+                *sample = bits.signed(bps)?;
+            }
+            residual(bits, samples, order)?;
+            for i in order..samples.len() {
+                let s = &samples[i - order..i];
+                samples[i] += match order {
+                    0 => 0,
+                    1 => s[0],
+                    2 => 2 * s[1] - s[0],
+                    3 => 3 * s[2] - 3 * s[1] + s[0],
+                    _ => 4 * s[3] - 6 * s[2] + 4 * s[1] - s[0],
+                };
+            }
+        }
+        kind @ 32..=63 => {
+            let order = kind as usize - 31;
+            if order > samples.len() {
+                return Err(invalid("predictor order exceeds block size"));
+            }
+            for sample in &mut samples[..order] {
+                *sample = bits.signed(bps)?;
+            }
+            let precision = bits.take(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(invalid("bad coefficient precision"));
+            }
+            let shift = bits.signed(5)?;
+            if shift < 0 {
+                return Err(invalid("negative prediction shift"));
+            }
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(bits.signed(precision)?);
+            }
+            residual(bits, samples, order)?;
+            for i in order..samples.len() {
+                let prediction: i64 = coefficients
+                    .iter()
+                    .zip(samples[i - order..i].iter().rev())
+                    .map(|(c, s)| c * s)
+                    .sum();
+                samples[i] += prediction >> shift;
+            }
+        }
+        _ => return Err(invalid("reserved subframe type")),
+    }
+    if wasted > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted;
+        }
+    }
+    Ok(())
+}
+
+/// A FLAC stream whose metadata has been read, ready to decode its frames.
+pub struct Decoder<R> {
+    bits: Bits<R>,
+    pub stream: StreamInfo,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Reads the metadata blocks up to the first frame. A leading ID3v2 tag
+    /// is skipped.
+    pub fn new(input: R) -> io::Result<Self> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic[..3] == b"ID3" {
+            let mut header = [0; 6];
+            input.read_exact(&mut header)?;
+            let size = header[2..]
+                .iter()
+                .fold(0u64, |size, &b| (size << 7) | (b & 0x7f) as u64);
+            io::copy(&mut (&mut input).take(size), &mut io::sink())?;
+            input.read_exact(&mut magic)?;
+        }
+        if &magic != b"fLaC" {
+            return Err(invalid("no fLaC marker"));
+        }
+        let mut stream = None;
+        loop {
+            let mut header = [0; 4];
+            input.read_exact(&mut header)?;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+            let mut data = Vec::new();
+            (&mut input).take(len).read_to_end(&mut data)?;
+            if data.len() as u64 != len {
+                return Err(invalid("truncated metadata"));
+            }
+            if header[0] & 0x7f == flac::STREAMINFO {
+                stream = flac::parse_stream_info(&data);
+            }
+            if header[0] & 0x80 != 0 {
+                break;
+            }
+        }
+        let stream = stream.ok_or_else(|| invalid("no STREAMINFO"))?;
+        Ok(Self {
+            bits: Bits {
+                input,
+                bits: 0,
+                count: 0,
+                crc8: 0,
+                crc16: 0,
+            },
+            stream,
+        })
+    }
+
+    /// Decodes all frames into `output` as interleaved little-endian signed
+    /// samples of whole bytes, as `flac --force-raw-format` reads them.
+    /// Returns the number of samples per channel. Frames that do not match
+    /// STREAMINFO or their CRCs, a wrong sample count and a wrong MD5 fail
+    /// with `InvalidData`.
+    pub fn decode<W: Write>(mut self, output: &mut W) -> io::Result<u64> {
+        let width = (self.stream.bits_per_sample as usize).div_ceil(8);
+        let mut channels = vec![Vec::new(); self.stream.channels as usize];
+        let mut md5 = Md5::new();
+        let mut buffer = Vec::new();
+        let mut total = 0;
+        while !self.bits.at_end()? {
+            let block_size = self.frame(&mut channels)?;
+            buffer.clear();
+            for i in 0..block_size {
+                for channel in &channels {
+                    buffer.extend_from_slice(&(channel[i] as i32).to_le_bytes()[..width]);
+                }
+            }
+            md5 = md5.update(&buffer);
+            output.write_all(&buffer)?;
+            total += block_size as u64;
+        }
+        if self.stream.total_samples != 0 && total != self.stream.total_samples {
+            return Err(invalid(&format!(
+                "{total} samples, STREAMINFO says {}",
+                self.stream.total_samples
+            )));
+        }
+        if self.stream.md5 != [0; 16] && md5.finish() != self.stream.md5 {
+            return Err(invalid("MD5 of the audio differs from STREAMINFO"));
+        }
+        Ok(total)
+    }
+
+    /// Decodes the next frame into `channels` and returns its block size.
+    fn frame(&mut self, channels: &mut [Vec<i64>]) -> io::Result<usize> {
+        let bits = &mut self.bits;
+        let stream = &self.stream;
+        bits.start_frame();
+        if bits.take(15)? != 0x7ffc {
+            return Err(invalid("lost frame sync"));
+        }
+        // Blocking strategy
+        bits.take(1)?;
+        let block_size_code = bits.take(4)?;
+        let sample_rate_code = bits.take(4)?;
+        let assignment = bits.take(4)?;
+        let bps = match bits.take(3)? {
+            0 => stream.bits_per_sample as u32,
+            1 => 8,
+            2 => 12,
+            4 => 16,
+            5 => 20,
+            6 => 24,
+            7 => 32,
+            _ => return Err(invalid("reserved sample size")),
+        };
+        if bits.take(1)? != 0 {
+            return Err(invalid("reserved bit set"));
+        }
+        // Frame or sample number, coded like UTF-8
+        let first = bits.take(8)?;
+        let extra = match (first as u8).leading_ones() {
+            0 => 0,
+            n @ 2..=7 => n - 1,
+            _ => return Err(invalid("bad frame number")),
+        };
+        for _ in 0..extra {
+            if bits.take(8)? & 0xc0 != 0x80 {
+                return Err(invalid("bad frame number"));
+            }
+        }
+        let block_size = match block_size_code {
+            0 => return Err(invalid("reserved block size")),
+            1 => 192,
+            2..=5 => 576 << (block_size_code - 2),
+            6 => bits.take(8)? as usize + 1,
+            7 => bits.take(16)? as usize + 1,
+            _ => 256 << (block_size_code - 8),
+        };
+        let sample_rate = match sample_rate_code {
+            0 => stream.sample_rate,
+            1 => 88200,
+            2 => 176400,
+            3 => 192000,
+            4 => 8000,
+            5 => 16000,
+            6 => 22050,
+            7 => 24000,
+            8 => 32000,
+            9 => 44100,
+            10 => 48000,
+            11 => 96000,
+            12 => bits.take(8)? as u32 * 1000,
+            13 => bits.take(16)? as u32,
+            14 => bits.take(16)? as u32 * 10,
+            _ => return Err(invalid("bad sample rate")),
+        };
+        let crc8 = bits.crc8;
+        if bits.take(8)? as u8 != crc8 {
+            return Err(invalid("frame header CRC mismatch"));
+        }
+        let count = match assignment {
+            0..=7 => assignment as usize + 1,
+            8..=10 => 2,
+            _ => return Err(invalid("reserved channel assignment")),
+        };
+        if sample_rate != stream.sample_rate
+            || bps != stream.bits_per_sample as u32
+            || count != channels.len()
+        {
+            return Err(invalid("frame format differs from STREAMINFO"));
+        }
+
+        for (i, channel) in channels.iter_mut().enumerate() {
+            // The side channel needs a bit more
+            let side = match assignment {
+                8 | 10 => i == 1,
+                9 => i == 0,
+                _ => false,
+            };
+            channel.resize(block_size, 0);
+            subframe(bits, channel, bps + side as u32)?;
+        }
+        bits.align();
+        let crc16 = bits.crc16;
+        if bits.take(16)? as u16 != crc16 {
+            return Err(invalid("frame CRC mismatch"));
+        }
+
+        if let [left, right] = channels {
+            for (a, b) in left.iter_mut().zip(right.iter_mut()) {
+                (*a, *b) = match assignment {
+                    // Left and side
+                    8 => (*a, *a - *b),
+                    // Side and right
+                    9 => (*a + *b, *b),
+                    // Mid and side
+                    10 => {
+                        let mid = (*a << 1) | (*b & 1);
+                        ((mid + *b) >> 1, (mid - *b) >> 1)
+                    }
+                    _ => (*a, *b),
+                };
+            }
+        }
+        Ok(block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs bits most significant first.
+    #[derive(Default)]
+    struct Writer {
+        bytes: Vec<u8>,
+        bits: u64,
+        count: u32,
+    }
+
+    impl Writer {
+        fn put(&mut self, n: u32, value: u64) {
+            for i in (0..n).rev() {
+                self.bits = (self.bits << 1) | ((value >> i) & 1);
+                self.count += 1;
+                if self.count == 8 {
+                    self.bytes.push(self.bits as u8);
+                    self.bits = 0;
+                    self.count = 0;
+                }
+            }
+        }
+
+        fn signed(&mut self, n: u32, value: i64) {
+            self.put(n, value as u64 & ((1 << n) - 1));
+        }
+
+        fn rice(&mut self, param: u32, value: i64) {
+            let folded = ((value << 1) ^ (value >> 63)) as u64;
+            for _ in 0..folded >> param {
+                self.put(1, 0);
+            }
+            self.put(1, 1);
+            self.put(param, folded & ((1 << param) - 1));
+        }
+
+        fn align(&mut self) {
+            while self.count != 0 {
+                self.put(1, 0);
+            }
+        }
+    }
+
+    enum Kind {
+        Constant,
+        Verbatim,
+        Fixed(usize),
+        /// Coefficients, their precision and the shift
+        Lpc(Vec<i64>, u32, u32),
+    }
+
+    /// Residuals of the first partition are Rice coded, those of the second
+    /// escaped.
+    fn residual(w: &mut Writer, residuals: &[i64], block_size: usize) {
+        w.put(2, 0);
+        w.put(4, 1);
+        let split = block_size / 2 - (block_size - residuals.len());
+        w.put(4, 3);
+        for &r in &residuals[..split] {
+            w.rice(3, r);
+        }
+        w.put(4, 15);
+        w.put(5, 20);
+        for &r in &residuals[split..] {
+            w.signed(20, r);
+        }
+    }
+
+    fn subframe(w: &mut Writer, samples: &[i64], bps: u32, kind: &Kind, wasted: u32) {
+        let code = match kind {
+            Kind::Constant => 0,
+            Kind::Verbatim => 1,
+            Kind::Fixed(order) => 8 + *order as u64,
+            Kind::Lpc(coefficients, ..) => 31 + coefficients.len() as u64,
+        };
+        w.put(8, code << 1 | (wasted > 0) as u64);
+        if wasted > 0 {
+            for _ in 1..wasted {
+                w.put(1, 0);
+            }
+            w.put(1, 1);
+        }
+        let bps = bps - wasted;
+        let samples: Vec<i64> = samples.iter().map(|s| s >> wasted).collect();
+        match kind {
+            Kind::Constant => w.signed(bps, samples[0]),
+            Kind::Verbatim => samples.iter().for_each(|&s| w.signed(bps, s)),
+            Kind::Fixed(order) => {
+                samples[..*order].iter().for_each(|&s| w.signed(bps, s));
+                let residuals: Vec<i64> = (*order..samples.len())
+                    .map(|i| {
+                        let s = &samples[i - order..i];
+                        samples[i]
+                            - match order {
+                                0 => 0,
+                                1 => s[0],
+                                2 => 2 * s[1] - s[0],
+                                3 => 3 * s[2] - 3 * s[1] + s[0],
+                                _ => 4 * s[3] - 6 * s[2] + 4 * s[1] - s[0],
+                            }
+                    })
+                    .collect();
+                residual(w, &residuals, samples.len());
+            }
+            Kind::Lpc(coefficients, precision, shift) => {
+                let order = coefficients.len();
+                samples[..order].iter().for_each(|&s| w.signed(bps, s));
+                w.put(4, *precision as u64 - 1);
+                w.put(5, *shift as u64);
+                coefficients.iter().for_each(|&c| w.signed(*precision, c));
+                let residuals: Vec<i64> = (order..samples.len())
+                    .map(|i| {
+                        let prediction: i64 = coefficients
+                            .iter()
+                            .zip(samples[i - order..i].iter().rev())
+                            .map(|(c, s)| c * s)
+                            .sum();
+                        samples[i] - (prediction >> shift)
+                    })
+                    .collect();
+                residual(w, &residuals, samples.len());
+            }
+        }
+    }
+
+    fn crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0, |crc, &b| CRC8[(crc ^ b) as usize])
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, &b| {
+            (crc << 8) ^ CRC16[((crc >> 8) as u8 ^ b) as usize]
+        })
+    }
+
+    /// A 16-bit stereo frame of 64 samples.
+    fn frame(
+        number: u8,
+        left: &[i64],
+        right: &[i64],
+        assignment: u64,
+        kinds: [(Kind, u32); 2],
+    ) -> Vec<u8> {
+        let mut w = Writer::default();
+        // Sync, fixed blocks; 8-bit block size, rate from STREAMINFO;
+        // 16 bits per sample
+        w.put(16, 0xfff8);
+        w.put(4, 6);
+        w.put(4, 0);
+        w.put(4, assignment);
+        w.put(4, 0b1000);
+        w.put(8, number as u64);
+        w.put(8, left.len() as u64 - 1);
+        let crc = crc8(&w.bytes);
+        w.put(8, crc as u64);
+        let side: Vec<i64> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+        let (first, second, side_bit) = match assignment {
+            8 => (left, side.as_slice(), [0, 1]),
+            9 => (side.as_slice(), right, [1, 0]),
+            10 => (mid.as_slice(), side.as_slice(), [0, 1]),
+            _ => (left, right, [0, 0]),
+        };
+        let [(a, wasted_a), (b, wasted_b)] = &kinds;
+        subframe(&mut w, first, 16 + side_bit[0], a, *wasted_a);
+        subframe(&mut w, second, 16 + side_bit[1], b, *wasted_b);
+        w.align();
+        let crc = crc16(&w.bytes);
+        w.put(16, crc as u64);
+        w.bytes
+    }
+
+    /// Four frames covering every subframe type and stereo decorrelation,
+    /// and the samples they decode to.
+    fn fixture() -> (Vec<u8>, Vec<u8>) {
+        let left: Vec<i64> = (0..256)
+            .map(|i| ((i as f64 * 0.2).sin() * 9000.0) as i64 * 4)
+            .collect();
+        let right: Vec<i64> = (0..256)
+            .map(|i| ((i as f64 * 0.05).cos() * 12000.0) as i64 * 4)
+            .collect();
+        let right_constant = vec![-1200; 64];
+        let mut frames = Vec::new();
+        frames.extend(frame(
+            0,
+            &left[..64],
+            &right_constant,
+            1,
+            [(Kind::Verbatim, 0), (Kind::Constant, 0)],
+        ));
+        frames.extend(frame(
+            1,
+            &left[64..128],
+            &right[64..128],
+            8,
+            [(Kind::Fixed(2), 0), (Kind::Lpc(vec![7, -3], 5, 2), 0)],
+        ));
+        frames.extend(frame(
+            2,
+            &left[128..192],
+            &right[128..192],
+            10,
+            [(Kind::Fixed(1), 1), (Kind::Verbatim, 2)],
+        ));
+        frames.extend(frame(
+            3,
+            &left[192..],
+            &right[192..],
+            9,
+            [(Kind::Fixed(0), 0), (Kind::Fixed(4), 2)],
+        ));
+        let mut pcm = Vec::new();
+        for i in 0..256 {
+            let r = if i < 64 { right_constant[i] } else { right[i] };
+            pcm.extend_from_slice(&(left[i] as i16).to_le_bytes());
+            pcm.extend_from_slice(&(r as i16).to_le_bytes());
+        }
+
+        let mut info = vec![0; 34];
+        info[10..18]
+            .copy_from_slice(&((44100u64 << 44) | (1 << 41) | (15 << 36) | 256).to_be_bytes());
+        info[18..].copy_from_slice(&Md5::new().update(&pcm).finish());
+        let mut data = b"fLaC\x80\x00\x00\x22".to_vec();
+        data.extend(info);
+        data.extend(frames);
+        (data, pcm)
+    }
+
+    fn decoded(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(data)?.decode(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn decodes_all_subframe_types() {
+        let (data, pcm) = fixture();
+        let decoder = Decoder::new(data.as_slice()).unwrap();
+        assert_eq!(decoder.stream.total_samples, 256);
+        let mut out = Vec::new();
+        assert_eq!(decoder.decode(&mut out).unwrap(), 256);
+        assert_eq!(out, pcm);
+    }
+
+    #[test]
+    fn id3_tags_are_skipped() {
+        let (data, pcm) = fixture();
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x03abc".to_vec();
+        tagged.extend(data);
+        assert_eq!(decoded(&tagged).unwrap(), pcm);
+    }
+
+    #[test]
+    fn rejects_damaged_streams() {
+        let (data, _) = fixture();
+        let error = |data: &[u8]| decoded(data).unwrap_err().to_string();
+        // A flipped bit among the verbatim samples of the first frame
+        let mut damaged = data.clone();
+        damaged[42 + 20] ^= 0x10;
+        assert!(error(&damaged).contains("CRC mismatch"));
+        // A frame missing at the end
+        let end = data.len() - 40;
+        assert!(error(&data[..end]).contains("unexpected end"));
+        // Audio that does not match the recorded MD5
+        let mut wrong = data.clone();
+        wrong[42 - 1] ^= 1;
+        assert!(error(&wrong).contains("MD5"));
+        // Frames of 16 bits in a stream of 8
+        let mut narrowed = data.clone();
+        narrowed[21] ^= 0x80;
+        assert!(error(&narrowed).contains("differs from STREAMINFO"));
+        assert!(Decoder::new(&b"RIFF....WAVE"[..]).is_err());
+    }
+}
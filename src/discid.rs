@@ -0,0 +1,194 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! MusicBrainz disc IDs computed from the durations of the sources, for
+//! looking up the release of a ripped CD.
+
+use std::path::{Path, PathBuf};
+
+use crate::{ReflacError, Result, flac, hash};
+
+/// Sectors before the first track (the two second pregap).
+const LEAD_IN: u64 = 150;
+/// Sectors per second.
+const SECTORS: u64 = 75;
+
+/// Table of contents of an audio CD: the sector offsets of the tracks and of
+/// the lead-out.
+#[derive(Debug, PartialEq)]
+pub struct Toc {
+    pub offsets: Vec<u64>,
+    pub lead_out: u64,
+}
+
+impl Toc {
+    /// The TOC a CD with tracks of these lengths (in sectors) would have.
+    pub fn from_lengths(lengths: &[u64]) -> Self {
+        let mut offsets = Vec::new();
+        let mut offset = LEAD_IN;
+        for length in lengths {
+            offsets.push(offset);
+            offset += length;
+        }
+        Toc {
+            offsets,
+            lead_out: offset,
+        }
+    }
+
+    /// The disc ID as defined by MusicBrainz: SHA-1 over the hexadecimal
+    /// TOC, in Base64 with `._-` in place of `+/=`.
+    pub fn disc_id(&self) -> String {
+        let mut text = format!("{:02X}{:02X}{:08X}", 1, self.offsets.len(), self.lead_out);
+        for i in 0..99 {
+            text.push_str(&format!(
+                "{:08X}",
+                self.offsets.get(i).copied().unwrap_or(0)
+            ));
+        }
+        base64(&hash::Sha1::new().update(text.as_bytes()).finish())
+            .replace('+', ".")
+            .replace('/', "_")
+            .replace('=', "-")
+    }
+
+    /// A MusicBrainz page listing the releases with this disc ID, or
+    /// offering releases of similar durations if there is none.
+    pub fn lookup_url(&self) -> String {
+        let offsets: Vec<String> = self.offsets.iter().map(u64::to_string).collect();
+        format!(
+            "https://musicbrainz.org/cdtoc/attach?id={}&tracks={}&toc=1+{}+{}+{}",
+            self.disc_id(),
+            self.offsets.len(),
+            self.offsets.len(),
+            self.lead_out,
+            offsets.join("+")
+        )
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The TOC of the FLAC files of one disc, in file name order.
+fn disc_toc(files: &[PathBuf]) -> Result<Toc> {
+    let mut lengths = Vec::new();
+    for file in files {
+        let stream = flac::read_metadata(file)?.stream;
+        if stream.total_samples == 0 {
+            return Err(ReflacError::InvalidFlac(file.clone()));
+        }
+        let samples = stream.total_samples * SECTORS;
+        let rate = stream.sample_rate as u64;
+        if stream.sample_rate != 44100 || !samples.is_multiple_of(rate) {
+            warning!(
+                discid: "{} is not a whole number of CD sectors, the disc ID may not match",
+                file.display()
+            );
+        }
+        lengths.push((samples + rate / 2) / rate);
+    }
+    Ok(Toc::from_lengths(&lengths))
+}
+
+/// `reflac discid DIR`: prints the disc ID and lookup URL of every disc
+/// (directory of FLAC files) of an album.
+pub fn run(dir: &Path) -> Result<()> {
+    let files = flac::album_files(dir)?;
+    if files.is_empty() {
+        return Err(ReflacError::NoFlacFilesFound(dir.to_path_buf()));
+    }
+    let mut start = 0;
+    while start < files.len() {
+        let parent = files[start].parent();
+        let end = start
+            + files[start..]
+                .iter()
+                .take_while(|f| f.parent() == parent)
+                .count();
+        if end - start > 99 {
+            warning!(
+                discid: "{}: more than 99 tracks, not a CD",
+                parent.unwrap().display()
+            );
+        } else {
+            let toc = disc_toc(&files[start..end])?;
+            info!("{}: {} tracks", parent.unwrap().display(), end - start);
+            println!("{} {}", toc.disc_id(), toc.lookup_url());
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn disc_ids() {
+        let toc = Toc {
+            offsets: vec![
+                150, 22767, 41887, 58317, 72102, 91375, 104652, 115380, 132165, 143932, 159870,
+                174597,
+            ],
+            lead_out: 267257,
+        };
+        assert_eq!(toc.disc_id(), "I5l9cCSFccLKFEKS.7wqSZAorPU-");
+        assert!(
+            toc.lookup_url()
+                .ends_with("&tracks=12&toc=1+12+267257+150+22767+41887+58317+72102+91375+104652+115380+132165+143932+159870+174597")
+        );
+        assert_eq!(
+            Toc::from_lengths(&[100, 200]),
+            Toc {
+                offsets: vec![150, 250],
+                lead_out: 450,
+            }
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::path::Path;
+
+use crate::console::escape;
+use crate::{ReflacError, Result, flac};
+
+/// A change applied to every file of an album by `reflac tag`.
+pub enum Edit {
+    Set(Vec<(String, String)>),
+    Rename(String, String),
+    Delete(Vec<String>),
+}
+
+/// Checks a Vorbis comment field name and brings it into canonical case.
+pub fn field_name(name: &str) -> Result<String> {
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|b| (0x20..=0x7d).contains(&b) && b != b'=')
+    {
+        return Err(ReflacError::InvalidFieldName(name.to_string()));
+    }
+    Ok(name.to_ascii_uppercase())
+}
+
+fn apply(edit: &Edit, comments: &[(String, String)]) -> Vec<(String, String)> {
+    match edit {
+        Edit::Set(values) => {
+            let mut result: Vec<_> = comments
+                .iter()
+                .filter(|(f, _)| !values.iter().any(|(field, _)| field == f))
+                .cloned()
+                .collect();
+            result.extend(values.iter().cloned());
+            result
+        }
+        Edit::Rename(from, to) => comments
+            .iter()
+            .map(|(f, v)| (if f == from { to.clone() } else { f.clone() }, v.clone()))
+            .collect(),
+        Edit::Delete(fields) => comments
+            .iter()
+            .filter(|(f, _)| !fields.contains(f))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn values<'a>(comments: &'a [(String, String)], field: &str) -> Vec<&'a str> {
+    comments
+        .iter()
+        .filter(|(f, _)| f == field)
+        .map(|(_, v)| v.as_str())
+        .collect()
+}
+
+/// Applies an edit to all FLAC files of an album, printing the changes. With
+/// `dry_run`, nothing is written.
+pub fn run(album_dir: &Path, edit: &Edit, dry_run: bool) -> Result<()> {
+    let files = flac::album_files(album_dir)?;
+    if files.is_empty() {
+        return Err(ReflacError::NoFlacFilesFound(album_dir.to_path_buf()));
+    }
+    for path in files {
+        let before = flac::read_metadata(&path)?.comments;
+        let after = apply(edit, &before);
+        if after == before {
+            continue;
+        }
+        println!(
+            "{}",
+            escape(
+                &path
+                    .strip_prefix(album_dir)
+                    .unwrap_or(&path)
+                    .display()
+                    .to_string()
+            )
+        );
+        let mut fields: Vec<&String> = Vec::new();
+        for (field, _) in before.iter().chain(&after) {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        for field in fields {
+            let (old, new) = (values(&before, field), values(&after, field));
+            if old != new {
+                println!(
+                    "  {}: \"{}\" → \"{}\"",
+                    escape(field),
+                    escape(&old.join("; ")),
+                    escape(&new.join("; "))
+                );
+            }
+        }
+        if !dry_run {
+            flac::write_comments(&path, &after)?;
+        }
+    }
+    if dry_run {
+        info!("Dry run, nothing written.");
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Estimates of the encoding time from the throughput of earlier runs on
+//! this machine.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::Result;
+
+/// Seconds of encoding per second of audio, per setup (job count and
+/// encoder settings), as measured by earlier runs.
+pub struct Calibration {
+    path: PathBuf,
+    factors: Vec<(String, f64)>,
+}
+
+impl Calibration {
+    /// `$XDG_STATE_HOME/reflac/calibration`, `~/.local/state/reflac/calibration`
+    /// or, without a home directory, `reflac-calibration` in the temporary
+    /// directory.
+    pub fn default_path() -> PathBuf {
+        if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+            PathBuf::from(dir).join("reflac/calibration")
+        } else if let Some(home) = env::var_os("HOME") {
+            PathBuf::from(home).join(".local/state/reflac/calibration")
+        } else {
+            env::temp_dir().join("reflac-calibration")
+        }
+    }
+
+    /// Reads the calibration, treating a missing file or malformed lines as
+    /// no measurements.
+    pub fn load(path: PathBuf) -> Self {
+        let factors = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (factor, setup) = line.split_once('\t')?;
+                let factor = factor.parse::<f64>().ok().filter(|f| *f > 0.0)?;
+                Some((setup.to_string(), factor))
+            })
+            .collect();
+        Self { path, factors }
+    }
+
+    pub fn estimate(&self, setup: &str, audio: Duration) -> Option<Duration> {
+        let (_, factor) = self.factors.iter().find(|(s, _)| s == setup)?;
+        Some(audio.mul_f64(*factor))
+    }
+
+    /// Folds the measurement of a run into the factor of `setup`; recent
+    /// runs count as much as all earlier ones together.
+    pub fn record(&mut self, setup: &str, audio: Duration, elapsed: Duration) {
+        if audio.is_zero() {
+            return;
+        }
+        let measured = elapsed.as_secs_f64() / audio.as_secs_f64();
+        match self.factors.iter_mut().find(|(s, _)| s == setup) {
+            Some((_, factor)) => *factor = (*factor + measured) / 2.0,
+            None => self.factors.push((setup.to_string(), measured)),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .factors
+            .iter()
+            .map(|(setup, factor)| format!("{factor}\t{setup}\n"))
+            .collect();
+        fs::write(&self.path, text)?;
+        Ok(())
+    }
+}
+
+/// Encoder settings for tracks that exhaustive search is not worth it for.
+pub const FAST_SETTINGS: &[&str] = &["--best"];
+
+/// Tracks at least this long are worth probing.
+const LONG_TRACK: Duration = Duration::from_secs(20 * 60);
+
+/// FLAC sources larger than this share of their PCM size are mostly noise,
+/// which searching harder hardly compresses any better.
+const NOISY_RATIO: f64 = 0.75;
+
+/// Whether a track should be encoded with `FAST_SETTINGS`: long tracks that
+/// barely compress (ambient, field recordings, noise), and tracks predicted
+/// to take longer than `budget`.
+pub fn prefers_fast(
+    duration: Duration,
+    ratio: Option<f64>,
+    predicted: Option<Duration>,
+    budget: Option<Duration>,
+) -> bool {
+    let noisy = duration >= LONG_TRACK && ratio.is_some_and(|r| r > NOISY_RATIO);
+    let slow = matches!((predicted, budget), (Some(p), Some(b)) if p > b);
+    noisy || slow
+}
+
+/// A rough duration such as "1h 42m", "12m" or "40s".
+pub fn format(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60) {
+        (0, 0) => format!("{secs}s"),
+        (0, m) => format!("{m}m"),
+        (h, m) => format!("{h}h {m}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format(Duration::from_secs(40)), "40s");
+        assert_eq!(format(Duration::from_secs(12 * 60 + 5)), "12m");
+        assert_eq!(format(Duration::from_secs(6120)), "1h 42m");
+    }
+
+    #[test]
+    fn costly_tracks_are_encoded_fast() {
+        let hour = Duration::from_secs(3600);
+        let song = Duration::from_secs(240);
+        assert!(prefers_fast(hour, Some(0.9), None, None));
+        assert!(!prefers_fast(hour, Some(0.5), None, None));
+        assert!(!prefers_fast(song, Some(0.9), None, None));
+        assert!(prefers_fast(song, Some(0.5), Some(hour), Some(song)));
+        assert!(!prefers_fast(song, Some(0.5), Some(song), Some(hour)));
+        assert!(!prefers_fast(song, None, None, Some(song)));
+    }
+
+    #[test]
+    fn measurements_are_kept() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("state/calibration");
+        let minute = Duration::from_secs(60);
+        let mut calibration = Calibration::load(path.clone());
+        assert_eq!(calibration.estimate("8 -8", minute), None);
+        calibration.record("8 -8", minute, Duration::from_secs(6));
+        calibration.record("8 -8", minute, Duration::from_secs(12));
+        calibration.record("1 -8", minute, Duration::from_secs(60));
+        calibration.save().unwrap();
+
+        let calibration = Calibration::load(path);
+        let estimate = |setup, audio| calibration.estimate(setup, audio).map(|d| d.as_secs());
+        assert_eq!(estimate("8 -8", Duration::from_secs(600)), Some(90));
+        assert_eq!(estimate("1 -8", minute), Some(60));
+    }
+}
@@ -0,0 +1,171 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs;
+use std::path::Path;
+
+use crate::flac::{self, Metadata};
+use crate::{ReflacError, Result};
+
+/// TRACKINFO keys and the Vorbis comments they are written to.
+const FIELDS: &[(&str, &str)] = &[
+    ("INPUT", ""),
+    ("ALBUM", "ALBUM"),
+    ("ARTIST", "ARTIST"),
+    ("LYRICIST", "LYRICIST"),
+    ("COMPOSER", "COMPOSER"),
+    ("ARRANGER", "ARRANGER"),
+    ("DISC", "DISCNUMBER"),
+    ("GENRE", "GENRE"),
+    ("DATE", "DATE"),
+    ("LABEL", "LABEL"),
+    ("COMMENT", "COMMENT"),
+    ("STYLE", "STYLE"),
+    ("MOOD", "MOOD"),
+    ("GROUPING", "GROUPING"),
+    ("WORK", "WORK"),
+    ("MOVEMENT", "MOVEMENTNAME"),
+    ("MOVEMENTNUMBER", "MOVEMENT"),
+    ("CONDUCTOR", "CONDUCTOR"),
+    ("ENSEMBLE", "ENSEMBLE"),
+    ("OPUS", "OPUS"),
+    ("COVER", ""),
+    ("TITLE", "TITLE"),
+];
+
+/// Value of a TRACKINFO key for one file, if it can be expressed.
+fn field_value(key: &str, comment: &str, path: &Path, meta: &Metadata) -> Option<String> {
+    match key {
+        "INPUT" => Some(path.parent().unwrap().display().to_string()),
+        // COVER may name a FLAC file to take the picture from
+        "COVER" => (!meta.pictures.is_empty())
+            .then(|| path.file_name().unwrap().to_str().unwrap().to_string()),
+        "DISC" => meta.number(comment).map(|d| d.to_string()),
+        "DATE" => {
+            let date = meta.first(comment)?;
+            let valid = date.len() >= 10
+                && date[..10].bytes().enumerate().all(|(i, b)| {
+                    if i == 4 || i == 7 {
+                        b == b'-'
+                    } else {
+                        b.is_ascii_digit()
+                    }
+                });
+            if !valid {
+                warning!(
+                    export: "DATE \"{date}\" of {} is not YYYY-MM-DD, skipped!",
+                    path.display()
+                );
+                return None;
+            }
+            Some(date.to_string())
+        }
+        "STYLE" | "MOOD" => {
+            let values: Vec<&str> = meta.get(comment).collect();
+            (!values.is_empty()).then(|| values.join("; ").replace(['\r', '\n'], " "))
+        }
+        _ => {
+            let mut values = meta.get(comment);
+            let value = values.next()?;
+            if values.next().is_some() {
+                warning!(
+                    export: "{key} of {} has several values, keeping the first!",
+                    path.display()
+                );
+            }
+            // TRACKINFO values cannot span lines
+            Some(value.replace(['\r', '\n'], " "))
+        }
+    }
+}
+
+/// Reconstructs a TRACKINFO file from the tags of a finished album.
+pub fn run(album_dir: &Path, output: Option<&Path>) -> Result<()> {
+    let album_dir = fs::canonicalize(album_dir)?;
+    let files = flac::album_files(&album_dir)?;
+    if files.is_empty() {
+        return Err(ReflacError::NoFlacFilesFound(album_dir));
+    }
+    let mut tracks = Vec::new();
+    for path in files {
+        let meta = flac::read_metadata(&path)?;
+        let disc = meta.number("DISCNUMBER");
+        let number = meta.number("TRACKNUMBER");
+        tracks.push((disc, number, path, meta));
+    }
+    tracks.sort_by(|a, b| (a.0, a.1, &a.2).cmp(&(b.0, b.1, &b.2)));
+
+    // TRACKINFO identifies tracks by number alone
+    let mut numbers: Vec<usize> = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, t)| t.1.unwrap_or(i + 1))
+        .collect();
+    let mut unique = numbers.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() != numbers.len() {
+        warning!(export: "Track numbers repeat across discs, numbering continuously!");
+        numbers = (1..=tracks.len()).collect();
+    }
+
+    let mut global = Vec::new();
+    let mut per_track: Vec<Vec<String>> = vec![Vec::new(); tracks.len()];
+    for &(key, comment) in FIELDS {
+        let values: Vec<Option<String>> = tracks
+            .iter()
+            .map(|(_, _, path, meta)| field_value(key, comment, path, meta))
+            .collect();
+        let common = values.iter().all(|v| *v == values[0]) && values[0].is_some();
+        let shared_picture = key == "COVER"
+            && values.iter().all(Option::is_some)
+            && tracks
+                .iter()
+                .all(|t| t.3.cover().unwrap().data == tracks[0].3.cover().unwrap().data)
+            && tracks.iter().all(|t| t.2.parent() == tracks[0].2.parent());
+        if key != "TITLE" && (common || shared_picture) {
+            global.push(format!("{key}={}", values[0].as_ref().unwrap()));
+            continue;
+        }
+        for ((lines, number), value) in per_track.iter_mut().zip(&numbers).zip(values) {
+            if let Some(value) = value {
+                lines.push(format!("{key}[{number}]={value}"));
+            }
+        }
+    }
+
+    let mut trackinfo = global.join("\n");
+    trackinfo.push('\n');
+    for (mut lines, number) in per_track.into_iter().zip(numbers) {
+        if lines.is_empty() {
+            lines.push(format!("TITLE[{number}]="));
+        }
+        trackinfo.push('\n');
+        trackinfo.push_str(&lines.join("\n"));
+        trackinfo.push('\n');
+    }
+    match output {
+        Some(path) => fs::write(path, trackinfo)?,
+        None => print!("{trackinfo}"),
+    }
+    Ok(())
+}
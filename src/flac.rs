@@ -0,0 +1,581 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::LazyLock;
+
+use crate::{ReflacError, Result};
+
+pub const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const APPLICATION: u8 = 2;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+const FRONT_COVER: u32 = 3;
+
+pub struct Picture {
+    pub kind: u32,
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+/// Audio format from the STREAMINFO block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    /// 0 if unknown
+    pub total_samples: u64,
+    /// MD5 of the decoded samples, zeros if unknown
+    pub md5: [u8; 16],
+}
+
+impl StreamInfo {
+    /// Whether the stream is inside the FLAC subset, which flac encodes
+    /// without `--lax`.
+    pub fn is_subset(&self) -> bool {
+        self.bits_per_sample <= 24 && self.sample_rate <= 655_350
+    }
+
+    /// The oldest flac release that can encode the stream and what it
+    /// needs it for, if older releases cannot.
+    pub fn required_version(&self) -> Option<((u32, u32), &'static str)> {
+        if self.bits_per_sample > 24 {
+            Some(((1, 4), "32-bit input"))
+        } else if self.sample_rate > 655_350 {
+            Some(((1, 4), "sample rates above 655350 Hz"))
+        } else {
+            None
+        }
+    }
+}
+
+/// The installed `flac` as it names itself, e.g. "flac 1.4.3".
+pub fn encoder() -> Option<&'static str> {
+    static ENCODER: LazyLock<Option<String>> = LazyLock::new(|| {
+        let output = Command::new("flac").arg("--version").output().ok()?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        Some(version.lines().next()?.trim().to_string()).filter(|v| !v.is_empty())
+    });
+    ENCODER.as_deref()
+}
+
+/// Whether `metaflac` can be run at all.
+pub fn metaflac_installed() -> bool {
+    Command::new("metaflac")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// Major and minor version of the installed `flac`.
+pub fn encoder_version() -> Option<(u32, u32)> {
+    parse_version(encoder()?)
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().strip_prefix("flac ")?.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Spawns a decoder writing the samples of a file to its stdout as raw
+/// little-endian signed integers.
+pub fn decode_raw(path: &Path) -> Result<Child> {
+    Ok(Command::new("flac")
+        .args(["--silent", "--decode", "--stdout", "--force-raw-format"])
+        .args(["--endian=little", "--sign=signed"])
+        .arg(path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?)
+}
+
+/// Waits for a decoder spawned by `decode_raw`.
+pub fn wait_decoder(child: &mut Child) -> Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(ReflacError::Subprocess {
+            command: "flac",
+            status: status.code(),
+            stderr: String::new(),
+        });
+    }
+    Ok(())
+}
+
+/// Metadata blocks of a FLAC file that reflac cares about.
+pub struct Metadata {
+    pub stream: StreamInfo,
+    pub comments: Vec<(String, String)>,
+    pub pictures: Vec<Picture>,
+    /// Extension of the file whose non-audio chunks `--keep-foreign-metadata`
+    /// stored (RIFF/BWF, AIFF or Wave64)
+    pub foreign: Option<&'static str>,
+}
+
+impl Metadata {
+    /// Values of a Vorbis comment field, compared case-insensitively.
+    pub fn get(&self, field: &str) -> impl Iterator<Item = &str> {
+        self.comments
+            .iter()
+            .filter(move |(f, _)| f.eq_ignore_ascii_case(field))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn first(&self, field: &str) -> Option<&str> {
+        self.get(field).next()
+    }
+
+    /// Leading number of a field such as `TRACKNUMBER=3/12`.
+    pub fn number(&self, field: &str) -> Option<usize> {
+        let value = self.first(field)?.trim();
+        let end = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        value[..end].parse().ok()
+    }
+
+    /// The front cover, or else the first picture.
+    pub fn cover(&self) -> Option<&Picture> {
+        self.pictures
+            .iter()
+            .find(|p| p.kind == FRONT_COVER)
+            .or(self.pictures.first())
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.data.len() {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn u32_le(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u32_be(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn string(&mut self, n: usize) -> Option<String> {
+        self.take(n)
+            .map(|b| String::from_utf8_lossy(b).into_owned())
+    }
+}
+
+pub fn parse_stream_info(data: &[u8]) -> Option<StreamInfo> {
+    // 20 bits sample rate, 3 bits channels - 1, 5 bits bits per sample - 1
+    // and 36 bits total samples
+    let bits = u64::from_be_bytes(data.get(10..18)?.try_into().unwrap());
+    Some(StreamInfo {
+        sample_rate: (bits >> 44) as u32,
+        channels: ((bits >> 41) & 0x7) as u8 + 1,
+        bits_per_sample: ((bits >> 36) & 0x1f) as u8 + 1,
+        total_samples: bits & 0xf_ffff_ffff,
+        md5: data
+            .get(18..34)
+            .map_or([0; 16], |md5| md5.try_into().unwrap()),
+    })
+}
+
+fn parse_comments(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut cur = Cursor { data };
+    // Vendor string
+    let len = cur.u32_le()? as usize;
+    cur.take(len)?;
+    let count = cur.u32_le()?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = cur.u32_le()? as usize;
+        let comment = cur.string(len)?;
+        if let Some((field, value)) = comment.split_once('=') {
+            comments.push((field.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+    Some(comments)
+}
+
+fn parse_picture(data: &[u8]) -> Option<Picture> {
+    let mut cur = Cursor { data };
+    let kind = cur.u32_be()?;
+    let len = cur.u32_be()? as usize;
+    let mime = cur.string(len)?;
+    // Description
+    let len = cur.u32_be()? as usize;
+    cur.take(len)?;
+    // Width, height, color depth and palette size
+    cur.take(16)?;
+    let len = cur.u32_be()? as usize;
+    let data = cur.take(len)?.to_vec();
+    Some(Picture { kind, mime, data })
+}
+
+/// Raw metadata blocks of a FLAC file.
+struct Blocks {
+    /// Offset of the `fLaC` marker (after an ID3v2 tag)
+    start: u64,
+    blocks: Vec<(u8, Vec<u8>)>,
+    /// Offset of the first audio frame
+    audio: u64,
+}
+
+/// Offset of the `fLaC` marker, after the ID3v2 tag some taggers put in
+/// front of it.
+fn find_marker<R: Read + Seek>(file: &mut R) -> Option<u64> {
+    let mut start = 0;
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).ok()?;
+    if &magic[..3] == b"ID3" {
+        let mut header = [0; 6];
+        file.read_exact(&mut header).ok()?;
+        let size = header[2..]
+            .iter()
+            .fold(0u64, |size, &b| (size << 7) | (b & 0x7f) as u64);
+        start = 10 + size;
+        file.seek(SeekFrom::Start(start)).ok()?;
+        file.read_exact(&mut magic).ok()?;
+    }
+    (&magic == b"fLaC").then_some(start)
+}
+
+/// Whether a file is FLAC, judged by its content rather than its name.
+pub fn is_flac(path: &Path) -> bool {
+    File::open(path).is_ok_and(|file| find_marker(&mut BufReader::new(file)).is_some())
+}
+
+fn read_blocks(path: &Path) -> Result<Blocks> {
+    let invalid = || ReflacError::InvalidFlac(path.to_path_buf());
+    let mut file = BufReader::new(File::open(path)?);
+    let start = find_marker(&mut file).ok_or_else(invalid)?;
+
+    let mut blocks = Vec::new();
+    let mut audio = start + 4;
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header).map_err(|_| invalid())?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data).map_err(|_| invalid())?;
+        blocks.push((header[0] & 0x7f, data));
+        audio += 4 + len as u64;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    if blocks.first().is_none_or(|(kind, _)| *kind != STREAMINFO) {
+        return Err(invalid());
+    }
+    Ok(Blocks {
+        start,
+        blocks,
+        audio,
+    })
+}
+
+/// Reads the metadata blocks at the start of a FLAC file without decoding
+/// any audio. A leading ID3v2 tag is skipped.
+pub fn read_metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
+    let path = path.as_ref();
+    let invalid = || ReflacError::InvalidFlac(path.to_path_buf());
+    let mut stream = None;
+    let mut comments = Vec::new();
+    let mut pictures = Vec::new();
+    let mut foreign = None;
+    for (kind, data) in read_blocks(path)?.blocks {
+        match kind {
+            STREAMINFO => stream = Some(parse_stream_info(&data).ok_or_else(invalid)?),
+            VORBIS_COMMENT => comments = parse_comments(&data).ok_or_else(invalid)?,
+            PICTURE => pictures.push(parse_picture(&data).ok_or_else(invalid)?),
+            APPLICATION => {
+                foreign = foreign.or(match data.get(..4) {
+                    Some(b"riff") => Some("wav"),
+                    Some(b"aiff") => Some("aiff"),
+                    Some(b"w64 ") => Some("w64"),
+                    _ => None,
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(Metadata {
+        // read_blocks ensures STREAMINFO comes first
+        stream: stream.unwrap(),
+        comments,
+        pictures,
+        foreign,
+    })
+}
+
+fn encode_comments(vendor: &[u8], comments: &[(String, String)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor);
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (field, value) in comments {
+        let comment = format!("{field}={value}");
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+fn encode_blocks(blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (i, (kind, data)) in blocks.iter().enumerate() {
+        let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        out.push(kind | last);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// Replaces the Vorbis comments of a FLAC file. The metadata is rewritten in
+/// place when it fits into the existing padding; otherwise the file is
+/// rewritten next to the original and renamed over it.
+pub fn write_comments<P: AsRef<Path>>(path: P, comments: &[(String, String)]) -> Result<()> {
+    const NEW_PADDING: usize = 8192;
+
+    let path = path.as_ref();
+    let Blocks {
+        start,
+        blocks,
+        audio,
+    } = read_blocks(path)?;
+    let vendor = blocks
+        .iter()
+        .find(|(kind, _)| *kind == VORBIS_COMMENT)
+        .and_then(|(_, data)| {
+            let len = u32::from_le_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+            data.get(4..4 + len).map(<[u8]>::to_vec)
+        })
+        .unwrap_or_else(|| b"reflac".to_vec());
+    let mut blocks: Vec<_> = blocks
+        .into_iter()
+        .filter(|(kind, _)| *kind != PADDING)
+        .collect();
+    let comment_block = (VORBIS_COMMENT, encode_comments(&vendor, comments));
+    match blocks.iter().position(|(kind, _)| *kind == VORBIS_COMMENT) {
+        Some(i) => blocks[i] = comment_block,
+        None => blocks.insert(1, comment_block),
+    }
+
+    let available = (audio - start - 4) as usize;
+    let used: usize = blocks.iter().map(|(_, data)| 4 + data.len()).sum();
+    if used == available || used + 4 <= available {
+        if used < available {
+            blocks.push((PADDING, vec![0; available - used - 4]));
+        }
+        let mut file = fs::OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(start + 4))?;
+        file.write_all(&encode_blocks(&blocks))?;
+        return Ok(());
+    }
+
+    blocks.push((PADDING, vec![0; NEW_PADDING]));
+    let tmp_path = path.with_extension("flac.reflac-tmp");
+    let result = (|| -> Result<()> {
+        let mut src = File::open(path)?;
+        let mut dest = File::create(&tmp_path)?;
+        io::copy(&mut (&mut src).take(start), &mut dest)?;
+        dest.write_all(b"fLaC")?;
+        dest.write_all(&encode_blocks(&blocks))?;
+        src.seek(SeekFrom::Start(audio))?;
+        io::copy(&mut src, &mut dest)?;
+        dest.sync_all()?;
+        Ok(())
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// FLAC files of an album directory and its disc subdirectories.
+pub fn album_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "flac") {
+                    files.push(path);
+                }
+            }
+        } else if path.extension().is_some_and(|e| e == "flac") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(padding: usize) -> Vec<u8> {
+        let mut info = vec![0; 34];
+        info[10..18].copy_from_slice(&((44100u64 << 44) | (1 << 41) | (15 << 36)).to_be_bytes());
+        let mut blocks = vec![
+            (STREAMINFO, info),
+            (
+                VORBIS_COMMENT,
+                encode_comments(b"test", &[(String::from("TITLE"), String::from("One"))]),
+            ),
+        ];
+        if padding > 0 {
+            blocks.push((PADDING, vec![0; padding]));
+        }
+        let mut data = b"fLaC".to_vec();
+        data.extend(encode_blocks(&blocks));
+        data.extend_from_slice(b"AUDIO FRAMES");
+        data
+    }
+
+    #[test]
+    fn flac_is_recognized_by_content() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let check = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, data).unwrap();
+            is_flac(&path)
+        };
+        assert!(check("01 Track", &fixture(0)));
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x03abc".to_vec();
+        tagged.extend(fixture(0));
+        assert!(check("02 Track.FLAC", &tagged));
+        assert!(!check(
+            "03 Track.flac",
+            b"ID3\x04\x00\x00\x00\x00\x00\x03abcMP3 audio"
+        ));
+        assert!(!check("cover.jpg", b"\xff\xd8\xff"));
+    }
+
+    fn rewrite(padding: usize) -> Vec<u8> {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("test.flac");
+        fs::write(&path, fixture(padding)).unwrap();
+        let comments = vec![
+            (String::from("TITLE"), String::from("Uno")),
+            (String::from("GENRE"), String::from("Rock")),
+        ];
+        write_comments(&path, &comments).unwrap();
+        let meta = read_metadata(&path).unwrap();
+        assert_eq!(meta.comments, comments);
+        assert_eq!(meta.number("TITLE"), None);
+        assert_eq!(
+            meta.stream,
+            StreamInfo {
+                sample_rate: 44100,
+                channels: 2,
+                bits_per_sample: 16,
+                total_samples: 0,
+                md5: [0; 16],
+            }
+        );
+        fs::read(&path).unwrap()
+    }
+
+    #[test]
+    fn comments_are_rewritten_in_padding() {
+        let before = fixture(100);
+        let after = rewrite(100);
+        assert_eq!(after.len(), before.len());
+        assert!(after.ends_with(b"AUDIO FRAMES"));
+    }
+
+    #[test]
+    fn comments_grow_the_file_without_padding() {
+        let after = rewrite(0);
+        assert!(after.len() > fixture(0).len());
+        assert!(after.ends_with(b"AUDIO FRAMES"));
+    }
+
+    #[test]
+    fn versions_and_formats() {
+        assert_eq!(parse_version("flac 1.4.3\n"), Some((1, 4)));
+        assert_eq!(parse_version("flac 1.3.4"), Some((1, 3)));
+        assert_eq!(parse_version("not flac"), None);
+        let stream = StreamInfo {
+            sample_rate: 96000,
+            channels: 2,
+            bits_per_sample: 24,
+            total_samples: 0,
+            md5: [0; 16],
+        };
+        assert!(stream.is_subset());
+        assert_eq!(stream.required_version(), None);
+        let stream = StreamInfo {
+            bits_per_sample: 32,
+            ..stream
+        };
+        assert!(!stream.is_subset());
+        assert_eq!(stream.required_version(), Some(((1, 4), "32-bit input")));
+    }
+
+    #[test]
+    fn foreign_metadata_is_recognized() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("test.flac");
+        fs::write(&path, fixture(0)).unwrap();
+        assert_eq!(read_metadata(&path).unwrap().foreign, None);
+        let mut data = b"fLaC".to_vec();
+        data.extend(encode_blocks(&[
+            (STREAMINFO, vec![0; 34]),
+            (APPLICATION, b"riffbext....".to_vec()),
+        ]));
+        fs::write(&path, data).unwrap();
+        assert_eq!(read_metadata(&path).unwrap().foreign, Some("wav"));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("test.flac");
+        fs::write(&path, b"RIFF....WAVE").unwrap();
+        assert!(read_metadata(&path).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::{ReflacError, Result, add_replay_gain, flac};
+
+/// Recomputes ReplayGain for all tracks of each album directory. With
+/// `per_disc`, every disc is treated as an album of its own; with `group`,
+/// all directories are (the volumes of a set, released separately).
+pub fn run(album_dirs: &[PathBuf], per_disc: bool, group: bool) -> Result<()> {
+    let mut albums = Vec::new();
+    for album_dir in album_dirs {
+        let files = flac::album_files(album_dir)?;
+        if files.is_empty() {
+            return Err(ReflacError::NoFlacFilesFound(album_dir.to_path_buf()));
+        }
+        albums.push(files);
+    }
+    if group {
+        info!("Treating {} albums as one ...", albums.len());
+        return add(albums.concat(), false);
+    }
+    for files in albums {
+        add(files, per_disc)?;
+    }
+    Ok(())
+}
+
+/// The ReplayGain tags of a track as metaflac wrote them, e.g. "-7.12 dB"
+/// and "0.98852539".
+pub struct ReplayGain {
+    pub track_gain: String,
+    pub track_peak: String,
+    pub album_gain: String,
+    pub album_peak: String,
+}
+
+impl ReplayGain {
+    /// The ReplayGain of a file, if it has all four tags.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let meta = flac::read_metadata(path)?;
+        let tag = |field| meta.first(field).map(String::from);
+        Ok(
+            match (
+                tag("REPLAYGAIN_TRACK_GAIN"),
+                tag("REPLAYGAIN_TRACK_PEAK"),
+                tag("REPLAYGAIN_ALBUM_GAIN"),
+                tag("REPLAYGAIN_ALBUM_PEAK"),
+            ) {
+                (Some(track_gain), Some(track_peak), Some(album_gain), Some(album_peak)) => {
+                    Some(Self {
+                        track_gain,
+                        track_peak,
+                        album_gain,
+                        album_peak,
+                    })
+                }
+                _ => None,
+            },
+        )
+    }
+
+    /// How much louder than its album the track is, in dB (negative if it is
+    /// quieter): the gain the album needs beyond the track's own.
+    pub fn louder_than_album(&self) -> Option<f64> {
+        let db = |gain: &str| {
+            gain.trim()
+                .trim_end_matches("dB")
+                .trim()
+                .parse::<f64>()
+                .ok()
+        };
+        Some(db(&self.album_gain)? - db(&self.track_gain)?)
+    }
+}
+
+/// Adds ReplayGain to the files of an album, per disc if asked to.
+pub fn add(files: Vec<PathBuf>, per_disc: bool) -> Result<()> {
+    let mut groups: BTreeMap<Option<usize>, Vec<PathBuf>> = BTreeMap::new();
+    for path in files {
+        let disc = if per_disc {
+            flac::read_metadata(&path)?.number("DISCNUMBER")
+        } else {
+            None
+        };
+        groups.entry(disc).or_default().push(path);
+    }
+    for (disc, paths) in groups {
+        match disc {
+            Some(disc) => info!("Adding ReplayGain to disc {disc} ..."),
+            None => info!("Adding ReplayGain ..."),
+        }
+        add_replay_gain(&paths)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loudness_is_compared_with_the_album() {
+        let gain = |track: &str, album: &str| ReplayGain {
+            track_gain: track.to_string(),
+            track_peak: String::from("0.5"),
+            album_gain: album.to_string(),
+            album_peak: String::from("0.9"),
+        };
+        assert_eq!(gain("-9.50 dB", "-7.00 dB").louder_than_album(), Some(2.5));
+        assert_eq!(gain("-4.00 dB", "-7.00 dB").louder_than_album(), Some(-3.0));
+        assert_eq!(gain("+1.25 dB", "+1.25 dB").louder_than_album(), Some(0.0));
+        assert_eq!(gain("loud", "-7.00 dB").louder_than_album(), None);
+    }
+}
@@ -0,0 +1,422 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    table
+}
+
+/// CRC-32 (IEEE) as used by SFV files and ZIP archives.
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self {
+            table: crc32_table(),
+            crc: 0xffffffff,
+        }
+    }
+
+    pub fn update(mut self, data: &[u8]) -> Self {
+        for &b in data {
+            self.crc = self.table[((self.crc ^ b as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+        self
+    }
+
+    pub fn finish(self) -> u32 {
+        self.crc ^ 0xffffffff
+    }
+}
+
+pub fn crc32_file<P: AsRef<Path>>(path: P) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut crc = Crc32::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(crc.finish());
+        }
+        crc = crc.update(&buf[..n]);
+    }
+}
+
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut sha = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(sha.finish());
+        }
+        sha = sha.update(&buf[..n]);
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, used to identify TRACKINFO files in provenance records and
+/// archives in the extraction cache.
+pub struct Sha256 {
+    state: [u32; 8],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+        self.block.clear();
+    }
+
+    pub fn update(mut self, data: &[u8]) -> Self {
+        self.len += data.len() as u64;
+        for &b in data {
+            self.block.push(b);
+            if self.block.len() == 64 {
+                self.compress();
+            }
+        }
+        self
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.len * 8;
+        self.block.push(0x80);
+        if self.block.len() > 56 {
+            self.block.resize(64, 0);
+            self.compress();
+        }
+        self.block.resize(56, 0);
+        self.block.extend_from_slice(&bits.to_be_bytes());
+        self.compress();
+        let mut digest = [0; 32];
+        for (chunk, s) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&s.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// SHA-1, used only for MusicBrainz disc IDs.
+pub struct Sha1 {
+    state: [u32; 5],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..20 => ((b & c) | (!b & d), 0x5a827999),
+                20..40 => (b ^ c ^ d, 0x6ed9eba1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+        self.block.clear();
+    }
+
+    pub fn update(mut self, data: &[u8]) -> Self {
+        self.len += data.len() as u64;
+        for &b in data {
+            self.block.push(b);
+            if self.block.len() == 64 {
+                self.compress();
+            }
+        }
+        self
+    }
+
+    pub fn finish(mut self) -> [u8; 20] {
+        let bits = self.len * 8;
+        self.block.push(0x80);
+        if self.block.len() > 56 {
+            self.block.resize(64, 0);
+            self.compress();
+        }
+        self.block.resize(56, 0);
+        self.block.extend_from_slice(&bits.to_be_bytes());
+        self.compress();
+        let mut digest = [0; 20];
+        for (chunk, s) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&s.to_be_bytes());
+        }
+        digest
+    }
+}
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// MD5, which FLAC files record of their decoded samples.
+pub struct Md5 {
+    state: [u32; 4],
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Md5 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            block: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut m = [0u32; 16];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            m[i] = u32::from_le_bytes(word.try_into().unwrap());
+        }
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i {
+                0..16 => ((b & c) | (!b & d), i),
+                16..32 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                32..48 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+        self.block.clear();
+    }
+
+    pub fn update(mut self, data: &[u8]) -> Self {
+        self.len += data.len() as u64;
+        for &b in data {
+            self.block.push(b);
+            if self.block.len() == 64 {
+                self.compress();
+            }
+        }
+        self
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bits = self.len * 8;
+        self.block.push(0x80);
+        if self.block.len() > 56 {
+            self.block.resize(64, 0);
+            self.compress();
+        }
+        self.block.resize(56, 0);
+        self.block.extend_from_slice(&bits.to_le_bytes());
+        self.compress();
+        let mut digest = [0; 16];
+        for (chunk, s) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&s.to_le_bytes());
+        }
+        digest
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(Crc32::new().update(b"123456789").finish(), 0xcbf43926);
+    }
+
+    #[test]
+    fn md5_test_vectors() {
+        assert_eq!(
+            hex(&Md5::new().finish()),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(
+            hex(&Md5::new().update(b"abc").finish()),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        // Spans two blocks and is fed in pieces
+        let text =
+            b"12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+        assert_eq!(
+            hex(&Md5::new().update(&text[..30]).update(&text[30..]).finish()),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(
+            hex(&Sha1::new().update(b"abc").finish()),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&Sha1::new()
+                .update(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")
+                .finish()),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn sha256_test_vectors() {
+        assert_eq!(
+            hex(&Sha256::new().update(b"abc").finish()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&Sha256::new().finish()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        // Spans two blocks and is fed in pieces
+        let text = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            hex(&Sha256::new()
+                .update(&text[..20])
+                .update(&text[20..])
+                .finish()),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! A decoder for DEFLATE streams (RFC 1951), the compression of ZIP
+//! archives. It favors simplicity over speed: FLAC data barely compresses,
+//! so most members are cheap to inflate or merely stored anyway.
+
+use std::io::{self, Read, Write};
+
+/// Distances reach back at most this far.
+const WINDOW: usize = 32 * 1024;
+/// Output is passed on in chunks of about this size.
+const FLUSH: usize = 256 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order in which the lengths of the code length code are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid deflate data: {reason}"),
+    )
+}
+
+/// Reads a stream least significant bit first, as DEFLATE packs it.
+struct Bits<R> {
+    input: R,
+    bits: u32,
+    count: u32,
+}
+
+impl<R: Read> Bits<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        match self.input.read_exact(&mut byte) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(invalid("unexpected end"))
+            }
+            result => result.map(|()| byte[0]),
+        }
+    }
+
+    fn take(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            self.bits |= (self.byte()? as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits = self.bits.checked_shr(n).unwrap_or(0);
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the bits left of the current byte; stored blocks start at a
+    /// byte boundary.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and
+/// the symbols ordered by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        // Incomplete codes are fine (one distance code is common), codes
+        // with more symbols than fit are not
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = 2 * left - count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed code"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode<R: Read>(&self, bits: &mut Bits<R>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("unknown code"))
+    }
+}
+
+/// Inflated data not yet written, preceded by the window that later
+/// matches may copy from.
+struct Output<'a, W> {
+    writer: &'a mut W,
+    buffer: Vec<u8>,
+    /// Bytes written to `writer`.
+    written: u64,
+}
+
+impl<W: Write> Output<'_, W> {
+    fn push(&mut self, byte: u8) -> io::Result<()> {
+        self.buffer.push(byte);
+        if self.buffer.len() >= WINDOW + FLUSH {
+            self.flush(WINDOW)?;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, distance: usize, length: usize) -> io::Result<()> {
+        if distance as u64 > self.written + self.buffer.len() as u64 {
+            return Err(invalid("distance too far back"));
+        }
+        for _ in 0..length {
+            self.push(self.buffer[self.buffer.len() - distance])?;
+        }
+        Ok(())
+    }
+
+    /// Writes out everything but the last `keep` bytes.
+    fn flush(&mut self, keep: usize) -> io::Result<()> {
+        let end = self.buffer.len().saturating_sub(keep);
+        self.writer.write_all(&self.buffer[..end])?;
+        self.buffer.drain(..end);
+        self.written += end as u64;
+        Ok(())
+    }
+}
+
+/// Inflates a raw DEFLATE stream (without zlib or gzip framing) from `input`
+/// into `output` and returns the number of bytes written. Corrupt data fails
+/// with `InvalidData`.
+pub fn inflate<R: Read, W: Write>(input: R, output: &mut W) -> io::Result<u64> {
+    let mut bits = Bits {
+        input,
+        bits: 0,
+        count: 0,
+    };
+    let mut out = Output {
+        writer: output,
+        buffer: Vec::new(),
+        written: 0,
+    };
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out)?,
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                compressed(
+                    &mut bits,
+                    &mut out,
+                    &Huffman::new(&lengths)?,
+                    &Huffman::new(&[5; 30])?,
+                )?
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                compressed(&mut bits, &mut out, &literals, &distances)?
+            }
+            _ => return Err(invalid("reserved block type")),
+        }
+        if last {
+            out.flush(0)?;
+            return Ok(out.written);
+        }
+    }
+}
+
+fn stored<R: Read, W: Write>(bits: &mut Bits<R>, out: &mut Output<W>) -> io::Result<()> {
+    bits.align();
+    let mut header = [0; 4];
+    for byte in &mut header {
+        *byte = bits.byte()?;
+    }
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    if length != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(invalid("stored block length does not match its complement"));
+    }
+    for _ in 0..length {
+        out.push(bits.byte()?)?;
+    }
+    Ok(())
+}
+
+/// Reads the codes of a block with dynamic Huffman codes.
+fn dynamic_codes<R: Read>(bits: &mut Bits<R>) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(invalid("too many codes"));
+    }
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = bits.take(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + bits.take(2)?),
+                None => return Err(invalid("repeat without a previous length")),
+            },
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        if lengths.len() + repeat as usize > literal_count + distance_count {
+            return Err(invalid("too many code lengths"));
+        }
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths[256] == 0 {
+        return Err(invalid("no end of block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+fn compressed<R: Read, W: Write>(
+    bits: &mut Bits<R>,
+    out: &mut Output<W>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8)?,
+            256 => return Ok(()),
+            257..=285 => {
+                let i = symbol - 257;
+                let length = LENGTH_BASE[i] as usize + bits.take(LENGTH_EXTRA[i] as u32)? as usize;
+                let i = distances.decode(bits)? as usize;
+                if i >= 30 {
+                    return Err(invalid("unknown distance code"));
+                }
+                let distance =
+                    DISTANCE_BASE[i] as usize + bits.take(DISTANCE_EXTRA[i] as u32)? as usize;
+                out.copy(distance, length)?;
+            }
+            _ => return Err(invalid("unknown length code")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inflated(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        inflate(data, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn inflates_all_block_types() {
+        assert_eq!(
+            inflated(b"\x01\x06\x00\xf9\xff\x73\x74\x6f\x72\x65\x64").unwrap(),
+            b"stored"
+        );
+        // Fixed codes, with a match overlapping its own output
+        assert_eq!(
+            inflated(b"\xf3\x48\xcd\xc9\xc9\xd7\x51\xf0\x40\xa2\x14\x01").unwrap(),
+            b"Hello, Hello, Hello!"
+        );
+        let dynamic = b"\xed\xc9\x39\x11\x00\x30\x10\x03\x31\xac\xeb\x87\x3f\x85\x84\xc7\
+            \xb9\xd4\x08\x5b\x69\x90\x0a\x81\x4a\x7c\xcb\x66\xb3\xd9\x1c\x9b\x07";
+        let text: Vec<u8> = (0..1040usize)
+            .map(|i| b'a' + (i * i * 7 % 26 % 5) as u8)
+            .collect();
+        assert_eq!(inflated(dynamic).unwrap(), text);
+    }
+
+    #[test]
+    fn long_streams_are_written_in_chunks() {
+        let data: Vec<u8> = (0..400_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let mut stream = Vec::new();
+        let blocks: Vec<&[u8]> = data.chunks(65535).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            stream.push((i == blocks.len() - 1) as u8);
+            stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            stream.extend_from_slice(block);
+        }
+        assert_eq!(inflated(&stream).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        for data in [
+            &b""[..],
+            b"\x07",
+            b"\x01\x06\x00\x00\x00stored",
+            b"\x01\x06\x00\xf9\xffsto",
+            // A match before the start of the output
+            b"\x03\x02\x00\x00",
+        ] {
+            let err = inflated(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{data:?}");
+        }
+    }
+}
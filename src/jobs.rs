@@ -0,0 +1,409 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Concurrent external processes, timeouts, hang detection and retries.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{ReflacError, Result};
+
+/// How often running processes are checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Encoders whose output has not grown for this long are considered hung.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Delays between retries double up to this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// What happens to a process that ran into its timeout after it is killed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimeoutPolicy {
+    Fail,
+    /// Run it at least once more before failing
+    Retry,
+}
+
+impl FromStr for TimeoutPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(TimeoutPolicy::Fail),
+            "retry" => Ok(TimeoutPolicy::Retry),
+            _ => Err(format!("Unknown timeout policy: {s}")),
+        }
+    }
+}
+
+/// How external processes are supervised.
+pub struct Policy {
+    /// Wall-clock limit of tools without a limit of their own
+    pub timeout: Option<Duration>,
+    pub tool_timeouts: HashMap<String, Duration>,
+    /// Limit on encoders not producing output
+    pub stall: Option<Duration>,
+    pub on_timeout: TimeoutPolicy,
+    /// Further attempts after a failure
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub retry_delay: Duration,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self {
+            timeout: None,
+            tool_timeouts: HashMap::new(),
+            stall: Some(DEFAULT_STALL_TIMEOUT),
+            on_timeout: TimeoutPolicy::Fail,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    pub fn timeout_for(&self, tool: &str) -> Option<Duration> {
+        self.tool_timeouts.get(tool).copied().or(self.timeout)
+    }
+
+    /// Whether to run a process again after its `attempt`-th failure
+    /// (counting from 0).
+    pub fn should_retry(&self, attempt: u32, timed_out: bool) -> bool {
+        attempt < self.retries
+            || (timed_out && attempt == 0 && self.on_timeout == TimeoutPolicy::Retry)
+    }
+
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Sets the policy for the rest of the run. Only the first call has an
+/// effect.
+pub fn set_policy(policy: Policy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn policy() -> &'static Policy {
+    POLICY.get_or_init(Policy::new)
+}
+
+/// A failed attempt that was retried.
+#[derive(Clone)]
+pub struct Retry {
+    pub command: String,
+    pub track: Option<String>,
+    /// Number of the failed attempt, counting from 1
+    pub attempt: u32,
+    pub reason: String,
+}
+
+static RETRIES: Mutex<Vec<Retry>> = Mutex::new(Vec::new());
+
+/// Notes a retry for the run report and waits out its backoff.
+pub fn retry(command: &str, track: Option<String>, attempt: u32, reason: String) {
+    let mut retries = RETRIES.lock().unwrap_or_else(|e| e.into_inner());
+    retries.push(Retry {
+        command: command.to_string(),
+        track,
+        attempt: attempt + 1,
+        reason,
+    });
+    drop(retries);
+    thread::sleep(policy().backoff(attempt));
+}
+
+pub fn retries() -> Vec<Retry> {
+    RETRIES.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Parses a number of seconds, where 0 means no limit.
+pub fn parse_seconds(s: &str) -> Option<Option<Duration>> {
+    let secs: u64 = s.trim().parse().ok()?;
+    Some(Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+}
+
+/// Runs a process to completion, killing it once `timeout` has passed.
+/// Returns the exit status and error output, or `None` on a timeout.
+pub fn run(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+) -> io::Result<Option<(ExitStatus, Vec<u8>)>> {
+    let output = supervise(cmd.stderr(Stdio::piped()), timeout)?;
+    Ok(output.map(|output| (output.status, output.stderr)))
+}
+
+/// Like [`run`], but collects the standard output as well.
+pub fn output(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Option<Output>> {
+    supervise(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()), timeout)
+}
+
+fn supervise(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Option<Output>> {
+    let Some(timeout) = timeout else {
+        return cmd.output().map(Some);
+    };
+    let mut child = cmd.spawn()?;
+    // Drained concurrently so a chatty process cannot block on a full pipe
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut data);
+            }
+            data
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            }));
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// A process to run in a pool, optionally with a thread writing its input
+/// (such as an in-process decoder feeding an encoder).
+pub struct Process {
+    pub child: Child,
+    pub feeder: Option<thread::JoinHandle<Result<()>>>,
+}
+
+impl From<Child> for Process {
+    fn from(child: Child) -> Self {
+        Self {
+            child,
+            feeder: None,
+        }
+    }
+}
+
+/// A finished process.
+pub struct Finished<T> {
+    pub job: T,
+    pub status: ExitStatus,
+    pub stderr: Vec<u8>,
+    /// Killed because its output stopped growing
+    pub stalled: bool,
+    /// Why its feeder failed, if it did
+    pub feed_error: Option<ReflacError>,
+}
+
+struct Running<T> {
+    job: T,
+    child: Child,
+    feeder: Option<thread::JoinHandle<Result<()>>>,
+    /// File written by the process, watched for progress
+    output: Option<PathBuf>,
+    size: u64,
+    progressed: Instant,
+}
+
+/// Running processes, each labelled with a job `T`. Processes are collected
+/// in the order they finish, so a long track does not hold up the slots
+/// freed by short ones. Processes whose output file has not grown for
+/// `stall` are killed. Dropping the pool kills what is still running.
+pub struct Pool<T> {
+    limit: usize,
+    stall: Option<Duration>,
+    running: Vec<Running<T>>,
+}
+
+impl<T> Pool<T> {
+    pub fn new(limit: usize, stall: Option<Duration>) -> Self {
+        Self {
+            limit: limit.max(1),
+            stall,
+            running: Vec::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.running.len() >= self.limit
+    }
+
+    pub fn push(&mut self, job: T, process: impl Into<Process>, output: Option<PathBuf>) {
+        let Process { child, feeder } = process.into();
+        self.running.push(Running {
+            job,
+            child,
+            feeder,
+            output,
+            size: 0,
+            progressed: Instant::now(),
+        });
+    }
+
+    /// Waits until any process finishes or stalls. Returns `None` once none
+    /// are left.
+    pub fn wait_any(&mut self) -> io::Result<Option<Finished<T>>> {
+        while !self.running.is_empty() {
+            for i in 0..self.running.len() {
+                let running = &mut self.running[i];
+                let mut stalled = false;
+                let status = match running.child.try_wait()? {
+                    Some(status) => status,
+                    None if self.stall.is_some_and(|stall| running.is_stalled(stall)) => {
+                        stalled = true;
+                        let _ = running.child.kill();
+                        running.child.wait()?
+                    }
+                    None => continue,
+                };
+                let mut running = self.running.swap_remove(i);
+                let mut stderr = Vec::new();
+                if let Some(mut pipe) = running.child.stderr.take() {
+                    pipe.read_to_end(&mut stderr)?;
+                }
+                // The process is gone, so a feeder still writing fails
+                // rather than blocks
+                let feed_error = running.feeder.take().and_then(|feeder| {
+                    feeder
+                        .join()
+                        .unwrap_or_else(|_| Err(io::Error::other("feeder panicked").into()))
+                        .err()
+                });
+                return Ok(Some(Finished {
+                    job: running.job,
+                    status,
+                    stderr,
+                    stalled,
+                    feed_error,
+                }));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(None)
+    }
+}
+
+impl<T> Running<T> {
+    fn is_stalled(&mut self, stall: Duration) -> bool {
+        let Some(ref output) = self.output else {
+            return false;
+        };
+        let size = fs::metadata(output).map(|m| m.len()).unwrap_or(0);
+        if size != self.size {
+            self.size = size;
+            self.progressed = Instant::now();
+        }
+        self.progressed.elapsed() >= stall
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        for running in &mut self.running {
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn collects_in_completion_order() {
+        let mut pool = Pool::new(2, None);
+        for (job, secs) in [("slow", "0.5"), ("fast", "0")] {
+            pool.push(job, Command::new("sleep").arg(secs).spawn().unwrap(), None);
+        }
+        assert!(pool.is_full());
+        assert_eq!(pool.wait_any().unwrap().unwrap().job, "fast");
+        assert_eq!(pool.wait_any().unwrap().unwrap().job, "slow");
+        assert!(pool.wait_any().unwrap().is_none());
+    }
+
+    #[test]
+    fn kills_stalled_processes() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let output = dir.path().join("out");
+        let mut pool = Pool::new(1, Some(Duration::from_millis(100)));
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        pool.push((), child, Some(output));
+        let finished = pool.wait_any().unwrap().unwrap();
+        assert!(finished.stalled);
+        assert!(!finished.status.success());
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+        assert!(
+            run(&mut cmd, Some(Duration::from_millis(100)))
+                .unwrap()
+                .is_none()
+        );
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo oops >&2; exit 3"]);
+        let (status, stderr) = run(&mut cmd, Some(Duration::from_secs(10)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(stderr, b"oops\n");
+        assert_eq!(parse_seconds("0"), Some(None));
+        assert_eq!(parse_seconds("90"), Some(Some(Duration::from_secs(90))));
+        assert_eq!(parse_seconds("soon"), None);
+    }
+
+    #[test]
+    fn retry_policy() {
+        let mut policy = Policy::new();
+        assert!(!policy.should_retry(0, false));
+        policy.on_timeout = TimeoutPolicy::Retry;
+        assert!(policy.should_retry(0, true));
+        assert!(!policy.should_retry(1, true));
+        policy.retries = 2;
+        assert!(policy.should_retry(1, false));
+        assert!(!policy.should_retry(2, true));
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(4));
+        assert_eq!(policy.backoff(40), MAX_RETRY_DELAY);
+    }
+}
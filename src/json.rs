@@ -0,0 +1,285 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fmt;
+
+/// A JSON value, written compactly by its `Display` implementation.
+#[derive(Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn string<S: ToString>(value: S) -> Self {
+        Json::String(value.to_string())
+    }
+
+    pub fn optional<S: ToString>(value: Option<S>) -> Self {
+        value.map(Json::string).unwrap_or(Json::Null)
+    }
+
+    /// Parses a JSON document; `None` if it is malformed.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parser = Parser {
+            chars: text.char_indices().peekable(),
+            text,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        parser.chars.peek().is_none().then_some(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    text: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|&(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+    }
+
+    fn expect(&mut self, word: &str) -> Option<()> {
+        for expected in word.chars() {
+            self.chars.next_if(|&(_, c)| c == expected)?;
+        }
+        Some(())
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        let &(start, c) = self.chars.peek()?;
+        match c {
+            'n' => self.expect("null").map(|()| Json::Null),
+            't' => self.expect("true").map(|()| Json::Bool(true)),
+            'f' => self.expect("false").map(|()| Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if(|&(_, c)| c == ']').is_some() {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next()?.1 {
+                        ',' => (),
+                        ']' => return Some(Json::Array(items)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if(|&(_, c)| c == '}').is_some() {
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next()?.1 {
+                        ',' => (),
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            '-' | '0'..='9' => {
+                let mut end = start;
+                while let Some((i, c)) = self
+                    .chars
+                    .next_if(|&(_, c)| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9'))
+                {
+                    end = i + c.len_utf8();
+                }
+                let number = &self.text[start..end];
+                number
+                    .parse::<f64>()
+                    .ok()
+                    .map(|_| Json::Number(number.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next()?.1 {
+                '"' => return Some(s),
+                '\\' => match self.chars.next()?.1 {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let unit = self.hex4()?;
+                        // Characters outside the BMP come as surrogate pairs
+                        let c = if (0xd800..0xdc00).contains(&unit) {
+                            self.expect("\\u")?;
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return None;
+                            }
+                            char::from_u32(0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00))
+                        } else {
+                            char::from_u32(unit)
+                        };
+                        s.push(c?);
+                    }
+                    _ => return None,
+                },
+                c if (c as u32) < 0x20 => return None,
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let mut unit = 0;
+        for _ in 0..4 {
+            unit = unit * 16 + self.chars.next()?.1.to_digit(16)?;
+        }
+        Some(unit)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Number(n) => f.write_str(n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_writes() {
+        let value = Json::Object(vec![
+            (String::from("name"), Json::string("a \"b\"\n\u{1}")),
+            (
+                String::from("list"),
+                Json::Array(vec![
+                    Json::Null,
+                    Json::Bool(true),
+                    Json::Number(String::from("-1.5e3")),
+                ]),
+            ),
+            (String::from("empty"), Json::Object(Vec::new())),
+        ]);
+        assert_eq!(Json::parse(&value.to_string()), Some(value));
+    }
+
+    #[test]
+    fn parses_escapes_and_whitespace() {
+        let value =
+            Json::parse(" { \"t\" : \"\\u00e9\\ud83c\\udfb5\\/\" , \"n\": [ ] }\n").unwrap();
+        assert_eq!(value.get("t"), Some(&Json::string("é🎵/")));
+        assert_eq!(value.get("n"), Some(&Json::Array(Vec::new())));
+        for bad in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "\"\\ud800\"",
+            "nul",
+            "1 2",
+            "\"\t\"",
+        ] {
+            assert_eq!(Json::parse(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Consistency checks of TRACKINFO files beyond their syntax.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{Result, Tag, format_date, is_future, parse_trackinfo_str};
+
+/// Words left lowercase inside titles in Title Case.
+const MINOR_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "for", "from", "in", "into", "nor", "of", "on",
+    "or", "the", "to", "vs", "with",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Casing {
+    Title,
+    Sentence,
+    Upper,
+    Lower,
+}
+
+impl Casing {
+    fn name(self) -> &'static str {
+        match self {
+            Casing::Title => "Title Case",
+            Casing::Sentence => "Sentence case",
+            Casing::Upper => "UPPERCASE",
+            Casing::Lower => "lowercase",
+        }
+    }
+}
+
+/// The casing style of a title, if it has enough words to tell.
+fn casing(title: &str) -> Option<Casing> {
+    let words: Vec<&str> = title
+        .split_whitespace()
+        .filter(|w| w.chars().next().is_some_and(char::is_alphabetic))
+        .collect();
+    if words.len() < 2 || !title.chars().any(|c| c.is_lowercase() || c.is_uppercase()) {
+        return None;
+    }
+    if !title.chars().any(char::is_lowercase) {
+        return Some(Casing::Upper);
+    }
+    if !title.chars().any(char::is_uppercase) {
+        return Some(Casing::Lower);
+    }
+    let capitalized = |w: &&str| w.chars().next().is_some_and(char::is_uppercase);
+    let later = &words[1..];
+    if later.iter().any(capitalized)
+        && later
+            .iter()
+            .all(|w| capitalized(w) || MINOR_WORDS.contains(&w.to_lowercase().as_str()))
+    {
+        Some(Casing::Title)
+    } else if capitalized(&words[0]) && !later.iter().any(capitalized) {
+        Some(Casing::Sentence)
+    } else {
+        None
+    }
+}
+
+fn whitespace(text: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let Some((_, value)) = line.split_once('=') else {
+            continue;
+        };
+        let n = n + 1;
+        if value.trim() != value {
+            warnings.push(format!("line {n}: leading or trailing whitespace"));
+        }
+        if value.contains("  ") {
+            warnings.push(format!("line {n}: repeated spaces"));
+        }
+        if value.chars().any(|c| c.is_whitespace() && c != ' ') {
+            warnings.push(format!("line {n}: tab or unusual space character"));
+        }
+    }
+    warnings
+}
+
+fn titles(tags: &[Tag]) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let styles: Vec<(&Tag, Casing)> = tags
+        .iter()
+        .filter_map(|t| Some((t, casing(t.title.as_deref()?)?)))
+        .collect();
+    let mut counts: BTreeMap<Casing, usize> = BTreeMap::new();
+    for (_, style) in &styles {
+        *counts.entry(*style).or_default() += 1;
+    }
+    if let Some((&usual, _)) = counts
+        .iter()
+        .max_by_key(|(style, n)| (**n, std::cmp::Reverse(**style)))
+        && counts.len() > 1
+    {
+        for (tag, style) in styles.iter().filter(|(_, s)| *s != usual) {
+            warnings.push(format!(
+                "#{}: title is in {} while most titles are in {}",
+                tag.id(),
+                style.name(),
+                usual.name()
+            ));
+        }
+    }
+
+    let mut seen: Vec<(String, Vec<String>)> = Vec::new();
+    for tag in tags {
+        let Some(title) = tag.title.as_deref() else {
+            continue;
+        };
+        let key = title.to_lowercase();
+        match seen.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, ids)) => ids.push(tag.id()),
+            None => seen.push((key, vec![tag.id()])),
+        }
+    }
+    for (title, ids) in seen.iter().filter(|(_, ids)| ids.len() > 1) {
+        let ids: Vec<String> = ids.iter().map(|id| format!("#{id}")).collect();
+        warnings.push(format!("{} share the title \"{title}\"", ids.join(", ")));
+    }
+    warnings
+}
+
+fn numbering(tags: &[Tag]) -> Vec<String> {
+    // Side positions are numbered continuously by the parser
+    if tags.iter().any(|t| t.position.is_some()) {
+        return Vec::new();
+    }
+    let numbers: Vec<usize> = tags.iter().map(|t| t.track.unwrap()).collect();
+    let max = numbers.iter().copied().max().unwrap_or(0);
+    let missing: Vec<String> = (1..max)
+        .filter(|n| !numbers.contains(n))
+        .map(|n| n.to_string())
+        .collect();
+    if missing.is_empty() {
+        Vec::new()
+    } else {
+        vec![format!("track numbers skip {}", missing.join(", "))]
+    }
+}
+
+fn no_tracks(tags: &[Tag]) -> Vec<String> {
+    if tags.is_empty() {
+        vec![String::from("no tracks")]
+    } else {
+        Vec::new()
+    }
+}
+
+fn missing_album(tags: &[Tag]) -> Vec<String> {
+    let missing: Vec<String> = tags
+        .iter()
+        .filter(|t| t.album.as_deref().is_none_or(|a| a.trim().is_empty()))
+        .map(|t| format!("#{}", t.id()))
+        .collect();
+    if missing.is_empty() {
+        Vec::new()
+    } else if missing.len() == tags.len() {
+        vec![String::from("ALBUM is missing or empty")]
+    } else {
+        vec![format!("{}: ALBUM is missing or empty", missing.join(", "))]
+    }
+}
+
+fn label_without_date(tags: &[Tag]) -> Vec<String> {
+    let undated: Vec<String> = tags
+        .iter()
+        .filter(|t| t.label.is_some() && t.date.is_none())
+        .map(|t| format!("#{}", t.id()))
+        .collect();
+    if undated.is_empty() {
+        Vec::new()
+    } else if undated.len() == tags.len() {
+        vec![String::from("LABEL is set but DATE is missing")]
+    } else {
+        vec![format!(
+            "{}: LABEL is set but DATE is missing",
+            undated.join(", ")
+        )]
+    }
+}
+
+/// A compilation with an artist for the whole album credits every track to
+/// that artist, which is rarely what was meant.
+fn compilation_artist(text: &str, tags: &[Tag]) -> Vec<String> {
+    let mut global_artist = false;
+    let mut no_inherit = false;
+    for (key, value) in text.lines().filter_map(|line| line.split_once('=')) {
+        match key {
+            "ARTIST" => global_artist = !matches!(value.trim(), "" | "VARIOUS"),
+            "NOINHERIT" => no_inherit = value.split(';').any(|f| f.trim() == "ARTIST"),
+            _ => (),
+        }
+    }
+    let compilation = |t: &Tag| t.extra.iter().any(|(field, _)| field == "COMPILATION");
+    let mut warnings = Vec::new();
+    if global_artist && !no_inherit && tags.iter().any(compilation) {
+        warnings.push(String::from(
+            "ARTIST is set for the whole album but COMPILATION=1, \
+             use ARTIST=VARIOUS or NOINHERIT=ARTIST",
+        ));
+    }
+    let unknown: Vec<String> = tags
+        .iter()
+        .filter(|t| compilation(t) && t.artist.is_none())
+        .map(|t| format!("#{}", t.id()))
+        .collect();
+    if !unknown.is_empty() {
+        warnings.push(format!("{}: ARTIST is missing", unknown.join(", ")));
+    }
+    warnings
+}
+
+fn future_dates(tags: &[Tag]) -> Vec<String> {
+    let mut dates: Vec<[u32; 3]> = tags
+        .iter()
+        .filter_map(|t| t.date)
+        .filter(|&d| is_future(d))
+        .collect();
+    dates.dedup();
+    dates
+        .into_iter()
+        .map(|d| format!("DATE {} is in the future", format_date(d)))
+        .collect()
+}
+
+/// Warnings about a TRACKINFO file. Fails only if it cannot be parsed.
+pub fn check(text: &str) -> Result<Vec<String>> {
+    let tags = parse_trackinfo_str(text)?;
+    let mut warnings = whitespace(text);
+    warnings.extend(no_tracks(&tags));
+    warnings.extend(missing_album(&tags));
+    warnings.extend(titles(&tags));
+    warnings.extend(numbering(&tags));
+    warnings.extend(label_without_date(&tags));
+    warnings.extend(compilation_artist(text, &tags));
+    warnings.extend(future_dates(&tags));
+    Ok(warnings)
+}
+
+/// Prints the warnings about a TRACKINFO file.
+pub fn run(path: &Path) -> Result<()> {
+    let warnings = check(&fs::read_to_string(path)?)?;
+    for warning in &warnings {
+        println!("{}: {warning}", path.display());
+    }
+    info!("{} warning(s)", warnings.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn casing_styles() {
+        assert!(casing("Walking in Memphis") == Some(Casing::Title));
+        assert!(casing("Walking in memphis") == Some(Casing::Sentence));
+        assert!(casing("WALKING IN MEMPHIS") == Some(Casing::Upper));
+        assert!(casing("walking in memphis") == Some(Casing::Lower));
+        assert!(casing("Intro").is_none());
+        assert!(casing("春の 歌").is_none());
+    }
+
+    #[test]
+    fn sloppy_trackinfo() {
+        let warnings = check(
+            "ALBUM=Album\nLABEL=Label\nTITLE[1]=First Song Here\nTITLE[2]=Second Song Here\n\
+             TITLE[3]=third song  here\nTITLE[5]=First song here\nTITLE[6]=Intro \n\
+             DATE[6]=2999-01-01\n",
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "line 5: repeated spaces",
+                "line 7: leading or trailing whitespace",
+                "#3: title is in lowercase while most titles are in Title Case",
+                "#5: title is in Sentence case while most titles are in Title Case",
+                "#1, #5 share the title \"first song here\"",
+                "track numbers skip 4",
+                "#1, #2, #3, #5: LABEL is set but DATE is missing",
+                "DATE 2999-01-01 is in the future",
+            ]
+        );
+    }
+
+    #[test]
+    fn compilation_artists() {
+        let warnings = check("ALBUM=Album\nARTIST=Someone\nCOMPILATION=1\nTITLE[1]=One\n").unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "ARTIST is set for the whole album but COMPILATION=1, use ARTIST=VARIOUS or NOINHERIT=ARTIST"
+            ]
+        );
+        let warnings =
+            check("ALBUM=Album\nARTIST=VARIOUS\nTITLE[1]=One\nARTIST[1]=A\nTITLE[2]=Two\n")
+                .unwrap();
+        assert_eq!(warnings, ["#2: ARTIST is missing"]);
+        let warnings =
+            check("ALBUM=Album\nARTIST=Conductor\nNOINHERIT=ARTIST\nCOMPILATION=1\nTITLE[1]=One\nARTIST[1]=A\n")
+                .unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn clean_trackinfo() {
+        let warnings = check(
+            "ALBUM=Album\nDATE=2020-01-02\nLABEL=Label\nDISC[1]=1\nTITLE[1]=One\nDISC[2]=2\nTITLE[2]=Two\n",
+        )
+        .unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn missing_album_and_tracks() {
+        let warnings = check("ALBUM=\nTITLE[1]=One\n").unwrap();
+        assert_eq!(warnings, ["ALBUM is missing or empty"]);
+        let warnings = check("TITLE[1]=One\nALBUM[2]=Album\nTITLE[2]=Two\n").unwrap();
+        assert_eq!(warnings, ["#1: ALBUM is missing or empty"]);
+        let warnings = check("ALBUM=Album\nARTIST=Someone\n").unwrap();
+        assert_eq!(warnings, ["no tracks"]);
+    }
+}
@@ -0,0 +1,96 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Advisory locks keeping concurrent runs off the same album.
+
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::{ReflacError, Result};
+
+/// Exclusive claim on an album directory, held until dropped. The lock is an
+/// `flock` on a hidden file next to the album, so it is released even if the
+/// process dies; the file itself is removed on drop.
+pub struct AlbumLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl AlbumLock {
+    /// Locks `album_path`, failing right away if another run holds it.
+    pub fn acquire(album_path: &Path) -> Result<Self> {
+        let name = album_path.file_name().unwrap().to_string_lossy();
+        let path = album_path.with_file_name(format!(".{name}.reflac-lock"));
+        loop {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(|err| ReflacError::CreateFileFailed(path.clone(), err))?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Err(ReflacError::AlbumLocked(album_path.to_path_buf()));
+                }
+                return Err(err.into());
+            }
+            // The previous holder may have removed the file between our open
+            // and flock, leaving us with a lock nobody else can see
+            let held = file.metadata()?;
+            match fs::metadata(&path) {
+                Ok(current) if current.ino() == held.ino() && current.dev() == held.dev() => {
+                    return Ok(Self { path, _file: file });
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Drop for AlbumLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so no other run can be holding it
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_fails_fast() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let album = dir.path().join("Album");
+        let lock = AlbumLock::acquire(&album).unwrap();
+        assert!(matches!(
+            AlbumLock::acquire(&album),
+            Err(ReflacError::AlbumLocked(_))
+        ));
+        drop(lock);
+        assert!(!dir.path().join(".Album.reflac-lock").exists());
+        AlbumLock::acquire(&album).unwrap();
+    }
+}
@@ -20,106 +20,600 @@
 // IN THE SOFTWARE.
 //
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fmt;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, ExitCode, Stdio};
+use std::process::{Child, Command, ExitCode, ExitStatus, Stdio};
 use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime};
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+#[macro_use]
+mod console;
 
-#[derive(Debug)]
+mod ab;
+mod archive;
+mod art;
+mod bench;
+mod cache;
+mod chapters;
+mod checkpoint;
+mod config;
+mod cue;
+mod decoder;
+mod discid;
+mod edit;
+mod estimate;
+mod export;
+mod flac;
+mod gain;
+mod hash;
+mod inflate;
+mod jobs;
+mod json;
+mod lint;
+mod lock;
+mod normalize;
+mod plugin;
+mod progress;
+mod provenance;
+mod prune;
+mod quality;
+mod report;
+mod sandbox;
+mod throttle;
+mod verify;
+mod zip;
+
+use archive::is_safe_member;
+use config::{Config, GainMode};
+use console::ColorChoice;
+use edit::Edit;
+use normalize::{FeatTarget, Typography};
+use provenance::{Environment, Provenance, TrackRecord};
+use report::{Check, Failure, Report, TrackReport};
+use sandbox::Sandbox;
+
+type Result<T> = std::result::Result<T, ReflacError>;
+
+/// FLAC settings used for recompressing.
+const ENCODER_SETTINGS: &[&str] = &[
+    "--best",
+    "--exhaustive-model-search",
+    "--qlp-coeff-precision-search",
+];
+
+/// Track mappings less certain than this (in percent) are warned about and
+/// listed for review.
+const REVIEW_CONFIDENCE: u8 = 50;
+
+/// Albums of the library that share a cover before it counts as a likely
+/// placeholder.
+const SHARED_COVER_ALBUMS: usize = 3;
+
+/// Lines of a failed command's error output kept for messages and reports.
+const STDERR_EXCERPT_LINES: usize = 5;
+
+/// The resolved TRACKINFO written into every album.
+const COMPLETED_TRACKINFO: &str = "reflac.trackinfo";
+
+/// Default limit on the bytes of a tag value; some players cut or choke on
+/// longer ones.
+const MAX_TAG_LENGTH: usize = 4096;
+
+#[derive(Debug, thiserror::Error)]
 enum ReflacError {
+    #[error("Already being processed by another reflac run: {}", .0.display())]
+    AlbumLocked(PathBuf),
+    #[error("Several TRACKINFO files in {}: {}", .0.display(), .1.join(", "))]
+    AmbiguousTrackinfo(PathBuf, Vec<String>),
+    #[error("{} was started from another TRACKINFO file, cannot resume", .0.display())]
+    CheckpointMismatch(PathBuf),
+    #[error("Could not create {}: {}", .0.display(), .1)]
+    CreateDirFailed(PathBuf, #[source] std::io::Error),
+    #[error("Could not create {}: {}", .0.display(), .1)]
+    CreateFileFailed(PathBuf, #[source] std::io::Error),
+    #[error(
+        "Your flac {}.{} is too old for {} ({}), flac {}.{} or later is needed",
+        .0.0,
+        .0.1,
+        .1,
+        .2.display(),
+        .3.0,
+        .3.1
+    )]
+    EncoderTooOld((u32, u32), &'static str, PathBuf, (u32, u32)),
+    #[error("Could not decode {0}: {1}")]
+    DecodeFailed(String, #[source] std::io::Error),
+    #[error("DATE {0} is in the future, pass --allow-future-date if it is right")]
+    FutureDate(String),
+    #[error("Audio format differs from the first track: {}", .0.display())]
+    IncompatibleTracks(PathBuf),
+    #[error("Input file not found for track: {0}")]
     InputTrackNotFound(usize),
+    #[error(
+        "Not enough free space in {}: about {} MiB needed, {} MiB available",
+        .0.display(),
+        .1.div_ceil(1 << 20),
+        .2 / (1 << 20)
+    )]
+    InsufficientSpace(PathBuf, u64, u64),
+    #[error("Invalid archive {}: {}", .0.display(), .1)]
+    InvalidArchive(PathBuf, String),
+    #[error("Unreadable checkpoint: {}", .0.display())]
+    InvalidCheckpoint(PathBuf),
+    #[error("Invalid config line: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid cue sheet {}: {}", .0.display(), .1)]
+    InvalidCueSheet(PathBuf, String),
+    #[error("Invalid value of {0}: {1}")]
+    InvalidEnvVar(&'static str, String),
+    #[error("Invalid tag name: {0}")]
+    InvalidFieldName(String),
+    #[error("Not a valid FLAC file: {}", .0.display())]
+    InvalidFlac(PathBuf),
+    #[error("Invalid input path: {}", .0.display())]
     InvalidInputPath(PathBuf),
+    #[error("Unreadable provenance record: {}", .0.display())]
+    InvalidProvenance(PathBuf),
+    #[error("Invalid TRACKINFO line: {0}")]
     InvalidTrackinfo(String),
+    #[error("Invalid TRACKINFO line: {0} ({1})")]
+    InvalidTrackinfoValue(String, &'static str),
+    #[error("Invalid tag values: {}", .0.join("; "))]
+    InvalidTagValues(Vec<String>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(
+        "Track {}: only {}% sure of the input file, --min-confidence is {}%",
+        .0,
+        .1,
+        .2
+    )]
+    LowConfidence(String, u8, u8),
+    #[error("TRACKINFO has no {0}")]
+    MissingField(&'static str),
+    #[error("Missing INPUT for track: {0}")]
     MissingInput(usize),
+    #[error("{0} is not installed, but {1}")]
+    MissingProgram(&'static str, &'static str),
+    #[error("Source of track {0} not found, pass it with --source")]
+    MissingSource(String),
+    #[error("Some tracks have a DISC and others do not")]
+    MixedDiscNumbers,
+    #[error("Tracks of an album cannot select different profiles")]
+    MixedProfiles,
+    #[error("Track numbers and side positions cannot be mixed")]
+    MixedTrackIdentifiers,
+    #[error("Tracks of the album differ in format: {}", .0.join(", "))]
+    MixedFormats(Vec<String>),
+    #[error("No FLAC files found: {}", .0.display())]
     NoFlacFilesFound(PathBuf),
+    #[error("No picture found: {}", .0.display())]
+    NoPictureFound(PathBuf),
+    #[error("No PLAYER configured")]
+    NoPlayer,
+    #[error("No TRACKINFO file found: {}", .0.display())]
+    NoTrackinfoFound(PathBuf),
+    #[error(
+        "Tracks would overwrite each other: {}; pass --on-collision suffix to number them",
+        .0.join(", ")
+    )]
+    OutputCollision(Vec<String>),
+    #[error("Output of track {0} differs from its source")]
+    OutputDiffers(String),
+    #[error("Not a directory: {}", .0.display())]
+    NotADirectory(PathBuf),
+    #[error("Not a FLAC source: {}", .0.display())]
+    NotAFlacSource(PathBuf),
+    #[error("Path does not exist: {}", .0.display())]
     PathDoesNotExist(PathBuf),
-    SubprocessError(&'static str),
+    #[error("Tag provider {0} failed: {1}")]
+    ProviderFailed(String, String),
+    #[error("Failure executing: {command}{}", status_suffix(*.status, .stderr))]
+    Subprocess {
+        command: &'static str,
+        status: Option<i32>,
+        stderr: String,
+    },
+    #[error("{} made no progress for {} s and was stopped", .0, .1.as_secs())]
+    Stalled(&'static str, std::time::Duration),
+    #[error("{} timed out after {} s", .0, .1.as_secs())]
+    TimedOut(&'static str, std::time::Duration),
+    #[error("Track {track}: {source}")]
+    Track {
+        track: String,
+        source: Box<ReflacError>,
+    },
+    #[error("Track is already in the album: {0}")]
+    TrackExists(String),
+    #[error("Profile not found in the configuration: {0}")]
+    UnknownProfile(String),
+    #[error("Tag provider not found in the configuration: {0}")]
+    UnknownProvider(String),
+    #[error("Track not found in the album: {0}")]
+    UnknownTrack(String),
+    #[error("Unknown archive type: {0}")]
     UnknownArchiveType(String),
+    #[error("{} was left unfinished, finish it with --resume", .0.display())]
+    UnfinishedAlbum(PathBuf),
+    #[error("Refusing to extract {}: unsafe member \"{}\"", .0.display(), .1)]
+    UnsafeArchiveMember(PathBuf, String),
+    #[error("{} verification failed: {}", .1, .0.display())]
+    VerificationFailed(PathBuf, &'static str),
+    #[error("{} is inside source directory {}", .0.display(), .1.display())]
+    WriteInsideSource(PathBuf, PathBuf),
+}
+
+fn status_suffix(status: Option<i32>, stderr: &str) -> String {
+    let mut suffix = match status {
+        Some(code) => format!(" (exit status {code})"),
+        None => String::new(),
+    };
+    if let Some(last) = stderr.lines().last() {
+        suffix.push_str(": ");
+        suffix.push_str(last);
+    }
+    suffix
 }
 
-impl fmt::Display for ReflacError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl ReflacError {
+    /// A failed external command. Keeps the last few lines of its error
+    /// output, if it was captured.
+    fn subprocess(command: &'static str, status: Option<ExitStatus>, stderr: &[u8]) -> Self {
+        let stderr = String::from_utf8_lossy(stderr);
+        let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+        ReflacError::Subprocess {
+            command,
+            status: status.and_then(|s| s.code()),
+            stderr: lines[lines.len().saturating_sub(STDERR_EXCERPT_LINES)..].join("\n"),
+        }
+    }
+
+    /// Attributes an error to a track.
+    fn in_track(self, track: String) -> Self {
+        ReflacError::Track {
+            track,
+            source: Box::new(self),
+        }
+    }
+
+    /// Stable identifier of the kind of failure, for the JSON report.
+    fn code(&self) -> &'static str {
+        match self {
+            ReflacError::AlbumLocked(_) => "album-locked",
+            ReflacError::AmbiguousTrackinfo(..) => "ambiguous-trackinfo",
+            ReflacError::CheckpointMismatch(_) => "checkpoint-mismatch",
+            ReflacError::CreateDirFailed(..) => "create-dir-failed",
+            ReflacError::CreateFileFailed(..) => "create-file-failed",
+            ReflacError::DecodeFailed(..) => "decode-failed",
+            ReflacError::EncoderTooOld(..) => "encoder-too-old",
+            ReflacError::FutureDate(_) => "future-date",
+            ReflacError::IncompatibleTracks(_) => "incompatible-tracks",
+            ReflacError::InputTrackNotFound(_) => "input-track-not-found",
+            ReflacError::InsufficientSpace(..) => "insufficient-space",
+            ReflacError::InvalidArchive(..) => "invalid-archive",
+            ReflacError::InvalidCheckpoint(_) => "invalid-checkpoint",
+            ReflacError::InvalidConfig(_) => "invalid-config",
+            ReflacError::InvalidCueSheet(..) => "invalid-cue-sheet",
+            ReflacError::InvalidEnvVar(..) => "invalid-env-var",
+            ReflacError::InvalidFieldName(_) => "invalid-field-name",
+            ReflacError::InvalidFlac(_) => "invalid-flac",
+            ReflacError::InvalidInputPath(_) => "invalid-input-path",
+            ReflacError::InvalidProvenance(_) => "invalid-provenance",
+            ReflacError::InvalidTrackinfo(_) => "invalid-trackinfo",
+            ReflacError::InvalidTrackinfoValue(..) => "invalid-trackinfo-value",
+            ReflacError::InvalidTagValues(_) => "invalid-tag-values",
+            ReflacError::Io(_) => "io",
+            ReflacError::LowConfidence(..) => "low-confidence",
+            ReflacError::MissingField(_) => "missing-field",
+            ReflacError::MissingInput(_) => "missing-input",
+            ReflacError::MissingProgram(..) => "missing-program",
+            ReflacError::MissingSource(_) => "missing-source",
+            ReflacError::MixedDiscNumbers => "mixed-disc-numbers",
+            ReflacError::MixedFormats(_) => "mixed-formats",
+            ReflacError::MixedProfiles => "mixed-profiles",
+            ReflacError::MixedTrackIdentifiers => "mixed-track-identifiers",
+            ReflacError::NoFlacFilesFound(_) => "no-flac-files-found",
+            ReflacError::NoPictureFound(_) => "no-picture-found",
+            ReflacError::NoPlayer => "no-player",
+            ReflacError::NoTrackinfoFound(_) => "no-trackinfo-found",
+            ReflacError::NotADirectory(_) => "not-a-directory",
+            ReflacError::NotAFlacSource(_) => "not-a-flac-source",
+            ReflacError::OutputCollision(_) => "output-collision",
+            ReflacError::OutputDiffers(_) => "output-differs",
+            ReflacError::PathDoesNotExist(_) => "path-does-not-exist",
+            ReflacError::ProviderFailed(..) => "provider-failed",
+            ReflacError::Stalled(..) => "stalled",
+            ReflacError::Subprocess { .. } => "subprocess-failed",
+            ReflacError::TimedOut(..) => "timed-out",
+            ReflacError::Track { source, .. } => source.code(),
+            ReflacError::TrackExists(_) => "track-exists",
+            ReflacError::UnknownArchiveType(_) => "unknown-archive-type",
+            ReflacError::UnfinishedAlbum(_) => "unfinished-album",
+            ReflacError::UnknownProfile(_) => "unknown-profile",
+            ReflacError::UnknownProvider(_) => "unknown-provider",
+            ReflacError::UnknownTrack(_) => "unknown-track",
+            ReflacError::UnsafeArchiveMember(..) => "unsafe-archive-member",
+            ReflacError::VerificationFailed(..) => "verification-failed",
+            ReflacError::WriteInsideSource(..) => "write-inside-source",
+        }
+    }
+
+    /// Details of the failure (track, path, command, ...) for the JSON
+    /// report.
+    fn context(&self) -> Vec<(&'static str, String)> {
         match self {
-            ReflacError::InputTrackNotFound(track) => {
-                write!(f, "Input file not found for track: {track}")
+            ReflacError::AlbumLocked(path)
+            | ReflacError::AmbiguousTrackinfo(path, _)
+            | ReflacError::CheckpointMismatch(path)
+            | ReflacError::CreateDirFailed(path, _)
+            | ReflacError::CreateFileFailed(path, _)
+            | ReflacError::EncoderTooOld(_, _, path, _)
+            | ReflacError::IncompatibleTracks(path)
+            | ReflacError::InsufficientSpace(path, ..)
+            | ReflacError::InvalidCheckpoint(path)
+            | ReflacError::InvalidFlac(path)
+            | ReflacError::InvalidInputPath(path)
+            | ReflacError::InvalidProvenance(path)
+            | ReflacError::NoFlacFilesFound(path)
+            | ReflacError::NoPictureFound(path)
+            | ReflacError::NoTrackinfoFound(path)
+            | ReflacError::NotADirectory(path)
+            | ReflacError::NotAFlacSource(path)
+            | ReflacError::PathDoesNotExist(path)
+            | ReflacError::UnfinishedAlbum(path)
+            | ReflacError::UnsafeArchiveMember(path, _)
+            | ReflacError::VerificationFailed(path, _)
+            | ReflacError::WriteInsideSource(path, _) => {
+                vec![("path", path.display().to_string())]
+            }
+            ReflacError::InputTrackNotFound(track) | ReflacError::MissingInput(track) => {
+                vec![("track", track.to_string())]
             }
-            ReflacError::InvalidInputPath(path) => {
-                write!(f, "Invalid input path: {}", path.display())
+            ReflacError::MissingSource(track)
+            | ReflacError::OutputDiffers(track)
+            | ReflacError::TrackExists(track)
+            | ReflacError::UnknownTrack(track) => {
+                vec![("track", track.clone())]
             }
-            ReflacError::InvalidTrackinfo(line) => write!(f, "Invalid TRACKINFO line: {line}"),
-            ReflacError::MissingInput(track) => write!(f, "Missing INPUT for track: {track}"),
-            ReflacError::NoFlacFilesFound(path) => {
-                write!(f, "No FLAC files found: {}", path.display())
+            ReflacError::LowConfidence(track, score, _) => {
+                vec![("track", track.clone()), ("confidence", score.to_string())]
+            }
+            ReflacError::DecodeFailed(source, _) => vec![("source", source.clone())],
+            ReflacError::UnknownProfile(name) => vec![("profile", name.clone())],
+            ReflacError::UnknownProvider(name) | ReflacError::ProviderFailed(name, _) => {
+                vec![("provider", name.clone())]
+            }
+            ReflacError::MissingProgram(program, _) => vec![("command", program.to_string())],
+            ReflacError::InvalidTrackinfo(line) | ReflacError::InvalidConfig(line) => {
+                vec![("line", line.clone())]
+            }
+            ReflacError::InvalidArchive(path, reason)
+            | ReflacError::InvalidCueSheet(path, reason) => vec![
+                ("path", path.display().to_string()),
+                ("reason", reason.clone()),
+            ],
+            ReflacError::InvalidTrackinfoValue(line, reason) => {
+                vec![("line", line.clone()), ("reason", reason.to_string())]
+            }
+            ReflacError::FutureDate(date) => vec![("date", date.clone())],
+            ReflacError::InvalidTagValues(problems) => {
+                problems.iter().map(|p| ("field", p.clone())).collect()
+            }
+            ReflacError::OutputCollision(collisions) => collisions
+                .iter()
+                .map(|c| ("collision", c.clone()))
+                .collect(),
+            ReflacError::InvalidEnvVar(name, _) => vec![("variable", name.to_string())],
+            ReflacError::MissingField(field) => vec![("field", field.to_string())],
+            ReflacError::MixedFormats(formats) => {
+                formats.iter().map(|f| ("format", f.clone())).collect()
+            }
+            ReflacError::Subprocess {
+                command,
+                status,
+                stderr,
+            } => {
+                let mut context = vec![("command", command.to_string())];
+                if let Some(status) = status {
+                    context.push(("status", status.to_string()));
+                }
+                if !stderr.is_empty() {
+                    context.push(("stderr", stderr.clone()));
+                }
+                context
             }
-            ReflacError::PathDoesNotExist(path) => {
-                write!(f, "Path does not exist: {}", path.display())
+            ReflacError::Stalled(command, limit) | ReflacError::TimedOut(command, limit) => vec![
+                ("command", command.to_string()),
+                ("seconds", limit.as_secs().to_string()),
+            ],
+            ReflacError::Track { track, source } => {
+                let mut context = vec![("track", track.clone())];
+                context.extend(source.context().into_iter().filter(|(k, _)| *k != "track"));
+                context
             }
-            ReflacError::SubprocessError(cmd) => write!(f, "Failure executing: {cmd}"),
-            ReflacError::UnknownArchiveType(ext) => write!(f, "Unknown archive type: {ext}"),
+            _ => Vec::new(),
         }
     }
 }
 
-impl std::error::Error for ReflacError {}
+/// Runs a command to completion within the timeout configured for it,
+/// retrying failures as configured. On failure the error carries the exit
+/// status and the end of the command's error output.
+fn run_command(cmd: &mut Command, name: &'static str) -> Result<()> {
+    let policy = jobs::policy();
+    let timeout = policy.timeout_for(name);
+    let mut attempt = 0;
+    loop {
+        let err = match jobs::run(cmd, timeout)? {
+            Some((status, _)) if status.success() => return Ok(()),
+            Some((status, stderr)) => ReflacError::subprocess(name, Some(status), &stderr),
+            None => ReflacError::TimedOut(name, timeout.unwrap()),
+        };
+        if !policy.should_retry(attempt, matches!(err, ReflacError::TimedOut(..))) {
+            return Err(err);
+        }
+        warning!(retry: "{err}, retrying ...");
+        jobs::retry(name, None, attempt, err.to_string());
+        attempt += 1;
+    }
+}
+
+/// Runs a command under the timeout policy and returns its exit status and
+/// error output, leaving it to the caller to judge the status.
+fn command_status(cmd: &mut Command, name: &'static str) -> Result<(ExitStatus, Vec<u8>)> {
+    let timeout = jobs::policy().timeout_for(name);
+    jobs::run(cmd, timeout)?.ok_or_else(|| ReflacError::TimedOut(name, timeout.unwrap()))
+}
+
+/// Like [`command_status`], but collects the standard output as well.
+fn command_output(cmd: &mut Command, name: &'static str) -> Result<std::process::Output> {
+    let timeout = jobs::policy().timeout_for(name);
+    jobs::output(cmd, timeout)?.ok_or_else(|| ReflacError::TimedOut(name, timeout.unwrap()))
+}
+
+/// Runs a `--pre-track` or `--post-track` command through the shell. The
+/// track is described in its environment: `REFLAC_TRACK`, `REFLAC_SOURCE`,
+/// `REFLAC_OUTPUT` and a `REFLAC_TAG_<FIELD>` for every tag.
+fn run_hook(
+    command: &str,
+    name: &'static str,
+    tag: &Tag,
+    source: &Source,
+    out_path: &Path,
+) -> Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        // stdout is for results
+        .stdout(std::io::stderr())
+        .env("REFLAC_TRACK", tag.id())
+        .env("REFLAC_SOURCE", source.display())
+        .env("REFLAC_OUTPUT", out_path);
+    let mut fields: Vec<(String, String)> = tag
+        .text_fields()
+        .into_iter()
+        .map(|(field, value)| (field.to_string(), value.clone()))
+        .collect();
+    let number = tag.number.or(tag.track).unwrap();
+    fields.push((String::from("TRACKNUMBER"), number.to_string()));
+    fields.extend(
+        tag.disc
+            .map(|d| (String::from("DISCNUMBER"), d.to_string())),
+    );
+    fields.extend(tag.date.map(|d| (String::from("DATE"), format_date(d))));
+    let mut env: Vec<(String, String)> = Vec::new();
+    for (field, value) in fields {
+        // Several values of a field (STYLE, MOOD) are joined
+        match env.iter_mut().find(|(f, _)| *f == field) {
+            Some((_, joined)) => *joined = format!("{joined}; {value}"),
+            None => env.push((field, value)),
+        }
+    }
+    for (field, value) in env {
+        cmd.env(format!("REFLAC_TAG_{field}"), value);
+    }
+    run_command(&mut cmd, name).map_err(|err| err.in_track(tag.id()))
+}
 
 struct TempDir {
     path: PathBuf,
+    keep: bool,
 }
 
-impl TempDir {
-    fn new(prefix: &str) -> Self {
-        let mut path = env::temp_dir().join(format!("{prefix}-{:08x}", rand::random::<u32>()));
-        while path.exists() {
-            path = env::temp_dir().join(format!("{prefix}-{:08x}", rand::random::<u32>()));
+/// Attempts at finding an unused random name before giving up.
+const TEMP_ATTEMPTS: usize = 64;
+
+/// Creates `make(path)` at a fresh random path in `parent`. Creation itself
+/// checks that the path is unused, so there is no window between choosing a
+/// name and claiming it.
+fn create_unique<T>(
+    parent: &Path,
+    prefix: &str,
+    ext: &str,
+    make: impl Fn(&Path) -> std::io::Result<T>,
+) -> std::io::Result<(PathBuf, T)> {
+    let mut last_err = None;
+    for _ in 0..TEMP_ATTEMPTS {
+        let path = parent.join(format!("{prefix}{:016x}{ext}", rand::random::<u64>()));
+        match make(&path) {
+            Ok(made) => return Ok((path, made)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => last_err = Some(err),
+            Err(err) => return Err(err),
         }
-        fs::create_dir(&path).expect("Could not create temporary directory");
-        Self { path }
+    }
+    Err(last_err.unwrap())
+}
+
+/// Directories are readable by the owner only, as extracted archives may
+/// hold private material.
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(0o700).create(path)
+}
+
+fn create_private_file(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+impl TempDir {
+    fn new(prefix: &str) -> Result<Self> {
+        Self::new_in(env::temp_dir(), prefix)
+    }
+
+    fn new_in<P: AsRef<Path>>(parent: P, prefix: &str) -> Result<Self> {
+        let parent = parent.as_ref();
+        let (path, ()) = create_unique(parent, &format!("{prefix}-"), "", create_private_dir)
+            .map_err(|err| ReflacError::CreateDirFailed(parent.join(prefix), err))?;
+        Ok(Self { path, keep: false })
     }
 
     fn path(&self) -> &Path {
         self.path.as_path()
     }
 
-    fn unique_subdir(&self) -> PathBuf {
-        let mut sub_path = self.path.join(format!("{:08x}", rand::random::<u32>()));
-        while sub_path.exists() {
-            sub_path = self.path.join(format!("{:08x}", rand::random::<u32>()));
-        }
-        fs::create_dir(&sub_path).expect("Could not create unique temporary subdirectory");
-        sub_path
+    /// Leaves the directory in place when dropped.
+    fn keep(&mut self) {
+        self.keep = true;
     }
 
-    fn unique_subfile(&self, ext: &str) -> (PathBuf, File) {
-        let mut sub_path = self
-            .path
-            .join(format!("{:08x}{ext}", rand::random::<u32>()));
-        while sub_path.exists() {
-            sub_path = self
-                .path
-                .join(format!("{:08x}{ext}", rand::random::<u32>()));
-        }
-        (
-            sub_path.clone(),
-            File::create(sub_path).expect("Could not create unique temporary subfile"),
-        )
+    fn unique_subdir(&self) -> Result<PathBuf> {
+        let (sub_path, ()) = create_unique(&self.path, "", "", create_private_dir)
+            .map_err(|err| ReflacError::CreateDirFailed(self.path.clone(), err))?;
+        Ok(sub_path)
+    }
+
+    fn unique_subfile(&self, ext: &str) -> Result<(PathBuf, File)> {
+        create_unique(&self.path, "", ext, create_private_file)
+            .map_err(|err| ReflacError::CreateFileFailed(self.path.clone(), err))
     }
 }
 
 impl Drop for TempDir {
     fn drop(&mut self) {
-        fs::remove_dir_all(&self.path).expect("Could not remove temporary directory");
+        if self.keep {
+            info!("Keeping temporary directory: {}", self.path.display());
+        } else if let Err(err) = fs::remove_dir_all(&self.path) {
+            warning!(
+                cleanup: "Could not remove temporary directory {}: {err}",
+                self.path.display()
+            );
+        }
     }
 }
 
 #[derive(Clone)]
 struct Tag {
     input: Option<String>,
+    /// File name of the track inside its INPUT, bypassing the matching
+    source: Option<String>,
     title: Option<String>,
     artist: Option<String>,
     lyricist: Option<String>,
@@ -127,18 +621,34 @@ struct Tag {
     arranger: Option<String>,
     album: Option<String>,
     track: Option<usize>,
+    position: Option<String>,
+    number: Option<usize>,
+    track_total: Option<usize>,
+    disc_total: Option<usize>,
     disc: Option<usize>,
     genre: Option<String>,
     date: Option<[u32; 3]>,
     label: Option<String>,
     comment: Option<String>,
     cover: Option<String>,
+    profile: Option<String>,
+    provider: Option<String>,
+    work: Option<String>,
+    movement: Option<String>,
+    movement_number: Option<usize>,
+    movement_total: Option<usize>,
+    conductor: Option<String>,
+    ensemble: Option<String>,
+    opus: Option<String>,
+    titles: Vec<(String, String)>,
+    extra: Vec<(String, String)>,
 }
 
 impl Tag {
     fn new() -> Self {
         Self {
             input: None,
+            source: None,
             title: None,
             artist: None,
             lyricist: None,
@@ -146,456 +656,1189 @@ impl Tag {
             arranger: None,
             album: None,
             track: None,
+            position: None,
+            number: None,
+            track_total: None,
+            disc_total: None,
             disc: None,
             genre: None,
             date: None,
             label: None,
             comment: None,
             cover: None,
+            profile: None,
+            provider: None,
+            work: None,
+            movement: None,
+            movement_number: None,
+            movement_total: None,
+            conductor: None,
+            ensemble: None,
+            opus: None,
+            titles: Vec::new(),
+            extra: Vec::new(),
+        }
+    }
+
+    fn text_fields(&self) -> Vec<(&str, &String)> {
+        [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("LYRICIST", &self.lyricist),
+            ("COMPOSER", &self.composer),
+            ("ARRANGER", &self.arranger),
+            ("ALBUM", &self.album),
+            ("GENRE", &self.genre),
+            ("LABEL", &self.label),
+            ("COMMENT", &self.comment),
+            ("WORK", &self.work),
+            ("MOVEMENTNAME", &self.movement),
+            ("CONDUCTOR", &self.conductor),
+            ("ENSEMBLE", &self.ensemble),
+            ("OPUS", &self.opus),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| value.as_ref().map(|v| (field, v)))
+        .chain(
+            self.extra
+                .iter()
+                .map(|(field, value)| (field.as_str(), value)),
+        )
+        .collect()
+    }
+
+    fn text_fields_mut(&mut self) -> Vec<&mut String> {
+        [
+            &mut self.title,
+            &mut self.artist,
+            &mut self.lyricist,
+            &mut self.composer,
+            &mut self.arranger,
+            &mut self.album,
+            &mut self.genre,
+            &mut self.label,
+            &mut self.comment,
+            &mut self.work,
+            &mut self.movement,
+            &mut self.conductor,
+            &mut self.ensemble,
+            &mut self.opus,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.extra.iter_mut().map(|(_, value)| value))
+        .collect()
+    }
+
+    fn select_title(&mut self, lang: Option<&str>, secondary: Option<(&str, &str)>) {
+        let find = |lang: &str| {
+            self.titles
+                .iter()
+                .find(|(l, _)| l == lang)
+                .map(|(_, title)| title.clone())
+        };
+        let primary = lang.and_then(find);
+        let secondary = secondary.and_then(|(lang, field)| find(lang).map(|t| (field, t)));
+        if let Some(title) = primary {
+            self.title = Some(title);
+        } else if self.title.is_none() {
+            self.title = self.titles.first().map(|(_, title)| title.clone());
+        }
+        if let Some((field, title)) = secondary
+            && self.title.as_ref() != Some(&title)
+        {
+            self.extra.push((field.to_string(), title));
         }
     }
 
-    fn output_path(&self, padding: usize) -> PathBuf {
+    fn id(&self) -> String {
+        self.position
+            .clone()
+            .unwrap_or_else(|| self.track.unwrap().to_string())
+    }
+
+    fn side(&self) -> Option<&str> {
+        self.position
+            .as_deref()
+            .map(|p| p.trim_end_matches(|c: char| c.is_ascii_digit()))
+    }
+
+    fn side_position(&self) -> Option<usize> {
+        self.position.as_deref().and_then(|p| {
+            p.trim_start_matches(|c: char| c.is_ascii_alphabetic())
+                .parse()
+                .ok()
+        })
+    }
+
+    /// The TRACKINFO keys (with `:LANG` for titles in other languages) and
+    /// values of the tag.
+    fn trackinfo_fields(&self) -> Vec<(String, String)> {
+        let extra = |key: &str| {
+            let values: Vec<&str> = self
+                .extra
+                .iter()
+                .filter(|(field, _)| field == key)
+                .map(|(_, value)| value.as_str())
+                .collect();
+            (!values.is_empty()).then(|| values.join("; "))
+        };
+        let fields = [
+            ("INPUT", self.input.clone()),
+            ("SOURCE", self.source.clone()),
+            ("TITLE", self.title.clone()),
+            ("ARTIST", self.artist.clone()),
+            ("LYRICIST", self.lyricist.clone()),
+            ("COMPOSER", self.composer.clone()),
+            ("ARRANGER", self.arranger.clone()),
+            ("ALBUM", self.album.clone()),
+            ("DISC", self.disc.map(|d| d.to_string())),
+            ("GENRE", self.genre.clone()),
+            ("DATE", self.date.map(format_date)),
+            ("LABEL", self.label.clone()),
+            ("COMMENT", self.comment.clone()),
+            ("COVER", self.cover.clone()),
+            ("PROFILE", self.profile.clone()),
+            ("PROVIDER", self.provider.clone()),
+            ("WORK", self.work.clone()),
+            ("MOVEMENT", self.movement.clone()),
+            (
+                "MOVEMENTNUMBER",
+                self.movement_number.map(|n| n.to_string()),
+            ),
+            ("CONDUCTOR", self.conductor.clone()),
+            ("ENSEMBLE", self.ensemble.clone()),
+            ("OPUS", self.opus.clone()),
+            ("STYLE", extra("STYLE")),
+            ("MOOD", extra("MOOD")),
+            ("GROUPING", extra("GROUPING")),
+            ("COMPILATION", extra("COMPILATION")),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .chain(
+                self.titles
+                    .iter()
+                    .map(|(lang, title)| (format!("TITLE:{lang}"), title.clone())),
+            )
+            .collect()
+    }
+
+    /// The tag as TRACKINFO lines of its own, inheriting nothing.
+    fn to_trackinfo(&self) -> String {
+        let id = self.id();
+        self.trackinfo_fields()
+            .into_iter()
+            .map(|(key, value)| format!("{key}[{id}]={value}\n"))
+            .collect()
+    }
+
+    /// Folder of the track's disc inside the album directory; no folder
+    /// when the template renders empty (a flat layout).
+    fn disc_dir(&self, template: Option<&str>) -> Option<String> {
+        let disc = self.disc?;
+        let name = render_template(template.unwrap_or("Disc {disc}"), |name| match name {
+            "disc" => Some(disc.to_string()),
+            "album" => self.album.clone(),
+            _ => None,
+        });
+        (!name.is_empty()).then(|| sanitize_file_name(&name, ""))
+    }
+
+    fn output_path(
+        &self,
+        padding: usize,
+        template: Option<&str>,
+        disc_template: Option<&str>,
+        naming: Naming,
+    ) -> PathBuf {
         let mut ret = PathBuf::new();
-        if let Some(disc) = self.disc {
-            ret = ret.join(format!("Disc {disc}"));
-        }
-        if let Some(ref artist) = self.artist {
-            if let Some(ref title) = self.title {
-                ret.join(
-                    format!(
-                        "{:0fill$}. {artist} - {title}.flac",
-                        self.track.unwrap(),
-                        fill = padding
-                    )
-                    .replace("/", "_"),
-                )
-            } else {
-                ret.join(
-                    format!(
-                        "{:0fill$}. {artist}.flac",
-                        self.track.unwrap(),
-                        fill = padding
-                    )
-                    .replace("/", "_"),
-                )
+        let disc_dir = self.disc_dir(disc_template);
+        if let Some(ref dir) = disc_dir {
+            ret = ret.join(dir);
+        }
+        let track = format!("{:0fill$}", self.track.unwrap(), fill = padding);
+        // Discs sharing a directory are told apart by the default file names
+        let numbered = match (self.disc, disc_dir) {
+            (Some(disc), None) => format!("{disc}-{track}"),
+            _ => track.clone(),
+        };
+        let name = if let Some(template) = template {
+            render_template(template, |name| match name {
+                "track" => Some(track.clone()),
+                "position" => Some(self.position.clone().unwrap_or(track.clone())),
+                "side" => self.side().map(String::from),
+                "disc" => self.disc.map(|d| d.to_string()),
+                "title" => self.title.clone(),
+                "artist" => self.artist.clone(),
+                "album" => self.album.clone(),
+                "composer" => self.composer.clone(),
+                "work" => self.work.clone(),
+                "movement" => self.movement.clone(),
+                "movementnumber" => self.movement_number.map(|n| n.to_string()),
+                _ => None,
+            })
+        } else if naming == Naming::Classical {
+            match (&self.work, &self.movement, &self.title) {
+                (Some(work), Some(movement), _) => match self.movement_number {
+                    Some(n) => format!("{numbered}. {work} - {}. {movement}", roman(n)),
+                    None => format!("{numbered}. {work} - {movement}"),
+                },
+                (_, _, Some(title)) => format!("{numbered}. {title}"),
+                (Some(work), None, None) => format!("{numbered}. {work}"),
+                (None, _, None) => numbered,
             }
-        } else if let Some(ref title) = self.title {
-            ret.join(
-                format!(
-                    "{:0fill$}. {title}.flac",
-                    self.track.unwrap(),
-                    fill = padding
-                )
-                .replace("/", "_"),
-            )
         } else {
-            ret.join(
-                format!("{:0fill$}.flac", self.track.unwrap(), fill = padding).replace("/", "_"),
-            )
+            // Soundtracks credit the composer; performers are often meaningless
+            let artist = match naming {
+                Naming::Soundtrack => self.composer.as_ref().or(self.artist.as_ref()),
+                _ => self.artist.as_ref(),
+            };
+            match (artist, &self.title) {
+                (Some(artist), Some(title)) => format!("{numbered}. {artist} - {title}"),
+                (Some(artist), None) => format!("{numbered}. {artist}"),
+                (None, Some(title)) => format!("{numbered}. {title}"),
+                (None, None) => numbered,
+            }
+        };
+        ret.join(sanitize_file_name(&name, ".flac"))
+    }
+}
+
+/// Turns a tag value into a single path component: separators and NUL
+/// bytes are replaced, "." and ".." are avoided and the name is shortened to
+/// the usual 255 byte limit of filesystems.
+fn sanitize_file_name(name: &str, ext: &str) -> String {
+    const MAX_LEN: usize = 255;
+    let mut name = name.replace(['/', '\0'], "_");
+    if name.is_empty() || name == "." || name == ".." {
+        name = String::from("_");
+    }
+    if name.len() + ext.len() > MAX_LEN {
+        let mut end = MAX_LEN - ext.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
         }
+        name.truncate(end);
     }
+    name + ext
 }
 
-fn parse_trackinfo<P: AsRef<Path>>(path: P) -> Result<Vec<Tag>> {
-    static INPUT_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"INPUT(?:\[(\d+)\])?=(.*)").unwrap());
-    static TITLE_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"TITLE(?:\[(\d+)\])?=(.*)").unwrap());
-    static ARTIST_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"ARTIST(?:\[(\d+)\])?=(.*)").unwrap());
-    static LYRICIST_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"LYRICIST(?:\[(\d+)\])?=(.*)").unwrap());
-    static COMPOSER_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"COMPOSER(?:\[(\d+)\])?=(.*)").unwrap());
-    static ARRANGER_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"ARRANGER(?:\[(\d+)\])?=(.*)").unwrap());
-    static ALBUM_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"ALBUM(?:\[(\d+)\])?=(.*)").unwrap());
-    static DISC_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"DISC(?:\[(\d+)\])?=(\d+)").unwrap());
-    static GENRE_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"GENRE(?:\[(\d+)\])?=(.*)").unwrap());
-    static DATE_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
-        regex::Regex::new(r"DATE(?:\[(\d+)\])?=(\d\d\d\d)-(\d\d)-(\d\d)").unwrap()
-    });
-    static LABEL_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"LABEL(?:\[(\d+)\])?=(.*)").unwrap());
-    static COMMENT_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"COMMENT(?:\[(\d+)\])?=(.*)").unwrap());
-    static COVER_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r"COVER(?:\[(\d+)\])?=(.*)").unwrap());
+/// How albums and tracks are named when no template is given.
+#[derive(Clone, Copy, PartialEq)]
+enum Naming {
+    /// `Album/NN. Artist - Title.flac`
+    Standard,
+    /// `Composer - Album/NN. Work - I. Movement.flac`
+    Classical,
+    /// `Album/NN. Composer - Title.flac`
+    Soundtrack,
+}
 
-    let mut tags: Vec<Tag> = Vec::new();
-    let mut global_tag = Tag::new();
-    for line in BufReader::new(File::open(path)?)
-        .lines()
-        .map(|l| l.unwrap())
-    {
-        if let Some(caps) = INPUT_RE.captures(line.as_str()) {
-            let field = if caps[2].is_empty() {
-                None
-            } else {
-                Some(caps[2].to_string())
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.input = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.input = field;
-                    tags.push(tag);
-                }
-            } else {
-                global_tag.input = field;
-            }
-        } else if let Some(caps) = TITLE_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
+impl std::str::FromStr for Naming {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Naming::Standard),
+            "classical" => Ok(Naming::Classical),
+            "soundtrack" => Ok(Naming::Soundtrack),
+            _ => Err(format!("Unknown naming: {s}")),
+        }
+    }
+}
+
+/// How the discs of an album are laid out on disk. It is decided once per
+/// run, so that folders, file names and disc tags agree.
+#[derive(Clone, Copy, PartialEq)]
+enum DiscLayout {
+    /// A folder per disc, named by the disc template
+    Folders,
+    /// All discs in the album directory, file names start with the disc:
+    /// `2-03. Artist - Title.flac`
+    Flat,
+}
+
+impl std::str::FromStr for DiscLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "folders" => Ok(DiscLayout::Folders),
+            "flat" => Ok(DiscLayout::Flat),
+            _ => Err(format!("Unknown disc layout: {s}")),
+        }
+    }
+}
+
+/// What happens when several tracks would be written to the same file.
+#[derive(Clone, Copy, PartialEq)]
+enum Collisions {
+    Fail,
+    /// Number the later files: `01. Intro (2).flac`
+    Suffix,
+}
+
+impl std::str::FromStr for Collisions {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Collisions::Fail),
+            "suffix" => Ok(Collisions::Suffix),
+            _ => Err(format!("Unknown collision policy: {s}")),
+        }
+    }
+}
+
+/// Finds tracks whose file names are equal (ignoring case, for
+/// case-insensitive filesystems) to each other's or to `taken` ones, and
+/// fails or adds a " (N)" suffix.
+fn resolve_collisions(
+    tags: &[Tag],
+    file_names: &mut [PathBuf],
+    taken: &[PathBuf],
+    policy: Collisions,
+) -> Result<()> {
+    let key = |path: &Path| path.to_string_lossy().to_lowercase();
+    let mut seen: HashMap<String, Option<String>> =
+        taken.iter().map(|path| (key(path), None)).collect();
+    let mut collisions = Vec::new();
+    for (tag, file_name) in tags.iter().zip(file_names.iter_mut()) {
+        let Some(first) = seen.get(&key(file_name)) else {
+            seen.insert(key(file_name), Some(tag.id()));
+            continue;
+        };
+        match policy {
+            Collisions::Fail => collisions.push(format!(
+                "#{} and {} → \"{}\"",
+                tag.id(),
+                first
+                    .as_ref()
+                    .map_or(String::from("an existing track"), |id| format!("#{id}")),
+                file_name.display()
+            )),
+            Collisions::Suffix => {
+                let stem = file_name
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                let renamed = (2..)
+                    .map(|n| {
+                        file_name
+                            .with_file_name(sanitize_file_name(&format!("{stem} ({n})"), ".flac"))
+                    })
+                    .find(|path| !seen.contains_key(&key(path)))
+                    .unwrap();
+                warning!(
+                    collision: "#{} would overwrite \"{}\", writing \"{}\" instead",
+                    tag.id(),
+                    file_name.display(),
+                    renamed.display()
+                );
+                seen.insert(key(&renamed), Some(tag.id()));
+                *file_name = renamed;
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.title = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.title = field;
-                    tags.push(tag);
+        }
+    }
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(ReflacError::OutputCollision(collisions))
+    }
+}
+
+fn roman(mut n: usize) -> String {
+    const NUMERALS: &[(usize, &str)] = &[
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    let mut out = String::new();
+    for &(value, numeral) in NUMERALS {
+        while n >= value {
+            out.push_str(numeral);
+            n -= value;
+        }
+    }
+    out
+}
+
+/// Numbers the movements of works spread over consecutive tracks, unless
+/// MOVEMENTNUMBER is given, and sets MOVEMENTTOTAL.
+fn number_movements(tags: &mut [Tag]) {
+    let mut order: Vec<usize> = (0..tags.len()).collect();
+    order.sort_by_key(|&i| (tags[i].disc, tags[i].track));
+    let mut start = 0;
+    while start < order.len() {
+        let work = tags[order[start]].work.clone();
+        let mut end = start + 1;
+        while end < order.len() && work.is_some() && tags[order[end]].work == work {
+            end += 1;
+        }
+        if work.is_some() {
+            for (n, &i) in order[start..end].iter().enumerate() {
+                if tags[i].movement_number.is_none() {
+                    tags[i].movement_number = Some(n + 1);
                 }
-            } else {
-                global_tag.title = field;
+                tags[i].movement_total = Some(end - start);
             }
-        } else if let Some(caps) = ARTIST_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
-            }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.artist = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.artist = field;
-                    tags.push(tag);
+        }
+        start = end;
+    }
+}
+
+/// Substitutes `{name}` placeholders; unknown or empty fields render as
+/// nothing.
+fn render_template<F: Fn(&str) -> Option<String>>(template: &str, field: F) -> String {
+    static FIELD_RE: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r"\{(\w+)(?::0(\d+))?\}").unwrap());
+    FIELD_RE
+        .replace_all(template, |caps: &regex::Captures| {
+            let value = field(&caps[1]).unwrap_or_default();
+            // {track:02} pads numbers with zeros
+            match caps.get(2).and_then(|width| width.as_str().parse().ok()) {
+                Some(width) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                    format!("{:0>width$}", value.trim_start_matches('0'), width = width)
                 }
-            } else {
-                global_tag.artist = field;
+                _ => value,
             }
-        } else if let Some(caps) = LYRICIST_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
+        })
+        .trim()
+        .to_string()
+}
+
+fn text_field(value: &str, line: &str) -> Option<String> {
+    let trimmed = value.trim();
+    if trimmed != value {
+        warning!(trimmed: "Line \"{line}\" trimmed!");
+    }
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+/// Problems of tag values that players mishandle: control characters and
+/// values longer than `limit` bytes (or the field's own limit; 0 means no
+/// limit).
+fn tag_problems(tags: &[Tag], limit: usize, field_limits: &HashMap<String, usize>) -> Vec<String> {
+    let mut problems = Vec::new();
+    for tag in tags {
+        for (field, value) in tag.text_fields() {
+            if let Some(c) = value.chars().find(|c| c.is_control()) {
+                problems.push(format!(
+                    "#{} {field} contains control character U+{:04X}",
+                    tag.id(),
+                    c as u32
+                ));
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.lyricist = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.lyricist = field;
-                    tags.push(tag);
-                }
-            } else {
-                global_tag.lyricist = field;
+            let limit = field_limits.get(field).copied().unwrap_or(limit);
+            if limit > 0 && value.len() > limit {
+                problems.push(format!(
+                    "#{} {field} is {} bytes long, the limit is {limit}",
+                    tag.id(),
+                    value.len()
+                ));
             }
-        } else if let Some(caps) = COMPOSER_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
+        }
+    }
+    problems
+}
+
+fn raw_field(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// A DATE value, YYYY-MM-DD; anything after the day (a time) is ignored.
+fn parse_date(value: &str) -> std::result::Result<[u32; 3], &'static str> {
+    static DATE_RE: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r"^(\d\d\d\d)-(\d\d)-(\d\d)").unwrap());
+
+    let caps = DATE_RE
+        .captures(value.trim())
+        .ok_or("DATE must be YYYY-MM-DD")?;
+    let [year, month, day] = [1, 2, 3].map(|i| caps[i].parse::<u32>().unwrap());
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err("month must be 01 to 12"),
+    };
+    if !(1..=days).contains(&day) {
+        return Err("no such day in that month");
+    }
+    Ok([year, month, day])
+}
+
+fn format_date(date: [u32; 3]) -> String {
+    format!("{:04}-{:02}-{:02}", date[0], date[1], date[2])
+}
+
+/// Whether a date lies after today (in UTC).
+fn is_future(date: [u32; 3]) -> bool {
+    format_date(date).as_str() > &provenance::timestamp(SystemTime::now())[..10]
+}
+
+/// A positive number such as a disc number; leading zeros and surrounding
+/// whitespace are fine.
+fn parse_number(value: &str) -> Option<usize> {
+    value.trim().parse().ok().filter(|&n| n > 0)
+}
+
+fn set_field(tag: &mut Tag, key: &str, lang: Option<&str>, value: &str, line: &str) -> Result<()> {
+    let invalid = |reason| ReflacError::InvalidTrackinfoValue(line.to_string(), reason);
+    match (key, lang) {
+        ("INPUT", None) => tag.input = raw_field(value),
+        ("SOURCE", None) => tag.source = raw_field(value),
+        ("TITLE", Some(lang)) => {
+            tag.titles.retain(|(l, _)| l != lang);
+            if let Some(title) = text_field(value, line) {
+                tag.titles.push((lang.to_string(), title));
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.composer = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.composer = field;
-                    tags.push(tag);
+        }
+        ("TITLE", None) => tag.title = text_field(value, line),
+        ("ARTIST", None) => tag.artist = text_field(value, line),
+        ("LYRICIST", None) => tag.lyricist = text_field(value, line),
+        ("COMPOSER", None) => tag.composer = text_field(value, line),
+        ("ARRANGER", None) => tag.arranger = text_field(value, line),
+        ("ALBUM", None) => tag.album = text_field(value, line),
+        ("DISC", None) if value.trim().is_empty() => tag.disc = None,
+        ("DISC", None) => {
+            tag.disc = Some(parse_number(value).ok_or_else(|| invalid("DISC must be 1 or more"))?)
+        }
+        ("GENRE", None) => tag.genre = text_field(value, line),
+        ("DATE", None) if value.trim().is_empty() => tag.date = None,
+        ("DATE", None) => tag.date = Some(parse_date(value).map_err(invalid)?),
+        ("LABEL", None) => tag.label = text_field(value, line),
+        ("COMMENT", None) => tag.comment = text_field(value, line),
+        ("COVER", None) => tag.cover = raw_field(value),
+        ("PROFILE", None) => tag.profile = raw_field(value),
+        ("PROVIDER", None) => tag.provider = raw_field(value),
+        ("WORK", None) => tag.work = text_field(value, line),
+        ("MOVEMENT", None) => tag.movement = text_field(value, line),
+        ("MOVEMENTNUMBER", None) if value.trim().is_empty() => tag.movement_number = None,
+        ("MOVEMENTNUMBER", None) => {
+            tag.movement_number = Some(
+                parse_number(value).ok_or_else(|| invalid("MOVEMENTNUMBER must be 1 or more"))?,
+            )
+        }
+        ("CONDUCTOR", None) => tag.conductor = text_field(value, line),
+        ("ENSEMBLE", None) => tag.ensemble = text_field(value, line),
+        ("OPUS", None) => tag.opus = text_field(value, line),
+        // Several styles or moods are separated by semicolons and written as
+        // one comment each
+        ("STYLE" | "MOOD", None) => {
+            tag.extra.retain(|(field, _)| field != key);
+            if !value.trim().is_empty() {
+                for item in value.split(';').map(str::trim) {
+                    if item.is_empty() {
+                        return Err(ReflacError::InvalidTrackinfo(line.to_string()));
+                    }
+                    tag.extra.push((key.to_string(), item.to_string()));
                 }
-            } else {
-                global_tag.composer = field;
-            }
-        } else if let Some(caps) = ARRANGER_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.arranger = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.arranger = field;
-                    tags.push(tag);
-                }
-            } else {
-                global_tag.arranger = field;
+        }
+        ("COMPILATION", None) => {
+            tag.extra.retain(|(field, _)| field != key);
+            match value.trim() {
+                "1" => tag.extra.push((key.to_string(), String::from("1"))),
+                "0" | "" => (),
+                _ => return Err(invalid("COMPILATION must be 0 or 1")),
             }
-        } else if let Some(caps) = ALBUM_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
+        }
+        ("GROUPING", None) => {
+            tag.extra.retain(|(field, _)| field != key);
+            if let Some(grouping) = text_field(value, line) {
+                tag.extra.push((key.to_string(), grouping));
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.album = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.album = field;
-                    tags.push(tag);
+        }
+        _ => return Err(ReflacError::InvalidTrackinfo(line.to_string())),
+    }
+    Ok(())
+}
+
+/// Fills in tags from a tag provider. Fields given in the TRACKINFO file win.
+fn provide_tags(tags: &[Tag], name: &str, command: &str) -> Result<Vec<Tag>> {
+    info!("Asking tag provider \"{name}\" ...");
+    let tracks: Vec<plugin::Track> = tags
+        .iter()
+        .map(|tag| plugin::Track {
+            id: tag.id(),
+            fields: tag.trackinfo_fields(),
+        })
+        .collect();
+    let provided = plugin::query(name, command, &tracks)?;
+    let mut text = String::new();
+    for (tag, provided) in tags.iter().zip(provided) {
+        for (key, value) in provided.fields {
+            // Brackets would address another track
+            let key = match edit::field_name(&key) {
+                Ok(key) if !key.contains(['[', ']']) => key,
+                _ => {
+                    return Err(ReflacError::ProviderFailed(
+                        name.to_string(),
+                        format!("invalid field name \"{key}\""),
+                    ));
                 }
-            } else {
-                global_tag.album = field;
-            }
-        } else if let Some(caps) = DISC_RE.captures(line.as_str()) {
-            let field = if caps[2].is_empty() {
-                None
-            } else {
-                Some(caps[2].parse().unwrap())
             };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.disc = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.disc = field;
-                    tags.push(tag);
+            text.push_str(&format!("{key}[{}]={value}\n", provided.id));
+        }
+        text.push_str(&tag.to_trackinfo());
+    }
+    parse_trackinfo_str(&text)
+}
+
+/// Whether a file name marks a TRACKINFO file: `trackinfo`, `trackinfo.txt`
+/// or `*.trackinfo`, in any case.
+fn is_trackinfo_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name == "trackinfo" || name == "trackinfo.txt" || name.ends_with(".trackinfo")
+}
+
+/// The TRACKINFO file inside an album directory.
+fn find_trackinfo(dir: &Path) -> Result<PathBuf> {
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_trackinfo_name(name))
+        .collect();
+    names.sort();
+    match names.len() {
+        0 => Err(ReflacError::NoTrackinfoFound(dir.to_path_buf())),
+        1 => Ok(dir.join(&names[0])),
+        _ => Err(ReflacError::AmbiguousTrackinfo(dir.to_path_buf(), names)),
+    }
+}
+
+fn parse_trackinfo_bytes(bytes: &[u8]) -> Result<Vec<Tag>> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => parse_trackinfo_str(text),
+        Err(err) => {
+            // Blame the line with the bad bytes rather than the whole file
+            let start = bytes[..err.valid_up_to()]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            let line = bytes[start..].split(|&b| b == b'\n').next().unwrap();
+            Err(ReflacError::InvalidTrackinfoValue(
+                String::from_utf8_lossy(line).into_owned(),
+                "not valid UTF-8",
+            ))
+        }
+    }
+}
+
+fn parse_trackinfo_str(text: &str) -> Result<Vec<Tag>> {
+    static LINE_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r"^([A-Z]+)(?::([A-Za-z-]+))?(?:\[\s*(\d+|[A-Z]+\d+)\s*\])?=(.*)$")
+            .unwrap()
+    });
+
+    let mut tags: Vec<Tag> = Vec::new();
+    let mut global_tag = Tag::new();
+    // Global fields that tracks do not inherit
+    let mut no_inherit: Vec<String> = Vec::new();
+    for line in text.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let Some(caps) = LINE_RE.captures(line) else {
+            return Err(ReflacError::InvalidTrackinfo(line.to_string()));
+        };
+        let key = &caps[1];
+        let lang = caps.get(2).map(|m| m.as_str());
+        let value = &caps[4];
+        if let Some(mat) = caps.get(3) {
+            let id = mat.as_str();
+            let (track, position) = if id.starts_with(|c: char| c.is_ascii_digit()) {
+                match id.parse() {
+                    Ok(track) => (Some(track), None),
+                    Err(_) => {
+                        return Err(ReflacError::InvalidTrackinfoValue(
+                            line.to_string(),
+                            "track number too large",
+                        ));
+                    }
                 }
             } else {
-                global_tag.disc = field;
-            }
-        } else if let Some(caps) = GENRE_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
-            }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
+                (None, Some(id.to_string()))
             };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.genre = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.genre = field;
-                    tags.push(tag);
-                }
-            } else {
-                global_tag.genre = field;
-            }
-        } else if let Some(caps) = DATE_RE.captures(line.as_str()) {
-            let field = if caps[2].is_empty() {
-                None
+            if let Some(tag) = tags
+                .iter_mut()
+                .find(|t| t.track == track && t.position == position)
+            {
+                set_field(tag, key, lang, value, line)?;
             } else {
-                Some([
-                    caps[2].parse().unwrap(),
-                    caps[3].parse().unwrap(),
-                    caps[4].parse().unwrap(),
-                ])
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.date = field
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.date = field;
-                    tags.push(tag);
+                if key == "NOINHERIT" || (key == "ARTIST" && value.trim() == "VARIOUS") {
+                    return Err(ReflacError::InvalidTrackinfoValue(
+                        line.to_string(),
+                        "only allowed for the whole album",
+                    ));
                 }
-            } else {
-                global_tag.date = field;
-            }
-        } else if let Some(caps) = LABEL_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
-            }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.label = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.label = field;
-                    tags.push(tag);
+                let mut tag = global_tag.clone();
+                tag.track = track;
+                tag.position = position;
+                for field in &no_inherit {
+                    set_field(&mut tag, field, None, "", line)?;
                 }
-            } else {
-                global_tag.label = field;
+                set_field(&mut tag, key, lang, value, line)?;
+                tags.push(tag);
             }
-        } else if let Some(caps) = COMMENT_RE.captures(line.as_str()) {
-            let trimmed = caps[2].trim().to_string();
-            if trimmed != caps[2] {
-                println!("WARNING: Line \"{}\" trimmed!", line);
+        } else if key == "NOINHERIT" {
+            for field in value.split(';').map(str::trim).filter(|f| !f.is_empty()) {
+                // Clearing the field on a scratch tag tells whether it exists
+                set_field(&mut Tag::new(), field, None, "", line).map_err(|_| {
+                    ReflacError::InvalidTrackinfoValue(line.to_string(), "unknown field")
+                })?;
+                no_inherit.push(field.to_string());
             }
-            let field = if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed)
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.comment = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.comment = field;
-                    tags.push(tag);
-                }
-            } else {
-                global_tag.comment = field;
+        } else if key == "ARTIST" && lang.is_none() && value.trim() == "VARIOUS" {
+            // A compilation: every track names its own artist
+            global_tag.artist = None;
+            no_inherit.push(String::from("ARTIST"));
+            set_field(&mut global_tag, "COMPILATION", None, "1", line)?;
+        } else {
+            set_field(&mut global_tag, key, lang, value, line)?;
+        }
+    }
+
+    // Number side/position tracks (A1, A2, B1, ...) continuously
+    if tags.iter().any(|t| t.position.is_some()) {
+        if tags.iter().any(|t| t.position.is_none()) {
+            return Err(ReflacError::MixedTrackIdentifiers);
+        }
+        let mut order: Vec<_> = (0..tags.len()).collect();
+        order.sort_by_key(|&i| (tags[i].side().map(String::from), tags[i].side_position()));
+        for (number, i) in order.into_iter().enumerate() {
+            tags[i].track = Some(number + 1);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Settings and state shared by everything that opens inputs.
+struct Extraction<'a> {
+    tmp_dir: &'a TempDir,
+    sandbox: Sandbox,
+    par2: bool,
+    par2_repair: bool,
+    verify_signatures: bool,
+    keyring: Option<PathBuf>,
+    cache: Option<cache::Cache>,
+    report: &'a mut Report,
+}
+
+impl Extraction<'_> {
+    fn check(&mut self, path: &Path, kind: &'static str, result: &str) {
+        info!("  {kind} check of \"{}\": {result}", path.display());
+        self.report.checks.push(Check {
+            path: path.to_path_buf(),
+            kind,
+            result: result.to_string(),
+        });
+    }
+}
+
+/// Finds `archive.zip.EXT` or `archive.EXT` next to `path`.
+fn sidecar(path: &Path, ext: &str) -> Option<PathBuf> {
+    let mut appended = path.as_os_str().to_owned();
+    appended.push(format!(".{ext}"));
+    [PathBuf::from(appended), path.with_extension(ext)]
+        .into_iter()
+        .find(|p| p.exists())
+}
+
+fn verify_sidecars(path: &Path, ctx: &mut Extraction) -> Result<()> {
+    if ctx.par2
+        && let Some(par2) = sidecar(path, "par2")
+    {
+        let run = |action: &str| -> Result<bool> {
+            let (status, _) = command_status(
+                Command::new("par2")
+                    .arg(action)
+                    .arg("-q")
+                    .arg(&par2)
+                    .stdout(Stdio::null()),
+                "par2",
+            )?;
+            Ok(status.success())
+        };
+        if run("verify")? {
+            ctx.check(path, "PAR2", "ok");
+        } else if ctx.par2_repair && run("repair")? {
+            ctx.check(path, "PAR2", "repaired");
+        } else {
+            ctx.check(path, "PAR2", "failed");
+            return Err(ReflacError::VerificationFailed(path.to_path_buf(), "PAR2"));
+        }
+    }
+    if ctx.verify_signatures {
+        if let Some(sig) = sidecar(path, "asc").or_else(|| sidecar(path, "sig")) {
+            let mut cmd = Command::new("gpg");
+            cmd.arg("--batch");
+            if let Some(ref keyring) = ctx.keyring {
+                cmd.arg("--no-default-keyring")
+                    .arg("--keyring")
+                    .arg(fs::canonicalize(keyring)?);
             }
-        } else if let Some(caps) = COVER_RE.captures(line.as_str()) {
-            let field = if caps[2].is_empty() {
-                None
-            } else {
-                Some(caps[2].to_string())
-            };
-            if let Some(mat) = caps.get(1) {
-                let track = Some(mat.as_str().parse().unwrap());
-                if let Some(tag) = tags.iter_mut().find(|t| t.track == track) {
-                    tag.cover = field;
-                } else {
-                    let mut tag = global_tag.clone();
-                    tag.track = track;
-                    tag.cover = field;
-                    tags.push(tag);
-                }
+            cmd.arg("--verify")
+                .arg(&sig)
+                .arg(path)
+                .stdout(Stdio::null());
+            if command_status(&mut cmd, "gpg")?.0.success() {
+                ctx.check(path, "Signature", "ok");
             } else {
-                global_tag.cover = field;
+                ctx.check(path, "Signature", "failed");
+                return Err(ReflacError::VerificationFailed(
+                    path.to_path_buf(),
+                    "Signature",
+                ));
             }
-        } else if !line.is_empty() {
-            return Err(ReflacError::InvalidTrackinfo(line).into());
+        } else {
+            ctx.check(path, "Signature", "missing");
         }
     }
+    Ok(())
+}
 
-    Ok(tags)
+fn verify_sfv(path: &Path) -> Result<bool> {
+    let dir = path.parent().unwrap();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let Some((name, crc)) = line.rsplit_once(char::is_whitespace) else {
+            return Ok(false);
+        };
+        let file = dir.join(name.trim());
+        if !file.exists() {
+            continue;
+        }
+        if u32::from_str_radix(crc, 16).ok() != Some(hash::crc32_file(file)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// What `--verify-source-checksums` does with the manifests shipped with the
+/// sources.
+#[derive(Clone, Copy, PartialEq)]
+enum ChecksumPolicy {
+    /// Manifests are ignored
+    Off,
+    /// A mismatch is reported as a warning
+    Warn,
+    /// A mismatch fails the run
+    Fail,
+}
+
+impl std::str::FromStr for ChecksumPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ChecksumPolicy::Fail),
+            "warn" => Ok(ChecksumPolicy::Warn),
+            _ => Err(format!("Unknown checksum policy: {s}")),
+        }
+    }
+}
+
+/// Verifies the `.md5`, `.sha256` and `.sfv` manifests found in `dir`.
+/// Files listed in a manifest but not present are skipped.
+fn verify_checksums(dir: &Path, policy: ChecksumPolicy, ctx: &mut Extraction) -> Result<()> {
+    if policy == ChecksumPolicy::Off {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        let (kind, tool) = match ext.as_deref() {
+            Some("md5") => ("MD5", "md5sum"),
+            Some("sha256") => ("SHA256", "sha256sum"),
+            Some("sfv") => ("SFV", ""),
+            _ => continue,
+        };
+        let ok = if kind == "SFV" {
+            verify_sfv(&path)?
+        } else {
+            command_status(
+                Command::new(tool)
+                    .arg("--check")
+                    .arg("--quiet")
+                    .arg("--ignore-missing")
+                    .arg(path.file_name().unwrap())
+                    .current_dir(dir)
+                    .stdout(Stdio::null()),
+                tool,
+            )?
+            .0
+            .success()
+        };
+        if ok {
+            ctx.check(&path, kind, "ok");
+        } else if policy == ChecksumPolicy::Warn {
+            ctx.check(&path, kind, "mismatch");
+            warning!(checksum: "Checksum mismatch in {}!", path.display());
+        } else {
+            ctx.check(&path, kind, "mismatch");
+            return Err(ReflacError::VerificationFailed(path, kind));
+        }
+    }
+    Ok(())
+}
+
+fn list_archive(path: &Path, work_dir: &Path, sandbox: Sandbox) -> Result<Vec<String>> {
+    let (tool, output) = match path.extension().and_then(|e| e.to_str()) {
+        Some("zip") => {
+            if let Some(zip) = open_zip(path)? {
+                return Ok(zip.members.into_iter().map(|m| m.name).collect());
+            }
+            (
+                "unzip",
+                command_output(
+                    sandbox::command(sandbox, "unzip", work_dir)
+                        .arg("-Z1")
+                        .arg(path),
+                    "unzip",
+                )?,
+            )
+        }
+        Some("rar") => (
+            "unrar",
+            command_output(
+                sandbox::command(sandbox, "unrar", work_dir)
+                    .arg("lt")
+                    .arg(path),
+                "unrar",
+            )?,
+        ),
+        Some(ext @ ("7z" | "iso")) => {
+            let tool = if ext == "iso" { "7z" } else { "7za" };
+            (
+                tool,
+                command_output(
+                    sandbox::command(sandbox, tool, work_dir)
+                        .arg("l")
+                        .arg("-slt")
+                        .arg(path),
+                    tool,
+                )?,
+            )
+        }
+        _ => return Ok(Vec::new()),
+    };
+    if !output.status.success() {
+        return Err(ReflacError::subprocess(
+            tool,
+            Some(output.status),
+            &output.stderr,
+        ));
+    }
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let members = match tool {
+        "unrar" => archive::parse_unrar_listing(&listing),
+        "unzip" => listing.lines().map(|m| (m.to_string(), false)).collect(),
+        _ => archive::parse_7z_listing(&listing),
+    };
+    // Links are refused before extraction, as their targets cannot be vetted
+    if let Some((member, _)) = members.iter().find(|(_, link)| *link) {
+        return Err(ReflacError::UnsafeArchiveMember(
+            path.to_path_buf(),
+            member.clone(),
+        ));
+    }
+    Ok(members.into_iter().map(|(m, _)| m).collect())
+}
+
+/// Opens a ZIP archive to be read in-process, or returns `None` if it uses
+/// features only `unzip` has. Archives containing symlinks are refused.
+fn open_zip(path: &Path) -> Result<Option<zip::Archive>> {
+    match zip::Archive::open(path) {
+        Ok(zip) => match zip.members.iter().find(|m| m.symlink) {
+            Some(member) => Err(ReflacError::UnsafeArchiveMember(
+                path.to_path_buf(),
+                member.name.clone(),
+            )),
+            None => Ok(Some(zip)),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => Ok(None),
+        Err(err) => Err(zip_error(path, err)),
+    }
+}
+
+fn zip_error(path: &Path, err: std::io::Error) -> ReflacError {
+    if err.kind() == std::io::ErrorKind::InvalidData {
+        ReflacError::InvalidArchive(path.to_path_buf(), err.to_string())
+    } else {
+        err.into()
+    }
+}
+
+/// Extracts the members of a ZIP archive that are `wanted` into `out_dir`,
+/// after checking that all of them are safe.
+fn extract_zip(
+    zip: &zip::Archive,
+    path: &Path,
+    out_dir: &Path,
+    wanted: impl Fn(&zip::Member) -> bool,
+) -> Result<()> {
+    if let Some(member) = zip.members.iter().find(|m| !is_safe_member(&m.name)) {
+        return Err(ReflacError::UnsafeArchiveMember(
+            path.to_path_buf(),
+            member.name.clone(),
+        ));
+    }
+    for member in zip.members.iter().filter(|m| wanted(m)) {
+        zip.extract(member, out_dir)
+            .map_err(|err| zip_error(path, err))?;
+    }
+    Ok(())
 }
 
-fn extract_archive<P: AsRef<Path>, Q: AsRef<Path>>(path: P, out_dir: Q) -> Result<()> {
-    if let Some(ext) = path.as_ref().extension() {
+/// Fails if anything below `dir` is a symlink leading outside of `root`.
+fn check_symlinks(dir: &Path, root: &Path, archive: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.file_type().is_symlink() {
+            let inside = fs::canonicalize(&path)
+                .map(|target| target.starts_with(root))
+                .unwrap_or(false);
+            if !inside {
+                let member = path.strip_prefix(root).unwrap().display().to_string();
+                return Err(ReflacError::UnsafeArchiveMember(
+                    archive.to_path_buf(),
+                    member,
+                ));
+            }
+        } else if meta.is_dir() {
+            check_symlinks(&path, root, archive)?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_archive<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    out_dir: Q,
+    ctx: &mut Extraction,
+) -> Result<()> {
+    // The tools run inside out_dir, so relative paths would break
+    let path = fs::canonicalize(path)?;
+    let out_dir = fs::canonicalize(out_dir)?;
+    if path.extension().is_some_and(|e| e == "zip")
+        && let Some(zip) = open_zip(&path)?
+    {
+        return extract_zip(&zip, &path, &out_dir, |_| true);
+    }
+    let sandbox = ctx.sandbox;
+    if let Some(member) = list_archive(&path, &out_dir, sandbox)?
+        .into_iter()
+        .find(|m| !is_safe_member(m))
+    {
+        return Err(ReflacError::UnsafeArchiveMember(path, member));
+    }
+    if let Some(ext) = path.extension() {
         match ext.to_str().unwrap() {
             "zip" => {
-                if !Command::new("unzip")
-                    .arg(path.as_ref())
-                    .arg("-d")
-                    .arg(out_dir.as_ref())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status()?
-                    .success()
-                {
-                    return Err(ReflacError::SubprocessError("unzip").into());
-                }
+                run_command(
+                    sandbox::command(sandbox, "unzip", &out_dir)
+                        .arg(&path)
+                        .arg("-d")
+                        .arg(&out_dir)
+                        .stdout(Stdio::null()),
+                    "unzip",
+                )?;
             }
             "rar" => {
-                if !Command::new("unrar")
-                    .arg("x")
-                    .arg(path.as_ref())
-                    .arg(out_dir.as_ref())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status()?
-                    .success()
-                {
-                    return Err(ReflacError::SubprocessError("unrar").into());
-                }
+                run_command(
+                    sandbox::command(sandbox, "unrar", &out_dir)
+                        .arg("x")
+                        .arg(&path)
+                        .arg(&out_dir)
+                        .stdout(Stdio::null()),
+                    "unrar",
+                )?;
             }
-            "7z" => {
-                if !Command::new("7za")
-                    .arg("x")
-                    .arg(format!("-o{}", out_dir.as_ref().to_str().unwrap()))
-                    .arg(path.as_ref())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status()?
-                    .success()
-                {
-                    return Err(ReflacError::SubprocessError("7za").into());
-                }
+            ext @ ("7z" | "iso") => {
+                let tool = if ext == "iso" { "7z" } else { "7za" };
+                run_command(
+                    sandbox::command(sandbox, tool, &out_dir)
+                        .arg("x")
+                        .arg(format!("-o{}", out_dir.to_str().unwrap()))
+                        .arg(&path)
+                        .stdout(Stdio::null()),
+                    tool,
+                )?;
             }
             _ => {
-                return Err(
-                    ReflacError::UnknownArchiveType(ext.to_str().unwrap().to_string()).into(),
-                );
+                return Err(ReflacError::UnknownArchiveType(
+                    ext.to_str().unwrap().to_string(),
+                ));
             }
         }
     }
-    Ok(())
+    check_symlinks(&out_dir, &out_dir, &path)
+}
+
+/// Verifies and extracts an archive into the work directory, or into the
+/// extraction cache if enabled, and returns the extracted tree.
+fn unpack_archive(path: &Path, ctx: &mut Extraction) -> Result<PathBuf> {
+    verify_sidecars(path, ctx)?;
+    match ctx.cache.clone() {
+        Some(cache) => cache.archive(path, |out| extract_archive(path, out, ctx)),
+        None => {
+            let tree = ctx.tmp_dir.unique_subdir()?;
+            extract_archive(path, &tree, ctx)?;
+            Ok(tree)
+        }
+    }
 }
 
-fn get_input<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
+fn get_input<P: AsRef<Path>>(path: P, ctx: &mut Extraction) -> Result<PathBuf> {
     let mut progress = PathBuf::new();
     let mut pos = PathBuf::new();
     for p in path.as_ref() {
         progress = progress.join(p);
         pos = pos.join(p);
         if !pos.exists() {
-            return Err(ReflacError::PathDoesNotExist(progress).into());
+            return Err(ReflacError::PathDoesNotExist(progress));
         }
         if pos.is_file() {
-            if let Some(ext) = pos.extension() {
-                let new_tree = tmp_dir.unique_subdir();
-                if ["zip", "rar", "7z"].contains(&ext.to_str().unwrap()) {
-                    extract_archive(pos, &new_tree)?;
-                } else {
-                    return Err(ReflacError::InvalidInputPath(progress).into());
+            if pos.extension().is_some() {
+                if !archive::is_archive(&pos) {
+                    return Err(ReflacError::InvalidInputPath(progress));
                 }
+                let new_tree = unpack_archive(&pos, ctx)?;
                 let dir_contents: Vec<_> = fs::read_dir(&new_tree)?.collect();
                 if dir_contents.len() == 1 {
                     pos = dir_contents[0].as_ref().unwrap().path();
@@ -603,30 +1846,28 @@ fn get_input<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
                     pos = new_tree;
                 }
             } else {
-                return Err(ReflacError::InvalidInputPath(progress).into());
+                return Err(ReflacError::InvalidInputPath(progress));
             }
         }
     }
     Ok(pos)
 }
 
-fn search_input<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
-    // Look for FLAC files
+fn search_input<P: AsRef<Path>>(path: P, ctx: &mut Extraction) -> Result<PathBuf> {
+    // Look for FLAC files, compressed or not
     for entry in fs::read_dir(&path)? {
         let entry = entry?;
-        if entry.path().is_file() {
-            if let Some(ext) = entry.path().extension() {
-                if ext == "flac" {
-                    return Ok(path.as_ref().to_path_buf());
-                }
-            }
+        if entry.path().is_file()
+            && (flac::is_flac(&entry.path()) || archive::decompressor(&entry.path()).is_some())
+        {
+            return Ok(path.as_ref().to_path_buf());
         }
     }
     // Look in directories
     for entry in fs::read_dir(&path)? {
         let entry = entry?;
         if entry.path().is_dir() {
-            let tree = search_input(entry.path(), tmp_dir);
+            let tree = search_input(entry.path(), ctx);
             if tree.is_ok() {
                 return tree;
             }
@@ -635,359 +1876,3744 @@ fn search_input<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
     // Look in archives
     for entry in fs::read_dir(&path)? {
         let entry = entry?;
-        if entry.path().is_file() {
-            if let Some(ext) = entry.path().extension() {
-                if ["zip", "rar", "7z"].contains(&ext.to_str().unwrap()) {
-                    let new_tree = tmp_dir.unique_subdir();
-                    extract_archive(entry.path(), &new_tree)?;
-                    let tree = search_input(new_tree, tmp_dir);
-                    if tree.is_ok() {
-                        return tree;
-                    }
-                }
+        if entry.path().is_file() && archive::is_archive(&entry.path()) {
+            let new_tree = unpack_archive(&entry.path(), ctx)?;
+            let tree = search_input(new_tree, ctx);
+            if tree.is_ok() {
+                return tree;
             }
         }
     }
     // Nothing found
-    Err(ReflacError::NoFlacFilesFound(path.as_ref().to_path_buf()).into())
+    Err(ReflacError::NoFlacFilesFound(path.as_ref().to_path_buf()))
 }
 
-fn get_track<P: AsRef<Path>>(track: usize, path: P) -> Result<PathBuf> {
-    static TRACKFILE_RE: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new(r".*?(\d+).*\.flac").unwrap());
-    for entry in path.as_ref().read_dir()? {
-        let entry = entry?;
-        if let Some(caps) = TRACKFILE_RE.captures(entry.file_name().to_str().unwrap()) {
-            if caps[1].parse::<usize>().unwrap() == track {
-                return Ok(entry.path());
+/// Where the audio of a track comes from.
+#[derive(Clone)]
+enum Source {
+    File(PathBuf),
+    /// A member of a ZIP archive that is decoded without extracting it
+    ZipMember(PathBuf, String, u64),
+    /// A member of an archive, a compressed file or a cue sheet track that
+    /// `--dry-run` only listed; it is never decoded
+    Listed(PathBuf, String),
+}
+
+impl Source {
+    fn name(&self) -> &str {
+        match self {
+            Source::File(path) => path.file_name().unwrap().to_str().unwrap(),
+            Source::ZipMember(_, member, _) | Source::Listed(_, member) => {
+                member.rsplit('/').next().unwrap()
             }
         }
     }
-    Err(ReflacError::InputTrackNotFound(track).into())
-}
 
-fn get_cover<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
-    if path.as_ref().exists() {
-        if let Some(ext) = path.as_ref().extension() {
-            if ext == "flac" {
-                let (tmp_path, tmp_file) = tmp_dir.unique_subfile("");
-                if !Command::new("metaflac")
-                    .arg("--export-picture-to=-")
-                    .arg(path.as_ref())
-                    .stdout(tmp_file)
-                    .stderr(Stdio::null())
-                    .status()?
-                    .success()
-                {
-                    eprintln!(
-                        "ERROR! Failed to extract cover from {}!",
-                        path.as_ref().display()
-                    );
-                    std::process::exit(1);
-                }
-                return Ok(tmp_path);
-            }
+    fn size(&self) -> u64 {
+        match self {
+            Source::File(path) => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            Source::ZipMember(_, _, size) => *size,
+            Source::Listed(..) => 0,
         }
-    } else {
-        return Err(ReflacError::PathDoesNotExist(path.as_ref().to_path_buf()).into());
     }
-    Ok(path.as_ref().to_path_buf())
-}
 
-fn get_album_name(tags: &Vec<Tag>) -> Option<&String> {
-    let mut albums = HashMap::new();
-    for tag in tags {
-        if let Some(ref album) = tag.album {
-            if let Some(cnt) = albums.get_mut(&album) {
-                *cnt += 1;
-            } else {
-                albums.insert(album, 1);
+    fn display(&self) -> String {
+        match self {
+            Source::File(path) => path.display().to_string(),
+            Source::ZipMember(archive, member, _) | Source::Listed(archive, member) => {
+                format!("{}:{member}", archive.display())
             }
         }
     }
-    let mut largest_cnt = 0;
-    static EMPTY_STRING: String = String::new();
-    let mut largest_album = &EMPTY_STRING;
-    for (album, cnt) in albums {
-        if cnt > largest_cnt {
-            largest_cnt = cnt;
-            largest_album = album;
+
+    /// Opens the archive of a ZIP member to read it in-process, unless it
+    /// needs `unzip`.
+    fn zip_member(archive: &Path, name: &str) -> Result<Option<(zip::Archive, zip::Member)>> {
+        let Some(zip) = open_zip(archive)? else {
+            return Ok(None);
+        };
+        match zip.members.iter().find(|m| m.name == name).cloned() {
+            Some(member) => Ok(Some((zip, member))),
+            None => Err(ReflacError::InvalidArchive(
+                archive.to_path_buf(),
+                format!("no member {name}"),
+            )),
         }
     }
-    if largest_cnt > 0 {
-        Some(largest_album)
-    } else {
-        None
+
+    /// Streams the raw FLAC data of a ZIP member.
+    fn unzip(archive: &Path, member: &str, sandbox: Sandbox, work_dir: &Path) -> Command {
+        let mut cmd = sandbox::command(sandbox, "unzip", work_dir);
+        cmd.arg("-p")
+            .arg(archive)
+            .arg(archive::unzip_pattern(member));
+        cmd
     }
-}
 
-fn recompress<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
-    in_path: P,
-    out_path: Q,
-    tag: &Tag,
-    cover: Option<R>,
-) -> Result<Child> {
-    let dec_proc = Command::new("flac")
-        .arg("--decode")
-        .arg("--stdout")
-        .arg(in_path.as_ref())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()?;
-    let mut args = vec![
-        String::from("--best"),
-        String::from("--exhaustive-model-search"),
-        String::from("--qlp-coeff-precision-search"),
-    ];
-    if let Some(ref title) = tag.title {
-        args.push(format!("--tag=TITLE={title}"));
+    /// Opens the FLAC data of a source for reading.
+    fn reader(&self, sandbox: Sandbox, work_dir: &Path) -> Result<Box<dyn Read + Send>> {
+        match self {
+            Source::File(path) => Ok(Box::new(File::open(path)?)),
+            Source::ZipMember(archive, member, _) => {
+                if let Some((zip, member)) = Self::zip_member(archive, member)? {
+                    // A failed read truncates the stream, which the decoder
+                    // reports
+                    let (reader, mut writer) = std::io::pipe()?;
+                    std::thread::spawn(move || zip.read(&member, &mut writer));
+                    return Ok(Box::new(reader));
+                }
+                let unzip = Self::unzip(archive, member, sandbox, work_dir)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                Ok(Box::new(unzip.stdout.unwrap()))
+            }
+            Source::Listed(..) => unreachable!("--dry-run encodes nothing"),
+        }
     }
-    if let Some(ref artist) = tag.artist {
-        args.push(format!("--tag=ARTIST={artist}"));
+
+    /// Prepares the audio of a source for the encoder, decoded in-process
+    /// where flac can take it as raw samples. With a salvage log, `flac`
+    /// decodes it instead, concealing damaged frames and reporting them into
+    /// the log.
+    fn decode(&self, sandbox: Sandbox, work_dir: &Path, salvage: Option<&Path>) -> Result<Decoded> {
+        if salvage.is_none() {
+            let decoder = decoder::Decoder::new(self.reader(sandbox, work_dir)?)
+                .map_err(|err| ReflacError::DecodeFailed(self.display(), err))?;
+            // Raw samples are read in whole bytes
+            if decoder.stream.bits_per_sample % 8 == 0 {
+                return Ok(Decoded::Frames(self.display(), decoder));
+            }
+        }
+        self.decode_process(sandbox, work_dir, salvage)
+            .map(Decoded::Process)
     }
-    if let Some(ref lyricist) = tag.lyricist {
-        args.push(format!("--tag=LYRICIST={lyricist}"));
+
+    /// Spawns `flac` decoding the source to its stdout.
+    fn decode_process(
+        &self,
+        sandbox: Sandbox,
+        work_dir: &Path,
+        salvage: Option<&Path>,
+    ) -> Result<Child> {
+        let mut cmd = Command::new("flac");
+        cmd.arg("--decode").arg("--stdout");
+        match salvage {
+            Some(log) => cmd
+                .arg("--decode-through-errors")
+                .stderr(File::create(log)?),
+            None => cmd.stderr(Stdio::null()),
+        };
+        match self {
+            Source::File(path) => Ok(cmd.arg(path).stdout(Stdio::piped()).spawn()?),
+            Source::ZipMember(archive, member, _) => {
+                if let Some((zip, member)) = Self::zip_member(archive, member)? {
+                    let mut child = cmd
+                        .arg("-")
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()?;
+                    // A failed read truncates the stream, which the decoder
+                    // reports
+                    let mut stdin = child.stdin.take().unwrap();
+                    std::thread::spawn(move || zip.read(&member, &mut stdin));
+                    return Ok(child);
+                }
+                let unzip = Self::unzip(archive, member, sandbox, work_dir)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                Ok(cmd
+                    .arg("-")
+                    .stdin(unzip.stdout.unwrap())
+                    .stdout(Stdio::piped())
+                    .spawn()?)
+            }
+            Source::Listed(..) => unreachable!("--dry-run encodes nothing"),
+        }
     }
-    if let Some(ref composer) = tag.composer {
-        args.push(format!("--tag=COMPOSER={composer}"));
+
+    fn copy_to<P: AsRef<Path>>(&self, dest: P, sandbox: Sandbox, work_dir: &Path) -> Result<()> {
+        match self {
+            Source::File(path) => copy_source(path, dest),
+            Source::ZipMember(archive, member, _) => {
+                if let Some((zip, member)) = Self::zip_member(archive, member)? {
+                    let mut out = BufWriter::new(throttle::Writer(File::create(dest)?));
+                    zip.read(&member, &mut out)
+                        .and_then(|()| out.flush())
+                        .map_err(|err| zip_error(archive, err))?;
+                    return Ok(());
+                }
+                // unzip would write past the limit
+                let staged = if throttle::is_limited() {
+                    work_dir.join("unzipped.flac")
+                } else {
+                    dest.as_ref().to_path_buf()
+                };
+                run_command(
+                    Self::unzip(archive, member, sandbox, work_dir).stdout(File::create(&staged)?),
+                    "unzip",
+                )?;
+                if staged != dest.as_ref() {
+                    throttle::move_file(&staged, dest.as_ref())?;
+                }
+                Ok(())
+            }
+            Source::Listed(..) => unreachable!("--dry-run encodes nothing"),
+        }
     }
-    if let Some(ref arranger) = tag.arranger {
-        args.push(format!("--tag=ARRANGER={arranger}"));
+}
+
+/// The audio of a source on its way to the encoder.
+enum Decoded {
+    /// A `flac --decode` process writing WAV to its stdout
+    Process(Child),
+    /// A source decoded in-process, by its name
+    Frames(String, decoder::Decoder<Box<dyn Read + Send>>),
+}
+
+/// Number of frames a decoder run with `--decode-through-errors` reported
+/// as damaged.
+fn count_bad_frames(log: &str) -> u64 {
+    log.lines()
+        .filter(|line| line.contains("*** Got error code"))
+        .count() as u64
+}
+
+/// Decompresses a single compressed source such as `01.flac.xz` into the
+/// work directory, keeping its name without the compression extension.
+fn decompress(path: &Path, tool: &'static str, ctx: &Extraction) -> Result<PathBuf> {
+    let dir = ctx.tmp_dir.unique_subdir()?;
+    let out_path = dir.join(path.file_stem().unwrap());
+    let out_file = File::create(&out_path)
+        .map_err(|err| ReflacError::CreateFileFailed(out_path.clone(), err))?;
+    run_command(
+        sandbox::command(ctx.sandbox, tool, &dir)
+            .arg("--decompress")
+            .arg("--stdout")
+            .arg(fs::canonicalize(path)?)
+            .stdout(out_file),
+        tool,
+    )?;
+    Ok(out_path)
+}
+
+fn list_sources<P: AsRef<Path>>(dir: P, ctx: &Extraction) -> Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match archive::decompressor(&path) {
+            Some(tool) => sources.push(Source::File(decompress(&path, tool, ctx)?)),
+            None if flac::is_flac(&path) => sources.push(Source::File(path)),
+            None => (),
+        }
     }
-    if let Some(ref album) = tag.album {
-        args.push(format!("--tag=ALBUM={album}"));
+    Ok(sources)
+}
+
+fn is_cue_sheet(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+fn read_cue_sheet(path: &Path) -> Result<Vec<cue::Track>> {
+    // Cue sheets from older rippers are often not UTF-8
+    let text = String::from_utf8_lossy(&fs::read(path)?).into_owned();
+    cue::parse(&text).map_err(|reason| ReflacError::InvalidCueSheet(path.to_path_buf(), reason))
+}
+
+/// The file a cue sheet track is split into, e.g. `03 - Title.flac`.
+fn cue_track_name(track: &cue::Track) -> String {
+    match track.title {
+        Some(ref title) => sanitize_file_name(&format!("{:02} - {title}", track.number), ".flac"),
+        None => format!("{:02}.flac", track.number),
     }
-    args.push(format!("--tag=TRACKNUMBER={}", tag.track.unwrap()));
-    if let Some(disc) = tag.disc {
-        args.push(format!("--tag=DISCNUMBER={disc}"));
+}
+
+/// The image a cue sheet track is in. Sheets written for the original rip
+/// often name `Image.wav` although the image has since been compressed to
+/// `Image.flac`, which is then preferred even if the WAV is still there.
+fn cue_image(cue_path: &Path, file: &str) -> Result<PathBuf> {
+    let image = cue_path.parent().unwrap().join(file);
+    if flac::is_flac(&image) {
+        return Ok(image);
     }
-    if let Some(ref genre) = tag.genre {
-        args.push(format!("--tag=GENRE={genre}"));
+    let flac = image.with_extension("flac");
+    if flac.exists() {
+        return Ok(flac);
     }
-    if let Some(ref date) = tag.date {
-        args.push(format!(
-            "--tag=DATE={:04}-{:02}-{:02}",
-            date[0], date[1], date[2]
+    if image.exists() {
+        return Err(ReflacError::InvalidCueSheet(
+            cue_path.to_path_buf(),
+            format!("image {file} must be FLAC"),
         ));
     }
-    if let Some(ref label) = tag.label {
-        args.push(format!("--tag=LABEL={label}"));
-    }
-    if let Some(ref comment) = tag.comment {
-        args.push(format!("--tag=COMMENT={comment}"));
-    }
-    if let Some(path) = cover {
-        args.push(format!("--picture={}", path.as_ref().to_str().unwrap()));
-    }
-    args.push(format!(
-        "--output-name={}",
-        out_path.as_ref().to_str().unwrap()
-    ));
-    args.push(String::from("-"));
-    Ok(Command::new("flac")
-        .args(args)
-        .stdin(dec_proc.stdout.unwrap())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?)
+    Err(ReflacError::PathDoesNotExist(image))
 }
 
-fn add_replay_gain(paths: &Vec<PathBuf>) -> Result<()> {
-    if !Command::new("metaflac")
-        .arg("--add-replay-gain")
-        .args(paths)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()?
-        .success()
-    {
-        todo!("Proper error handling");
+/// Opens a cue sheet INPUT: the FLAC images it refers to are split into a
+/// file per track in the work directory, cut at the exact sample.
+fn open_cue_sheet(cue_path: &Path, ctx: &mut Extraction) -> Result<(PathBuf, Vec<Source>)> {
+    let tracks = read_cue_sheet(cue_path)?;
+    let root = cue_path.parent().unwrap().to_path_buf();
+    let dir = ctx.tmp_dir.unique_subdir()?;
+    let mut sample_rates = HashMap::new();
+    let mut sources = Vec::new();
+    for (i, track) in tracks.iter().enumerate() {
+        let image = cue_image(cue_path, &track.file)?;
+        if !sample_rates.contains_key(&track.file) {
+            let rate = flac::read_metadata(&image)?.stream.sample_rate;
+            sample_rates.insert(track.file.clone(), rate as u64);
+        }
+        let (start, end) = cue::sample_range(&tracks, i, sample_rates[&track.file]);
+        let out_path = dir.join(cue_track_name(track));
+        let mut cmd = Command::new("flac");
+        cmd.args(["--silent", "--force", "-0"])
+            .arg(format!("--skip={start}"));
+        if let Some(end) = end {
+            cmd.arg(format!("--until={end}"));
+        }
+        run_command(
+            cmd.arg(format!("--output-name={}", out_path.display()))
+                .arg(&image),
+            "flac",
+        )?;
+        sources.push(Source::File(out_path));
     }
-    Ok(())
+    Ok((root, sources))
 }
 
-fn run() -> Result<()> {
-    // Assess command line
-    if env::args().len() < 2 || env::args().len() > 3 {
-        eprintln!(
-            "USAGE: {} TRACKINFO [OUTPUT_DIR]",
-            env::args().next().unwrap()
-        );
-        std::process::exit(1);
+/// Resolves an INPUT for `--dry-run` without extracting or decompressing
+/// anything: archives are only listed.
+fn plan_input(input_path: &Path, ctx: &Extraction) -> Result<(PathBuf, Vec<Source>)> {
+    if is_cue_sheet(input_path) {
+        let sources = read_cue_sheet(input_path)?
+            .iter()
+            .map(|track| Source::Listed(input_path.to_path_buf(), cue_track_name(track)))
+            .collect();
+        return Ok((input_path.parent().unwrap().to_path_buf(), sources));
     }
-    let trackinfo_path = PathBuf::from(env::args().nth(1).unwrap());
-    let trackinfo_parent = trackinfo_path.parent().unwrap();
-    let output_dir = if let Some(arg) = env::args().nth(2) {
-        PathBuf::from(arg)
-    } else if let Some(dirname) = trackinfo_path.parent() {
-        dirname.to_path_buf()
-    } else {
-        eprintln!("ERROR: Could not evaluate TRACKINFO parent directory");
-        std::process::exit(1);
-    };
-    if !trackinfo_path.exists() {
-        eprintln!("ERROR: {} does not exist!", trackinfo_path.display());
-        std::process::exit(1);
+    let mut pos = PathBuf::new();
+    let mut components = input_path.components();
+    while let Some(component) = components.next() {
+        pos.push(component);
+        if !pos.exists() {
+            return Err(ReflacError::PathDoesNotExist(pos));
+        }
+        if pos.is_file() {
+            if !archive::is_archive(&pos) {
+                return Err(ReflacError::InvalidInputPath(pos));
+            }
+            let sources = list_members(&pos, components.as_path(), ctx)?;
+            return Ok((pos, sources));
+        }
     }
-    if !output_dir.exists() {
-        eprintln!("ERROR: {} does not exist!", output_dir.display());
-        std::process::exit(1);
+    let sources = plan_search(&pos, ctx)?;
+    Ok((pos, sources))
+}
+
+/// Like `search_input`, but lists archives instead of extracting them.
+fn plan_search(dir: &Path, ctx: &Extraction) -> Result<Vec<Source>> {
+    let entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    if entries.iter().any(|path| {
+        path.is_file() && (flac::is_flac(path) || archive::decompressor(path).is_some())
+    }) {
+        return Ok(entries
+            .into_iter()
+            .filter_map(|path| match archive::decompressor(&path) {
+                Some(_) => {
+                    let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+                    Some(Source::Listed(path, name))
+                }
+                None => flac::is_flac(&path).then_some(Source::File(path)),
+            })
+            .collect());
     }
-    if !output_dir.is_dir() {
-        eprintln!("ERROR: {} is not a directory!", output_dir.display());
-        std::process::exit(1);
+    for subdir in entries.iter().filter(|path| path.is_dir()) {
+        if let Ok(sources) = plan_search(subdir, ctx) {
+            return Ok(sources);
+        }
     }
-
-    // Parse trackinfo
-    println!("Parsing track info file ...");
-    let tags = parse_trackinfo(&trackinfo_path)?;
-
-    // Work directory
-    let work_dir = TempDir::new("reflac");
-
-    // Resolve inputs
-    let mut inputs_root: HashMap<&String, PathBuf> = HashMap::new();
-    let mut inputs_flac: HashMap<&String, PathBuf> = HashMap::new();
-    let mut input_map_roots: HashMap<usize, PathBuf> = HashMap::new();
-    let mut input_map_flacs: HashMap<usize, PathBuf> = HashMap::new();
-    for tag in &tags {
-        let track = tag.track.unwrap();
-        if let Some(ref input) = tag.input {
-            if inputs_root.contains_key(input) {
-                input_map_roots.insert(track, inputs_root[input].clone());
-                input_map_flacs.insert(track, inputs_flac[input].clone());
-            } else {
-                println!("Opening input \"{input}\" ...");
-                let root_path = get_input(trackinfo_parent.join(input), &work_dir)?;
-                let flac_path = search_input(&root_path, &work_dir)?;
-                input_map_roots.insert(track, root_path.clone());
-                input_map_flacs.insert(track, flac_path.clone());
-                inputs_root.insert(input, root_path);
-                inputs_flac.insert(input, flac_path);
+    for file in entries.iter().filter(|path| path.is_file()) {
+        if archive::is_archive(file) {
+            match list_members(file, Path::new(""), ctx) {
+                Err(ReflacError::NoFlacFilesFound(_)) => continue,
+                result => return result,
             }
-        } else {
-            todo!("Proper error handling");
         }
     }
+    Err(ReflacError::NoFlacFilesFound(dir.to_path_buf()))
+}
+
+/// The FLAC members of the first directory in an archive holding any, for
+/// `--dry-run`. With an `inner` path, only directories ending in it count.
+fn list_members(archive: &Path, inner: &Path, ctx: &Extraction) -> Result<Vec<Source>> {
+    let archive = fs::canonicalize(archive)?;
+    let inner = inner.to_str().unwrap().trim_end_matches('/');
+    let member_dir = |name: &str| name.rsplit_once('/').map_or("", |(dir, _)| dir).to_string();
+    let mut members: Vec<String> = list_archive(&archive, ctx.tmp_dir.path(), ctx.sandbox)?
+        .into_iter()
+        .filter(|name| name.to_lowercase().ends_with(".flac"))
+        .filter(|name| {
+            let dir = member_dir(name);
+            inner.is_empty() || dir == inner || dir.ends_with(&format!("/{inner}"))
+        })
+        .collect();
+    members.sort();
+    let Some(dir) = members.first().map(|name| member_dir(name)) else {
+        return Err(ReflacError::NoFlacFilesFound(archive));
+    };
+    Ok(members
+        .into_iter()
+        .filter(|name| member_dir(name) == dir)
+        .map(|name| Source::Listed(archive.clone(), name))
+        .collect())
+}
+
+/// Opens an INPUT: returns the directory it resolves to and its sources.
+fn open_input(
+    input_path: &Path,
+    options: &Options,
+    ctx: &mut Extraction,
+) -> Result<(PathBuf, Vec<Source>)> {
+    if options.dry_run {
+        return plan_input(input_path, ctx);
+    }
+    let (root_path, sources) = if (options.stream_archives || options.low_mem)
+        && input_path.is_file()
+        && input_path.extension().is_some_and(|e| e == "zip")
+    {
+        open_zip_streaming(input_path, ctx)?
+    } else if is_cue_sheet(input_path) {
+        open_cue_sheet(input_path, ctx)?
+    } else {
+        let root_path = get_input(input_path, ctx)?;
+        let flac_path = search_input(&root_path, ctx)?;
+        if flac_path != root_path {
+            verify_checksums(&flac_path, options.checksums, ctx)?;
+        }
+        (root_path, list_sources(flac_path, ctx)?)
+    };
+    verify_checksums(&root_path, options.checksums, ctx)?;
+    Ok((root_path, sources))
+}
+
+/// Opens a ZIP input without extracting its FLAC files. Everything else
+/// (covers, logs, manifests) is extracted into a work directory which serves
+/// as the input root. The FLAC members of the first directory holding any are
+/// returned as sources.
+fn open_zip_streaming(archive: &Path, ctx: &mut Extraction) -> Result<(PathBuf, Vec<Source>)> {
+    let archive = fs::canonicalize(archive)?;
+    verify_sidecars(&archive, ctx)?;
+    let root = fs::canonicalize(ctx.tmp_dir.unique_subdir()?)?;
+    let is_flac = |name: &str| name.to_lowercase().ends_with(".flac");
+    let mut members: Vec<(String, u64)> = match open_zip(&archive)? {
+        Some(zip) => {
+            extract_zip(&zip, &archive, &root, |m| !is_flac(&m.name))?;
+            zip.members.into_iter().map(|m| (m.name, m.size)).collect()
+        }
+        None => unzip_all_but_flac(&archive, &root, ctx)?,
+    };
+    members.retain(|(name, _)| is_flac(name));
+    members.sort();
+    let Some(dir) = members
+        .first()
+        .map(|(name, _)| name.rsplit_once('/').map_or("", |(dir, _)| dir).to_string())
+    else {
+        return Err(ReflacError::NoFlacFilesFound(archive));
+    };
+    let sources = members
+        .into_iter()
+        .filter(|(name, _)| name.rsplit_once('/').map_or("", |(d, _)| d) == dir)
+        .map(|(name, size)| Source::ZipMember(archive.clone(), name, size))
+        .collect();
+
+    let dir_contents: Vec<_> = fs::read_dir(&root)?.collect::<std::io::Result<_>>()?;
+    if dir_contents.len() == 1 && dir_contents[0].path().is_dir() {
+        Ok((dir_contents[0].path(), sources))
+    } else {
+        Ok((root, sources))
+    }
+}
+
+/// Extracts everything but the FLAC files of a ZIP archive with `unzip`
+/// and lists its members with their sizes.
+fn unzip_all_but_flac(archive: &Path, root: &Path, ctx: &Extraction) -> Result<Vec<(String, u64)>> {
+    if let Some(member) = list_archive(archive, root, ctx.sandbox)?
+        .into_iter()
+        .find(|m| !is_safe_member(m))
+    {
+        return Err(ReflacError::UnsafeArchiveMember(
+            archive.to_path_buf(),
+            member,
+        ));
+    }
+
+    // Exit status 11 means nothing but FLAC files in the archive
+    let (status, stderr) = command_status(
+        sandbox::command(ctx.sandbox, "unzip", root)
+            .arg("-q")
+            .arg(archive)
+            .args(["-x", "*.flac", "*.FLAC", "-d"])
+            .arg(root)
+            .stdout(Stdio::null()),
+        "unzip",
+    )?;
+    if !matches!(status.code(), Some(0) | Some(11)) {
+        return Err(ReflacError::subprocess("unzip", Some(status), &stderr));
+    }
+    check_symlinks(root, root, archive)?;
+
+    let output = command_output(
+        sandbox::command(ctx.sandbox, "unzip", root)
+            .arg("-l")
+            .arg(archive),
+        "unzip",
+    )?;
+    if !output.status.success() {
+        return Err(ReflacError::subprocess(
+            "unzip",
+            Some(output.status),
+            &output.stderr,
+        ));
+    }
+    Ok(archive::parse_unzip_listing(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+fn get_track(tag: &Tag, sources: &[Source]) -> Result<Source> {
+    static TRACKFILE_RE: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r".*?(\d+)").unwrap());
+    let track = tag.track.unwrap();
+    if let Some(ref name) = tag.source {
+        return sources
+            .iter()
+            .find(|s| s.name() == name)
+            .cloned()
+            .ok_or(ReflacError::InputTrackNotFound(track));
+    }
+    if let Some(ref position) = tag.position {
+        let position_re = regex::Regex::new(&format!(
+            r"(?i)(?:^|[^a-z0-9]){}(?:[^0-9].*)?$",
+            regex::escape(position)
+        ))
+        .unwrap();
+        if let Some(source) = sources.iter().find(|s| position_re.is_match(s.name())) {
+            return Ok(source.clone());
+        }
+    }
+    for source in sources {
+        if let Some(caps) = TRACKFILE_RE.captures(source.name())
+            && caps[1].parse::<usize>().unwrap() == track
+        {
+            return Ok(source.clone());
+        }
+    }
+    Err(ReflacError::InputTrackNotFound(track))
+}
+
+/// Picks the best of the inputs holding a track, according to `policy`.
+/// Streamed archive members cannot be analysed and lose to files.
+fn best_source<'a>(
+    tag: &Tag,
+    candidates: &[(&'a str, Source)],
+    policy: &[quality::Criterion],
+) -> Result<&'a str> {
+    let mut best: Option<(&str, Option<quality::Profile>)> = None;
+    for (input, source) in candidates {
+        let profile = match source {
+            Source::File(path) => Some(quality::Profile::of(path)?),
+            Source::ZipMember(..) | Source::Listed(..) => None,
+        };
+        info!(
+            track = tag.id();
+            "in \"{input}\": {}",
+            profile
+                .as_ref()
+                .map_or(String::from("not analysed"), quality::Profile::describe)
+        );
+        let better = match (&best, &profile) {
+            (None, _) => true,
+            (Some((_, None)), Some(_)) => true,
+            (Some((_, Some(current))), Some(profile)) => {
+                profile.compare(current, policy) == std::cmp::Ordering::Greater
+            }
+            (Some(_), None) => false,
+        };
+        if better {
+            best = Some((input, profile));
+        }
+    }
+    let input = best.unwrap().0;
+    info!(track = tag.id(); ": using \"{input}\"");
+    Ok(input)
+}
+
+/// Lowercase words of a title or file name, without the numbers that
+/// usually are track numbers.
+fn title_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && !w.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How sure the mapping of `source` to `tag` by file name is, in percent:
+/// the share of title words found in the source's file name or TITLE tag.
+/// Sources or tracks without a title to compare score 50.
+fn mapping_confidence(tag: &Tag, source: &Source) -> u8 {
+    if tag.source.is_some() {
+        return 100;
+    }
+    let Some(ref title) = tag.title else {
+        return 50;
+    };
+    let title = title_words(title);
+    let name = source
+        .name()
+        .rsplit_once('.')
+        .map_or(source.name(), |(stem, _)| stem);
+    let mut candidates = vec![title_words(name)];
+    if let Source::File(path) = source
+        && let Ok(meta) = flac::read_metadata(path)
+        && let Some(source_title) = meta.first("TITLE")
+    {
+        candidates.push(title_words(source_title));
+    }
+    candidates.retain(|words| !words.is_empty());
+    if title.is_empty() || candidates.is_empty() {
+        return 50;
+    }
+    candidates
+        .iter()
+        .map(|words| {
+            let found = title.iter().filter(|w| words.contains(w)).count();
+            (found * 100 / title.len()) as u8
+        })
+        .max()
+        .unwrap()
+}
+
+fn get_cover<P: AsRef<Path>>(path: P, tmp_dir: &TempDir) -> Result<PathBuf> {
+    if path.as_ref().exists() {
+        if path
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pdf"))
+        {
+            return art::render_booklet(path.as_ref(), &tmp_dir.unique_subdir()?);
+        }
+        if let Some(ext) = path.as_ref().extension()
+            && ext == "flac"
+        {
+            let (tmp_path, tmp_file) = tmp_dir.unique_subfile("")?;
+            let (status, _) = command_status(
+                Command::new("metaflac")
+                    .arg("--export-picture-to=-")
+                    .arg(path.as_ref())
+                    .stdout(tmp_file),
+                "metaflac",
+            )?;
+            if !status.success() {
+                return Err(ReflacError::NoPictureFound(path.as_ref().to_path_buf()));
+            }
+            return Ok(tmp_path);
+        }
+    } else {
+        return Err(ReflacError::PathDoesNotExist(path.as_ref().to_path_buf()));
+    }
+    Ok(path.as_ref().to_path_buf())
+}
+
+/// Returns the composer shared by every track, if there is one.
+fn get_album_composer(tags: &[Tag]) -> Option<&String> {
+    let composer = tags.first()?.composer.as_ref()?;
+    tags.iter()
+        .all(|tag| tag.composer.as_ref() == Some(composer))
+        .then_some(composer)
+}
+
+fn get_album_name(tags: &Vec<Tag>) -> Option<&String> {
+    let mut albums = HashMap::new();
+    for tag in tags {
+        if let Some(ref album) = tag.album {
+            if let Some(cnt) = albums.get_mut(&album) {
+                *cnt += 1;
+            } else {
+                albums.insert(album, 1);
+            }
+        }
+    }
+    let mut largest_cnt = 0;
+    static EMPTY_STRING: String = String::new();
+    let mut largest_album = &EMPTY_STRING;
+    for (album, cnt) in albums {
+        if cnt > largest_cnt {
+            largest_cnt = cnt;
+            largest_album = album;
+        }
+    }
+    if largest_cnt > 0 {
+        Some(largest_album)
+    } else {
+        None
+    }
+}
+
+fn vorbis_comments(tag: &Tag) -> Vec<String> {
+    let mut comments = Vec::new();
+    if let Some(ref title) = tag.title {
+        comments.push(format!("TITLE={title}"));
+    }
+    if let Some(ref artist) = tag.artist {
+        comments.push(format!("ARTIST={artist}"));
+    }
+    if let Some(ref lyricist) = tag.lyricist {
+        comments.push(format!("LYRICIST={lyricist}"));
+    }
+    if let Some(ref composer) = tag.composer {
+        comments.push(format!("COMPOSER={composer}"));
+    }
+    if let Some(ref arranger) = tag.arranger {
+        comments.push(format!("ARRANGER={arranger}"));
+    }
+    if let Some(ref album) = tag.album {
+        comments.push(format!("ALBUM={album}"));
+    }
+    comments.push(format!("TRACKNUMBER={}", tag.number.or(tag.track).unwrap()));
+    if let Some(total) = tag.track_total {
+        comments.push(format!("TRACKTOTAL={total}"));
+    }
+    if let Some(disc) = tag.disc {
+        comments.push(format!("DISCNUMBER={disc}"));
+    }
+    if let Some(total) = tag.disc_total {
+        comments.push(format!("DISCTOTAL={total}"));
+    }
+    if let Some(ref genre) = tag.genre {
+        comments.push(format!("GENRE={genre}"));
+    }
+    if let Some(ref date) = tag.date {
+        comments.push(format!("DATE={}", format_date(*date)));
+    }
+    if let Some(ref label) = tag.label {
+        comments.push(format!("LABEL={label}"));
+    }
+    if let Some(ref comment) = tag.comment {
+        comments.push(format!("COMMENT={comment}"));
+    }
+    // Movement fields as MusicBrainz Picard writes them
+    if let Some(ref work) = tag.work {
+        comments.push(format!("WORK={work}"));
+    }
+    if let Some(ref movement) = tag.movement {
+        comments.push(format!("MOVEMENTNAME={movement}"));
+    }
+    if let Some(number) = tag.movement_number {
+        comments.push(format!("MOVEMENT={number}"));
+    }
+    if let Some(total) = tag.movement_total {
+        comments.push(format!("MOVEMENTTOTAL={total}"));
+    }
+    if let Some(ref conductor) = tag.conductor {
+        comments.push(format!("CONDUCTOR={conductor}"));
+    }
+    if let Some(ref ensemble) = tag.ensemble {
+        comments.push(format!("ENSEMBLE={ensemble}"));
+    }
+    if let Some(ref opus) = tag.opus {
+        comments.push(format!("OPUS={opus}"));
+    }
+    for (field, value) in &tag.extra {
+        comments.push(format!("{field}={value}"));
+    }
+    comments
+}
+
+/// ENCODER and ENCODERSETTINGS comments of the files reflac encodes.
+fn encoder_comments(settings: &[String], lax: bool) -> Vec<(String, String)> {
+    let mut settings = settings.join(" ");
+    if lax {
+        settings.push_str(" --lax");
+    }
+    let mut comments = Vec::new();
+    if let Some(encoder) = flac::encoder() {
+        comments.push((String::from("ENCODER"), encoder.to_string()));
+    }
+    comments.push((String::from("ENCODERSETTINGS"), settings));
+    comments
+}
+
+/// Whether a file carries the encoder comments reflac would write now, so
+/// encoding it again cannot make it smaller.
+fn already_encoded(meta: &flac::Metadata, settings: &[String], lax: bool) -> bool {
+    let comments = encoder_comments(settings, lax);
+    comments.len() == 2
+        && comments
+            .iter()
+            .all(|(field, value)| meta.first(field) == Some(value.as_str()))
+}
+
+fn recompress<Q: AsRef<Path>, R: AsRef<Path>>(
+    decoded: Decoded,
+    out_path: Q,
+    tag: &Tag,
+    cover: Option<R>,
+    settings: &[String],
+    lax: bool,
+) -> Result<jobs::Process> {
+    let mut args = encoder_args(out_path, tag, cover, settings, lax);
+    // Progress output is silenced so errors fit in the pipe
+    let mut cmd = Command::new("flac");
+    cmd.arg("--silent")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    match decoded {
+        Decoded::Process(dec_proc) => {
+            args.push(String::from("-"));
+            Ok(cmd
+                .args(args)
+                .stdin(dec_proc.stdout.unwrap())
+                .spawn()?
+                .into())
+        }
+        Decoded::Frames(source, decoder) => {
+            let stream = decoder.stream;
+            args.extend([
+                String::from("--force-raw-format"),
+                String::from("--endian=little"),
+                String::from("--sign=signed"),
+                format!("--channels={}", stream.channels),
+                format!("--bps={}", stream.bits_per_sample),
+                format!("--sample-rate={}", stream.sample_rate),
+                String::from("-"),
+            ]);
+            let mut child = cmd.args(args).stdin(Stdio::piped()).spawn()?;
+            let bytes_per_second = stream.sample_rate as f64
+                * stream.channels as f64
+                * stream.bits_per_sample.div_ceil(8) as f64;
+            let mut stdin = BufWriter::new(progress::Meter::new(
+                child.stdin.take().unwrap(),
+                tag.id(),
+                bytes_per_second,
+            ));
+            // The encoder takes a truncated stream for the whole, so decoding
+            // errors are reported when it is collected
+            let feeder = std::thread::spawn(move || {
+                decoder
+                    .decode(&mut stdin)
+                    .and_then(|_| stdin.flush())
+                    .map_err(|err| ReflacError::DecodeFailed(source, err))
+            });
+            Ok(jobs::Process {
+                child,
+                feeder: Some(feeder),
+            })
+        }
+    }
+}
+
+/// Encodes a file decoded with `--keep-foreign-metadata`, restoring its
+/// non-audio chunks; flac only does so from file to file.
+fn recompress_file<Q: AsRef<Path>, R: AsRef<Path>>(
+    input: &Path,
+    out_path: Q,
+    tag: &Tag,
+    cover: Option<R>,
+    settings: &[String],
+    lax: bool,
+) -> Result<Child> {
+    let mut args = encoder_args(out_path, tag, cover, settings, lax);
+    args.push(String::from("--keep-foreign-metadata"));
+    Ok(Command::new("flac")
+        .arg("--silent")
+        .args(args)
+        .arg(input)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?)
+}
+
+fn encoder_args<Q: AsRef<Path>, R: AsRef<Path>>(
+    out_path: Q,
+    tag: &Tag,
+    cover: Option<R>,
+    settings: &[String],
+    lax: bool,
+) -> Vec<String> {
+    let mut args = settings.to_vec();
+    // flac refuses streams outside the subset (32-bit, very high sample
+    // rates) unless told otherwise
+    if lax {
+        args.push(String::from("--lax"));
+    }
+    for comment in vorbis_comments(tag) {
+        args.push(format!("--tag={comment}"));
+    }
+    for (field, value) in encoder_comments(settings, lax) {
+        args.push(format!("--tag={field}={value}"));
+    }
+    if let Some(path) = cover {
+        args.push(format!("--picture={}", path.as_ref().to_str().unwrap()));
+    }
+    args.push(format!(
+        "--output-name={}",
+        out_path.as_ref().to_str().unwrap()
+    ));
+    args
+}
+
+/// The error of a failed encoder or of the decoder feeding it, if either
+/// failed.
+fn encode_error<T>(finished: &mut jobs::Finished<T>) -> Option<ReflacError> {
+    if finished.stalled {
+        Some(ReflacError::Stalled("flac", jobs::policy().stall.unwrap()))
+    } else if !finished.status.success() {
+        Some(ReflacError::subprocess(
+            "flac",
+            Some(finished.status),
+            &finished.stderr,
+        ))
+    } else {
+        finished.feed_error.take()
+    }
+}
+
+/// Copies an already optimal source into the output tree, cloning the
+/// extents where the filesystem supports it (btrfs, XFS). Hard links are not
+/// an option since the copy is retagged afterwards.
+fn copy_source<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> Result<()> {
+    use std::os::fd::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    let src = File::open(source.as_ref())?;
+    let dst = File::create(dest.as_ref())?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } != 0 {
+        drop(dst);
+        throttle::copy(source.as_ref(), dest.as_ref())?;
+    }
+    Ok(())
+}
+
+fn retag<P: AsRef<Path>, R: AsRef<Path>>(path: P, tag: &Tag, cover: Option<R>) -> Result<()> {
+    run_command(
+        Command::new("metaflac")
+            .arg("--remove")
+            .arg("--block-type=VORBIS_COMMENT,PICTURE")
+            .arg(path.as_ref())
+            .stdout(Stdio::null()),
+        "metaflac",
+    )?;
+    let mut args: Vec<_> = vorbis_comments(tag)
+        .into_iter()
+        .map(|c| format!("--set-tag={c}"))
+        .collect();
+    if let Some(path) = cover {
+        args.push(format!(
+            "--import-picture-from={}",
+            path.as_ref().to_str().unwrap()
+        ));
+    }
+    run_command(
+        Command::new("metaflac")
+            .args(args)
+            .arg(path.as_ref())
+            .stdout(Stdio::null()),
+        "metaflac",
+    )?;
+    Ok(())
+}
+
+/// Retags a copy of a file reflac encoded (or would have encoded the same)
+/// for another track, keeping its encoder comments.
+fn retag_encoded(
+    path: &Path,
+    tag: &Tag,
+    cover: Option<&PathBuf>,
+    settings: &[String],
+    lax_tracks: &HashSet<usize>,
+) -> Result<()> {
+    retag(path, tag, cover)?;
+    for (field, value) in encoder_comments(settings, lax_tracks.contains(&tag.track.unwrap())) {
+        set_tag(path, &field, &value)?;
+    }
+    Ok(())
+}
+
+/// Replaces all values of a single tag.
+fn set_tag<P: AsRef<Path>>(path: P, field: &str, value: &str) -> Result<()> {
+    run_command(
+        Command::new("metaflac")
+            .arg(format!("--remove-tag={field}"))
+            .arg(format!("--set-tag={field}={value}"))
+            .arg(path.as_ref())
+            .stdout(Stdio::null()),
+        "metaflac",
+    )?;
+    Ok(())
+}
+
+fn free_space<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let output = command_output(Command::new("df").arg("-Pk").arg(path.as_ref()), "df")?;
+    if !output.status.success() {
+        return Err(ReflacError::subprocess(
+            "df",
+            Some(output.status),
+            &output.stderr,
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|avail| avail.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| ReflacError::subprocess("df", None, &[]))
+}
+
+fn add_replay_gain(paths: &Vec<PathBuf>) -> Result<()> {
+    run_command(
+        Command::new("metaflac")
+            .arg("--add-replay-gain")
+            .args(paths)
+            .stdout(Stdio::null()),
+        "metaflac",
+    )?;
+    Ok(())
+}
+
+struct Options {
+    trackinfo_path: PathBuf,
+    base_dir: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    config_path: Option<PathBuf>,
+    provider: Option<String>,
+    title_lang: Option<String>,
+    secondary_title_lang: Option<String>,
+    secondary_title_tag: String,
+    typography: Option<Typography>,
+    feat: Option<FeatTarget>,
+    feat_separator: Option<String>,
+    pad_width: usize,
+    file_template: Option<String>,
+    disc_template: Option<String>,
+    naming: Option<Naming>,
+    side_numbering: bool,
+    side_tag: bool,
+    temp_dir: Option<PathBuf>,
+    sandbox: Option<Sandbox>,
+    par2: bool,
+    verify_signatures: bool,
+    keyring: Option<PathBuf>,
+    report_path: Option<PathBuf>,
+    pre_track: Option<String>,
+    post_track: Option<String>,
+    review_path: Option<PathBuf>,
+    min_confidence: Option<u8>,
+    read_only_sources: bool,
+    only_if_smaller: bool,
+    keep_foreign_metadata: bool,
+    require_uniform_format: bool,
+    replay_gain: Option<GainMode>,
+    /// dB a track may be louder or quieter than its album without a warning
+    gain_outliers: Option<f64>,
+    force_reencode: bool,
+    allow_future_date: bool,
+    max_tag_length: Option<usize>,
+    encode_duplicates: bool,
+    best_source: bool,
+    source_policy: Option<Vec<quality::Criterion>>,
+    salvage: bool,
+    cache: bool,
+    cache_dir: Option<PathBuf>,
+    cover_max_size: Option<u64>,
+    interactive: bool,
+    stream_archives: bool,
+    low_mem: bool,
+    /// Encoders running at once
+    jobs: Option<usize>,
+    /// Megabytes per second
+    max_write_rate: Option<f64>,
+    adaptive: bool,
+    max_track_time: Option<std::time::Duration>,
+    keep_temp: bool,
+    timeout: Option<Option<std::time::Duration>>,
+    stall_timeout: Option<Option<std::time::Duration>>,
+    on_timeout: Option<jobs::TimeoutPolicy>,
+    on_collision: Option<Collisions>,
+    disc_layout: Option<DiscLayout>,
+    retries: Option<u32>,
+    retry_delay: Option<std::time::Duration>,
+    append: bool,
+    resume: bool,
+    single_file: bool,
+    create_output_dir: bool,
+    checksums: ChecksumPolicy,
+    dry_run: bool,
+}
+
+enum Mode {
+    Encode(Box<Options>),
+    Bench(PathBuf, Vec<usize>),
+    ExportTrackinfo(PathBuf, Option<PathBuf>),
+    Gain(Vec<PathBuf>, bool, bool),
+    ArtExtract(PathBuf, Option<PathBuf>),
+    ArtSet(PathBuf, PathBuf),
+    Tag(PathBuf, Edit, bool),
+    Lint(PathBuf),
+    CacheClean(Option<PathBuf>),
+    DiscId(PathBuf),
+    Ab(ab::Options),
+    PruneReport(PathBuf, Option<u64>),
+    Verify(PathBuf),
+}
+
+/// The help text; `reflac --help` prints it to stdout, mistakes in the
+/// arguments to stderr.
+fn usage_text(program: &str) -> String {
+    let mut text = String::new();
+    macro_rules! say {
+        () => {
+            text.push('\n')
+        };
+        ($($arg:tt)*) => {{
+            text.push_str(&format!($($arg)*));
+            text.push('\n');
+        }};
+    }
+    say!("USAGE: {program} [encode] [OPTIONS] TRACKINFO|DIR|- [OUTPUT_DIR]");
+    say!("       {program} plan [OPTIONS] TRACKINFO|DIR|- [OUTPUT_DIR]");
+    say!("       {program} verify ALBUM_DIR");
+    say!("       {program} bench [--threads N,...] FILE.flac");
+    say!("       {program} export-trackinfo ALBUM_DIR [TRACKINFO]");
+    say!("       {program} lint TRACKINFO");
+    say!("       {program} cache clean [--config FILE]");
+    say!("       {program} discid DIR");
+    say!("       {program} ab ALBUM_DIR TRACK [--source FILE] [--play] [--config FILE]");
+    say!("       {program} prune-report [--quota SIZE] LIBRARY");
+    say!("       {program} gain [--per-disc|--group] ALBUM_DIR...");
+    say!("       {program} art extract ALBUM_OR_FILE [--out FILE]");
+    say!("       {program} art set ALBUM_DIR IMAGE");
+    say!("       {program} tag set [--dry-run] ALBUM_DIR FIELD=VALUE ...");
+    say!("       {program} tag rename [--dry-run] ALBUM_DIR OLD NEW");
+    say!("       {program} tag delete [--dry-run] ALBUM_DIR FIELD ...");
+    say!();
+    say!("  -h, --help                   Print this help and exit");
+    say!("  -V, --version                Print the version and exit");
+    say!();
+    say!("OPTIONS:");
+    say!("  -q, --quiet                  Only print warnings and errors");
+    say!("  --color WHEN                 Color messages (auto, never or always)");
+    say!("  --config FILE                Read settings from FILE");
+    say!("  --base-dir DIR               Resolve INPUT paths against DIR (default: the");
+    say!("                               TRACKINFO directory, or . for - on stdin)");
+    say!("  --provider NAME              Fill in tags from the configured PROVIDER[NAME]");
+    say!("  --title-lang LANG            Use TITLE:LANG lines for TITLE");
+    say!("  --secondary-title-lang LANG  Also write TITLE:LANG lines into another tag");
+    say!("  --secondary-title-tag TAG    Tag for secondary titles (default: TITLESORT)");
+    say!("  --typography STYLE           Normalize quotes, dashes and ellipses");
+    say!("                               (ascii or typographic)");
+    say!("  --feat TARGET                Move featured artists into artist or title");
+    say!("  --feat-separator SEP         Write featured artists as \"SEP X\"");
+    say!("  --pad-width N                Minimum digits of track numbers in file names");
+    say!("                               (default: 2)");
+    say!("  --file-template TEMPLATE     File name template, e.g. \"{{position}} {{title}}\"");
+    say!(
+        "  --disc-template TEMPLATE     Disc folder template, e.g. \"CD{{disc}}\" (\"\" for none)"
+    );
+    say!("  --disc-layout LAYOUT         A folder per disc (folders, default) or all discs");
+    say!("                               in the album directory (flat)");
+    say!("  --on-collision POLICY        Tracks with the same file name: fail (default)");
+    say!("                               or suffix them with (2), (3), ...");
+    say!("  --naming MODE                Default naming (standard, classical or");
+    say!("                               soundtrack)");
+    say!("  --side-numbering             Restart TRACKNUMBER on every side (A1, B1, ...)");
+    say!("  --side-tag                   Write a SIDE tag for side positions");
+    say!("  --temp-dir DIR               Extract sources below DIR instead of the");
+    say!("                               system temporary directory");
+    say!("  --sandbox KIND               Run extraction tools in a sandbox");
+    say!("                               (none, bwrap or firejail)");
+    say!("  --par2                       Verify (and repair) archives with .par2 files");
+    say!("  --verify-signatures          Check .asc/.sig signatures of archives");
+    say!("  --keyring FILE               GnuPG keyring for signature checks");
+    say!("  --report FILE                Write a JSON report of the run to FILE");
+    say!("  --pre-track CMD              Run CMD (with sh) before encoding each track");
+    say!("  --post-track CMD             Run CMD on each encoded track before ReplayGain");
+    say!("  --review FILE                List uncertain track mappings in FILE");
+    say!("  --min-confidence PERCENT     Refuse track mappings less certain than this");
+    say!("  --read-only-sources          Never write into source directories");
+    say!("  --only-if-smaller            Keep sources that do not shrink when recompressed");
+    say!("  --replaygain album|disc|off  How ReplayGain is added (default: album)");
+    say!("  --gain-outliers DB           Warn about tracks DB louder or quieter than the album");
+    say!("  --keep-foreign-metadata      Keep WAV/AIFF chunks (BWF) stored in the sources");
+    say!("  --require-uniform-format     Fail if tracks differ in sample rate or bit depth");
+    say!("  --force-reencode             Encode sources this flac already encoded");
+    say!("  --allow-future-date          Accept a DATE after today");
+    say!("  --max-tag-length BYTES       Longest tag value accepted (default: 4096,");
+    say!("                               0: no limit)");
+    say!("  --encode-duplicates          Encode identical audio once per track");
+    say!("  --best-source                Compare all INPUT alternatives per track");
+    say!("  --source-policy LIST         Criteria for --best-source, most important first");
+    say!("  --salvage                    Conceal decode errors in damaged sources");
+    say!("  --cache                      Keep extracted archives for later runs");
+    say!("  --cache-dir DIR              Cache directory (default: ~/.cache/reflac)");
+    say!("  --cover-max-size BYTES       Largest image COVER=auto picks without warning");
+    say!("  --interactive                Ask which image COVER=auto should use");
+    say!("  --stream-archives            Decode FLAC files straight out of ZIP inputs");
+    say!("  --low-mem                    Encode one track at a time and stream archives");
+    say!("  -j, --jobs N                 Run up to N encoders at once (default: one per");
+    say!("                               CPU, or REFLAC_JOBS)");
+    say!("  --max-write-MBps RATE        Limit writes to the output tree to RATE MB/s");
+    say!("  --adaptive                   Encode long, noisy tracks with a faster preset");
+    say!("  --max-track-time SECONDS     Also use it for tracks predicted to take longer");
+    say!("  --keep-temp                  Keep the work directory for debugging");
+    say!("  --timeout SECS               Stop external tools running longer than SECS");
+    say!("  --stall-timeout SECS         Stop encoders making no progress for SECS");
+    say!("                               (default 300, 0 disables)");
+    say!("  --on-timeout POLICY          fail (default) or retry once");
+    say!("  --retries N                  Retry failed external tools up to N times");
+    say!("  --retry-delay SECS           Delay before the first retry (default 1)");
+    say!("  --verify-source-checksums[=fail|warn]");
+    say!("                               Check .md5/.sha256/.sfv manifests of sources");
+    say!("  --append                     Add tracks to an existing album directory");
+    say!("  --resume                     Finish an album whose run stopped after encoding");
+    say!("  --single-file                Join each disc into one file with chapter marks");
+    say!("  -o, --output-dir OUTPUT_DIR  Same as the positional OUTPUT_DIR");
+    say!("  -p, --create-output-dir      Create OUTPUT_DIR and its parents if missing");
+    say!("  --dry-run                    Stop after printing the processed tags (as plan)");
+    text
+}
+
+fn usage(program: &str) -> ! {
+    eprint!("{}", usage_text(program));
+    std::process::exit(1);
+}
+
+fn parse_bench_args(program: &str, mut args: impl Iterator<Item = String>) -> Mode {
+    let mut threads = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        match flag {
+            "--threads" => {
+                let list = inline
+                    .or_else(|| args.next())
+                    .unwrap_or_else(|| usage(program));
+                threads = Some(
+                    list.split(',')
+                        .map(|n| n.trim().parse().unwrap_or_else(|_| usage(program)))
+                        .collect::<Vec<usize>>(),
+                );
+            }
+            _ if flag.starts_with("--") || path.is_some() => usage(program),
+            _ => path = Some(PathBuf::from(&arg)),
+        }
+    }
+    let Some(path) = path else { usage(program) };
+    let threads = threads.unwrap_or_else(|| {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if cpus > 1 && bench::supports_threads() {
+            vec![1, cpus]
+        } else {
+            vec![1]
+        }
+    });
+    Mode::Bench(path, threads)
+}
+
+/// Parses a number of concurrent jobs, at least 1.
+fn parse_jobs(s: &str) -> Option<usize> {
+    s.trim().parse().ok().filter(|&jobs| jobs > 0)
+}
+
+fn parse_args() -> Mode {
+    let mut args: Vec<String> = env::args().collect();
+    let program = args.remove(0);
+
+    // --color and --quiet apply to all subcommands, as do --help and
+    // --version
+    let mut color = ColorChoice::Auto;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "-h" || args[i] == "--help" {
+            print!("{}", usage_text(&program));
+            std::process::exit(0);
+        } else if args[i] == "-V" || args[i] == "--version" {
+            match option_env!("REFLAC_GIT_HASH") {
+                Some(hash) => println!("reflac {} ({hash})", env!("CARGO_PKG_VERSION")),
+                None => println!("reflac {}", env!("CARGO_PKG_VERSION")),
+            }
+            std::process::exit(0);
+        } else if args[i] == "-q" || args[i] == "--quiet" {
+            console::set_quiet(true);
+            args.remove(i);
+        } else if args[i] == "--color" && i + 1 < args.len() {
+            color = args[i + 1].parse().unwrap_or_else(|_| usage(&program));
+            args.drain(i..i + 2);
+        } else if let Some(value) = args[i].strip_prefix("--color=") {
+            color = value.parse().unwrap_or_else(|_| usage(&program));
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    console::set_color(color);
+
+    let mut args = args.into_iter().peekable();
+    let mut dry_run = false;
+    match args.peek().map(String::as_str) {
+        Some("encode") => {
+            args.next();
+        }
+        Some("plan") => {
+            args.next();
+            dry_run = true;
+        }
+        Some("verify") => {
+            args.next();
+            let positional: Vec<String> = args.collect();
+            if positional.len() != 1 || positional[0].starts_with("--") {
+                usage(&program);
+            }
+            return Mode::Verify(PathBuf::from(&positional[0]));
+        }
+        Some("bench") => {
+            args.next();
+            return parse_bench_args(&program, args);
+        }
+        Some("export-trackinfo") => {
+            args.next();
+            let positional: Vec<String> = args.collect();
+            if positional.is_empty() || positional.len() > 2 || positional[0].starts_with("--") {
+                usage(&program);
+            }
+            return Mode::ExportTrackinfo(
+                PathBuf::from(&positional[0]),
+                positional.get(1).map(PathBuf::from),
+            );
+        }
+        Some("lint") => {
+            args.next();
+            let positional: Vec<String> = args.collect();
+            if positional.len() != 1 || positional[0].starts_with("--") {
+                usage(&program);
+            }
+            return Mode::Lint(PathBuf::from(&positional[0]));
+        }
+        Some("cache") => {
+            args.next();
+            let rest: Vec<String> = args.collect();
+            return match rest.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["clean"] => Mode::CacheClean(None),
+                ["clean", "--config", path] => Mode::CacheClean(Some(PathBuf::from(path))),
+                _ => usage(&program),
+            };
+        }
+        Some("discid") => {
+            args.next();
+            let positional: Vec<String> = args.collect();
+            if positional.len() != 1 || positional[0].starts_with("--") {
+                usage(&program);
+            }
+            return Mode::DiscId(PathBuf::from(&positional[0]));
+        }
+        Some("prune-report") => {
+            args.next();
+            let rest: Vec<String> = args.collect();
+            return match rest.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                [library] if !library.starts_with("--") => {
+                    Mode::PruneReport(PathBuf::from(library), None)
+                }
+                ["--quota", size, library] => Mode::PruneReport(
+                    PathBuf::from(library),
+                    Some(cache::parse_size(size).unwrap_or_else(|| usage(&program))),
+                ),
+                _ => usage(&program),
+            };
+        }
+        Some("ab") => {
+            args.next();
+            let mut positional = Vec::new();
+            let mut source = None;
+            let mut play = false;
+            let mut config = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--source" => {
+                        source = Some(PathBuf::from(
+                            args.next().unwrap_or_else(|| usage(&program)),
+                        ))
+                    }
+                    "--play" => play = true,
+                    "--config" => {
+                        config = Some(PathBuf::from(
+                            args.next().unwrap_or_else(|| usage(&program)),
+                        ))
+                    }
+                    _ if arg.starts_with("--") => usage(&program),
+                    _ => positional.push(arg),
+                }
+            }
+            let [album_dir, track] =
+                <[String; 2]>::try_from(positional).unwrap_or_else(|_| usage(&program));
+            return Mode::Ab(ab::Options {
+                album_dir: PathBuf::from(album_dir),
+                track,
+                source,
+                play,
+                config,
+            });
+        }
+        Some("gain") => {
+            args.next();
+            let mut per_disc = false;
+            let mut group = false;
+            let mut album_dirs = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--per-disc" => per_disc = true,
+                    "--group" => group = true,
+                    _ if arg.starts_with("--") => usage(&program),
+                    _ => album_dirs.push(PathBuf::from(arg)),
+                }
+            }
+            // Discs of different volumes share their numbers
+            if album_dirs.is_empty() || (group && per_disc) {
+                usage(&program)
+            }
+            return Mode::Gain(album_dirs, per_disc, group);
+        }
+        Some("art") => {
+            args.next();
+            let action = args.next().unwrap_or_else(|| usage(&program));
+            let mut out = None;
+            let mut positional = Vec::new();
+            while let Some(arg) = args.next() {
+                match arg.split_once('=') {
+                    Some(("--out", value)) => out = Some(PathBuf::from(value)),
+                    _ if arg == "--out" => {
+                        out = Some(PathBuf::from(
+                            args.next().unwrap_or_else(|| usage(&program)),
+                        ))
+                    }
+                    _ if arg.starts_with("--") => usage(&program),
+                    _ => positional.push(PathBuf::from(arg)),
+                }
+            }
+            return match (action.as_str(), positional.len(), out) {
+                ("extract", 1, out) => Mode::ArtExtract(positional.remove(0), out),
+                ("set", 2, None) => {
+                    let image = positional.pop().unwrap();
+                    Mode::ArtSet(positional.pop().unwrap(), image)
+                }
+                _ => usage(&program),
+            };
+        }
+        Some("tag") => {
+            args.next();
+            let action = args.next().unwrap_or_else(|| usage(&program));
+            let mut dry_run = false;
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--dry-run" => dry_run = true,
+                    _ if arg.starts_with("--") => usage(&program),
+                    _ => positional.push(arg),
+                }
+            }
+            if positional.len() < 2 {
+                usage(&program);
+            }
+            let album_dir = PathBuf::from(positional.remove(0));
+            let field = |name: &str| {
+                edit::field_name(name).unwrap_or_else(|err| {
+                    error!("{err}");
+                    usage(&program)
+                })
+            };
+            let edit = match action.as_str() {
+                "set" => Edit::Set(
+                    positional
+                        .iter()
+                        .map(|arg| match arg.split_once('=') {
+                            Some((name, value)) => (field(name), value.to_string()),
+                            None => usage(&program),
+                        })
+                        .collect(),
+                ),
+                "rename" if positional.len() == 2 => {
+                    Edit::Rename(field(&positional[0]), field(&positional[1]))
+                }
+                "delete" => Edit::Delete(positional.iter().map(|name| field(name)).collect()),
+                _ => usage(&program),
+            };
+            return Mode::Tag(album_dir, edit, dry_run);
+        }
+        _ => (),
+    }
+    let mut positional = Vec::new();
+    let mut base_dir = None;
+    let mut config_path = None;
+    let mut provider = None;
+    let mut title_lang = None;
+    let mut secondary_title_lang = None;
+    let mut secondary_title_tag = String::from("TITLESORT");
+    let mut typography = None;
+    let mut feat = None;
+    let mut feat_separator = None;
+    let mut pad_width = 2;
+    let mut file_template = None;
+    let mut disc_template = None;
+    let mut naming = None;
+    let mut side_numbering = false;
+    let mut side_tag = false;
+    let mut temp_dir = None;
+    let mut sandbox = None;
+    let mut par2 = false;
+    let mut verify_signatures = false;
+    let mut keyring = None;
+    let mut report_path = None;
+    let mut pre_track = None;
+    let mut post_track = None;
+    let mut review_path = None;
+    let mut min_confidence = None;
+    let mut read_only_sources = false;
+    let mut only_if_smaller = false;
+    let mut keep_foreign_metadata = false;
+    let mut require_uniform_format = false;
+    let mut replay_gain = None;
+    let mut gain_outliers = None;
+    let mut force_reencode = false;
+    let mut allow_future_date = false;
+    let mut encode_duplicates = false;
+    let mut best_source = false;
+    let mut source_policy = None;
+    let mut salvage = false;
+    let mut cache = false;
+    let mut cache_dir = None;
+    let mut cover_max_size = None;
+    let mut max_tag_length = None;
+    let mut interactive = false;
+    let mut stream_archives = false;
+    let mut low_mem = false;
+    let mut jobs = None;
+    let mut max_write_rate = None;
+    let mut adaptive = false;
+    let mut max_track_time = None;
+    let mut keep_temp = false;
+    let mut timeout = None;
+    let mut stall_timeout = None;
+    let mut on_timeout = None;
+    let mut on_collision = None;
+    let mut disc_layout = None;
+    let mut retries = None;
+    let mut retry_delay = None;
+    let mut append = false;
+    let mut resume = false;
+    let mut single_file = false;
+    let mut create_output_dir = false;
+    let mut output_dir = None;
+    let mut checksums = ChecksumPolicy::Off;
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        if flag == "--verify-source-checksums" {
+            checksums = match inline {
+                None => ChecksumPolicy::Fail,
+                Some(value) => value.parse().unwrap_or_else(|_| usage(&program)),
+            };
+            continue;
+        }
+        let value = || {
+            inline
+                .or_else(|| args.next())
+                .unwrap_or_else(|| usage(&program))
+        };
+        match flag.as_str() {
+            "--config" => config_path = Some(PathBuf::from(value())),
+            "--base-dir" => base_dir = Some(PathBuf::from(value())),
+            "--provider" => provider = Some(value()),
+            "--title-lang" => title_lang = Some(value()),
+            "--secondary-title-lang" => secondary_title_lang = Some(value()),
+            "--secondary-title-tag" => secondary_title_tag = value(),
+            "--typography" => {
+                typography = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--feat" => feat = Some(value().parse().unwrap_or_else(|_| usage(&program))),
+            "--feat-separator" => feat_separator = Some(value()),
+            "--pad-width" => pad_width = value().parse().unwrap_or_else(|_| usage(&program)),
+            "--file-template" => file_template = Some(value()),
+            "--disc-template" => disc_template = Some(value()),
+            "--naming" => naming = Some(value().parse().unwrap_or_else(|_| usage(&program))),
+            "--side-numbering" => side_numbering = true,
+            "--side-tag" => side_tag = true,
+            "--temp-dir" => temp_dir = Some(PathBuf::from(value())),
+            "--sandbox" => sandbox = Some(value().parse().unwrap_or_else(|_| usage(&program))),
+            "--par2" => par2 = true,
+            "--verify-signatures" => verify_signatures = true,
+            "--keyring" => keyring = Some(PathBuf::from(value())),
+            "--report" => report_path = Some(PathBuf::from(value())),
+            "--pre-track" => pre_track = Some(value()),
+            "--post-track" => post_track = Some(value()),
+            "--review" => review_path = Some(PathBuf::from(value())),
+            "--min-confidence" => {
+                min_confidence = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--read-only-sources" => read_only_sources = true,
+            "--only-if-smaller" => only_if_smaller = true,
+            "--keep-foreign-metadata" => keep_foreign_metadata = true,
+            "--require-uniform-format" => require_uniform_format = true,
+            "--replaygain" => {
+                replay_gain = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--gain-outliers" => {
+                gain_outliers = Some(
+                    value()
+                        .parse()
+                        .ok()
+                        .filter(|db: &f64| *db >= 0.0)
+                        .unwrap_or_else(|| usage(&program)),
+                )
+            }
+            "--force-reencode" => force_reencode = true,
+            "--allow-future-date" => allow_future_date = true,
+            "--max-tag-length" => {
+                max_tag_length = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--encode-duplicates" => encode_duplicates = true,
+            "--best-source" => best_source = true,
+            "--source-policy" => {
+                source_policy =
+                    Some(quality::parse_policy(&value()).unwrap_or_else(|| usage(&program)));
+            }
+            "--salvage" => salvage = true,
+            "--cache" => cache = true,
+            "--cache-dir" => cache_dir = Some(PathBuf::from(value())),
+            "--cover-max-size" => {
+                cover_max_size = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--interactive" => interactive = true,
+            "--stream-archives" => stream_archives = true,
+            "--low-mem" => low_mem = true,
+            "-j" | "--jobs" => jobs = Some(parse_jobs(&value()).unwrap_or_else(|| usage(&program))),
+            "--max-write-MBps" => {
+                max_write_rate =
+                    Some(throttle::parse_rate(&value()).unwrap_or_else(|| usage(&program)))
+            }
+            "--adaptive" => adaptive = true,
+            "--max-track-time" => {
+                max_track_time = jobs::parse_seconds(&value()).unwrap_or_else(|| usage(&program));
+                adaptive = true;
+            }
+            "--keep-temp" => keep_temp = true,
+            "--timeout" => {
+                timeout = Some(jobs::parse_seconds(&value()).unwrap_or_else(|| usage(&program)))
+            }
+            "--stall-timeout" => {
+                stall_timeout =
+                    Some(jobs::parse_seconds(&value()).unwrap_or_else(|| usage(&program)))
+            }
+            "--on-timeout" => {
+                on_timeout = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--on-collision" => {
+                on_collision = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--disc-layout" => {
+                disc_layout = Some(value().parse().unwrap_or_else(|_| usage(&program)))
+            }
+            "--retries" => retries = Some(value().parse().unwrap_or_else(|_| usage(&program))),
+            "--retry-delay" => {
+                retry_delay = Some(
+                    jobs::parse_seconds(&value())
+                        .unwrap_or_else(|| usage(&program))
+                        .unwrap_or_default(),
+                )
+            }
+            "--append" => append = true,
+            "--resume" => resume = true,
+            "--single-file" => single_file = true,
+            "-o" | "--output-dir" | "--output" => output_dir = Some(PathBuf::from(value())),
+            "-p" | "--create-output-dir" => create_output_dir = true,
+            "--dry-run" => dry_run = true,
+            _ if flag.starts_with("--") => usage(&program),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty()
+        || positional.len() > 2
+        || (append && single_file)
+        || (append && resume)
+    {
+        usage(&program);
+    }
+    // Questions would be read from the piped TRACKINFO
+    if positional[0] == "-" && interactive {
+        usage(&program);
+    }
+    // OUTPUT_DIR may be given either way, but only once
+    if positional.len() == 2 {
+        if output_dir.is_some() {
+            usage(&program);
+        }
+        output_dir = positional.get(1).map(PathBuf::from);
+    }
+    Mode::Encode(Box::new(Options {
+        trackinfo_path: PathBuf::from(&positional[0]),
+        base_dir,
+        output_dir,
+        config_path,
+        provider,
+        title_lang,
+        secondary_title_lang,
+        secondary_title_tag,
+        typography,
+        feat,
+        feat_separator,
+        pad_width,
+        file_template,
+        disc_template,
+        naming,
+        side_numbering,
+        side_tag,
+        temp_dir,
+        sandbox,
+        par2,
+        verify_signatures,
+        keyring,
+        report_path,
+        pre_track,
+        post_track,
+        review_path,
+        min_confidence,
+        read_only_sources,
+        only_if_smaller,
+        keep_foreign_metadata,
+        require_uniform_format,
+        replay_gain,
+        gain_outliers,
+        force_reencode,
+        allow_future_date,
+        max_tag_length,
+        encode_duplicates,
+        best_source,
+        source_policy,
+        salvage,
+        cache,
+        cache_dir,
+        cover_max_size,
+        interactive,
+        stream_archives,
+        low_mem,
+        jobs,
+        max_write_rate,
+        adaptive,
+        max_track_time,
+        keep_temp,
+        timeout,
+        stall_timeout,
+        on_timeout,
+        on_collision,
+        disc_layout,
+        retries,
+        retry_delay,
+        append,
+        resume,
+        single_file,
+        create_output_dir,
+        checksums,
+        dry_run,
+    }))
+}
+
+/// A track in the album being appended to, as (path, disc, number, side).
+type ExistingTrack = (PathBuf, Option<usize>, Option<usize>, Option<String>);
+
+/// What a run is going to do, as decided from the command line, the
+/// configuration and the TRACKINFO file before any input is opened.
+struct Plan {
+    started: SystemTime,
+    trackinfo_path: PathBuf,
+    trackinfo_parent: PathBuf,
+    config: Config,
+    output_dir: PathBuf,
+    environment: Environment,
+    process_cnt: usize,
+    tags: Vec<Tag>,
+    settings: Vec<String>,
+    cover_max_size: u64,
+    gain_mode: GainMode,
+    album: String,
+    album_path: PathBuf,
+    /// Held until the run ends
+    album_lock: Option<lock::AlbumLock>,
+    trackinfo_sha256: String,
+    checkpoint: Option<checkpoint::Checkpoint>,
+    resumed: bool,
+    /// Tracks already in the album when appending
+    existing: Vec<ExistingTrack>,
+    existing_totals: Vec<usize>,
+    disc_template: Option<String>,
+    file_names: Vec<PathBuf>,
+}
+
+fn run(options: &Options, report: &mut Report) -> Result<()> {
+    let plan = plan(options, report)?;
+    execute(plan, options, report)
+}
+
+fn plan(options: &Options, report: &mut Report) -> Result<Plan> {
+    let started = SystemTime::now();
+
+    // Assess command line
+    let from_stdin = options.trackinfo_path == Path::new("-");
+    let trackinfo_path = if !from_stdin && options.trackinfo_path.is_dir() {
+        &find_trackinfo(&options.trackinfo_path)?
+    } else {
+        options.trackinfo_path.as_path()
+    };
+    let trackinfo_parent = match options.base_dir {
+        Some(ref dir) => dir.as_path(),
+        None if from_stdin => Path::new("."),
+        None => trackinfo_path.parent().unwrap(),
+    };
+
+    // Load configuration
+    let config = Config::load(options.config_path.as_deref())?;
+
+    let output_dir = if let Some(ref dir) = options.output_dir {
+        dir.clone()
+    } else if let Some(ref root) = config.output_root {
+        root.clone()
+    } else if from_stdin {
+        info!(
+            "No OUTPUT_DIR given and no OUTPUT_ROOT configured, writing to {}",
+            trackinfo_parent.display()
+        );
+        trackinfo_parent.to_path_buf()
+    } else if let Some(dirname) = trackinfo_path.parent() {
+        info!(
+            "No OUTPUT_DIR given and no OUTPUT_ROOT configured, writing next to {}",
+            trackinfo_path.display()
+        );
+        dirname.to_path_buf()
+    } else {
+        return Err(ReflacError::InvalidInputPath(trackinfo_path.to_path_buf()));
+    };
+    if !from_stdin && !trackinfo_path.exists() {
+        return Err(ReflacError::PathDoesNotExist(trackinfo_path.to_path_buf()));
+    }
+    if !output_dir.exists() {
+        if !options.create_output_dir {
+            return Err(ReflacError::PathDoesNotExist(output_dir));
+        }
+        if !options.dry_run
+            && let Err(err) = fs::create_dir_all(&output_dir)
+        {
+            return Err(ReflacError::CreateDirFailed(output_dir, err));
+        }
+    } else if !output_dir.is_dir() {
+        return Err(ReflacError::NotADirectory(output_dir));
+    }
+
+    report.trackinfo = Some(trackinfo_path.to_path_buf());
+
+    let environment = Environment::capture(&config);
+    report.environment = Some(environment.clone());
+    let mut policy = jobs::Policy::new();
+    policy.timeout = options.timeout.or(config.timeout).flatten();
+    policy.tool_timeouts = config.tool_timeouts.clone();
+    if let Some(stall) = options.stall_timeout.or(config.stall_timeout) {
+        policy.stall = stall;
+    }
+    if let Some(on_timeout) = options.on_timeout.or(config.on_timeout) {
+        policy.on_timeout = on_timeout;
+    }
+    if let Some(retries) = options.retries.or(config.retries) {
+        policy.retries = retries;
+    }
+    if let Some(delay) = options.retry_delay.or(config.retry_delay) {
+        policy.retry_delay = delay;
+    }
+    jobs::set_policy(policy);
+    if let Some(rate) = options.max_write_rate {
+        throttle::set_limit(rate);
+    }
+
+    // Each job holds a decoder and an encoder connected by a pipe, so memory
+    // use grows with the number of concurrent jobs rather than track length
+    let process_cnt = match (options.jobs, env::var("REFLAC_JOBS")) {
+        (Some(jobs), _) => jobs,
+        _ if options.low_mem => 1,
+        (None, Ok(value)) => {
+            parse_jobs(&value).ok_or(ReflacError::InvalidEnvVar("REFLAC_JOBS", value))?
+        }
+        (None, Err(_)) => std::thread::available_parallelism()?.get(),
+    };
+
+    // Parse trackinfo
+    info!("Parsing track info file ...");
+    let trackinfo = if from_stdin {
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut bytes)?;
+        bytes
+    } else {
+        fs::read(trackinfo_path)?
+    };
+    let mut tags = parse_trackinfo_bytes(&trackinfo)?;
+    let provider = options
+        .provider
+        .clone()
+        .or_else(|| tags.iter().find_map(|t| t.provider.clone()));
+    if let Some(name) = provider {
+        let command = config
+            .providers
+            .get(&name)
+            .ok_or_else(|| ReflacError::UnknownProvider(name.clone()))?;
+        tags = provide_tags(&tags, &name, command)?;
+    }
+    for tag in &mut tags {
+        tag.select_title(
+            options.title_lang.as_deref(),
+            options
+                .secondary_title_lang
+                .as_deref()
+                .map(|lang| (lang, options.secondary_title_tag.as_str())),
+        );
+    }
+    // Mostly typos, but pre-orders and announced releases are dated ahead
+    if !options.allow_future_date
+        && let Some(date) = tags.iter().filter_map(|t| t.date).find(|&d| is_future(d))
+    {
+        return Err(ReflacError::FutureDate(format_date(date)));
+    }
+
+    // Select the profile
+    let profile_name = tags.first().and_then(|t| t.profile.clone());
+    if tags.iter().any(|t| t.profile != profile_name) {
+        return Err(ReflacError::MixedProfiles);
+    }
+    let profile = match profile_name {
+        Some(name) => {
+            info!("Using profile \"{name}\"");
+            match config.profiles.get(&name) {
+                Some(profile) => profile.clone(),
+                None => return Err(ReflacError::UnknownProfile(name)),
+            }
+        }
+        None => config::Profile::default(),
+    };
+    let settings = profile
+        .encoder_settings
+        .clone()
+        .unwrap_or_else(|| ENCODER_SETTINGS.iter().map(|s| s.to_string()).collect());
+    let cover_max_size = options
+        .cover_max_size
+        .or(profile.cover_max_size)
+        .unwrap_or(art::MAX_PICTURE_BYTES);
+    let gain_mode = options
+        .replay_gain
+        .or(profile.replay_gain)
+        .unwrap_or(GainMode::Album);
+
+    // Normalize tags
+    info!("Normalizing tags ...");
+    let original_tags = tags.clone();
+    let mut unknown_genres = Vec::new();
+    for tag in &mut tags {
+        if let Some(ref genre) = tag.genre {
+            let (normalized, known) = config.normalize_genre(genre);
+            if !known && !unknown_genres.contains(&normalized) {
+                warning!(genre: "Unknown genre \"{normalized}\"!");
+                unknown_genres.push(normalized.clone());
+            }
+            tag.genre = Some(normalized);
+        }
+    }
+    let feat = options.feat.or(config.feat);
+    let feat_separator = options
+        .feat_separator
+        .as_ref()
+        .or(config.feat_separator.as_ref());
+    if feat.is_some() || feat_separator.is_some() {
+        let separator = feat_separator.map(|s| s.as_str()).unwrap_or("feat.");
+        for tag in &mut tags {
+            normalize::featured_artists(&mut tag.title, &mut tag.artist, feat, separator);
+        }
+    }
+    if let Some(style) = options.typography.or(config.typography) {
+        for tag in &mut tags {
+            for value in tag.text_fields_mut() {
+                *value = normalize::typography(value, style);
+            }
+        }
+    }
+    for (before, after) in original_tags.iter().zip(&tags) {
+        let track = after.id();
+        let old_fields = before.text_fields();
+        let new_fields = after.text_fields();
+        for (field, old) in &old_fields {
+            match new_fields.iter().find(|(f, _)| f == field) {
+                Some((_, new)) if new != old => {
+                    info!(track = track; "{field}: \"{old}\" → \"{new}\"")
+                }
+                None => info!(track = track; "{field}: \"{old}\" → (removed)"),
+                _ => (),
+            }
+        }
+        for (field, new) in &new_fields {
+            if !old_fields.iter().any(|(f, _)| f == field) {
+                info!(track = track; "{field}: (none) → \"{new}\"");
+            }
+        }
+    }
+    let problems = tag_problems(
+        &tags,
+        options
+            .max_tag_length
+            .or(config.max_tag_length)
+            .unwrap_or(MAX_TAG_LENGTH),
+        &config.field_max_lengths,
+    );
+    if !problems.is_empty() {
+        return Err(ReflacError::InvalidTagValues(problems));
+    }
+    // Album directory
+    let album_name = get_album_name(&tags);
+    let Some(album) = album_name.cloned() else {
+        return Err(ReflacError::MissingField("ALBUM"));
+    };
+    let naming = options.naming.or(config.naming).unwrap_or(Naming::Standard);
+    if naming == Naming::Classical {
+        number_movements(&mut tags);
+    }
+    let album_dir = match (naming, get_album_composer(&tags)) {
+        (Naming::Classical, Some(composer)) => format!("{composer} - {album}"),
+        _ => album.clone(),
+    };
+    let album_path = output_dir.join(sanitize_file_name(&album_dir, ""));
+    info!("Writing album to {} ...", album_path.display());
+    let album_lock = match options.dry_run {
+        true => None,
+        false => Some(lock::AlbumLock::acquire(&album_path)?),
+    };
+    // A run that stopped after encoding left a checkpoint to go on from
+    let trackinfo_sha256 = hash::hex(&hash::Sha256::new().update(&trackinfo).finish());
+    let checkpoint = match options.resume {
+        true => checkpoint::Checkpoint::load(&album_path)?,
+        false => None,
+    };
+    if checkpoint
+        .as_ref()
+        .is_some_and(|c| c.trackinfo_sha256 != trackinfo_sha256)
+    {
+        return Err(ReflacError::CheckpointMismatch(album_path));
+    }
+    let resumed = checkpoint.is_some();
+    if !options.append && !resumed && album_path.join(checkpoint::FILE_NAME).exists() {
+        return Err(ReflacError::UnfinishedAlbum(album_path));
+    }
+    if !options.append && !resumed && album_path.exists() {
+        return Err(ReflacError::CreateDirFailed(
+            album_path,
+            std::io::ErrorKind::AlreadyExists.into(),
+        ));
+    }
+
+    // Tracks already in the album when appending, as (path, disc, number,
+    // side)
+    let mut existing = Vec::new();
+    if options.append {
+        if !album_path.is_dir() {
+            return Err(ReflacError::PathDoesNotExist(album_path));
+        }
+        for path in flac::album_files(&album_path)? {
+            let meta = flac::read_metadata(&path)?;
+            let side = meta.first("SIDE").map(String::from);
+            existing.push((
+                path,
+                meta.number("DISCNUMBER"),
+                meta.number("TRACKNUMBER"),
+                side,
+            ));
+        }
+        for tag in &tags {
+            if existing
+                .iter()
+                .any(|(_, disc, number, _)| *disc == tag.disc && *number == tag.track)
+            {
+                return Err(ReflacError::TrackExists(tag.id()));
+            }
+        }
+    }
+
+    // Track totals (a track 0 is a hidden or pregap track and not counted)
+    let per_side = options.side_numbering;
+    let count = |disc: Option<usize>, side: Option<&str>| {
+        tags.iter()
+            .filter(|o| o.disc == disc && o.track != Some(0))
+            .filter(|o| !per_side || o.side() == side)
+            .count()
+            + existing
+                .iter()
+                .filter(|(_, d, number, _)| *d == disc && *number != Some(0))
+                .filter(|(_, _, _, s)| !per_side || s.as_deref() == side)
+                .count()
+    };
+    let totals: Vec<_> = tags.iter().map(|t| count(t.disc, t.side())).collect();
+    let existing_totals: Vec<_> = existing
+        .iter()
+        .map(|(_, disc, _, side)| count(*disc, side.as_deref()))
+        .collect();
+    // Disc totals count the discs of tracks already in the album as well
+    let discs: Vec<Option<usize>> = tags
+        .iter()
+        .map(|t| t.disc)
+        .chain(existing.iter().map(|(_, disc, ..)| *disc))
+        .collect();
+    if discs.contains(&None) && discs.iter().any(Option::is_some) {
+        return Err(ReflacError::MixedDiscNumbers);
+    }
+    let disc_total = discs.into_iter().flatten().max();
+    for (tag, total) in tags.iter_mut().zip(totals) {
+        tag.track_total = Some(total);
+        tag.disc_total = disc_total;
+        if per_side {
+            tag.number = tag.side_position();
+        }
+        if options.side_tag
+            && let Some(side) = tag.side()
+        {
+            tag.extra.push((String::from("SIDE"), side.to_string()));
+        }
+    }
+
+    // Padding
+    let padding = tags
+        .iter()
+        .map(|t| t.track.unwrap())
+        .max()
+        .unwrap()
+        .to_string()
+        .len()
+        .max(options.pad_width);
+
+    let file_template = options
+        .file_template
+        .as_deref()
+        .or(config.file_template.as_deref());
+    let disc_template = options
+        .disc_template
+        .as_deref()
+        .or(config.disc_template.as_deref());
+    // An empty disc template is the older way to ask for a flat layout
+    let disc_layout = options
+        .disc_layout
+        .or(config.disc_layout)
+        .unwrap_or(match disc_template {
+            Some("") => DiscLayout::Flat,
+            _ => DiscLayout::Folders,
+        });
+    let disc_template = match disc_layout {
+        DiscLayout::Flat => Some(""),
+        DiscLayout::Folders => disc_template.filter(|t| !t.is_empty()),
+    };
+    let mut file_names: Vec<PathBuf> = tags
+        .iter()
+        .map(|t| t.output_path(padding, file_template, disc_template, naming))
+        .collect();
+    if !options.single_file {
+        let taken: Vec<PathBuf> = existing
+            .iter()
+            .filter_map(|(path, ..)| path.strip_prefix(&album_path).ok())
+            .map(Path::to_path_buf)
+            .collect();
+        let on_collision = options
+            .on_collision
+            .or(config.on_collision)
+            .unwrap_or(Collisions::Fail);
+        resolve_collisions(&tags, &mut file_names, &taken, on_collision)?;
+    }
+
+    // Keep writes away from the sources
+    if options.read_only_sources {
+        let temp_parent = options
+            .temp_dir
+            .clone()
+            .or(config.temp_dir.clone())
+            .unwrap_or_else(env::temp_dir);
+        let targets: Vec<_> = [&output_dir, &temp_parent]
+            .into_iter()
+            .filter_map(|p| fs::canonicalize(p).ok())
+            .collect();
+        for input in tags
+            .iter()
+            .filter_map(|t| t.input.as_ref())
+            .flat_map(|input| input.split('|'))
+        {
+            let source = trackinfo_parent.join(input.trim());
+            let Some(root) = source.ancestors().find(|p| p.is_dir()) else {
+                continue;
+            };
+            let root = fs::canonicalize(root)?;
+            if let Some(target) = targets.iter().find(|t| t.starts_with(&root)) {
+                return Err(ReflacError::WriteInsideSource(target.clone(), root));
+            }
+        }
+    }
+
+    let disc_template = disc_template.map(String::from);
+    Ok(Plan {
+        started,
+        trackinfo_path: trackinfo_path.to_path_buf(),
+        trackinfo_parent: trackinfo_parent.to_path_buf(),
+        config,
+        output_dir,
+        environment,
+        process_cnt,
+        tags,
+        settings,
+        cover_max_size,
+        gain_mode,
+        album,
+        album_path,
+        album_lock,
+        trackinfo_sha256,
+        checkpoint,
+        resumed,
+        existing,
+        existing_totals,
+        disc_template,
+        file_names,
+    })
+}
+
+/// Opens the inputs, maps and encodes the tracks of `plan` and finishes the
+/// album. A dry run stops after mapping.
+fn execute(plan: Plan, options: &Options, report: &mut Report) -> Result<()> {
+    let Plan {
+        started,
+        trackinfo_path,
+        trackinfo_parent,
+        config,
+        output_dir,
+        environment,
+        process_cnt,
+        mut tags,
+        settings,
+        cover_max_size,
+        gain_mode,
+        album,
+        album_path,
+        album_lock: _album_lock,
+        trackinfo_sha256,
+        checkpoint,
+        resumed,
+        existing,
+        existing_totals,
+        disc_template,
+        file_names,
+    } = plan;
+    let disc_template = disc_template.as_deref();
+
+    // Work directory
+    // Extracted archives and covers live here. Encoded files are written
+    // straight into the album directory, unless --max-write-MBps stages them
+    // here to be moved into the album at the limited rate.
+    let mut work_dir = match options.temp_dir.as_ref().or(config.temp_dir.as_ref()) {
+        Some(dir) => TempDir::new_in(dir, "reflac")?,
+        None => TempDir::new("reflac")?,
+    };
+    if options.keep_temp {
+        work_dir.keep();
+    }
+
+    // Resolve inputs
+    let mut ctx = Extraction {
+        tmp_dir: &work_dir,
+        sandbox: options.sandbox.or(config.sandbox).unwrap_or(Sandbox::None),
+        par2: options.par2,
+        par2_repair: !options.read_only_sources,
+        verify_signatures: options.verify_signatures,
+        keyring: options.keyring.clone().or(config.keyring.clone()),
+        cache: (options.cache || config.cache).then(|| {
+            cache::Cache::new(
+                options
+                    .cache_dir
+                    .clone()
+                    .or(config.cache_dir.clone())
+                    .unwrap_or_else(cache::Cache::default_dir),
+                config.cache_max_size.unwrap_or(cache::DEFAULT_MAX_SIZE),
+            )
+        }),
+        report,
+    };
+    let mut inputs: HashMap<String, (PathBuf, Vec<Source>)> = HashMap::new();
+    let mut failed_inputs = HashSet::new();
+    let mut input_map_roots: HashMap<usize, PathBuf> = HashMap::new();
+    let mut input_map_flacs: HashMap<usize, Vec<Source>> = HashMap::new();
+    let mut chosen_inputs: HashMap<usize, String> = HashMap::new();
+    let source_policy = options
+        .source_policy
+        .as_deref()
+        .or(config.source_policy.as_deref())
+        .unwrap_or(quality::DEFAULT_POLICY);
+    for tag in &tags {
+        let track = tag.track.unwrap();
+        let Some(ref input) = tag.input else {
+            return Err(ReflacError::MissingInput(track));
+        };
+        // Alternatives are tried in order until one opens and has the track,
+        // or all are compared with --best-source
+        let alternatives: Vec<&str> = input.split('|').map(str::trim).collect();
+        let mut candidates = Vec::new();
+        for (i, &alternative) in alternatives.iter().enumerate() {
+            let last = i + 1 == alternatives.len();
+            if !inputs.contains_key(alternative) && !failed_inputs.contains(alternative) {
+                info!("Opening input \"{alternative}\" ...");
+                match open_input(&trackinfo_parent.join(alternative), options, &mut ctx) {
+                    Ok(opened) => {
+                        inputs.insert(alternative.to_string(), opened);
+                    }
+                    Err(err) if !last || !candidates.is_empty() => {
+                        warning!(fallback: "  {err}");
+                        failed_inputs.insert(alternative.to_string());
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            let Some((_, sources)) = inputs.get(alternative) else {
+                continue;
+            };
+            if let Ok(source) = get_track(tag, sources) {
+                candidates.push((alternative, source));
+                if !options.best_source {
+                    break;
+                }
+            }
+        }
+        let chosen = match candidates[..] {
+            [] => return Err(ReflacError::InputTrackNotFound(track)),
+            [(alternative, _)] => {
+                if alternative != alternatives[0] {
+                    warning!(
+                        fallback: track = tag.id();
+                        ": falling back to \"{alternative}\""
+                    );
+                }
+                alternative
+            }
+            _ => best_source(tag, &candidates, source_policy)?,
+        };
+        let (root_path, sources) = &inputs[chosen];
+        input_map_roots.insert(track, root_path.clone());
+        input_map_flacs.insert(track, sources.clone());
+        chosen_inputs.insert(track, chosen.to_string());
+    }
+    let sandbox = ctx.sandbox;
+    // Record the inputs that won
+    for tag in &mut tags {
+        tag.input = chosen_inputs.remove(&tag.track.unwrap());
+    }
 
     // Map input tracks
-    println!("Mapping tracks ...");
+    info!("Mapping tracks ...");
     let mut source_map = HashMap::new();
+    let mut confidence = HashMap::new();
+    let mut low_confidence = None;
+    let mut review = String::new();
+    let min_confidence = options.min_confidence.unwrap_or(0);
     for tag in &tags {
         let track = tag.track.unwrap();
-        let path = get_track(track, &input_map_flacs[&track])?;
-        println!(
-            "  #{track} ← \"{}\"",
-            path.file_name().unwrap().to_str().unwrap()
+        let source = get_track(tag, &input_map_flacs[&track])?;
+        let score = mapping_confidence(tag, &source);
+        if score < REVIEW_CONFIDENCE.max(min_confidence) {
+            warning!(
+                matching: track = tag.id();
+                "← \"{}\" ({score}% sure)",
+                source.name()
+            );
+            review.push_str(&format!(
+                "#{} ({score}%): {} ← {}\n",
+                tag.id(),
+                tag.title.as_deref().unwrap_or(""),
+                source.display()
+            ));
+        } else {
+            info!(track = tag.id(); "← \"{}\"", source.name());
+        }
+        if score < min_confidence {
+            low_confidence.get_or_insert((tag.id(), score));
+        }
+        confidence.insert(track, score);
+        source_map.insert(track, source);
+    }
+    if let Some(ref path) = options.review_path {
+        fs::write(path, &review)?;
+    }
+    if let Some((track, score)) = low_confidence {
+        return Err(ReflacError::LowConfidence(track, score, min_confidence));
+    }
+    if options.dry_run {
+        for (tag, file_name) in tags.iter().zip(&file_names) {
+            let mut plan = match options.single_file {
+                true => String::from("tags:"),
+                false => format!("→ \"{}\"", album_path.join(file_name).display()),
+            };
+            for comment in vorbis_comments(tag) {
+                plan.push('\n');
+                plan.push_str(&comment);
+            }
+            info!(track = tag.id(); "{plan}");
+        }
+        info!("Dry run, not encoding.");
+        return Ok(());
+    }
+
+    // Rather fail now than after encoding everything
+    let has_metaflac = flac::metaflac_installed();
+    if !has_metaflac {
+        if gain_mode != GainMode::Off {
+            return Err(ReflacError::MissingProgram(
+                "metaflac",
+                "ReplayGain is added with it; pass --replaygain=off to skip ReplayGain",
+            ));
+        }
+        if options.append || options.only_if_smaller {
+            return Err(ReflacError::MissingProgram(
+                "metaflac",
+                "--append and --only-if-smaller retag files with it",
+            ));
+        }
+        warning!(tools: "metaflac is not installed, every track is encoded");
+    }
+
+    // Check the encoder can handle the sources and which it has encoded
+    // already; streamed archive members are only seen by the encoder
+    let version = flac::encoder_version();
+    let mut lax_tracks = HashSet::new();
+    let mut kept_tracks = HashSet::new();
+    let mut md5s = HashMap::new();
+    let mut durations = HashMap::new();
+    let mut ratios = HashMap::new();
+    let mut foreign_tracks = HashMap::new();
+    let mut channel_masks = HashMap::new();
+    let mut formats = HashMap::new();
+    for (&track, source) in &source_map {
+        let Source::File(path) = source else {
+            continue;
+        };
+        let Ok(meta) = flac::read_metadata(path) else {
+            continue;
+        };
+        if let (Some(version), Some((required, feature))) =
+            (version, meta.stream.required_version())
+            && version < required
+        {
+            return Err(ReflacError::EncoderTooOld(
+                version,
+                feature,
+                path.clone(),
+                required,
+            ));
+        }
+        if !meta.stream.is_subset() {
+            lax_tracks.insert(track);
+        }
+        formats.insert(
+            track,
+            (meta.stream.sample_rate, meta.stream.bits_per_sample),
         );
-        source_map.insert(track, path);
+        if let Some(mask) = meta.first("WAVEFORMATEXTENSIBLE_CHANNEL_MASK") {
+            channel_masks.insert(track, mask.to_string());
+        }
+        if let Some(extension) = meta.foreign {
+            if !options.keep_foreign_metadata {
+                warning!(
+                    metadata: "{} carries {} chunks that are dropped, pass --keep-foreign-metadata to keep them",
+                    path.display(),
+                    extension.to_uppercase()
+                );
+            } else if options.salvage {
+                warning!(metadata: "{}: --salvage drops its foreign metadata", path.display());
+            } else {
+                foreign_tracks.insert(track, extension);
+            }
+        }
+        let pcm_size = meta.stream.total_samples
+            * meta.stream.channels as u64
+            * (meta.stream.bits_per_sample as u64).div_ceil(8);
+        if pcm_size > 0 {
+            ratios.insert(track, source.size() as f64 / pcm_size as f64);
+        }
+        if meta.stream.sample_rate > 0 {
+            durations.insert(
+                track,
+                Duration::from_secs_f64(
+                    meta.stream.total_samples as f64 / meta.stream.sample_rate as f64,
+                ),
+            );
+        }
+        // Salvaging needs the decoder's log
+        // Reused encodings are retagged with metaflac
+        if !options.force_reencode
+            && !options.salvage
+            && has_metaflac
+            && already_encoded(&meta, &settings, lax_tracks.contains(&track))
+        {
+            kept_tracks.insert(track);
+        }
+        if !options.encode_duplicates
+            && !options.salvage
+            && has_metaflac
+            && meta.stream.md5 != [0; 16]
+        {
+            md5s.insert(track, meta.stream.md5);
+        }
+    }
+
+    // Mixed formats within an album usually mean tracks from different
+    // rips or masterings
+    let mut album_formats: Vec<((u32, u8), Vec<String>)> = Vec::new();
+    for tag in &tags {
+        let Some(&format) = formats.get(&tag.track.unwrap()) else {
+            continue;
+        };
+        match album_formats.iter_mut().find(|(f, _)| *f == format) {
+            Some((_, tracks)) => tracks.push(tag.id()),
+            None => album_formats.push((format, vec![tag.id()])),
+        }
+    }
+    if album_formats.len() > 1 {
+        let described: Vec<String> = album_formats
+            .iter()
+            .map(|((rate, bits), tracks)| format!("{rate} Hz/{bits} bit (#{})", tracks.join(", #")))
+            .collect();
+        if options.require_uniform_format {
+            return Err(ReflacError::MixedFormats(described));
+        }
+        warning!(
+            format: "Tracks of the album differ in format: {}",
+            described.join(", ")
+        );
+    }
+
+    // Speaker layouts other than the default survive retagging
+    for tag in &mut tags {
+        if let Some(mask) = channel_masks.get(&tag.track.unwrap())
+            && !tag
+                .extra
+                .iter()
+                .any(|(field, _)| field == "WAVEFORMATEXTENSIBLE_CHANNEL_MASK")
+        {
+            tag.extra.push((
+                String::from("WAVEFORMATEXTENSIBLE_CHANNEL_MASK"),
+                mask.clone(),
+            ));
+        }
+    }
+
+    // Check free space (the output is about as large as the sources)
+    let needed: u64 = source_map.values().map(Source::size).sum();
+    match free_space(&output_dir) {
+        _ if resumed => (),
+        Ok(available) if available < needed => {
+            return Err(ReflacError::InsufficientSpace(
+                output_dir, needed, available,
+            ));
+        }
+        Ok(_) => (),
+        Err(err) => warning!(disk_space: "Could not check free space: {err}"),
     }
 
     // Locate covers
     let mut covers: HashMap<&String, PathBuf> = HashMap::new();
+    let mut chosen_covers: HashMap<&PathBuf, PathBuf> = HashMap::new();
     let mut cover_map: HashMap<usize, PathBuf> = HashMap::new();
+    // Booklets the covers were rendered from go into the album as well
+    let mut booklets: Vec<PathBuf> = Vec::new();
     for tag in &tags {
         let track = tag.track.unwrap();
         if let Some(ref cover) = tag.cover {
-            if let Some(path) = covers.get(cover) {
+            if cover == "auto" {
+                let root = &input_map_roots[&track];
+                if !chosen_covers.contains_key(root) {
+                    let path = match art::choose(root, cover_max_size, options.interactive) {
+                        Err(ReflacError::NoPictureFound(dir)) => match art::booklet(root)? {
+                            Some(pdf) => {
+                                info!("  Cover: first page of {}", pdf.display());
+                                booklets.push(pdf.clone());
+                                art::render_booklet(&pdf, &work_dir.unique_subdir()?)?
+                            }
+                            None => return Err(ReflacError::NoPictureFound(dir)),
+                        },
+                        result => result?,
+                    };
+                    report.covers.push(path.clone());
+                    chosen_covers.insert(root, path);
+                }
+                cover_map.insert(track, chosen_covers[root].clone());
+            } else if let Some(path) = covers.get(cover) {
                 cover_map.insert(track, path.clone());
             } else {
-                let path = get_cover(input_map_roots[&track].join(cover), &work_dir)?;
+                let source = input_map_roots[&track].join(cover);
+                let path = get_cover(&source, &work_dir)?;
+                if path != source && cover.to_lowercase().ends_with(".pdf") {
+                    booklets.push(source);
+                }
                 cover_map.insert(track, path.clone());
                 covers.insert(cover, path);
             }
         }
     }
 
-    // Padding
-    let padding = tags
-        .iter()
-        .map(|t| t.track.unwrap())
-        .max()
-        .unwrap()
-        .to_string()
-        .len();
+    // Placeholders, such as a provider's "no image" graphic, show up on
+    // many albums
+    let mut cover_hashes: HashMap<PathBuf, String> = HashMap::new();
+    for path in cover_map.values() {
+        if !cover_hashes.contains_key(path) {
+            let digest = hash::Sha256::new().update(&fs::read(path)?).finish();
+            cover_hashes.insert(path.clone(), hash::hex(&digest));
+        }
+    }
+    cover_hashes.retain(|path, digest| {
+        let blocked = config.cover_blocklist.contains(digest);
+        if blocked {
+            warning!(cover: "Not embedding {}, it is in COVER_BLOCKLIST", path.display());
+        }
+        !blocked
+    });
+    cover_map.retain(|_, path| cover_hashes.contains_key(path));
+    if !cover_hashes.is_empty() {
+        match provenance::cover_uses(&output_dir) {
+            Ok(uses) => {
+                for (path, digest) in &cover_hashes {
+                    let count = uses.get(digest).copied().unwrap_or(0);
+                    if count >= SHARED_COVER_ALBUMS {
+                        warning!(
+                            cover: "{} is already the cover of {count} albums, it may be a placeholder \
+                             (COVER_BLOCKLIST={digest} skips it)",
+                            path.display()
+                        );
+                    }
+                }
+            }
+            Err(err) => warning!(cover: "Could not look for duplicate covers: {err}"),
+        }
+    }
 
     // Create album directory
-    let album_path;
-    let album_name = get_album_name(&tags);
-    if let Some(album) = album_name {
-        album_path = output_dir.join(album.replace("/", "_"));
-    } else {
-        todo!("Proper error handling");
+    report.album = Some(album);
+    report.output = Some(album_path.clone());
+    if !options.append && !resumed {
+        fs::create_dir(&album_path)?;
+    }
+    for pdf in &booklets {
+        let out_path = album_path.join(pdf.file_name().unwrap());
+        if !out_path.exists() {
+            info!("Copying booklet {} ...", pdf.display());
+            throttle::copy(pdf, &out_path)?;
+        }
     }
-    fs::create_dir(&album_path)?;
     let mut discs = Vec::new();
     for tag in &tags {
-        if let Some(disc) = tag.disc {
-            if !discs.contains(&disc) {
-                fs::create_dir(album_path.join(format!("Disc {disc}")))?;
-                discs.push(disc);
-            }
+        if let Some(dir) = tag.disc_dir(disc_template)
+            && !discs.contains(&dir)
+            && !album_path.join(&dir).is_dir()
+        {
+            fs::create_dir(album_path.join(&dir))?;
+            discs.push(dir);
         }
     }
 
     // Recompress
-    println!("Recompressing ...");
+    if resumed {
+        info!("Resuming after encoding ...");
+    } else {
+        info!("Recompressing ...");
+    }
     let mut out_paths = Vec::new();
-    let process_cnt = std::thread::available_parallelism()?.get();
-    let mut process_next = VecDeque::from(tags);
-    let mut process_working = VecDeque::with_capacity(process_cnt);
-    for _ in 0..(std::cmp::min(process_next.len(), process_cnt) - 1) {
-        let job = process_next.pop_front().unwrap();
-        let out_path = album_path.join(job.output_path(padding));
+    let mut encoded = Vec::new();
+
+    // Estimate from the audio that is actually encoded, at the throughput of
+    // earlier runs with the same job count and settings
+    let mut calibration = estimate::Calibration::load(estimate::Calibration::default_path());
+    let setup = format!("{process_cnt} {}", settings.join(" "));
+    let mut seen = HashSet::new();
+    let to_encode: Vec<_> = tags
+        .iter()
+        .map(|tag| tag.track.unwrap())
+        .filter(|_| !resumed)
+        .filter(|track| !kept_tracks.contains(track))
+        .filter(|track| md5s.get(track).is_none_or(|md5| seen.insert(*md5)))
+        .collect();
+    let audio: Duration = to_encode.iter().filter_map(|t| durations.get(t)).sum();
+    // Streamed archive members have no known duration
+    let fully_known = to_encode.iter().all(|t| durations.contains_key(t));
+    match calibration.estimate(&setup, audio) {
+        Some(estimate) if !to_encode.is_empty() => info!(
+            "  estimated {}{}",
+            estimate::format(estimate),
+            if fully_known { "" } else { " (or more)" }
+        ),
+        _ => {}
+    }
+
+    // Exhaustive search is not worth it for every track
+    let mut fast_tracks = HashSet::new();
+    if options.adaptive || config.adaptive {
+        let budget = options.max_track_time.or(config.max_track_time);
+        // Jobs run side by side, so each takes about that many times its share
+        let parallel = process_cnt.min(to_encode.len()).max(1) as u32;
+        for tag in tags
+            .iter()
+            .filter(|t| to_encode.contains(&t.track.unwrap()))
+        {
+            let track = tag.track.unwrap();
+            let Some(&duration) = durations.get(&track) else {
+                continue;
+            };
+            let predicted = calibration.estimate(&setup, duration).map(|d| d * parallel);
+            if estimate::prefers_fast(duration, ratios.get(&track).copied(), predicted, budget) {
+                info!(
+                    track = tag.id();
+                    "is costly to search, using a faster preset"
+                );
+                fast_tracks.insert(track);
+            }
+        }
+    }
+    let fast_settings: Vec<String> = estimate::FAST_SETTINGS
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let settings_of = |track: &usize| {
+        if fast_tracks.contains(track) {
+            &fast_settings
+        } else {
+            &settings
+        }
+    };
+    let encode_started = Instant::now();
+    let salvage_log = |track: usize| work_dir.path().join(format!("decode-{track}.log"));
+    let spawn = |job: &Tag, out_path: &Path| {
         let track = job.track.unwrap();
-        println!(
-            "  #{track} → \"{}\"",
-            out_path.file_name().unwrap().to_str().unwrap()
-        );
-        process_working.push_back(recompress(
-            &source_map[&track],
-            &out_path,
-            &job,
-            cover_map.get(&track),
-        )?);
+        progress::begin(&job.id(), durations.get(&track).copied());
+        // Decoded up front: flac keeps foreign metadata only between files
+        if let (Some(extension), Source::File(path)) =
+            (foreign_tracks.get(&track), &source_map[&track])
+        {
+            let decoded = work_dir.path().join(format!("foreign-{track}.{extension}"));
+            return run_command(
+                Command::new("flac")
+                    .args(["--silent", "--decode", "--force", "--keep-foreign-metadata"])
+                    .arg(format!("--output-name={}", decoded.display()))
+                    .arg(path),
+                "flac",
+            )
+            .and_then(|()| {
+                recompress_file(
+                    &decoded,
+                    out_path,
+                    job,
+                    cover_map.get(&track),
+                    settings_of(&track),
+                    lax_tracks.contains(&track),
+                )
+            })
+            .map(jobs::Process::from)
+            .map_err(|err| err.in_track(job.id()));
+        }
+        let log = options.salvage.then(|| salvage_log(track));
+        source_map[&track]
+            .decode(sandbox, work_dir.path(), log.as_deref())
+            .and_then(|decoded| {
+                let lax = lax_tracks.contains(&track);
+                recompress(
+                    decoded,
+                    out_path,
+                    job,
+                    cover_map.get(&track),
+                    settings_of(&track),
+                    lax,
+                )
+            })
+            .map_err(|err| err.in_track(job.id()))
+    };
+    // With a write limit encoders write to the work directory, and their
+    // output is moved into the album at the limited rate
+    let staged = |index: usize, out_path: &Path| {
+        if throttle::is_limited() {
+            work_dir.path().join(format!("encoded-{index}.flac"))
+        } else {
+            out_path.to_path_buf()
+        }
+    };
+    let progress_bars = (!to_encode.is_empty()).then(|| progress::start(audio));
+    // Jobs are identified by their index and attempt
+    let mut encoders = jobs::Pool::new(process_cnt, jobs::policy().stall);
+    let mut first_encoded: HashMap<[u8; 16], usize> = HashMap::new();
+    let mut duplicates = Vec::new();
+    let finish = |encoders: &mut jobs::Pool<(usize, u32)>,
+                  mut finished: jobs::Finished<(usize, u32)>,
+                  encoded: &[Tag],
+                  out_paths: &[PathBuf]|
+     -> Result<()> {
+        let (index, attempt) = finished.job;
+        let staged_path = staged(index, &out_paths[index]);
+        let Some(err) = encode_error(&mut finished) else {
+            progress::end(&encoded[index].id());
+            if staged_path != out_paths[index] {
+                throttle::move_file(&staged_path, &out_paths[index])?;
+            }
+            return Ok(());
+        };
+        let track = encoded[index].id();
+        if !jobs::policy().should_retry(attempt, finished.stalled) {
+            return Err(err.in_track(track));
+        }
+        warning!(retry: track = track; ": {err}, retrying ...");
+        jobs::retry("flac", Some(track), attempt, err.to_string());
+        // flac refuses to overwrite the partial output
+        if staged_path.exists() {
+            fs::remove_file(&staged_path)?;
+        }
+        let encoder = spawn(&encoded[index], &staged_path)?;
+        encoders.push((index, attempt + 1), encoder, Some(staged_path));
+        Ok(())
+    };
+    for (job, file_name) in tags.into_iter().zip(file_names) {
+        // A retry takes the slot back, so waiting may take more than one round
+        while encoders.is_full()
+            && let Some(finished) = encoders.wait_any()?
+        {
+            finish(&mut encoders, finished, &encoded, &out_paths)?;
+        }
+        let out_path = album_path.join(file_name);
+        let track = job.track.unwrap();
+        if !resumed {
+            info!(
+                track = job.id();
+                "→ \"{}\"",
+                out_path.file_name().unwrap().to_str().unwrap()
+            );
+        }
+        if let Some(ref hook) = options.pre_track
+            && !resumed
+        {
+            run_hook(hook, "pre-track", &job, &source_map[&track], &out_path)?;
+        }
+        // Identical audio (by the MD5 in STREAMINFO) is encoded only once
+        let duplicate_of = md5s.get(&track).and_then(|md5| first_encoded.get(md5));
+        if resumed {
+            // Done by the run that left the checkpoint
+        } else if kept_tracks.contains(&track) {
+            info!(track = job.id(); "is already optimal, keeping source");
+            source_map[&track].copy_to(&out_path, sandbox, work_dir.path())?;
+            retag_encoded(
+                &out_path,
+                &job,
+                cover_map.get(&track),
+                &settings,
+                &lax_tracks,
+            )?;
+        } else if let Some(&first) = duplicate_of {
+            info!(
+                track = job.id();
+                "has the same audio as #{}, reusing its encoding",
+                encoded[first].id()
+            );
+            duplicates.push((encoded.len(), first));
+        } else {
+            if let Some(&md5) = md5s.get(&track) {
+                first_encoded.insert(md5, encoded.len());
+            }
+            let staged_path = staged(encoded.len(), &out_path);
+            let encoder = spawn(&job, &staged_path)?;
+            encoders.push((encoded.len(), 0), encoder, Some(staged_path));
+        }
+        report.tracks.push(TrackReport {
+            track: job.id(),
+            input: job.input.clone().unwrap(),
+            source: source_map[&track].display(),
+            output: out_path.clone(),
+            confidence: confidence[&track],
+            bad_frames: None,
+            replay_gain: None,
+        });
         out_paths.push(out_path);
+        encoded.push(job);
     }
-    while let Some(job) = process_next.pop_front() {
-        let out_path = album_path.join(job.output_path(padding));
-        let track = job.track.unwrap();
-        println!(
-            "  #{track} → \"{}\"",
-            out_path.file_name().unwrap().to_str().unwrap()
-        );
-        process_working.push_back(recompress(
-            &source_map[&track],
-            &out_path,
-            &job,
+    while let Some(finished) = encoders.wait_any()? {
+        finish(&mut encoders, finished, &encoded, &out_paths)?;
+    }
+    drop(progress_bars);
+    // Mixed presets would skew the measurement
+    if fully_known && !to_encode.is_empty() && fast_tracks.is_empty() {
+        calibration.record(&setup, audio, encode_started.elapsed());
+        if let Err(err) = calibration.save() {
+            warning!(calibration: "Could not save the encoding time calibration: {err}");
+        }
+    }
+    for &(index, first) in &duplicates {
+        throttle::copy(&out_paths[first], &out_paths[index])?;
+        let track = encoded[index].track.unwrap();
+        retag_encoded(
+            &out_paths[index],
+            &encoded[index],
             cover_map.get(&track),
-        )?);
-        out_paths.push(out_path);
+            settings_of(&encoded[first].track.unwrap()),
+            &lax_tracks,
+        )?;
+    }
+
+    // Mark tracks decoded from damaged sources
+    let mut bad_frames = HashMap::new();
+    if options.salvage && !resumed {
+        for (i, (job, out_path)) in encoded.iter().zip(&out_paths).enumerate() {
+            let track = job.track.unwrap();
+            let count = count_bad_frames(&fs::read_to_string(salvage_log(track))?);
+            if count > 0 {
+                warning!(
+                    salvage: track = job.id();
+                    ": salvaged, {count} damaged frame(s) concealed"
+                );
+                set_tag(out_path, "REFLAC_BAD_FRAMES", &count.to_string())?;
+                report.tracks[i].bad_frames = Some(count);
+                bad_frames.insert(track, count);
+            }
+        }
+    }
+
+    // Keep sources that did not get smaller (unless they are damaged)
+    if options.only_if_smaller && !resumed {
+        for (job, out_path) in encoded.iter().zip(&out_paths) {
+            let track = job.track.unwrap();
+            let source = &source_map[&track];
+            if !bad_frames.contains_key(&track)
+                && !kept_tracks.contains(&track)
+                && fs::metadata(out_path)?.len() >= source.size()
+            {
+                info!(track = job.id(); "is already optimal, keeping source");
+                source.copy_to(out_path, sandbox, work_dir.path())?;
+                retag(out_path, job, cover_map.get(&track))?;
+            }
+        }
+    }
+
+    if let Some(ref hook) = options.post_track
+        && !resumed
+    {
+        for (job, out_path) in encoded.iter().zip(&out_paths) {
+            run_hook(
+                hook,
+                "post-track",
+                job,
+                &source_map[&job.track.unwrap()],
+                out_path,
+            )?;
+        }
+    }
 
-        if !process_working.pop_front().unwrap().wait()?.success() {
-            todo!("Proper error handling");
+    // Join discs into single files
+    if options.single_file && !resumed {
+        info!("Joining chapters ...");
+        let mut start = 0;
+        while start < encoded.len() {
+            let disc = encoded[start].disc;
+            let end = start
+                + encoded[start..]
+                    .iter()
+                    .take_while(|t| t.disc == disc)
+                    .count();
+            let album = report.album.as_ref().unwrap();
+            // Discs of a flat layout would share the file name
+            let name = match disc {
+                Some(disc) if encoded[start].disc_dir(disc_template).is_none() => {
+                    sanitize_file_name(&format!("{album} (Disc {disc})"), ".flac")
+                }
+                _ => sanitize_file_name(album, ".flac"),
+            };
+            let out_path = out_paths[start].with_file_name(name);
+            info!("  → \"{}\"", out_path.display());
+            let cover = cover_map.get(&encoded[start].track.unwrap());
+            chapters::join(
+                &out_paths[start..end],
+                &out_path,
+                cover.map(PathBuf::as_path),
+                &settings,
+                &work_dir,
+            )?;
+            out_paths[start..end].fill(out_path.clone());
+            for track in &mut report.tracks[start..end] {
+                track.output = out_path.clone();
+            }
+            start = end;
         }
     }
-    while let Some(ref mut job) = process_working.pop_front() {
-        if !job.wait()?.success() {
-            todo!("Proper error handling");
+
+    // Update totals of the tracks already in the album
+    if !existing.is_empty() {
+        info!("Updating existing tracks ...");
+        for ((path, _, number, _), total) in existing.iter().zip(&existing_totals) {
+            if *number != Some(0) {
+                set_tag(path, "TRACKTOTAL", &total.to_string())?;
+            }
         }
     }
 
+    // Checkpoint the encoded album, or take the outputs of the run that left
+    // one
+    let mut checkpoint = match checkpoint {
+        Some(checkpoint) => {
+            if checkpoint.tracks.len() != encoded.len() {
+                return Err(ReflacError::CheckpointMismatch(album_path));
+            }
+            for (i, (output, frames)) in checkpoint.tracks.iter().enumerate() {
+                out_paths[i] = output.clone();
+                report.tracks[i].output = output.clone();
+                report.tracks[i].bad_frames = *frames;
+                if let Some(count) = *frames {
+                    bad_frames.insert(encoded[i].track.unwrap(), count);
+                }
+            }
+            checkpoint
+        }
+        None => {
+            let checkpoint = checkpoint::Checkpoint {
+                stage: checkpoint::Stage::Encoded,
+                trackinfo_sha256: trackinfo_sha256.clone(),
+                tracks: encoded
+                    .iter()
+                    .zip(&out_paths)
+                    .map(|(tag, path)| (path.clone(), bad_frames.get(&tag.track.unwrap()).copied()))
+                    .collect(),
+            };
+            // Appending runs cannot be resumed
+            if !options.append {
+                checkpoint.save(&album_path)?;
+            }
+            checkpoint
+        }
+    };
+
     // Add ReplayGain
-    println!("Adding ReplayGain ...");
-    add_replay_gain(&out_paths)?;
+    let mut files = out_paths.clone();
+    // Joined tracks share their file
+    files.dedup();
+    let gain_paths: Vec<PathBuf> = files
+        .iter()
+        .cloned()
+        .chain(existing.iter().map(|(path, ..)| path.clone()))
+        .collect();
+    match gain_mode {
+        _ if checkpoint.stage >= checkpoint::Stage::Gain => {
+            info!("ReplayGain already added");
+        }
+        GainMode::Album => {
+            info!("Adding ReplayGain ...");
+            add_replay_gain(&gain_paths)?;
+        }
+        GainMode::Disc => gain::add(gain_paths, true)?,
+        GainMode::Off => info!("Skipping ReplayGain"),
+    }
+    if checkpoint.stage < checkpoint::Stage::Gain {
+        checkpoint.stage = checkpoint::Stage::Gain;
+        if !options.append {
+            checkpoint.save(&album_path)?;
+        }
+    }
+    if gain_mode != GainMode::Off {
+        for track in &mut report.tracks {
+            track.replay_gain = gain::ReplayGain::read(&track.output)?;
+        }
+    }
+    // Much louder or quieter than the rest, a track may be mastered
+    // differently or not be the one it is supposed to be
+    if let Some(threshold) = options.gain_outliers {
+        for track in &report.tracks {
+            let Some(louder) = track
+                .replay_gain
+                .as_ref()
+                .and_then(gain::ReplayGain::louder_than_album)
+            else {
+                continue;
+            };
+            if louder.abs() > threshold {
+                let (amount, comparison) = match louder > 0.0 {
+                    true => (louder, "louder"),
+                    false => (-louder, "quieter"),
+                };
+                warning!(
+                    loudness: track = track.track;
+                    "is {amount:.1} dB {comparison} than the album"
+                );
+            }
+        }
+    }
+
+    // Record provenance
+    let mut inputs: Vec<(String, Option<u64>)> = Vec::new();
+    for input in encoded.iter().filter_map(|t| t.input.as_ref()) {
+        if !inputs.iter().any(|(i, _)| i == input) {
+            let path = trackinfo_parent.join(input);
+            let size = Some(&path)
+                .filter(|p| p.is_file())
+                .and_then(|p| fs::metadata(p).ok())
+                .map(|m| m.len());
+            inputs.push((input.clone(), size));
+        }
+    }
+    if checkpoint.stage < checkpoint::Stage::Recorded {
+        Provenance {
+            trackinfo: trackinfo_path.to_path_buf(),
+            base_dir: std::path::absolute(&trackinfo_parent)?,
+            trackinfo_sha256,
+            inputs,
+            settings,
+            tracks: encoded
+                .iter()
+                .zip(&out_paths)
+                .map(|(tag, out_path)| TrackRecord {
+                    track: tag.id(),
+                    input: tag.input.clone().unwrap(),
+                    source: source_map[&tag.track.unwrap()].name().to_string(),
+                    output: out_path.clone(),
+                    bad_frames: bad_frames.get(&tag.track.unwrap()).copied(),
+                    cover: cover_map
+                        .get(&tag.track.unwrap())
+                        .map(|path| cover_hashes[path].clone()),
+                })
+                .collect(),
+            environment,
+            started,
+        }
+        .write(&album_path)?;
+        checkpoint.stage = checkpoint::Stage::Recorded;
+        if !options.append {
+            checkpoint.save(&album_path)?;
+        }
+    }
+
+    // The metadata as used, with the inputs and source files pinned, so the
+    // album can be encoded again from it alone
+    let mut completed = String::new();
+    for tag in &encoded {
+        let track = tag.track.unwrap();
+        let mut tag = tag.clone();
+        let input = trackinfo_parent.join(tag.input.as_ref().unwrap());
+        let input = fs::canonicalize(&input).unwrap_or(input);
+        tag.input = Some(input.display().to_string());
+        tag.source = Some(source_map[&track].name().to_string());
+        // A picked image is named, a rendered booklet page is picked again
+        if tag.cover.as_deref() == Some("auto")
+            && let Some(path) = cover_map.get(&track)
+            && let Ok(relative) = path.strip_prefix(&input_map_roots[&track])
+        {
+            tag.cover = Some(relative.display().to_string());
+        }
+        if !completed.is_empty() {
+            completed.push('\n');
+        }
+        completed.push_str(&tag.to_trackinfo());
+    }
+    // Appended tracks are added to the earlier ones
+    if checkpoint.stage < checkpoint::Stage::Finalized {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(album_path.join(COMPLETED_TRACKINFO))?;
+        if file.metadata()?.len() > 0 {
+            completed.insert(0, '\n');
+        }
+        std::io::Write::write_all(&mut file, completed.as_bytes())?;
+        checkpoint.stage = checkpoint::Stage::Finalized;
+        if !options.append {
+            checkpoint.save(&album_path)?;
+        }
+    }
+    checkpoint::Checkpoint::remove(&album_path)?;
+
+    // Results
+    for path in &files {
+        println!("{}", path.display());
+    }
+
+    // Warn when the library outgrows its destination
+    if let Some(quota) = config.quota {
+        match prune::albums(&output_dir) {
+            Ok(albums) => {
+                let total: u64 = albums.iter().map(|a| a.bytes).sum();
+                if total > quota {
+                    warning!(
+                        quota: "{} holds {} MiB, over its quota of {} MiB; see reflac prune-report",
+                        output_dir.display(),
+                        total.div_ceil(1 << 20),
+                        quota.div_ceil(1 << 20)
+                    );
+                }
+            }
+            Err(err) => warning!(quota: "Could not check the quota: {err}"),
+        }
+    }
 
     Ok(())
 }
 
-fn main() -> ExitCode {
-    match run() {
+fn exit_code(result: Result<()>) -> ExitCode {
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            eprintln!("ERROR: {err}");
-            eprintln!("Exiting with failure ...");
+            error!("{err}");
+            info!("Exiting with failure ...");
             ExitCode::FAILURE
         }
     }
 }
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Mode::Encode(options) => options,
+        Mode::Bench(path, threads) => return exit_code(bench::run(&path, &threads)),
+        Mode::ExportTrackinfo(album_dir, output) => {
+            return exit_code(export::run(&album_dir, output.as_deref()));
+        }
+        Mode::Lint(path) => return exit_code(lint::run(&path)),
+        Mode::DiscId(dir) => return exit_code(discid::run(&dir)),
+        Mode::Verify(album_dir) => return exit_code(verify::run(&album_dir)),
+        Mode::Ab(options) => return exit_code(ab::run(&options)),
+        Mode::PruneReport(library, quota) => return exit_code(prune::run(&library, quota)),
+        Mode::CacheClean(config_path) => {
+            return exit_code(Config::load(config_path.as_deref()).and_then(|config| {
+                let dir = config.cache_dir.unwrap_or_else(cache::Cache::default_dir);
+                cache::run_clean(&cache::Cache::new(dir, 0))
+            }));
+        }
+        Mode::Gain(album_dirs, per_disc, group) => {
+            return exit_code(gain::run(&album_dirs, per_disc, group));
+        }
+        Mode::ArtExtract(path, out) => return exit_code(art::extract(&path, out.as_deref())),
+        Mode::ArtSet(album_dir, image) => return exit_code(art::set(&album_dir, &image)),
+        Mode::Tag(album_dir, edit, dry_run) => {
+            return exit_code(edit::run(&album_dir, &edit, dry_run));
+        }
+    };
+    let mut report = Report::new();
+    let result = run(&options, &mut report);
+    report.retries = jobs::retries();
+    report.warnings = console::warnings();
+    if !report.warnings.is_empty() {
+        info!(
+            "{} {}: {}",
+            report.warnings.len(),
+            if report.warnings.len() == 1 {
+                "warning"
+            } else {
+                "warnings"
+            },
+            console::summary(&report.warnings)
+        );
+    }
+    if let Some(ref path) = options.report_path {
+        if let Err(ref err) = result {
+            report.error = Some(Failure {
+                code: err.code(),
+                message: err.to_string(),
+                context: err.context(),
+            });
+        }
+        if let Err(err) = report.write(path) {
+            error!("Could not write report: {err}");
+        }
+    }
+    exit_code(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(trackinfo: &str) -> Result<Vec<Tag>> {
+        parse_trackinfo_str(trackinfo)
+    }
+
+    fn sources(names: &[&str]) -> Vec<Source> {
+        names
+            .iter()
+            .map(|n| Source::File(PathBuf::from(format!("/in/{n}"))))
+            .collect()
+    }
+
+    #[test]
+    fn global_lines_apply_to_later_tracks() {
+        let tags = parse("ALBUM=A\nARTIST=X\nTITLE[1]=One\nTITLE[2]=Two\nARTIST[2]=Y\n").unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].album.as_deref(), Some("A"));
+        assert_eq!(tags[0].artist.as_deref(), Some("X"));
+        assert_eq!(tags[1].artist.as_deref(), Some("Y"));
+        assert_eq!(tags[1].title.as_deref(), Some("Two"));
+    }
+
+    #[test]
+    fn bad_tag_values_are_listed() {
+        let tags =
+            parse("ALBUM=A\u{7}\nTITLE[1]=One\nCOMMENT[2]=Long comment\nTITLE[2]=Two\n").unwrap();
+        let limits = HashMap::from([(String::from("TITLE"), 0)]);
+        assert_eq!(
+            tag_problems(&tags, 4, &limits),
+            [
+                "#1 ALBUM contains control character U+0007",
+                "#2 ALBUM contains control character U+0007",
+                "#2 COMMENT is 12 bytes long, the limit is 4",
+            ]
+        );
+        assert!(tag_problems(&tags[..0], 4, &limits).is_empty());
+
+        match parse_trackinfo_bytes(b"ALBUM=A\nTITLE[1]=Caf\xe9\nTITLE[2]=Two\n") {
+            Err(ReflacError::InvalidTrackinfoValue(line, reason)) => {
+                assert_eq!(line, "TITLE[1]=Caf\u{fffd}");
+                assert_eq!(reason, "not valid UTF-8");
+            }
+            _ => panic!("invalid UTF-8 accepted"),
+        }
+    }
+
+    #[test]
+    fn completed_trackinfo_parses_back() {
+        let text = "ALBUM=A\nARTIST=X\nDATE=2001-02-03\nSTYLE=Dub; Ska\nTITLE[1]=One\n\
+                    TITLE:ja[1]=一\nSOURCE[1]=01 one.flac\nDISC[1]=2\n";
+        let tags = parse(text).unwrap();
+        assert_eq!(
+            tags[0].to_trackinfo(),
+            "SOURCE[1]=01 one.flac\nTITLE[1]=One\nARTIST[1]=X\nALBUM[1]=A\nDISC[1]=2\n\
+             DATE[1]=2001-02-03\nSTYLE[1]=Dub; Ska\nTITLE:ja[1]=一\n"
+        );
+        let again = parse(&tags[0].to_trackinfo()).unwrap();
+        assert_eq!(again[0].to_trackinfo(), tags[0].to_trackinfo());
+
+        let sources = sources(&["01 one.flac", "01 - One.flac"]);
+        assert_eq!(get_track(&tags[0], &sources).unwrap().name(), "01 one.flac");
+    }
+
+    #[test]
+    fn inheritance_can_be_turned_off() {
+        let tags =
+            parse("ARTIST=VARIOUS\nALBUM=A\nTITLE[1]=One\nARTIST[1]=X\nTITLE[2]=Two\n").unwrap();
+        assert_eq!(tags[0].artist.as_deref(), Some("X"));
+        assert_eq!(tags[1].artist, None);
+        assert_eq!(tags[1].album.as_deref(), Some("A"));
+        assert!(
+            tags[1]
+                .text_fields()
+                .contains(&("COMPILATION", &String::from("1")))
+        );
+
+        let tags = parse("ARTIST=X\nGENRE=G\nNOINHERIT=ARTIST; GENRE\nTITLE[1]=One\n").unwrap();
+        assert_eq!(tags[0].artist, None);
+        assert_eq!(tags[0].genre, None);
+
+        assert!(parse("NOINHERIT=COLOR\nTITLE[1]=One\n").is_err());
+        assert!(parse("TITLE[1]=One\nARTIST[2]=VARIOUS\n").is_err());
+        assert!(parse("COMPILATION=yes\nTITLE[1]=One\n").is_err());
+    }
+
+    #[test]
+    fn dates_and_discs_are_parsed() {
+        let tags = parse("DATE=1999-12-31\nDISC=2\nTITLE[1]=One\n").unwrap();
+        assert_eq!(tags[0].date, Some([1999, 12, 31]));
+        assert_eq!(tags[0].disc, Some(2));
+        assert!(parse("DISC=two\nTITLE[1]=One\n").is_err());
+
+        // Leading zeros and stray whitespace are fine
+        let tags = parse("DATE= 2000-02-29\nDISC=02 \nTITLE[ 03 ]=Three\n").unwrap();
+        assert_eq!(tags[0].date, Some([2000, 2, 29]));
+        assert_eq!(tags[0].disc, Some(2));
+        assert_eq!(tags[0].track, Some(3));
+
+        let reason = |trackinfo| match parse(trackinfo) {
+            Err(ReflacError::InvalidTrackinfoValue(_, reason)) => reason,
+            other => panic!("{:?}", other.map(|tags| tags.len())),
+        };
+        assert_eq!(reason("DATE=2020-13-01\n"), "month must be 01 to 12");
+        assert_eq!(reason("DATE=1900-02-29\n"), "no such day in that month");
+        assert_eq!(reason("DATE=2020-04-31\n"), "no such day in that month");
+        assert_eq!(reason("DATE=20-01-01\n"), "DATE must be YYYY-MM-DD");
+        assert_eq!(reason("DISC=0\n"), "DISC must be 1 or more");
+        assert!(is_future([2999, 1, 1]));
+        assert!(!is_future([1999, 12, 31]));
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        assert!(parse("TITLE[1]=One\nNOT A LINE\n").is_err());
+        assert!(parse("UNKNOWN=x\n").is_err());
+    }
+
+    #[test]
+    fn sides_are_numbered_continuously() {
+        let tags = parse("TITLE[B1]=Baz\nTITLE[A2]=Bar\nTITLE[A1]=Foo\n").unwrap();
+        let numbers: Vec<_> = tags.iter().map(|t| (t.id(), t.track.unwrap())).collect();
+        assert_eq!(
+            numbers,
+            [
+                (String::from("B1"), 3),
+                (String::from("A2"), 2),
+                (String::from("A1"), 1)
+            ]
+        );
+        let err = parse("TITLE[A1]=Foo\nTITLE[2]=Bar\n").err().unwrap();
+        assert!(matches!(err, ReflacError::MixedTrackIdentifiers));
+    }
+
+    #[test]
+    fn subprocess_errors_keep_stderr_excerpt() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let stderr = (1..=8).map(|n| format!("line {n}\n")).collect::<String>();
+        let err = ReflacError::subprocess(
+            "flac",
+            Some(ExitStatus::from_raw(1 << 8)),
+            stderr.as_bytes(),
+        )
+        .in_track(String::from("A2"));
+        assert_eq!(err.code(), "subprocess-failed");
+        assert_eq!(
+            err.to_string(),
+            "Track A2: Failure executing: flac (exit status 1): line 8"
+        );
+        assert_eq!(
+            err.context(),
+            [
+                ("track", String::from("A2")),
+                ("command", String::from("flac")),
+                ("status", String::from("1")),
+                (
+                    "stderr",
+                    String::from("line 4\nline 5\nline 6\nline 7\nline 8")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn trackinfo_is_found_in_album_directories() {
+        let dir = TempDir::new("reflac-test").unwrap();
+        assert!(matches!(
+            find_trackinfo(dir.path()),
+            Err(ReflacError::NoTrackinfoFound(_))
+        ));
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        fs::write(dir.path().join("Album.trackinfo"), "").unwrap();
+        assert_eq!(
+            find_trackinfo(dir.path()).unwrap(),
+            dir.path().join("Album.trackinfo")
+        );
+        fs::write(dir.path().join("TrackInfo.txt"), "").unwrap();
+        let err = find_trackinfo(dir.path()).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with(": Album.trackinfo, TrackInfo.txt")
+        );
+    }
+
+    #[test]
+    fn temp_dir_creation_fails_gracefully() {
+        let err = TempDir::new_in("/nonexistent/reflac", "reflac")
+            .err()
+            .unwrap();
+        assert!(matches!(err, ReflacError::CreateDirFailed(..)));
+    }
+
+    #[test]
+    fn temp_dirs_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("reflac-test").unwrap();
+        let sub = dir.unique_subdir().unwrap();
+        let (file_path, _) = dir.unique_subfile(".flac").unwrap();
+        for path in [dir.path(), sub.as_path(), file_path.as_path()] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0, "{}", path.display());
+        }
+        assert!(file_path.to_str().unwrap().ends_with(".flac"));
+    }
+
+    #[test]
+    fn style_mood_and_grouping() {
+        let tags = parse(
+            "STYLE=Shoegaze; Dream Pop\nMOOD=Calm\nGROUPING=Side Project\n\
+             TITLE[1]=One\nMOOD[2]=Restless\nTITLE[2]=Two\n",
+        )
+        .unwrap();
+        let comments = vorbis_comments(&tags[0]);
+        for expected in [
+            "STYLE=Shoegaze",
+            "STYLE=Dream Pop",
+            "MOOD=Calm",
+            "GROUPING=Side Project",
+        ] {
+            assert!(comments.iter().any(|c| c == expected), "{comments:?}");
+        }
+        let comments = vorbis_comments(&tags[1]);
+        assert!(comments.iter().any(|c| c == "MOOD=Restless"));
+        assert!(!comments.iter().any(|c| c == "MOOD=Calm"));
+        assert!(parse("STYLE=Shoegaze;;Dream Pop\n").is_err());
+    }
+
+    #[test]
+    fn classical_naming() {
+        let mut tags = parse(
+            "COMPOSER=Johann Sebastian Bach\nCONDUCTOR=Karl Richter\n\
+             WORK[1]=Brandenburg Concerto No. 1\nOPUS[1]=BWV 1046\nMOVEMENT[1]=Allegro\n\
+             WORK[2]=Brandenburg Concerto No. 1\nMOVEMENT[2]=Adagio\n\
+             TITLE[3]=Chorale\n",
+        )
+        .unwrap();
+        number_movements(&mut tags);
+        assert_eq!(get_album_composer(&tags).unwrap(), "Johann Sebastian Bach");
+        assert_eq!(
+            tags[1].output_path(2, None, None, Naming::Classical),
+            PathBuf::from("02. Brandenburg Concerto No. 1 - II. Adagio.flac")
+        );
+        assert_eq!(
+            tags[2].output_path(2, None, None, Naming::Classical),
+            PathBuf::from("03. Chorale.flac")
+        );
+        let comments = vorbis_comments(&tags[0]);
+        for expected in [
+            "WORK=Brandenburg Concerto No. 1",
+            "MOVEMENTNAME=Allegro",
+            "MOVEMENT=1",
+            "MOVEMENTTOTAL=2",
+            "CONDUCTOR=Karl Richter",
+            "OPUS=BWV 1046",
+        ] {
+            assert!(comments.iter().any(|c| c == expected), "{comments:?}");
+        }
+        assert_eq!(tags[2].movement_number, None);
+        assert_eq!(roman(1994), "MCMXCIV");
+        assert!(parse("MOVEMENTNUMBER[1]=0\n").is_err());
+    }
+
+    #[test]
+    fn soundtrack_naming() {
+        let tags = parse(
+            "ARTIST=Tokyo Philharmonic\nCOMPOSER=Nobuo Uematsu\n\
+             TITLE[1]=Prelude\nCOMPOSER[2]=Masashi Hamauzu\nARRANGER[2]=Shiro Hamaguchi\n\
+             TITLE[2]=Blinded by Light\n",
+        )
+        .unwrap();
+        assert_eq!(
+            tags[0].output_path(2, None, None, Naming::Soundtrack),
+            PathBuf::from("01. Nobuo Uematsu - Prelude.flac")
+        );
+        assert_eq!(
+            tags[1].output_path(2, None, None, Naming::Soundtrack),
+            PathBuf::from("02. Masashi Hamauzu - Blinded by Light.flac")
+        );
+        assert_eq!(tags[1].arranger.as_deref(), Some("Shiro Hamaguchi"));
+    }
+
+    #[test]
+    fn bad_frames_are_counted() {
+        let log = "track.flac: *** Got error code 0:FLAC__STREAM_DECODER_ERROR_STATUS_LOST_SYNC\n\
+                   track.flac: *** Got error code 3:FLAC__STREAM_DECODER_ERROR_STATUS_UNPARSEABLE_STREAM\n\
+                   track.flac: done\n";
+        assert_eq!(count_bad_frames(log), 2);
+        assert_eq!(count_bad_frames(""), 0);
+    }
+
+    #[test]
+    fn mapping_confidence_compares_titles() {
+        let tags = parse("TITLE[1]=Blue Monday\nTITLE[2]=Age of Consent\n").unwrap();
+        let exact = Source::File(PathBuf::from("01 - Blue Monday.flac"));
+        assert_eq!(mapping_confidence(&tags[0], &exact), 100);
+        let half = Source::File(PathBuf::from("01 Monday Morning.flac"));
+        assert_eq!(mapping_confidence(&tags[0], &half), 50);
+        let wrong = Source::File(PathBuf::from("02 - Blue Monday.flac"));
+        assert_eq!(mapping_confidence(&tags[1], &wrong), 0);
+        let bare = Source::File(PathBuf::from("02.flac"));
+        assert_eq!(mapping_confidence(&tags[1], &bare), 50);
+    }
+
+    #[test]
+    fn title_languages() {
+        let mut tags = parse("TITLE:ja[1]=春\nTITLE:romaji[1]=Haru\n").unwrap();
+        tags[0].select_title(Some("romaji"), Some(("ja", "TITLESORT")));
+        assert_eq!(tags[0].title.as_deref(), Some("Haru"));
+        assert_eq!(
+            tags[0].extra,
+            [(String::from("TITLESORT"), String::from("春"))]
+        );
+    }
+
+    #[test]
+    fn collisions_with_appended_tracks() {
+        let tags = parse("TITLE[1]=One\nTITLE[2]=Two\n").unwrap();
+        let names = || vec![PathBuf::from("01. One.flac"), PathBuf::from("02. Two.flac")];
+        let taken = [PathBuf::from("02. TWO.flac")];
+        match resolve_collisions(&tags, &mut names(), &taken, Collisions::Fail) {
+            Err(ReflacError::OutputCollision(collisions)) => {
+                assert_eq!(collisions, ["#2 and an existing track → \"02. Two.flac\""]);
+            }
+            _ => panic!("collision not found"),
+        }
+        let mut file_names = names();
+        resolve_collisions(&tags, &mut file_names, &taken, Collisions::Suffix).unwrap();
+        assert_eq!(file_names[1], PathBuf::from("02. Two (2).flac"));
+    }
+
+    #[test]
+    fn output_paths() {
+        let mut tags = parse("ARTIST=A/B\nTITLE[3]=Song\nDISC[3]=2\n").unwrap();
+        assert_eq!(
+            tags[0].output_path(2, None, None, Naming::Standard),
+            PathBuf::from("Disc 2/03. A_B - Song.flac")
+        );
+        tags[0].artist = None;
+        assert_eq!(
+            tags[0].output_path(3, None, None, Naming::Standard),
+            PathBuf::from("Disc 2/003. Song.flac")
+        );
+        assert_eq!(
+            tags[0].output_path(
+                2,
+                Some("{disc}-{track} {title} {missing}"),
+                None,
+                Naming::Standard
+            ),
+            PathBuf::from("Disc 2/2-03 Song.flac")
+        );
+        assert_eq!(
+            tags[0].output_path(
+                1,
+                Some("{disc}-{track:02} {title}"),
+                Some(""),
+                Naming::Standard
+            ),
+            PathBuf::from("2-03 Song.flac")
+        );
+        assert_eq!(
+            tags[0].output_path(2, None, Some("CD{disc}"), Naming::Standard),
+            PathBuf::from("CD2/03. Song.flac")
+        );
+        assert_eq!(
+            tags[0].output_path(2, None, Some(""), Naming::Standard),
+            PathBuf::from("2-03. Song.flac")
+        );
+    }
+
+    #[test]
+    fn tracks_map_to_files() {
+        let tags = parse("TITLE[1]=One\nTITLE[12]=Twelve\n").unwrap();
+        let files = sources(&["cover.jpg", "01 - One.flac", "12 Twelve.flac", "2.flac"]);
+        assert_eq!(get_track(&tags[0], &files).unwrap().name(), "01 - One.flac");
+        assert_eq!(
+            get_track(&tags[1], &files).unwrap().name(),
+            "12 Twelve.flac"
+        );
+
+        let tags = parse("TITLE[3]=Three\n").unwrap();
+        assert!(get_track(&tags[0], &files).is_err());
+    }
+
+    #[test]
+    fn positions_map_before_numbers() {
+        let tags = parse("TITLE[A1]=Foo\nTITLE[B1]=Baz\n").unwrap();
+        let files = sources(&["1 - B1 Baz.flac", "2 - A1 Foo.flac"]);
+        assert_eq!(
+            get_track(&tags[0], &files).unwrap().name(),
+            "2 - A1 Foo.flac"
+        );
+        assert_eq!(
+            get_track(&tags[1], &files).unwrap().name(),
+            "1 - B1 Baz.flac"
+        );
+    }
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(sanitize_file_name("AC/DC", ""), "AC_DC");
+        assert_eq!(sanitize_file_name("..", ""), "_");
+        assert_eq!(sanitize_file_name("", ".flac"), "_.flac");
+        let long = sanitize_file_name(&"ä".repeat(200), ".flac");
+        assert!(long.len() <= 255 && long.ends_with("ä.flac"));
+    }
+
+    #[test]
+    fn huge_track_numbers_are_rejected() {
+        assert!(parse("TITLE[99999999999999999999999]=x\n").is_err());
+    }
+
+    fn assert_valid_component(name: &str) {
+        assert!(!name.is_empty() && name != "." && name != "..", "{name:?}");
+        assert!(!name.contains(['/', '\0']), "{name:?}");
+        assert!(name.len() <= 255, "{name:?}");
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parser_never_panics(text in "\\PC*") {
+            let _ = parse_trackinfo_str(&text);
+        }
+
+        #[test]
+        fn parser_never_panics_on_trackinfo_like_lines(
+            lines in proptest::collection::vec(
+                "[A-Z]{1,8}(:[a-z]{1,3})?(\\[([0-9]{1,25}|[A-Z]{1,2}[0-9]{1,3})\\])?=\\PC*",
+                0..20,
+            )
+        ) {
+            let _ = parse_trackinfo_str(&lines.join("\n"));
+        }
+
+        #[test]
+        fn output_paths_are_valid_and_unique(
+            tracks in proptest::collection::btree_map(
+                0usize..1_000_000_000,
+                ("\\PC*", proptest::option::of("\\PC*")),
+                1..20,
+            ),
+            album in "\\PC*",
+            disc in proptest::option::of(1usize..100),
+            padding in 1usize..4,
+        ) {
+            let mut trackinfo = format!("ALBUM={album}\n");
+            if let Some(disc) = disc {
+                trackinfo.push_str(&format!("DISC={disc}\n"));
+            }
+            for (track, (title, artist)) in &tracks {
+                trackinfo.push_str(&format!("TITLE[{track}]={title}\n"));
+                if let Some(artist) = artist {
+                    trackinfo.push_str(&format!("ARTIST[{track}]={artist}\n"));
+                }
+            }
+            // Values with line breaks split into other lines and may fail
+            let Ok(tags) = parse_trackinfo_str(&trackinfo) else {
+                return Ok(());
+            };
+            assert_valid_component(&sanitize_file_name(&album, ""));
+            let mut paths = std::collections::HashSet::new();
+            for tag in &tags {
+                let path = tag.output_path(padding, None, None, Naming::Standard);
+                for component in path.iter() {
+                    assert_valid_component(component.to_str().unwrap());
+                }
+                assert!(path.to_str().unwrap().ends_with(".flac"));
+                assert!(paths.insert(path));
+            }
+        }
+    }
+}
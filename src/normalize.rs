@@ -0,0 +1,188 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+#[derive(Clone, Copy)]
+pub enum Typography {
+    Ascii,
+    Typographic,
+}
+
+impl FromStr for Typography {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(Typography::Ascii),
+            "typographic" => Ok(Typography::Typographic),
+            _ => Err(format!("Unknown typography style: {s}")),
+        }
+    }
+}
+
+pub fn typography(value: &str, style: Typography) -> String {
+    match style {
+        Typography::Ascii => value
+            .chars()
+            .map(|c| match c {
+                '‘' | '’' | '‚' | '‛' | '′' => "'".to_string(),
+                '“' | '”' | '„' | '‟' | '″' => "\"".to_string(),
+                '‐' | '‑' | '‒' | '–' | '—' | '―' => "-".to_string(),
+                '…' => "...".to_string(),
+                _ => c.to_string(),
+            })
+            .collect(),
+        Typography::Typographic => {
+            let value = value.replace("...", "…").replace(" - ", " – ");
+            let mut ret = String::with_capacity(value.len());
+            let mut prev: Option<char> = None;
+            for c in value.chars() {
+                let opening = prev.is_none_or(|p| p.is_whitespace() || "([{<“‘–—/".contains(p));
+                ret.push(match c {
+                    '\'' if opening => '‘',
+                    '\'' => '’',
+                    '"' if opening => '“',
+                    '"' => '”',
+                    _ => c,
+                });
+                prev = Some(c);
+            }
+            ret
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FeatTarget {
+    Artist,
+    Title,
+}
+
+impl FromStr for FeatTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "artist" => Ok(FeatTarget::Artist),
+            "title" => Ok(FeatTarget::Title),
+            _ => Err(format!("Unknown featured artist target: {s}")),
+        }
+    }
+}
+
+fn split_feat(value: &str) -> Option<(String, String)> {
+    static FEAT_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(
+            r"(?i)^(.*?)\s*(?:[(\[](?:feat\.?|ft\.?|featuring)\s+([^)\]]+?)\s*[)\]]|\b(?:feat\.|ft\.|featuring)\s+(.+?))\s*$",
+        )
+        .unwrap()
+    });
+    let caps = FEAT_RE.captures(value)?;
+    let feat = caps.get(2).or(caps.get(3)).unwrap().as_str();
+    if caps[1].is_empty() {
+        None
+    } else {
+        Some((caps[1].to_string(), feat.to_string()))
+    }
+}
+
+/// Moves "feat. X" credits between TITLE and ARTIST, writing them with the
+/// given separator. Without a target, credits stay where they are.
+pub fn featured_artists(
+    title: &mut Option<String>,
+    artist: &mut Option<String>,
+    target: Option<FeatTarget>,
+    separator: &str,
+) {
+    let title_feat = title.as_deref().and_then(split_feat);
+    let artist_feat = artist.as_deref().and_then(split_feat);
+    match (target, title_feat, artist_feat) {
+        (Some(FeatTarget::Artist), Some((main, feat)), None) => {
+            *title = Some(main);
+            *artist = Some(match artist {
+                Some(artist) => format!("{artist} {separator} {feat}"),
+                None => feat,
+            });
+        }
+        (Some(FeatTarget::Title), None, Some((main, feat))) if title.is_some() => {
+            *title = Some(format!("{} ({separator} {feat})", title.as_ref().unwrap()));
+            *artist = Some(main);
+        }
+        _ => (),
+    }
+    if let Some((main, feat)) = title.as_deref().and_then(split_feat) {
+        *title = Some(format!("{main} ({separator} {feat})"));
+    }
+    if let Some((main, feat)) = artist.as_deref().and_then(split_feat) {
+        *artist = Some(format!("{main} {separator} {feat}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_typography() {
+        assert_eq!(
+            typography("“Curly” – ‘x’…", Typography::Ascii),
+            "\"Curly\" - 'x'..."
+        );
+    }
+
+    #[test]
+    fn typographic_quotes() {
+        assert_eq!(
+            typography("\"Hello\" - it's 'me'...", Typography::Typographic),
+            "“Hello” – it’s ‘me’…"
+        );
+    }
+
+    #[test]
+    fn featured_artists_move_into_artist() {
+        let mut title = Some(String::from("Song (ft. Guest)"));
+        let mut artist = Some(String::from("Main"));
+        featured_artists(&mut title, &mut artist, Some(FeatTarget::Artist), "feat.");
+        assert_eq!(title.as_deref(), Some("Song"));
+        assert_eq!(artist.as_deref(), Some("Main feat. Guest"));
+    }
+
+    #[test]
+    fn featured_artists_move_into_title() {
+        let mut title = Some(String::from("Song"));
+        let mut artist = Some(String::from("Main featuring Guest"));
+        featured_artists(&mut title, &mut artist, Some(FeatTarget::Title), "feat.");
+        assert_eq!(title.as_deref(), Some("Song (feat. Guest)"));
+        assert_eq!(artist.as_deref(), Some("Main"));
+    }
+
+    #[test]
+    fn featured_artists_are_respelled_in_place() {
+        let mut title = Some(String::from("Song [featuring Guest]"));
+        let mut artist = None;
+        featured_artists(&mut title, &mut artist, None, "ft.");
+        assert_eq!(title.as_deref(), Some("Song (ft. Guest)"));
+        assert_eq!(artist, None);
+    }
+}
@@ -0,0 +1,229 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Tag providers: external programs that fill in tags, for instance from an
+//! in-house database.
+//!
+//! A provider is configured as `PROVIDER[name]=program args` and receives the
+//! album on stdin:
+//!
+//! ```text
+//! {"version":"1","tracks":[{"track":"1","fields":{"ALBUM":"…","TITLE":"…"}}]}
+//! ```
+//!
+//! It answers on stdout with TRACKINFO keys for the whole album, for single
+//! tracks or both:
+//!
+//! ```text
+//! {"album":{"LABEL":"…"},"tracks":[{"track":"1","fields":{"GENRE":"…"}}]}
+//! ```
+
+use std::io::{self, Write};
+use std::process::Command;
+use std::thread;
+
+use crate::json::Json;
+use crate::{ReflacError, Result, jobs};
+
+/// Version of the protocol, sent with every request.
+const VERSION: &str = "1";
+
+/// A track by its TRACKINFO identifier and its fields.
+pub struct Track {
+    pub id: String,
+    pub fields: Vec<(String, String)>,
+}
+
+fn request(tracks: &[Track]) -> Json {
+    Json::Object(vec![
+        (String::from("version"), Json::string(VERSION)),
+        (
+            String::from("tracks"),
+            Json::Array(
+                tracks
+                    .iter()
+                    .map(|track| {
+                        Json::Object(vec![
+                            (String::from("track"), Json::string(&track.id)),
+                            (
+                                String::from("fields"),
+                                Json::Object(
+                                    track
+                                        .fields
+                                        .iter()
+                                        .map(|(key, value)| (key.clone(), Json::string(value)))
+                                        .collect(),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+/// Fields of a JSON object of TRACKINFO keys; nulls are left out.
+fn fields(value: &Json) -> std::result::Result<Vec<(String, String)>, String> {
+    let Json::Object(members) = value else {
+        return Err(String::from("fields must be an object"));
+    };
+    let mut fields = Vec::new();
+    for (key, value) in members {
+        match value {
+            Json::Null => (),
+            Json::String(s) if s.contains(['\n', '\r']) => {
+                return Err(format!("{key} spans several lines"));
+            }
+            Json::String(s) => fields.push((key.clone(), s.clone())),
+            Json::Number(n) => fields.push((key.clone(), n.clone())),
+            _ => return Err(format!("{key} is not a string")),
+        }
+    }
+    Ok(fields)
+}
+
+/// The fields a provider answered with for each of the tracks `ids`.
+fn response(text: &str, ids: &[String]) -> std::result::Result<Vec<Track>, String> {
+    let json = Json::parse(text).ok_or("the answer is not JSON")?;
+    let mut tracks: Vec<Track> = ids
+        .iter()
+        .map(|id| Track {
+            id: id.clone(),
+            fields: Vec::new(),
+        })
+        .collect();
+    if let Some(album) = json.get("album") {
+        let album = fields(album)?;
+        for track in &mut tracks {
+            track.fields.extend(album.iter().cloned());
+        }
+    }
+    match json.get("tracks") {
+        None => (),
+        Some(Json::Array(items)) => {
+            for item in items {
+                let id = match item.get("track") {
+                    Some(Json::String(id) | Json::Number(id)) => id,
+                    _ => return Err(String::from("a track has no \"track\"")),
+                };
+                let Some(track) = tracks.iter_mut().find(|t| t.id == *id) else {
+                    warning!(provider: "Tag provider answered for unknown track {id}");
+                    continue;
+                };
+                if let Some(value) = item.get("fields") {
+                    track.fields.extend(fields(value)?);
+                }
+            }
+        }
+        Some(_) => return Err(String::from("\"tracks\" must be an array")),
+    }
+    Ok(tracks)
+}
+
+/// Asks the provider `name`, run as `command`, for the tags of `tracks`.
+pub fn query(name: &str, command: &str, tracks: &[Track]) -> Result<Vec<Track>> {
+    let failed = |reason: String| ReflacError::ProviderFailed(name.to_string(), reason);
+    let mut words = command.split_whitespace();
+    let Some(program) = words.next() else {
+        return Err(ReflacError::UnknownProvider(name.to_string()));
+    };
+    // Written concurrently so a provider answering before it has read
+    // everything cannot block on a full pipe
+    let (reader, mut writer) = io::pipe()?;
+    let input = request(tracks).to_string();
+    let feeder = thread::spawn(move || {
+        // Providers need not read their input
+        let _ = writer.write_all(input.as_bytes());
+    });
+    let mut cmd = Command::new(program);
+    cmd.args(words).stdin(reader);
+    let timeout = jobs::policy().timeout_for(program);
+    let output =
+        jobs::output(&mut cmd, timeout).map_err(|err| failed(format!("{program}: {err}")))?;
+    // Closes the read end, so the feeder cannot block if nothing was read
+    drop(cmd);
+    let _ = feeder.join();
+    let Some(output) = output else {
+        return Err(failed(format!(
+            "timed out after {}s",
+            timeout.unwrap().as_secs()
+        )));
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last = stderr.lines().rfind(|l| !l.trim().is_empty()).unwrap_or("");
+        return Err(failed(match output.status.code() {
+            Some(code) => format!(
+                "exit status {code}{}",
+                if last.is_empty() {
+                    String::new()
+                } else {
+                    format!(": {last}")
+                }
+            ),
+            None => String::from("killed by a signal"),
+        }));
+    }
+    let text = String::from_utf8(output.stdout)
+        .map_err(|_| failed(String::from("the answer is not UTF-8")))?;
+    let ids: Vec<String> = tracks.iter().map(|t| t.id.clone()).collect();
+    response(&text, &ids).map_err(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_and_responses() {
+        let tracks = [Track {
+            id: String::from("1"),
+            fields: vec![(String::from("TITLE"), String::from("One"))],
+        }];
+        assert_eq!(
+            request(&tracks).to_string(),
+            r#"{"version":"1","tracks":[{"track":"1","fields":{"TITLE":"One"}}]}"#
+        );
+
+        let ids = [String::from("1"), String::from("2")];
+        let tracks = response(
+            r#"{"album":{"LABEL":"L","COMMENT":null},"tracks":[{"track":2,"fields":{"GENRE":"Jazz"}}]}"#,
+            &ids,
+        )
+        .unwrap();
+        assert_eq!(
+            tracks[0].fields,
+            [(String::from("LABEL"), String::from("L"))]
+        );
+        assert_eq!(
+            tracks[1].fields,
+            [
+                (String::from("LABEL"), String::from("L")),
+                (String::from("GENRE"), String::from("Jazz"))
+            ]
+        );
+        assert!(response("not json", &ids).is_err());
+        assert!(response(r#"{"album":{"TITLE":"a\nb"}}"#, &ids).is_err());
+        assert!(response(r#"{"tracks":{}}"#, &ids).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Progress bars for the encoding jobs and the run as a whole, drawn below
+//! the log on a terminal. Progress is measured in seconds of audio fed to
+//! the encoders.
+
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::console;
+use crate::estimate;
+
+/// How often the bars are redrawn.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Width of a bar in characters.
+const WIDTH: usize = 24;
+
+struct Bar {
+    track: String,
+    /// Length of the track's audio, if known
+    total: Option<Duration>,
+    done: Duration,
+    started: Instant,
+}
+
+struct State {
+    bars: Vec<Bar>,
+    /// Audio of all tracks to encode
+    total: Duration,
+    /// Audio of the tracks finished so far
+    finished: Duration,
+    started: Instant,
+    /// Lines drawn below the log
+    drawn: usize,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn state() -> std::sync::MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Shows the bars until dropped.
+pub struct Guard(());
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut state = state();
+        if let Some(state) = state.as_mut() {
+            let _ = io::stderr().lock().write_all(erase(state).as_bytes());
+        }
+        *state = None;
+    }
+}
+
+/// Starts showing progress towards encoding `total` of audio, if stderr is
+/// a terminal and the run is not `--quiet`.
+pub fn start(total: Duration) -> Guard {
+    if !console::is_tty() || console::is_quiet() {
+        return Guard(());
+    }
+    *state() = Some(State {
+        bars: Vec::new(),
+        total,
+        finished: Duration::ZERO,
+        started: Instant::now(),
+        drawn: 0,
+    });
+    thread::spawn(|| {
+        loop {
+            thread::sleep(TICK);
+            let mut state = state();
+            let Some(state) = state.as_mut() else {
+                return;
+            };
+            let text = erase(state) + &draw(state, Instant::now());
+            let _ = io::stderr().lock().write_all(text.as_bytes());
+        }
+    });
+    Guard(())
+}
+
+/// A track started (or restarted) encoding.
+pub fn begin(track: &str, total: Option<Duration>) {
+    if let Some(state) = state().as_mut() {
+        state.bars.retain(|bar| bar.track != track);
+        state.bars.push(Bar {
+            track: track.to_string(),
+            total,
+            done: Duration::ZERO,
+            started: Instant::now(),
+        });
+    }
+}
+
+/// More audio of a track reached its encoder.
+pub fn advance(track: &str, audio: Duration) {
+    if let Some(state) = state().as_mut()
+        && let Some(bar) = state.bars.iter_mut().find(|bar| bar.track == track)
+    {
+        bar.done += audio;
+    }
+}
+
+/// A track finished encoding.
+pub fn end(track: &str) {
+    if let Some(state) = state().as_mut()
+        && let Some(i) = state.bars.iter().position(|bar| bar.track == track)
+    {
+        let bar = state.bars.remove(i);
+        state.finished += bar.total.unwrap_or(bar.done);
+    }
+}
+
+/// Counts the audio on its way from a track's decoder to its encoder.
+pub struct Meter<W> {
+    inner: W,
+    track: String,
+    bytes_per_second: f64,
+}
+
+impl<W> Meter<W> {
+    pub fn new(inner: W, track: String, bytes_per_second: f64) -> Self {
+        Self {
+            inner,
+            track,
+            bytes_per_second,
+        }
+    }
+}
+
+impl<W: Write> Write for Meter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        advance(
+            &self.track,
+            Duration::from_secs_f64(n as f64 / self.bytes_per_second),
+        );
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes log text to stderr above the bars.
+pub fn print(text: &str) {
+    let mut state = state();
+    let text = match state.as_mut() {
+        Some(state) => erase(state) + text + &draw(state, Instant::now()),
+        None => text.to_string(),
+    };
+    let _ = io::stderr().lock().write_all(text.as_bytes());
+}
+
+/// Moves the cursor up over the bars and clears them.
+fn erase(state: &mut State) -> String {
+    let lines = std::mem::take(&mut state.drawn);
+    match lines {
+        0 => String::new(),
+        n => format!("\x1b[{n}A\x1b[J"),
+    }
+}
+
+fn draw(state: &mut State, now: Instant) -> String {
+    let mut text = String::new();
+    for bar in &state.bars {
+        let label = format!("#{}", bar.track);
+        text.push_str(&line(&label, bar.done, bar.total, now - bar.started));
+    }
+    let done = state.finished + state.bars.iter().map(|bar| bar.done).sum::<Duration>();
+    text.push_str(&line("All", done, Some(state.total), now - state.started));
+    state.drawn = state.bars.len() + 1;
+    text
+}
+
+/// "  #3  [=========>       ]  45%  12s left", without a percentage and
+/// estimate when the length is unknown.
+fn line(label: &str, done: Duration, total: Option<Duration>, elapsed: Duration) -> String {
+    let Some(total) = total.filter(|t| !t.is_zero()) else {
+        return format!("  {label:<5} [{:WIDTH$}]\n", "");
+    };
+    let share = (done.as_secs_f64() / total.as_secs_f64()).min(1.0);
+    let filled = (share * WIDTH as f64) as usize;
+    let mut bar = "=".repeat(filled);
+    if filled < WIDTH {
+        bar.push('>');
+    }
+    let mut text = format!("  {label:<5} [{bar:<WIDTH$}] {:3.0}%", share * 100.0);
+    if share > 0.0 && share < 1.0 {
+        let left = elapsed.mul_f64((1.0 - share) / share);
+        text.push_str(&format!("  {} left", estimate::format(left)));
+    }
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_show_share_and_time_left() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            line("#3", secs(30), Some(secs(120)), secs(10)),
+            "  #3    [======>                 ]  25%  30s left\n"
+        );
+        assert_eq!(
+            line("All", secs(120), Some(secs(120)), secs(40)),
+            "  All   [========================] 100%\n"
+        );
+        assert_eq!(
+            line("#1", secs(5), None, secs(1)),
+            "  #1    [                        ]\n"
+        );
+    }
+
+    #[test]
+    fn bars_are_drawn_below_the_log() {
+        let now = Instant::now();
+        let mut state = State {
+            bars: vec![Bar {
+                track: String::from("2"),
+                total: Some(Duration::from_secs(60)),
+                done: Duration::from_secs(30),
+                started: now,
+            }],
+            total: Duration::from_secs(120),
+            finished: Duration::from_secs(60),
+            started: now,
+            drawn: 0,
+        };
+        let text = draw(&mut state, now);
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.starts_with("  #2    [============>"), "{text}");
+        assert!(
+            text.contains("All   [==================>     ]  75%"),
+            "{text}"
+        );
+        assert_eq!(erase(&mut state), "\x1b[2A\x1b[J");
+        assert_eq!(erase(&mut state), "");
+    }
+}
@@ -0,0 +1,474 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::json::Json;
+use crate::{ReflacError, Result};
+
+/// Name of the sidecar written into every finished album directory.
+pub const FILE_NAME: &str = "reflac-run.toml";
+
+pub struct TrackRecord {
+    pub track: String,
+    pub input: String,
+    /// File name of the source inside the input
+    pub source: String,
+    pub output: PathBuf,
+    /// Damaged frames concealed with `--salvage`
+    pub bad_frames: Option<u64>,
+    /// SHA-256 of the embedded cover
+    pub cover: Option<String>,
+}
+
+/// What a run depended on besides its inputs, so that a re-encode coming
+/// out different years later can be explained.
+#[derive(Clone)]
+pub struct Environment {
+    pub reflac: String,
+    /// Commit reflac was built from
+    pub git: Option<String>,
+    pub os: String,
+    pub tools: Vec<(String, String)>,
+    /// Configuration file and its settings
+    pub config: Option<(PathBuf, Vec<String>)>,
+}
+
+impl Environment {
+    pub fn capture(config: &Config) -> Self {
+        Environment {
+            reflac: env!("CARGO_PKG_VERSION").to_string(),
+            git: option_env!("REFLAC_GIT_HASH").map(String::from),
+            os: os(),
+            tools: ["flac", "metaflac"]
+                .into_iter()
+                .map(|program| (program.to_string(), tool_version(program)))
+                .collect(),
+            config: config.path.clone().map(|path| (path, config.lines.clone())),
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            (String::from("reflac"), Json::string(&self.reflac)),
+            (String::from("git"), Json::optional(self.git.as_ref())),
+            (String::from("os"), Json::string(&self.os)),
+            (
+                String::from("tools"),
+                Json::Object(
+                    self.tools
+                        .iter()
+                        .map(|(program, version)| (program.clone(), Json::string(version)))
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("config"),
+                match &self.config {
+                    Some((path, lines)) => Json::Object(vec![
+                        (String::from("path"), Json::string(path.display())),
+                        (
+                            String::from("lines"),
+                            Json::Array(lines.iter().map(Json::string).collect()),
+                        ),
+                    ]),
+                    None => Json::Null,
+                },
+            ),
+        ])
+    }
+}
+
+/// Name, release and architecture of the operating system.
+fn os() -> String {
+    // SAFETY: uname only writes into the zeroed struct
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return format!("{} {}", std::env::consts::OS, std::env::consts::ARCH);
+    }
+    let field = |chars: &[libc::c_char]| {
+        // SAFETY: uname NUL-terminates its fields
+        unsafe { std::ffi::CStr::from_ptr(chars.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    format!(
+        "{} {} {}",
+        field(&name.sysname),
+        field(&name.release),
+        field(&name.machine)
+    )
+}
+
+/// Everything needed to tell how an album directory was produced. Unlike
+/// tags embedded in the audio files, the sidecar survives retagging by other
+/// tools.
+pub struct Provenance {
+    pub trackinfo: PathBuf,
+    /// Absolute directory the INPUT values are relative to
+    pub base_dir: PathBuf,
+    pub trackinfo_sha256: String,
+    /// INPUT values with their size in bytes (archives) or none (directories)
+    pub inputs: Vec<(String, Option<u64>)>,
+    pub settings: Vec<String>,
+    pub tracks: Vec<TrackRecord>,
+    pub environment: Environment,
+    pub started: SystemTime,
+}
+
+/// Formats a point in time as an RFC 3339 UTC timestamp.
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// First line printed by `program --version`.
+pub fn tool_version(program: &str) -> String {
+    Command::new(program)
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .and_then(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .next()
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Quotes a TOML basic string.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => write!(quoted, "\\u{:04X}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Reverses `quote`.
+fn unquote(value: &str) -> Option<String> {
+    let mut chars = value.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push('\n'),
+            't' => out.push('\t'),
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// The directory INPUT values are relative to and the tracks recorded in
+/// one provenance file. Files written before the base directory was
+/// recorded fall back to the directory of the TRACKINFO file.
+fn parse_tracks(text: &str) -> Option<(PathBuf, Vec<TrackRecord>)> {
+    let mut trackinfo: Option<PathBuf> = None;
+    let mut base_dir = None;
+    let mut tracks = Vec::new();
+    let mut section = "";
+    for line in text.lines() {
+        if line.starts_with('[') {
+            section = line;
+            if line == "[[track]]" {
+                tracks.push(TrackRecord {
+                    track: String::new(),
+                    input: String::new(),
+                    source: String::new(),
+                    output: PathBuf::new(),
+                    bad_frames: None,
+                    cover: None,
+                });
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        match (section, key) {
+            ("[trackinfo]", "path") => trackinfo = Some(PathBuf::from(unquote(value)?)),
+            ("[trackinfo]", "base_dir") => base_dir = Some(PathBuf::from(unquote(value)?)),
+            ("[[track]]", _) => {
+                let track = tracks.last_mut().unwrap();
+                match key {
+                    "track" => track.track = unquote(value)?,
+                    "input" => track.input = unquote(value)?,
+                    "source" => track.source = unquote(value)?,
+                    "output" => track.output = PathBuf::from(unquote(value)?),
+                    "bad_frames" => track.bad_frames = Some(value.parse().ok()?),
+                    "cover_sha256" => track.cover = Some(unquote(value)?),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    let base_dir = base_dir.or_else(|| Some(trackinfo?.parent()?.to_path_buf()))?;
+    Some((base_dir, tracks))
+}
+
+/// Finds the record of `track` in the provenance files of an album. Returns
+/// the directory the INPUT of that run is relative to and the record, whose
+/// output is resolved against the album directory.
+pub fn find_track(album_path: &Path, track: &str) -> Result<(PathBuf, TrackRecord)> {
+    let mut path = album_path.join(FILE_NAME);
+    let mut n = 1;
+    while path.exists() {
+        let text = fs::read_to_string(&path)?;
+        let (base_dir, tracks) =
+            parse_tracks(&text).ok_or_else(|| ReflacError::InvalidProvenance(path.clone()))?;
+        if let Some(mut record) = tracks.into_iter().find(|t| t.track == track) {
+            record.output = album_path.join(&record.output);
+            return Ok((base_dir, record));
+        }
+        n += 1;
+        path = album_path.join(format!("reflac-run-{n}.toml"));
+    }
+    Err(ReflacError::UnknownTrack(track.to_string()))
+}
+
+/// How many albums below `library` embed each cover, by its SHA-256.
+/// Unreadable records are skipped.
+pub fn cover_uses(library: &Path) -> Result<HashMap<String, usize>> {
+    let mut uses = HashMap::new();
+    let mut dirs = vec![library.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if !dir.join(FILE_NAME).is_file() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                }
+            }
+            continue;
+        }
+        let mut covers = HashSet::new();
+        let mut path = dir.join(FILE_NAME);
+        let mut n = 1;
+        while path.exists() {
+            if let Some((_, tracks)) = parse_tracks(&fs::read_to_string(&path)?) {
+                covers.extend(tracks.into_iter().filter_map(|t| t.cover));
+            }
+            n += 1;
+            path = dir.join(format!("reflac-run-{n}.toml"));
+        }
+        for cover in covers {
+            *uses.entry(cover).or_default() += 1;
+        }
+    }
+    Ok(uses)
+}
+
+impl Provenance {
+    pub fn write<P: AsRef<Path>>(&self, album_path: P) -> Result<()> {
+        let album_path = album_path.as_ref();
+        // Formatting into a String cannot fail
+        let out = self.to_toml(album_path).unwrap();
+        // Earlier runs (before appending tracks) are kept
+        let mut path = album_path.join(FILE_NAME);
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = album_path.join(format!("reflac-run-{n}.toml"));
+        }
+        fs::write(path, out)?;
+        Ok(())
+    }
+
+    fn to_toml(&self, album_path: &Path) -> std::result::Result<String, std::fmt::Error> {
+        let mut out = String::new();
+        let env = &self.environment;
+        writeln!(out, "reflac = {}", quote(&env.reflac))?;
+        if let Some(ref git) = env.git {
+            writeln!(out, "git = {}", quote(git))?;
+        }
+        writeln!(out, "os = {}", quote(&env.os))?;
+        writeln!(out, "started = {}", timestamp(self.started))?;
+        writeln!(out, "finished = {}", timestamp(SystemTime::now()))?;
+        writeln!(out)?;
+        writeln!(out, "[trackinfo]")?;
+        writeln!(
+            out,
+            "path = {}",
+            quote(&self.trackinfo.display().to_string())
+        )?;
+        writeln!(
+            out,
+            "base_dir = {}",
+            quote(&self.base_dir.display().to_string())
+        )?;
+        writeln!(out, "sha256 = {}", quote(&self.trackinfo_sha256))?;
+        writeln!(out)?;
+        writeln!(out, "[tools]")?;
+        for (program, version) in &env.tools {
+            writeln!(out, "{program} = {}", quote(version))?;
+        }
+        if let Some((ref path, ref lines)) = env.config {
+            writeln!(out)?;
+            writeln!(out, "[config]")?;
+            writeln!(out, "path = {}", quote(&path.display().to_string()))?;
+            let lines: Vec<_> = lines.iter().map(|l| quote(l)).collect();
+            writeln!(out, "lines = [{}]", lines.join(", "))?;
+        }
+        writeln!(out)?;
+        writeln!(out, "[encoder]")?;
+        let settings: Vec<_> = self.settings.iter().map(|s| quote(s)).collect();
+        writeln!(out, "settings = [{}]", settings.join(", "))?;
+        for (input, size) in &self.inputs {
+            writeln!(out)?;
+            writeln!(out, "[[input]]")?;
+            writeln!(out, "name = {}", quote(input))?;
+            if let Some(size) = size {
+                writeln!(out, "size = {size}")?;
+            }
+        }
+        for track in &self.tracks {
+            let output = track
+                .output
+                .strip_prefix(album_path)
+                .unwrap_or(&track.output);
+            writeln!(out)?;
+            writeln!(out, "[[track]]")?;
+            writeln!(out, "track = {}", quote(&track.track))?;
+            writeln!(out, "input = {}", quote(&track.input))?;
+            writeln!(out, "source = {}", quote(&track.source))?;
+            writeln!(out, "output = {}", quote(&output.display().to_string()))?;
+            if let Some(bad_frames) = track.bad_frames {
+                writeln!(out, "bad_frames = {bad_frames}")?;
+            }
+            if let Some(ref cover) = track.cover {
+                writeln!(out, "cover_sha256 = {}", quote(cover))?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(951782400 + 3723)),
+            "2000-02-29T01:02:03Z"
+        );
+    }
+
+    #[test]
+    fn toml_strings() {
+        assert_eq!(quote("a \"b\" \\ c\n"), "\"a \\\"b\\\" \\\\ c\\n\"");
+        let odd = "a \"b\" \\ c\n\u{7}";
+        assert_eq!(unquote(&quote(odd)).as_deref(), Some(odd));
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let provenance = Provenance {
+            trackinfo: PathBuf::from("-"),
+            base_dir: PathBuf::from("/home/me/rips"),
+            trackinfo_sha256: String::new(),
+            inputs: Vec::new(),
+            settings: Vec::new(),
+            tracks: vec![TrackRecord {
+                track: String::from("A1"),
+                input: String::from("Album.zip"),
+                source: String::from("01 Intro.flac"),
+                output: PathBuf::from("/music/Album/01. Intro.flac"),
+                bad_frames: Some(3),
+                cover: Some(String::from("ab12")),
+            }],
+            environment: Environment {
+                reflac: String::from("1.0.0"),
+                git: Some(String::from("0123456789ab")),
+                os: String::from("Linux 6.1.0 x86_64"),
+                tools: vec![(String::from("flac"), String::from("flac 1.4.3"))],
+                config: Some((
+                    PathBuf::from("/home/me/.config/reflac/config"),
+                    vec![String::from("GENRE=Jazz")],
+                )),
+            },
+            started: UNIX_EPOCH,
+        };
+        let toml = provenance.to_toml(Path::new("/music/Album")).unwrap();
+        assert!(toml.contains("git = \"0123456789ab\"\n"));
+        assert!(toml.contains("flac = \"flac 1.4.3\"\n"));
+        assert!(toml.contains("lines = [\"GENRE=Jazz\"]\n"));
+        let (base_dir, tracks) = parse_tracks(&toml).unwrap();
+        assert_eq!(base_dir, PathBuf::from("/home/me/rips"));
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track, "A1");
+        assert_eq!(tracks[0].input, "Album.zip");
+        assert_eq!(tracks[0].source, "01 Intro.flac");
+        assert_eq!(tracks[0].output, PathBuf::from("01. Intro.flac"));
+        assert_eq!(tracks[0].bad_frames, Some(3));
+        assert_eq!(tracks[0].cover.as_deref(), Some("ab12"));
+
+        // Older records without a base directory
+        let old = toml.replace("base_dir = \"/home/me/rips\"\n", "");
+        let old = old.replace("path = \"-\"", "path = \"rips/TRACKINFO\"");
+        assert_eq!(parse_tracks(&old).unwrap().0, PathBuf::from("rips"));
+    }
+}
@@ -0,0 +1,228 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Finding albums of an output library that could go when it outgrows
+//! its destination: duplicates first, then the least space-efficient.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Result, flac, provenance};
+
+/// Albums listed without a quota.
+const DEFAULT_LIMIT: usize = 10;
+
+/// Size statistics of an album reflac wrote.
+pub struct Album {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Audio MD5s of the tracks that have one
+    pub md5s: Vec<[u8; 16]>,
+}
+
+impl Album {
+    fn read(path: &Path) -> Result<Self> {
+        let mut album = Album {
+            path: path.to_path_buf(),
+            bytes: 0,
+            seconds: 0.0,
+            md5s: Vec::new(),
+        };
+        for file in flac::album_files(path)? {
+            let stream = flac::read_metadata(&file)?.stream;
+            album.bytes += fs::metadata(&file)?.len();
+            if stream.sample_rate > 0 {
+                album.seconds += stream.total_samples as f64 / stream.sample_rate as f64;
+            }
+            if stream.md5 != [0; 16] {
+                album.md5s.push(stream.md5);
+            }
+        }
+        Ok(album)
+    }
+
+    /// Average bitrate in kbit/s; albums of unknown length come out
+    /// infinitely expensive.
+    pub fn kbps(&self) -> f64 {
+        self.bytes as f64 * 8.0 / 1000.0 / self.seconds
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reason {
+    /// All its audio is in other albums
+    Duplicate,
+    Inefficient,
+}
+
+/// Albums below `library`, recognized by their provenance record.
+pub fn albums(library: &Path) -> Result<Vec<Album>> {
+    let mut albums = Vec::new();
+    let mut dirs = vec![library.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if dir.join(provenance::FILE_NAME).is_file() {
+            albums.push(Album::read(&dir)?);
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+    albums.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(albums)
+}
+
+/// Albums to prune, in order: duplicates, then by bitrate. With a quota,
+/// only as many as it takes to fit; otherwise the duplicates and a few of
+/// the rest.
+pub fn candidates(albums: &[Album], quota: Option<u64>) -> Vec<(usize, Reason)> {
+    let mut holders: HashMap<[u8; 16], usize> = HashMap::new();
+    for album in albums {
+        for md5 in &album.md5s {
+            *holders.entry(*md5).or_default() += 1;
+        }
+    }
+    let mut picked = Vec::new();
+    for (i, album) in albums.iter().enumerate() {
+        // Pruning it must leave every track in another album
+        if !album.md5s.is_empty() && album.md5s.iter().all(|md5| holders[md5] > 1) {
+            for md5 in &album.md5s {
+                *holders.get_mut(md5).unwrap() -= 1;
+            }
+            picked.push((i, Reason::Duplicate));
+        }
+    }
+    let mut rest: Vec<usize> = (0..albums.len())
+        .filter(|i| !picked.iter().any(|(p, _)| p == i))
+        .collect();
+    rest.sort_by(|&a, &b| albums[b].kbps().total_cmp(&albums[a].kbps()));
+    picked.extend(rest.into_iter().map(|i| (i, Reason::Inefficient)));
+
+    let Some(quota) = quota else {
+        let duplicates = picked
+            .iter()
+            .filter(|(_, r)| *r == Reason::Duplicate)
+            .count();
+        picked.truncate(duplicates + DEFAULT_LIMIT);
+        return picked;
+    };
+    let mut total: u64 = albums.iter().map(|a| a.bytes).sum();
+    let mut count = 0;
+    while total > quota && count < picked.len() {
+        total -= albums[picked[count].0].bytes;
+        count += 1;
+    }
+    picked.truncate(count);
+    picked
+}
+
+/// `reflac prune-report LIBRARY`: lists albums worth pruning, one per line
+/// with the reason, size and bitrate.
+pub fn run(library: &Path, quota: Option<u64>) -> Result<()> {
+    let albums = albums(library)?;
+    let total: u64 = albums.iter().map(|a| a.bytes).sum();
+    info!("{} albums, {} MiB", albums.len(), total.div_ceil(1 << 20));
+    if let Some(quota) = quota
+        && total <= quota
+    {
+        info!("Within the quota of {} MiB", quota.div_ceil(1 << 20));
+        return Ok(());
+    }
+    let mut freed = 0;
+    for (i, reason) in candidates(&albums, quota) {
+        let album = &albums[i];
+        freed += album.bytes;
+        println!(
+            "{}\t{} MiB\t{:.0} kbps\t{}",
+            match reason {
+                Reason::Duplicate => "duplicate",
+                Reason::Inefficient => "inefficient",
+            },
+            album.bytes.div_ceil(1 << 20),
+            album.kbps(),
+            album.path.display()
+        );
+    }
+    info!("Pruning these frees {} MiB", freed.div_ceil(1 << 20));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album(name: &str, bytes: u64, seconds: f64, md5s: &[u8]) -> Album {
+        Album {
+            path: PathBuf::from(name),
+            bytes,
+            seconds,
+            md5s: md5s.iter().map(|&b| [b; 16]).collect(),
+        }
+    }
+
+    #[test]
+    fn duplicates_come_first() {
+        let albums = [
+            album("A", 100, 10.0, &[1, 2]),
+            album("B", 300, 10.0, &[3, 4]),
+            album("Hits", 50, 10.0, &[2, 3]),
+            album("C", 200, 10.0, &[]),
+        ];
+        assert_eq!(
+            candidates(&albums, None),
+            vec![
+                (2, Reason::Duplicate),
+                (1, Reason::Inefficient),
+                (3, Reason::Inefficient),
+                (0, Reason::Inefficient),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates_keep_one_copy() {
+        let albums = [album("A", 100, 10.0, &[1]), album("B", 100, 10.0, &[1])];
+        assert_eq!(
+            candidates(&albums, None),
+            vec![(0, Reason::Duplicate), (1, Reason::Inefficient)]
+        );
+    }
+
+    #[test]
+    fn quotas_stop_early() {
+        let albums = [
+            album("A", 100, 10.0, &[]),
+            album("B", 300, 10.0, &[]),
+            album("C", 200, 10.0, &[]),
+        ];
+        assert_eq!(
+            candidates(&albums, Some(350)),
+            vec![(1, Reason::Inefficient)]
+        );
+        assert_eq!(candidates(&albums, Some(600)), vec![]);
+    }
+}
@@ -0,0 +1,294 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Judging sources of the same track against each other, for picking the
+//! better one when an album comes from several complete inputs.
+
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{Result, flac};
+
+/// Samples per analysis block.
+const BLOCK: usize = 2048;
+/// Blocks analysed for a cut-off, about two minutes at 44.1 kHz.
+const MAX_BLOCKS: usize = 2600;
+/// Frequencies (Hz) carrying content in any music.
+const REFERENCE_BANDS: &[f64] = &[3000.0, 5000.0, 7000.0, 9000.0];
+/// Frequencies (Hz) that lossy encoders usually cut.
+const HIGH_BANDS: &[f64] = &[17500.0, 18500.0, 19500.0, 20500.0];
+/// Power of the high bands relative to the reference bands below which a
+/// source counts as cut off (-50 dB).
+const CUTOFF_RATIO: f64 = 1e-5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Criterion {
+    /// No sign of having been transcoded from a lossy format
+    Lossless,
+    /// Higher effective bit depth
+    Bits,
+    /// Higher sample rate
+    Rate,
+    /// Longer
+    Duration,
+    /// Larger file
+    Size,
+}
+
+impl FromStr for Criterion {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "lossless" => Ok(Criterion::Lossless),
+            "bits" => Ok(Criterion::Bits),
+            "rate" => Ok(Criterion::Rate),
+            "duration" => Ok(Criterion::Duration),
+            "size" => Ok(Criterion::Size),
+            _ => Err(()),
+        }
+    }
+}
+
+pub const DEFAULT_POLICY: &[Criterion] = &[
+    Criterion::Lossless,
+    Criterion::Bits,
+    Criterion::Rate,
+    Criterion::Duration,
+    Criterion::Size,
+];
+
+/// Parses a comma-separated list of criteria, most important first.
+pub fn parse_policy(s: &str) -> Option<Vec<Criterion>> {
+    s.split(',')
+        .map(|c| c.trim().parse().ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|policy| !policy.is_empty())
+}
+
+/// What is known about the audio of a source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    /// Bits per sample actually used; lower than the stream's for padded
+    /// sources
+    pub bits: u32,
+    pub sample_rate: u32,
+    pub samples: u64,
+    pub bytes: u64,
+    /// High frequencies are missing as after lossy encoding
+    pub cut_off: bool,
+}
+
+impl Profile {
+    /// Decodes a FLAC file and analyses its samples.
+    pub fn of(path: &Path) -> Result<Self> {
+        let stream = flac::read_metadata(path)?.stream;
+        let mut decoder = flac::decode_raw(path)?;
+        let analysis = analyse(
+            BufReader::new(decoder.stdout.take().unwrap()),
+            stream.channels as usize,
+            stream.bits_per_sample as u32,
+            stream.sample_rate,
+        )?;
+        flac::wait_decoder(&mut decoder)?;
+        Ok(Profile {
+            bits: analysis.bits,
+            sample_rate: stream.sample_rate,
+            samples: stream.total_samples,
+            bytes: fs::metadata(path)?.len(),
+            cut_off: analysis.cut_off,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let seconds = self.samples as f64 / self.sample_rate.max(1) as f64;
+        format!(
+            "{} bits, {} Hz, {seconds:.1} s, {} KiB{}",
+            self.bits,
+            self.sample_rate,
+            self.bytes / 1024,
+            if self.cut_off { ", cut off" } else { "" }
+        )
+    }
+
+    /// Orders two profiles by the first criterion that tells them apart;
+    /// the better one is greater.
+    pub fn compare(&self, other: &Self, policy: &[Criterion]) -> Ordering {
+        policy
+            .iter()
+            .map(|criterion| match criterion {
+                Criterion::Lossless => other.cut_off.cmp(&self.cut_off),
+                Criterion::Bits => self.bits.cmp(&other.bits),
+                Criterion::Rate => self.sample_rate.cmp(&other.sample_rate),
+                Criterion::Duration => self.samples.cmp(&other.samples),
+                Criterion::Size => self.bytes.cmp(&other.bytes),
+            })
+            .find(|order| order.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+struct Analysis {
+    bits: u32,
+    cut_off: bool,
+}
+
+/// Power of one frequency in a block (Goertzel).
+fn power(block: &[f64], freq: f64, sample_rate: u32) -> f64 {
+    let coeff = 2.0 * (2.0 * PI * freq / sample_rate as f64).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for x in block {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
+
+/// Reads raw interleaved little-endian samples and finds the bit depth in
+/// use and whether the high frequencies are missing.
+fn analyse(
+    mut reader: impl Read,
+    channels: usize,
+    bits: u32,
+    sample_rate: u32,
+) -> io::Result<Analysis> {
+    let sample_bytes = bits.div_ceil(8) as usize;
+    let frame_bytes = sample_bytes * channels;
+    let window: Vec<f64> = (0..BLOCK)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / BLOCK as f64).cos())
+        .collect();
+    let measurable = HIGH_BANDS.iter().all(|&f| f < sample_rate as f64 / 2.0);
+
+    let mut used = 0u32;
+    let (mut reference, mut high) = (0.0, 0.0);
+    let mut raw = vec![0; frame_bytes * BLOCK];
+    let mut block = vec![0.0; BLOCK];
+    let mut blocks = 0;
+    loop {
+        let mut len = 0;
+        while len < raw.len() {
+            match reader.read(&mut raw[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let frames = len / frame_bytes;
+        for (frame, mono) in raw[..frames * frame_bytes]
+            .chunks(frame_bytes)
+            .zip(block.iter_mut())
+        {
+            let mut sum = 0.0;
+            for sample in frame.chunks(sample_bytes) {
+                // Sign-extend from the top byte
+                let mut value = (sample[sample_bytes - 1] as i8) as i32;
+                for &byte in sample[..sample_bytes - 1].iter().rev() {
+                    value = (value << 8) | byte as i32;
+                }
+                used |= value as u32;
+                sum += value as f64;
+            }
+            *mono = sum / channels as f64;
+        }
+        if frames == BLOCK && measurable && blocks < MAX_BLOCKS {
+            let windowed: Vec<f64> = block.iter().zip(&window).map(|(x, w)| x * w).collect();
+            reference += REFERENCE_BANDS
+                .iter()
+                .map(|&f| power(&windowed, f, sample_rate))
+                .sum::<f64>();
+            high += HIGH_BANDS
+                .iter()
+                .map(|&f| power(&windowed, f, sample_rate))
+                .sum::<f64>();
+            blocks += 1;
+        }
+        if len < raw.len() {
+            break;
+        }
+    }
+    Ok(Analysis {
+        bits: bits.saturating_sub(used.trailing_zeros()).max(1),
+        cut_off: reference > 0.0 && high < reference * CUTOFF_RATIO,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Raw 16-bit stereo samples of a sum of sines.
+    fn tones(freqs: &[f64], seconds: f64, shift: u32) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for i in 0..(44100.0 * seconds) as usize {
+            let t = i as f64 / 44100.0;
+            let x: f64 = freqs.iter().map(|f| (2.0 * PI * f * t).sin()).sum();
+            let sample = ((x * 6000.0) as i16 >> shift) << shift;
+            for _ in 0..2 {
+                raw.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn cut_offs_are_detected() {
+        let full = analyse(&tones(&[5000.0, 9000.0, 19500.0], 1.0, 0)[..], 2, 16, 44100).unwrap();
+        assert!(!full.cut_off);
+        let cut = analyse(&tones(&[5000.0, 9000.0], 1.0, 0)[..], 2, 16, 44100).unwrap();
+        assert!(cut.cut_off);
+    }
+
+    #[test]
+    fn padded_bits_are_detected() {
+        let raw = tones(&[1000.0], 0.5, 0);
+        assert_eq!(analyse(&raw[..], 2, 16, 44100).unwrap().bits, 16);
+        let raw = tones(&[1000.0], 0.5, 4);
+        assert_eq!(analyse(&raw[..], 2, 16, 44100).unwrap().bits, 12);
+    }
+
+    #[test]
+    fn policies_decide_in_order() {
+        let web = Profile {
+            bits: 24,
+            sample_rate: 48000,
+            samples: 48000 * 200,
+            bytes: 40 << 20,
+            cut_off: true,
+        };
+        let cd = Profile {
+            bits: 16,
+            sample_rate: 44100,
+            samples: 44100 * 200,
+            bytes: 25 << 20,
+            cut_off: false,
+        };
+        assert_eq!(cd.compare(&web, DEFAULT_POLICY), Ordering::Greater);
+        let policy = parse_policy("bits, lossless").unwrap();
+        assert_eq!(cd.compare(&web, &policy), Ordering::Less);
+        assert_eq!(parse_policy("bits,loudness"), None);
+        assert_eq!(parse_policy(""), None);
+    }
+}
@@ -0,0 +1,236 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+use crate::console::Warning;
+use crate::gain::ReplayGain;
+use crate::jobs::Retry;
+use crate::json::Json;
+use crate::provenance::Environment;
+
+pub struct TrackReport {
+    pub track: String,
+    /// The INPUT (of its alternatives) the track was taken from
+    pub input: String,
+    pub source: String,
+    pub output: PathBuf,
+    /// Certainty of the mapping to `source`, in percent
+    pub confidence: u8,
+    /// Damaged frames concealed with `--salvage`
+    pub bad_frames: Option<u64>,
+    pub replay_gain: Option<ReplayGain>,
+}
+
+/// Result of verifying a source against a sidecar file (PAR2, signature).
+pub struct Check {
+    pub path: PathBuf,
+    pub kind: &'static str,
+    pub result: String,
+}
+
+/// Why a run failed: a stable code, the message and details such as the
+/// track, path or command involved.
+pub struct Failure {
+    pub code: &'static str,
+    pub message: String,
+    pub context: Vec<(&'static str, String)>,
+}
+
+pub struct Report {
+    pub trackinfo: Option<PathBuf>,
+    pub album: Option<String>,
+    pub output: Option<PathBuf>,
+    pub tracks: Vec<TrackReport>,
+    /// Images picked by COVER=auto
+    pub covers: Vec<PathBuf>,
+    pub checks: Vec<Check>,
+    pub retries: Vec<Retry>,
+    pub warnings: Vec<Warning>,
+    pub environment: Option<Environment>,
+    pub error: Option<Failure>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self {
+            trackinfo: None,
+            album: None,
+            output: None,
+            tracks: Vec::new(),
+            covers: Vec::new(),
+            checks: Vec::new(),
+            retries: Vec::new(),
+            warnings: Vec::new(),
+            environment: None,
+            error: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            (
+                String::from("status"),
+                Json::string(if self.error.is_some() { "failed" } else { "ok" }),
+            ),
+            (
+                String::from("error"),
+                Json::optional(self.error.as_ref().map(|e| &e.message)),
+            ),
+            (
+                String::from("error_code"),
+                Json::optional(self.error.as_ref().map(|e| e.code)),
+            ),
+            (
+                String::from("error_context"),
+                match &self.error {
+                    Some(error) => Json::Object(
+                        error
+                            .context
+                            .iter()
+                            .map(|(key, value)| (key.to_string(), Json::string(value)))
+                            .collect(),
+                    ),
+                    None => Json::Null,
+                },
+            ),
+            (
+                String::from("trackinfo"),
+                Json::optional(self.trackinfo.as_ref().map(|p| p.display())),
+            ),
+            (String::from("album"), Json::optional(self.album.as_ref())),
+            (
+                String::from("output"),
+                Json::optional(self.output.as_ref().map(|p| p.display())),
+            ),
+            (
+                String::from("tracks"),
+                Json::Array(
+                    self.tracks
+                        .iter()
+                        .map(|t| {
+                            Json::Object(vec![
+                                (String::from("track"), Json::string(&t.track)),
+                                (String::from("input"), Json::string(&t.input)),
+                                (String::from("source"), Json::string(&t.source)),
+                                (String::from("output"), Json::string(t.output.display())),
+                                (String::from("confidence"), Json::string(t.confidence)),
+                                (String::from("bad_frames"), Json::optional(t.bad_frames)),
+                                (
+                                    String::from("replaygain"),
+                                    t.replay_gain.as_ref().map_or(Json::Null, |g| {
+                                        Json::Object(vec![
+                                            (
+                                                String::from("track_gain"),
+                                                Json::string(&g.track_gain),
+                                            ),
+                                            (
+                                                String::from("track_peak"),
+                                                Json::string(&g.track_peak),
+                                            ),
+                                            (
+                                                String::from("album_gain"),
+                                                Json::string(&g.album_gain),
+                                            ),
+                                            (
+                                                String::from("album_peak"),
+                                                Json::string(&g.album_peak),
+                                            ),
+                                        ])
+                                    }),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("covers"),
+                Json::Array(
+                    self.covers
+                        .iter()
+                        .map(|path| Json::string(path.display()))
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("checks"),
+                Json::Array(
+                    self.checks
+                        .iter()
+                        .map(|c| {
+                            Json::Object(vec![
+                                (String::from("path"), Json::string(c.path.display())),
+                                (String::from("kind"), Json::string(c.kind)),
+                                (String::from("result"), Json::string(&c.result)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("retries"),
+                Json::Array(
+                    self.retries
+                        .iter()
+                        .map(|r| {
+                            Json::Object(vec![
+                                (String::from("command"), Json::string(&r.command)),
+                                (String::from("track"), Json::optional(r.track.as_ref())),
+                                (String::from("attempt"), Json::string(r.attempt)),
+                                (String::from("reason"), Json::string(&r.reason)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("warnings"),
+                Json::Array(
+                    self.warnings
+                        .iter()
+                        .map(|w| {
+                            Json::Object(vec![
+                                (String::from("category"), Json::string(w.category)),
+                                (String::from("message"), Json::string(&w.message)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("environment"),
+                self.environment
+                    .as_ref()
+                    .map(Environment::to_json)
+                    .unwrap_or(Json::Null),
+            ),
+        ])
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, format!("{}\n", self.to_json()))?;
+        Ok(())
+    }
+}
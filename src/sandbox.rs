@@ -0,0 +1,107 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::ffi::OsStr;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// How external extraction tools are confined. Even without a sandbox the
+/// tools run inside their output directory with resource limits.
+#[derive(Clone, Copy)]
+pub enum Sandbox {
+    None,
+    Bwrap,
+    Firejail,
+}
+
+impl FromStr for Sandbox {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Sandbox::None),
+            "bwrap" => Ok(Sandbox::Bwrap),
+            "firejail" => Ok(Sandbox::Firejail),
+            _ => Err(format!("Unknown sandbox: {s}")),
+        }
+    }
+}
+
+const CPU_SECONDS: libc::rlim_t = 60 * 60;
+const FILE_BYTES: libc::rlim_t = 64 << 30;
+
+fn limit_resources() -> std::io::Result<()> {
+    for (resource, limit) in [
+        (libc::RLIMIT_CORE, 0),
+        (libc::RLIMIT_CPU, CPU_SECONDS),
+        (libc::RLIMIT_FSIZE, FILE_BYTES),
+    ] {
+        let rlim = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Builds a command for `program` that may only write below `work_dir`.
+pub fn command<S: AsRef<OsStr>>(sandbox: Sandbox, program: S, work_dir: &Path) -> Command {
+    let mut cmd = match sandbox {
+        Sandbox::None => Command::new(program),
+        Sandbox::Bwrap => {
+            let mut cmd = Command::new("bwrap");
+            cmd.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"])
+                .arg("--bind")
+                .arg(work_dir)
+                .arg(work_dir)
+                .args(["--unshare-all", "--die-with-parent", "--new-session", "--"])
+                .arg(program);
+            cmd
+        }
+        Sandbox::Firejail => {
+            let mut cmd = Command::new("firejail");
+            cmd.args([
+                "--quiet",
+                "--noprofile",
+                "--net=none",
+                "--caps.drop=all",
+                "--nonewprivs",
+                "--seccomp",
+                "--read-only=/",
+            ])
+            .arg(format!("--read-write={}", work_dir.display()))
+            .arg("--")
+            .arg(program);
+            cmd
+        }
+    };
+    cmd.current_dir(work_dir);
+    unsafe {
+        cmd.pre_exec(limit_resources);
+    }
+    cmd
+}
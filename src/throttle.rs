@@ -0,0 +1,155 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! `--max-write-MBps`: a write budget shared by all jobs, so an overnight
+//! batch leaves enough of the disk to other users of the same array.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the pieces copies are written in.
+const CHUNK: usize = 256 * 1024;
+
+/// Hands out the time at which each write may start, so writes follow each
+/// other at the limited rate, whichever thread they come from.
+pub struct Limiter {
+    bytes_per_second: f64,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Limiter {
+    pub fn new(bytes_per_second: f64) -> Self {
+        Self {
+            bytes_per_second,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Waits until `bytes` may be written.
+    pub fn wait(&self, bytes: usize) {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        // Time not used for writing is not saved up for bursts
+        let start = next.map_or(now, |next| next.max(now));
+        *next = Some(start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second));
+        drop(next);
+        thread::sleep(start - now);
+    }
+}
+
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+/// Limits writes for the rest of the run to `megabytes` (10^6 bytes) per
+/// second. Only the first call has an effect.
+pub fn set_limit(megabytes: f64) {
+    let _ = LIMITER.set(Limiter::new(megabytes * 1e6));
+}
+
+/// Parses a rate in megabytes per second such as "20" or "2.5".
+pub fn parse_rate(s: &str) -> Option<f64> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+}
+
+pub fn is_limited() -> bool {
+    LIMITER.get().is_some()
+}
+
+/// A writer paced by the limit, if there is one.
+pub struct Writer<W>(pub W);
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = &buf[..buf.len().min(CHUNK)];
+        if let Some(limiter) = LIMITER.get() {
+            limiter.wait(buf.len());
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// `fs::copy`, paced by the limit.
+pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
+    if !is_limited() {
+        return fs::copy(from, to);
+    }
+    let mut input = File::open(from)?;
+    let mut output = Writer(File::create(to)?);
+    let mut buffer = vec![0; CHUNK];
+    let mut copied = 0;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// Moves a file staged in the work directory to its place in the output
+/// tree, usually on another disk.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_positive() {
+        assert_eq!(parse_rate("20"), Some(20.0));
+        assert_eq!(parse_rate("2.5"), Some(2.5));
+        assert_eq!(parse_rate("0"), None);
+        assert_eq!(parse_rate("-1"), None);
+        assert_eq!(parse_rate("inf"), None);
+    }
+
+    #[test]
+    fn writes_are_paced_across_threads() {
+        let limiter = Limiter::new(1e6);
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        limiter.wait(10_000);
+                    }
+                });
+            }
+        });
+        // The first write starts right away, the last after the other 190 kB
+        assert!(started.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -0,0 +1,58 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! `reflac verify`: checks the FLAC files of an album against the MD5
+//! signatures of their audio, as written by the encoder.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{ReflacError, Result, flac, run_command};
+
+/// Tests every FLAC file in `album_dir` (and its disc directories) with
+/// `flac --test` and prints one "ok" or "FAILED" line per file. Fails if
+/// any file is broken, but only after all of them were tested.
+pub fn run(album_dir: &Path) -> Result<()> {
+    let files = flac::album_files(album_dir)?;
+    if files.is_empty() {
+        return Err(ReflacError::NoFlacFilesFound(album_dir.to_path_buf()));
+    }
+    let mut broken: Vec<PathBuf> = Vec::new();
+    for file in &files {
+        let mut cmd = Command::new("flac");
+        cmd.args(["--test", "--silent"]).arg(file);
+        match run_command(&mut cmd, "flac") {
+            Ok(()) => println!("{}: ok", file.display()),
+            Err(err @ ReflacError::Subprocess { .. }) => {
+                warning!(verify: "{}: {err}", file.display());
+                println!("{}: FAILED", file.display());
+                broken.push(file.clone());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    info!("{} of {} files ok", files.len() - broken.len(), files.len());
+    match broken.into_iter().next() {
+        Some(file) => Err(ReflacError::VerificationFailed(file, "FLAC")),
+        None => Ok(()),
+    }
+}
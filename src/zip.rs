@@ -0,0 +1,425 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Reading ZIP archives in-process, so the most common archive type needs no
+//! `unzip`. Only what album downloads use is supported: stored and deflated
+//! members, ZIP64 and UTF-8 names. Anything else fails with `Unsupported`,
+//! which tells the caller to fall back to `unzip`.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::hash::Crc32;
+use crate::inflate::inflate;
+
+const END_OF_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const ZIP64_END_OF_DIRECTORY: u32 = 0x06064b50;
+const DIRECTORY_ENTRY: u32 = 0x02014b50;
+const LOCAL_HEADER: u32 = 0x04034b50;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn unsupported(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, reason)
+}
+
+/// Little-endian fields of a header, failing instead of panicking on
+/// truncated input.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn bytes(&self, at: usize, len: usize) -> io::Result<&[u8]> {
+        self.0
+            .get(at..at.saturating_add(len))
+            .ok_or_else(|| invalid("truncated header"))
+    }
+
+    fn u16(&self, at: usize) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(at, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, at: usize) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(at, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, at: usize) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(at, 8)?.try_into().unwrap()))
+    }
+}
+
+#[derive(Clone)]
+pub struct Member {
+    pub name: String,
+    /// Uncompressed size
+    pub size: u64,
+    /// Whether this is a symbolic link, which the caller should refuse
+    pub symlink: bool,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    header_offset: u64,
+}
+
+impl Member {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+pub struct Archive {
+    file: File,
+    pub members: Vec<Member>,
+}
+
+impl Archive {
+    /// Opens an archive and reads its central directory.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        // The end record (22 bytes) is followed by a comment of up to 64 KiB
+        let tail_len = len.min(22 + 0xffff);
+        file.seek(SeekFrom::Start(len - tail_len))?;
+        let mut tail = vec![0; tail_len as usize];
+        file.read_exact(&mut tail)?;
+        let end = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&i| tail[i..i + 4] == END_OF_DIRECTORY.to_le_bytes())
+            .ok_or_else(|| invalid("no end of central directory record"))?;
+        let record = Fields(&tail[end..]);
+        if record.u16(4)? != 0 || record.u16(6)? != 0 {
+            return Err(unsupported(String::from("split archives")));
+        }
+        let mut count = record.u16(10)? as u64;
+        let mut directory_len = record.u32(12)? as u64;
+        let mut directory_offset = record.u32(16)? as u64;
+        if count == 0xffff || directory_len == 0xffffffff || directory_offset == 0xffffffff {
+            // ZIP64: the real values are in a record found through a locator
+            // right before the end record
+            let locator_start = (len - tail_len + end as u64)
+                .checked_sub(20)
+                .ok_or_else(|| invalid("no ZIP64 locator"))?;
+            let mut locator = [0; 20];
+            file.seek(SeekFrom::Start(locator_start))?;
+            file.read_exact(&mut locator)?;
+            let locator = Fields(&locator);
+            if locator.u32(0)? != ZIP64_LOCATOR {
+                return Err(invalid("no ZIP64 locator"));
+            }
+            let mut record = [0; 56];
+            file.seek(SeekFrom::Start(locator.u64(8)?))?;
+            file.read_exact(&mut record)?;
+            let record = Fields(&record);
+            if record.u32(0)? != ZIP64_END_OF_DIRECTORY {
+                return Err(invalid("no ZIP64 end of central directory record"));
+            }
+            count = record.u64(32)?;
+            directory_len = record.u64(40)?;
+            directory_offset = record.u64(48)?;
+        }
+        if directory_offset.saturating_add(directory_len) > len {
+            return Err(invalid("central directory beyond the end of the file"));
+        }
+        let mut directory = vec![0; directory_len as usize];
+        file.seek(SeekFrom::Start(directory_offset))?;
+        file.read_exact(&mut directory)?;
+
+        let mut members = Vec::new();
+        let mut at = 0;
+        for _ in 0..count {
+            let (member, len) = parse_entry(Fields(directory.get(at..).unwrap_or(&[])))?;
+            members.push(member);
+            at += len;
+        }
+        Ok(Archive { file, members })
+    }
+
+    /// Writes the contents of a member to `out`, checking them against the
+    /// size and CRC the archive recorded.
+    pub fn read<W: Write>(&self, member: &Member, out: &mut W) -> io::Result<()> {
+        let mut file = &self.file;
+        let mut header = [0; 30];
+        file.seek(SeekFrom::Start(member.header_offset))?;
+        file.read_exact(&mut header)?;
+        let header = Fields(&header);
+        if header.u32(0)? != LOCAL_HEADER {
+            return Err(invalid("no local header"));
+        }
+        let skip = header.u16(26)? as i64 + header.u16(28)? as i64;
+        file.seek(SeekFrom::Current(skip))?;
+        let data = file.take(member.compressed_size);
+        let mut out = Checked {
+            inner: out,
+            crc: Some(Crc32::new()),
+            len: 0,
+        };
+        match member.method {
+            0 => {
+                io::copy(&mut BufReader::new(data), &mut out)?;
+            }
+            _ => {
+                inflate(BufReader::new(data), &mut out)?;
+            }
+        }
+        if out.len != member.size || out.crc.unwrap().finish() != member.crc {
+            return Err(invalid(&format!("CRC mismatch in {}", member.name)));
+        }
+        Ok(())
+    }
+
+    /// Extracts a member below `dir`. Its name must have been checked with
+    /// `archive::is_safe_member`.
+    pub fn extract(&self, member: &Member, dir: &Path) -> io::Result<()> {
+        let path = dir.join(&member.name);
+        if member.is_dir() {
+            return fs::create_dir_all(path);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        self.read(member, &mut out)?;
+        out.flush()
+    }
+}
+
+/// Parses an entry of the central directory and returns it with its
+/// length.
+fn parse_entry(entry: Fields) -> io::Result<(Member, usize)> {
+    if entry.u32(0)? != DIRECTORY_ENTRY {
+        return Err(invalid("broken central directory"));
+    }
+    let name_len = entry.u16(28)? as usize;
+    let extra_len = entry.u16(30)? as usize;
+    let comment_len = entry.u16(32)? as usize;
+    let raw_name = entry.bytes(46, name_len)?;
+    let extra = Fields(entry.bytes(46 + name_len, extra_len)?);
+
+    let mut size = entry.u32(24)? as u64;
+    let mut compressed_size = entry.u32(20)? as u64;
+    let mut header_offset = entry.u32(42)? as u64;
+    let mut name = std::str::from_utf8(raw_name).ok().map(String::from);
+    let mut at = 0;
+    while at + 4 <= extra_len {
+        let id = extra.u16(at)?;
+        let data = Fields(extra.bytes(at + 4, extra.u16(at + 2)? as usize)?);
+        match id {
+            // ZIP64 sizes and offset, present where the field is all ones
+            0x0001 => {
+                let mut i = 0;
+                for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                    if *value == 0xffffffff {
+                        *value = data.u64(i)?;
+                        i += 8;
+                    }
+                }
+            }
+            // Info-ZIP Unicode path, valid while it matches the plain name
+            0x7075
+                if data.0.len() > 5
+                    && data.0[0] == 1
+                    && data.u32(1)? == Crc32::new().update(raw_name).finish() =>
+            {
+                name = std::str::from_utf8(&data.0[5..]).ok().map(String::from);
+            }
+            _ => (),
+        }
+        at += 4 + data.0.len();
+    }
+
+    let Some(name) = name else {
+        return Err(unsupported(String::from(
+            "member names in a legacy encoding",
+        )));
+    };
+    let flags = entry.u16(8)?;
+    let method = entry.u16(10)?;
+    let unix = entry.u16(4)? >> 8 == 3;
+    let mode = entry.u32(38)? >> 16;
+    if flags & 1 != 0 {
+        return Err(unsupported(format!("encrypted member {name}")));
+    }
+    if method != 0 && method != 8 {
+        return Err(unsupported(format!(
+            "compression method {method} of {name}"
+        )));
+    }
+    let member = Member {
+        name,
+        symlink: unix && mode & 0o170000 == 0o120000,
+        size,
+        method,
+        crc: entry.u32(16)?,
+        compressed_size,
+        header_offset,
+    };
+    Ok((member, 46 + name_len + extra_len + comment_len))
+}
+
+/// Passes data on while computing its length and CRC.
+struct Checked<'a, W> {
+    inner: &'a mut W,
+    crc: Option<Crc32>,
+    len: u64,
+}
+
+impl<W: Write> Write for Checked<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = self.crc.take().map(|crc| crc.update(&buf[..n]));
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Name, flags, compression method, data and contents
+    type TestMember<'a> = (&'a str, u16, u16, &'a [u8], &'a [u8]);
+
+    fn archive(members: &[TestMember]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, flags, method, data, contents) in members {
+            // From the flags to the length of the extra field
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&flags.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]); // time and date
+            fields.extend_from_slice(&Crc32::new().update(contents).finish().to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+
+            directory.extend_from_slice(&DIRECTORY_ENTRY.to_le_bytes());
+            directory.extend_from_slice(&[20, 3, 20, 0]); // made on Unix
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 6]); // comment, disk, attributes
+            directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            directory.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            out.extend_from_slice(&20u16.to_le_bytes());
+            out.extend_from_slice(&fields);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        out.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    const HELLO: &[u8] = b"\xf3\x48\xcd\xc9\xc9\xd7\x51\xf0\x40\xa2\x14\x01";
+
+    #[test]
+    fn reads_stored_and_deflated_members() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("album.zip");
+        fs::write(
+            &path,
+            archive(&[
+                ("Album/", 0, 0, b"", b""),
+                ("Album/01 One.flac", 0, 0, b"fLaC", b"fLaC"),
+                ("Album/info.txt", 0x800, 8, HELLO, b"Hello, Hello, Hello!"),
+            ]),
+        )
+        .unwrap();
+        let zip = Archive::open(&path).unwrap();
+        let names: Vec<&str> = zip.members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["Album/", "Album/01 One.flac", "Album/info.txt"]);
+        assert_eq!(zip.members[2].size, 20);
+
+        let out = dir.path().join("out");
+        for member in &zip.members {
+            zip.extract(member, &out).unwrap();
+        }
+        assert_eq!(fs::read(out.join("Album/01 One.flac")).unwrap(), b"fLaC");
+        assert_eq!(
+            fs::read(out.join("Album/info.txt")).unwrap(),
+            b"Hello, Hello, Hello!"
+        );
+    }
+
+    #[test]
+    fn damaged_and_unsupported_archives() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("album.zip");
+        let kind = |members: &[TestMember]| {
+            fs::write(&path, archive(members)).unwrap();
+            match Archive::open(&path) {
+                Ok(zip) => zip
+                    .read(&zip.members[0], &mut Vec::new())
+                    .unwrap_err()
+                    .kind(),
+                Err(err) => err.kind(),
+            }
+        };
+        assert_eq!(
+            kind(&[("a", 0, 0, b"fLaC", b"fLaX")]),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            kind(&[("a", 0, 8, &HELLO[..6], b"Hello")]),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(kind(&[("a", 1, 0, b"", b"")]), io::ErrorKind::Unsupported);
+        assert_eq!(kind(&[("a", 0, 14, b"", b"")]), io::ErrorKind::Unsupported);
+
+        fs::write(&path, b"PK\x03\x04 not really").unwrap();
+        let err = Archive::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn flags_symlinks() {
+        let dir = crate::TempDir::new("reflac-test").unwrap();
+        let path = dir.path().join("album.zip");
+        let mut data = archive(&[("a", 0, 0, b"/etc", b"/etc"), ("b", 0, 0, b"", b"")]);
+        let entry = data
+            .windows(4)
+            .position(|w| w == DIRECTORY_ENTRY.to_le_bytes())
+            .unwrap();
+        data[entry + 38..entry + 42].copy_from_slice(&(0o120777u32 << 16).to_le_bytes());
+        fs::write(&path, data).unwrap();
+        let zip = Archive::open(&path).unwrap();
+        assert!(zip.members[0].symlink);
+        assert!(!zip.members[1].symlink);
+    }
+}
@@ -0,0 +1,456 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Fixtures for the integration tests: tiny FLAC files generated in-process,
+//! stored ZIP archives, TRACKINFO files and stand-ins for the `flac` and
+//! `metaflac` tools, so the pipeline runs without any audio software
+//! installed.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+pub const SAMPLE_RATE: u32 = 44100;
+const BLOCK_SIZE: usize = 4096;
+
+/// A scratch directory removed when dropped.
+pub struct Scratch {
+    pub path: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "reflac-test-{name}-{}-{:08x}",
+            std::process::id(),
+            rand_u32()
+        ));
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn rand_u32() -> u32 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish() as u32
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc ^ 0xffffffff
+}
+
+/// MD5, which STREAMINFO records of the decoded samples.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_le_bytes());
+    let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let m: Vec<u32> = chunk
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 16];
+    for (chunk, s) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_le_bytes());
+    }
+    digest
+}
+
+/// Frame numbers are coded like UTF-8.
+fn utf8_number(n: u64) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    let mut bytes = Vec::new();
+    let mut n = n;
+    let mut limit = 0x1f;
+    while n > limit {
+        bytes.insert(0, 0x80 | (n & 0x3f) as u8);
+        n >>= 6;
+        limit >>= 1;
+    }
+    let prefix = !(0xffu8 >> (bytes.len() + 1));
+    bytes.insert(0, prefix | n as u8);
+    bytes
+}
+
+fn block(kind: u8, last: bool, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind | if last { 0x80 } else { 0 }];
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Vorbis comment block contents.
+fn comment_data(comments: &[(&str, &str)]) -> Vec<u8> {
+    let vendor = b"reflac test";
+    let mut data = (vendor.len() as u32).to_le_bytes().to_vec();
+    data.extend_from_slice(vendor);
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (field, value) in comments {
+        let comment = format!("{field}={value}");
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+fn picture_data(image: &[u8]) -> Vec<u8> {
+    let mime = b"image/png";
+    let mut data = 3u32.to_be_bytes().to_vec();
+    data.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    data.extend_from_slice(mime);
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&[0; 16]);
+    data.extend_from_slice(&(image.len() as u32).to_be_bytes());
+    data.extend_from_slice(image);
+    data
+}
+
+/// Interleaved 16-bit stereo samples of a sine burst.
+pub fn sine(frequency: f64, seconds: f64) -> Vec<[i16; 2]> {
+    let n = (SAMPLE_RATE as f64 * seconds) as usize;
+    (0..n)
+        .map(|i| {
+            let t = i as f64 / SAMPLE_RATE as f64;
+            let envelope = (std::f64::consts::PI * i as f64 / n as f64).sin();
+            let v = (t * frequency * std::f64::consts::TAU).sin() * envelope * 12000.0;
+            [v as i16, (v * 0.5) as i16]
+        })
+        .collect()
+}
+
+/// Samples as a decoder writes them: interleaved, little-endian.
+pub fn pcm(samples: &[[i16; 2]]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|s| [s[0].to_le_bytes(), s[1].to_le_bytes()])
+        .flatten()
+        .collect()
+}
+
+/// Encodes samples as a valid FLAC file using verbatim subframes.
+pub fn flac_bytes(
+    samples: &[[i16; 2]],
+    comments: &[(&str, &str)],
+    image: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut out = b"fLaC".to_vec();
+
+    let mut info = Vec::new();
+    info.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    info.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    info.extend_from_slice(&[0; 6]);
+    let packed = ((SAMPLE_RATE as u64) << 44) | (1 << 41) | (15 << 36) | samples.len() as u64;
+    info.extend_from_slice(&packed.to_be_bytes());
+    info.extend_from_slice(&md5(&pcm(samples)));
+    out.extend(block(0, false, &info));
+    out.extend(block(4, image.is_none(), &comment_data(comments)));
+    if let Some(image) = image {
+        out.extend(block(6, true, &picture_data(image)));
+    }
+
+    for (number, chunk) in samples.chunks(BLOCK_SIZE).enumerate() {
+        // Sync code, fixed block size; block size from the end of the header,
+        // sample rate from STREAMINFO; independent stereo, 16 bits per sample
+        let mut frame = vec![0xff, 0xf8, 0x70, 0x18];
+        frame.extend(utf8_number(number as u64));
+        frame.extend_from_slice(&((chunk.len() - 1) as u16).to_be_bytes());
+        frame.push(crc8(&frame));
+        for channel in 0..2 {
+            // Verbatim subframe without wasted bits
+            frame.push(0x02);
+            for sample in chunk {
+                frame.extend_from_slice(&sample[channel].to_be_bytes());
+            }
+        }
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        out.extend(frame);
+    }
+    out
+}
+
+pub fn write_flac(path: &Path, seconds: f64, comments: &[(&str, &str)], image: Option<&[u8]>) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, flac_bytes(&sine(440.0, seconds), comments, image)).unwrap();
+}
+
+/// Writes a ZIP archive with stored (uncompressed) members.
+pub fn write_zip(path: &Path, members: &[(&str, &[u8])]) {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, data) in members {
+        let offset = out.len() as u32;
+        let crc = crc32(data);
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        common.extend_from_slice(&0u16.to_le_bytes()); // flags
+        common.extend_from_slice(&0u16.to_le_bytes()); // stored
+        common.extend_from_slice(&0u16.to_le_bytes()); // time
+        common.extend_from_slice(&0x21u16.to_le_bytes()); // date (1980-01-01)
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra length
+
+        out.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        central.extend_from_slice(&common);
+        central.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        central.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        central.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+    }
+    let central_offset = out.len() as u32;
+    out.extend_from_slice(&central);
+    out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(members.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&central_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    fs::write(path, out).unwrap();
+}
+
+/// Installs stand-ins for `flac` and `metaflac` into `dir`. The encoder
+/// copies its input (raw samples behind a STREAMINFO block) and records the
+/// tags it was asked to write in `OUTPUT.tags`, the tester only checks the
+/// FLAC marker; `metaflac` accepts everything.
+pub fn fake_tools(dir: &Path) -> PathBuf {
+    let bin = dir.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let flac = r#"#!/bin/sh
+out=""; dec=0; test=0; raw=0; channels=2; bps=16; rate=44100; last=""
+: > "${TMPDIR:-/tmp}/reflac-fake-tags.$$"
+for a in "$@"; do
+  case "$a" in
+    --decode|-d) dec=1;;
+    --test|-t) test=1;;
+    --force-raw-format) raw=1;;
+    --channels=*) channels="${a#--channels=}";;
+    --bps=*) bps="${a#--bps=}";;
+    --sample-rate=*) rate="${a#--sample-rate=}";;
+    --output-name=*) out="${a#--output-name=}";;
+    --tag=*) printf '%s\n' "${a#--tag=}" >> "${TMPDIR:-/tmp}/reflac-fake-tags.$$";;
+    --version) echo "flac 1.4.3"; exit 0;;
+  esac
+  last="$a"
+done
+tags="${TMPDIR:-/tmp}/reflac-fake-tags.$$"
+if [ $test = 1 ]; then
+  rm -f "$tags"; [ "$(head -c 4 "$last")" = fLaC ] || { echo "$last: not a FLAC file" >&2; exit 1; }
+elif [ $dec = 1 ]; then
+  # Its own encodings decode to the samples it was given, anything else
+  # passes through
+  if [ "$(od -An -tx1 -j4 -N1 "$last")" = " 80" ]; then
+    audio() { tail -c +43 "$last"; }
+  else
+    audio() { cat "$last"; }
+  fi
+  if [ -n "$out" ]; then audio > "$out"; else audio; fi
+elif [ "$last" = "-" ] && [ $raw = 1 ]; then
+  cat > "$out.raw"
+  samples=$(( $(wc -c < "$out.raw") / (channels * bps / 8) ))
+  packed=$(( (rate << 44) | ((channels - 1) << 41) | ((bps - 1) << 36) | samples ))
+  { printf 'fLaC\200\000\000\042'; printf '\000%.0s' 1 2 3 4 5 6 7 8 9 10
+    for shift in 56 48 40 32 24 16 8 0; do
+      printf "\\$(printf %o $(( (packed >> shift) & 255 )))"
+    done
+    printf '\000%.0s' 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16
+    cat "$out.raw"; } > "$out"
+  rm -f "$out.raw"; cp "$tags" "$out.tags"
+elif [ "$last" = "-" ]; then
+  cat > "$out"; cp "$tags" "$out.tags"
+else
+  cat "$last" > "$out"
+fi
+rm -f "$tags"
+"#;
+    let metaflac = "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\"\nexit 0\n";
+    for (name, script) in [("flac", flac), ("metaflac", metaflac)] {
+        let path = bin.join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    bin
+}
+
+/// Replaces one of the fake tools for a single test.
+pub fn override_tool(scratch: &Scratch, name: &str, script: &str) {
+    let bin = scratch.join("override");
+    fs::create_dir_all(&bin).unwrap();
+    let path = bin.join(name);
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Runs reflac with the fake tools (and overrides) first in `PATH`.
+pub fn reflac(scratch: &Scratch, args: &[&str]) -> Output {
+    reflac_command(scratch, args).output().unwrap()
+}
+
+/// Runs reflac with `input` piped into it.
+pub fn reflac_with_stdin(scratch: &Scratch, args: &[&str], input: &str) -> Output {
+    let mut child = reflac_command(scratch, args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn reflac_command(scratch: &Scratch, args: &[&str]) -> Command {
+    let bin = fake_tools(&scratch.path);
+    fs::create_dir_all(scratch.join("tmp")).unwrap();
+    let path = format!(
+        "{}:{}:{}",
+        scratch.join("override").display(),
+        bin.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    let mut command = Command::new(env!("CARGO_BIN_EXE_reflac"));
+    command
+        .args(args)
+        .current_dir(&scratch.path)
+        .env("PATH", path)
+        .env("XDG_CONFIG_HOME", scratch.join("config"))
+        .env("XDG_STATE_HOME", scratch.join("state"))
+        .env("TMPDIR", scratch.join("tmp"))
+        .env_remove("NO_COLOR")
+        .env_remove("REFLAC_JOBS");
+    command
+}
+
+/// Runs reflac with additional environment variables.
+pub fn reflac_with_env(scratch: &Scratch, args: &[&str], vars: &[(&str, &str)]) -> Output {
+    reflac_command(scratch, args)
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
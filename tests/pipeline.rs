@@ -0,0 +1,1774 @@
+//
+// Copyright 2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+mod common;
+
+use std::fs;
+
+use common::{
+    Scratch, override_tool, reflac, reflac_with_env, reflac_with_stdin, stderr, stdout, write_flac,
+    write_zip,
+};
+
+/// Three tracks of 0.2 s, each a different tone.
+fn album_fixture(scratch: &Scratch, trackinfo: &str) {
+    fs::create_dir_all(scratch.join("src")).unwrap();
+    for n in 1..=3 {
+        fs::write(
+            scratch.join(format!("src/{n:02} - Track.flac")),
+            common::flac_bytes(&tone(n), &[("TITLE", "Old")], None),
+        )
+        .unwrap();
+    }
+    fs::write(scratch.join("TRACKINFO"), trackinfo).unwrap();
+}
+
+fn tags(path: &std::path::Path) -> Vec<String> {
+    let mut tags_path = path.as_os_str().to_owned();
+    tags_path.push(".tags");
+    fs::read_to_string(tags_path)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// The samples the fake encoder was given for `path`.
+fn encoded_audio(path: &std::path::Path) -> Vec<u8> {
+    // Behind the marker and a STREAMINFO block
+    fs::read(path).unwrap()[42..].to_vec()
+}
+
+fn tone(n: usize) -> Vec<[i16; 2]> {
+    common::sine(220.0 * (n + 1) as f64, 0.2)
+}
+
+#[test]
+fn encodes_album_from_directory() {
+    let scratch = Scratch::new("directory");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nDATE=2020-01-02\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let album = scratch.join("Album");
+    for (n, title) in [(1, "One"), (2, "Two"), (3, "Three")] {
+        let path = album.join(format!("{n:02}. Artist - {title}.flac"));
+        assert_eq!(encoded_audio(&path), common::pcm(&tone(n)));
+        let tags = tags(&path);
+        assert!(tags.contains(&format!("TITLE={title}")));
+        assert!(tags.contains(&String::from("ARTIST=Artist")));
+        assert!(tags.contains(&format!("TRACKNUMBER={n}")));
+        assert!(tags.contains(&String::from("TRACKTOTAL=3")));
+        assert!(tags.contains(&String::from("DATE=2020-01-02")));
+        assert!(tags.contains(&String::from("ENCODER=flac 1.4.3")));
+        assert!(tags.contains(&String::from(
+            "ENCODERSETTINGS=--best --exhaustive-model-search --qlp-coeff-precision-search"
+        )));
+    }
+    assert!(album.join("reflac-run.toml").is_file());
+
+    // Only results go to stdout
+    let results: Vec<_> = stdout(&output).lines().map(String::from).collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[0].ends_with("01. Artist - One.flac"));
+    assert!(stderr(&output).contains("Recompressing ..."));
+}
+
+#[test]
+fn trackinfo_is_found_in_album_directory() {
+    let scratch = Scratch::new("discovery");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\n",
+    );
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/01. Artist - One.flac").is_file());
+
+    fs::write(scratch.join("other.trackinfo"), "").unwrap();
+    let output = reflac(&scratch, &["."]);
+    assert!(stderr(&output).contains("Several TRACKINFO files in .: TRACKINFO, other.trackinfo"));
+}
+
+#[test]
+fn disc_layout_keeps_folders_and_tags_consistent() {
+    let scratch = Scratch::new("disc-layout");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\n\
+         DISC=1\nTITLE[1]=One\nTITLE[2]=Two\nDISC=2\nTITLE[3]=Three\n",
+    );
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let tags = tags(&scratch.join("Album/Disc 2/03. Artist - Three.flac"));
+    assert!(tags.contains(&String::from("DISCNUMBER=2")));
+    assert!(tags.contains(&String::from("DISCTOTAL=2")));
+
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["--disc-layout", "flat", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/1-01. Artist - One.flac").is_file());
+    assert!(scratch.join("Album/2-03. Artist - Three.flac").is_file());
+    assert!(!scratch.join("Album/Disc 2").exists());
+
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=src\nALBUM=Mixed\nTITLE[1]=One\nDISC[2]=2\nTITLE[2]=Two\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["."]);
+    assert!(stderr(&output).contains("Some tracks have a DISC and others do not"));
+}
+
+#[test]
+fn encodes_album_from_disc_image() {
+    let scratch = Scratch::new("iso");
+    write_flac(&scratch.join("disc/FLAC/01 Foo.flac"), 0.1, &[], None);
+    // The fake 7z reads tar files instead of disc images
+    let status = std::process::Command::new("tar")
+        .args(["-cf", "album.iso", "-C", "disc", "FLAC"])
+        .current_dir(&scratch.path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::remove_dir_all(scratch.join("disc")).unwrap();
+    override_tool(
+        &scratch,
+        "7z",
+        "#!/bin/sh\n\
+         case \"$1\" in\n\
+         l) echo ----------; tar -tf \"$3\" | sed 's|/$||; s|^|Path = |' ;;\n\
+         x) tar -xf \"$3\" -C \"${2#-o}\" ;;\n\
+         esac\n",
+    );
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=album.iso\nALBUM=Imaged\nTITLE[1]=Foo\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Imaged/01. Foo.flac").is_file());
+}
+
+#[test]
+fn sources_are_recognized_by_content() {
+    let scratch = Scratch::new("probe");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    for (from, to) in [
+        ("01 - Track.flac", "01 - Track.FLAC"),
+        ("02 - Track.flac", "02 - Track"),
+        ("03 - Track.flac", "03 - Track.Flac"),
+    ] {
+        fs::rename(scratch.join("src").join(from), scratch.join("src").join(to)).unwrap();
+    }
+    fs::write(scratch.join("src/01 - Track.log"), "not audio").unwrap();
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#1 ← \"01 - Track.FLAC\""));
+    assert!(stderr(&output).contains("#2 ← \"02 - Track\""));
+    assert!(scratch.join("Album/03. Artist - Three.flac").is_file());
+}
+
+#[test]
+fn cue_sheets_are_split_into_tracks() {
+    let scratch = Scratch::new("cue");
+    write_flac(&scratch.join("rip/Image.flac"), 0.5, &[], None);
+    fs::write(
+        scratch.join("rip/Image.cue"),
+        "FILE \"Image.flac\" WAVE\n\
+         \x20 TRACK 01 AUDIO\n    TITLE \"One\"\n    INDEX 01 00:00:00\n\
+         \x20 TRACK 02 AUDIO\n    TITLE \"Two\"\n    INDEX 01 00:00:15\n\
+         \x20 TRACK 03 AUDIO\n    INDEX 00 00:00:20\n    INDEX 01 00:00:30\n",
+    )
+    .unwrap();
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=rip/Image.cue\nALBUM=Album\nARTIST=Artist\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    )
+    .unwrap();
+    override_tool(
+        &scratch,
+        "flac",
+        "#!/bin/sh\n\
+         for a; do case \"$a\" in --skip=*|--until=*) printf '%s ' \"$a\" >> splits;; esac; done\n\
+         case \"$*\" in *--skip=*) echo >> splits;; esac\n\
+         exec \"$(dirname \"$0\")/../bin/flac\" \"$@\"\n",
+    );
+
+    let output = reflac(&scratch, &["--dry-run", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#2 ← \"02 - Two.flac\""));
+    assert!(!scratch.join("splits").exists());
+
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(scratch.join("splits")).unwrap(),
+        "--skip=0 --until=8820 \n--skip=8820 --until=17640 \n--skip=17640 \n"
+    );
+    assert!(scratch.join("Album/03. Artist - Three.flac").is_file());
+
+    // A sheet naming the uncompressed image finds the FLAC one, even next to
+    // the WAV
+    let sheet = fs::read_to_string(scratch.join("rip/Image.cue")).unwrap();
+    fs::write(
+        scratch.join("rip/Image.cue"),
+        sheet.replace("\"Image.flac\"", "\"Image.wav\""),
+    )
+    .unwrap();
+    fs::write(scratch.join("rip/Image.wav"), "RIFF\0\0\0\0WAVE").unwrap();
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/03. Artist - Three.flac").is_file());
+
+    // Without it, a WAV image is refused by name
+    fs::remove_file(scratch.join("rip/Image.flac")).unwrap();
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["."]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Image.cue: image Image.wav must be FLAC"),
+        "{}",
+        stderr(&output)
+    );
+
+    fs::write(scratch.join("rip/Image.cue"), "TRACK 01 AUDIO\n").unwrap();
+    let output = reflac(&scratch, &["--dry-run", "."]);
+    assert!(
+        stderr(&output).contains("Image.cue: line 1: TRACK before FILE"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn compressed_sources_are_decompressed() {
+    let scratch = Scratch::new("compressed");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    for (tool, track) in [("gzip", "01"), ("xz", "02")] {
+        let status = std::process::Command::new(tool)
+            .arg(scratch.join(format!("src/{track} - Track.flac")))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let output = reflac(&scratch, &["."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/01. Artist - One.flac").is_file());
+    assert_eq!(
+        encoded_audio(&scratch.join("Album/02. Artist - Two.flac")),
+        common::pcm(&tone(2))
+    );
+}
+
+#[test]
+fn encodes_album_from_zip() {
+    let scratch = Scratch::new("zip");
+    let one = common::flac_bytes(&common::sine(440.0, 0.1), &[], None);
+    let two = common::flac_bytes(&common::sine(880.0, 0.1), &[], None);
+    write_zip(
+        &scratch.join("album.zip"),
+        &[
+            ("Album/01 Foo.flac", one.as_slice()),
+            ("Album/02 Bar.flac", two.as_slice()),
+            ("Album/cover.png", b"PNG"),
+        ],
+    );
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=album.zip\nALBUM=Zipped\nCOVER=cover.png\nTITLE[1]=Foo\nTITLE[2]=Bar\n",
+    )
+    .unwrap();
+    // ZIP archives are read without unzip
+    override_tool(&scratch, "unzip", "#!/bin/sh\nexit 1\n");
+
+    for args in [
+        &["./TRACKINFO", "."][..],
+        &["--stream-archives", "./TRACKINFO", "."],
+    ] {
+        let _ = fs::remove_dir_all(scratch.join("Zipped"));
+        let output = reflac(&scratch, args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(
+            encoded_audio(&scratch.join("Zipped/02. Bar.flac")),
+            common::pcm(&common::sine(880.0, 0.1)),
+            "{args:?}"
+        );
+    }
+}
+
+#[test]
+fn inputs_fall_back_in_order() {
+    let scratch = Scratch::new("fallback");
+    album_fixture(
+        &scratch,
+        "INPUT=gone.zip|web|src\nALBUM=Album\nARTIST=Artist\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    for n in 1..=2 {
+        write_flac(
+            &scratch.join(format!("web/{n:02} - Track.flac")),
+            0.1,
+            &[],
+            None,
+        );
+    }
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#3: falling back to \"src\""));
+    assert_eq!(
+        encoded_audio(&scratch.join("Album/01. Artist - One.flac")),
+        common::pcm(&common::sine(440.0, 0.1))
+    );
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(report.contains(r#""track":"1","input":"web""#));
+    assert!(report.contains(r#""track":"3","input":"src""#));
+}
+
+#[test]
+fn environment_is_recorded() {
+    let scratch = Scratch::new("environment");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(scratch.join("config/reflac/config"), "# mine\nGENRE=Jazz\n").unwrap();
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""tools":{"flac":"flac 1.4.3","#),
+        "{report}"
+    );
+    assert!(report.contains(r#""lines":["GENRE=Jazz"]"#), "{report}");
+    let provenance = fs::read_to_string(scratch.join("Album/reflac-run.toml")).unwrap();
+    assert!(provenance.contains("\nos = \""), "{provenance}");
+    assert!(
+        provenance.contains("lines = [\"GENRE=Jazz\"]"),
+        "{provenance}"
+    );
+}
+
+#[test]
+fn warnings_are_collected() {
+    let scratch = Scratch::new("warnings");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album \nARTIST=Artist\nTITLE[1]=One \nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("5 warnings: 3 matching, 2 trimmed"),
+        "{}",
+        stderr(&output)
+    );
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(
+            r#""warnings":[{"category":"trimmed","message":"Line \"ALBUM=Album \" trimmed!"}"#
+        ),
+        "{report}"
+    );
+}
+
+#[test]
+fn colliding_file_names_are_caught() {
+    let scratch = Scratch::new("collisions");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=Intro\nTITLE[2]=Song\nTITLE[3]=intro\n",
+    );
+    let args = ["--file-template", "{title}", "./TRACKINFO", "."];
+    let output = reflac(&scratch, &args);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Tracks would overwrite each other: #3 and #1 → \"intro.flac\""),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!scratch.join("Album").exists());
+
+    let output = reflac(
+        &scratch,
+        &[&["--on-collision", "suffix"], &args[..]].concat(),
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/Intro.flac").exists());
+    assert!(scratch.join("Album/intro (2).flac").exists());
+}
+
+#[test]
+fn trackinfo_is_read_from_stdin() {
+    let scratch = Scratch::new("stdin");
+    let trackinfo =
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n";
+    album_fixture(&scratch, "");
+    fs::remove_file(scratch.join("TRACKINFO")).unwrap();
+    fs::create_dir(scratch.join("rips")).unwrap();
+    fs::rename(scratch.join("src"), scratch.join("rips/src")).unwrap();
+
+    let output = reflac_with_stdin(&scratch, &["--base-dir", "rips", "-", "."], trackinfo);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/02. Artist - Two.flac").exists());
+}
+
+#[test]
+fn completed_trackinfo_reproduces_the_album() {
+    let scratch = Scratch::new("completed");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nGENRE=Jazz\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let completed = fs::read_to_string(scratch.join("Album/reflac.trackinfo")).unwrap();
+    let src = fs::canonicalize(scratch.join("src")).unwrap();
+    assert!(
+        completed.starts_with(&format!(
+            "INPUT[1]={}\nSOURCE[1]=01 - Track.flac\nTITLE[1]=One\nARTIST[1]=Artist\n",
+            src.display()
+        )),
+        "{completed}"
+    );
+    assert!(completed.contains("\n\nINPUT[2]="), "{completed}");
+    assert!(completed.contains("GENRE[3]=Jazz\n"), "{completed}");
+
+    // The album directory itself can be encoded again
+    let output = reflac(&scratch, &["-p", "./Album", "./again"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("again/Album/03. Artist - Three.flac").exists());
+}
+
+#[test]
+fn hooks_run_for_every_track() {
+    let scratch = Scratch::new("hooks");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nSTYLE=Dub; Ska\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let output = reflac(
+        &scratch,
+        &[
+            "--pre-track",
+            r#"echo "pre $REFLAC_TRACK $REFLAC_TAG_TITLE ${REFLAC_SOURCE##*/}" >> hooks.log"#,
+            "--post-track",
+            r#"test -f "$REFLAC_OUTPUT" && echo "post $REFLAC_TRACK $REFLAC_TAG_STYLE" >> hooks.log"#,
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(scratch.join("hooks.log")).unwrap(),
+        "pre 1 One 01 - Track.flac\npre 2 Two 02 - Track.flac\npre 3 Three 03 - Track.flac\n\
+         post 1 Dub; Ska\npost 2 Dub; Ska\npost 3 Dub; Ska\n"
+    );
+
+    let output = reflac(
+        &scratch,
+        &["--pre-track", "exit 3", "./TRACKINFO", "out", "-p"],
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Track 1: Failure executing: pre-track (exit status 3)"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn output_root_is_the_default_output() {
+    let scratch = Scratch::new("output-root");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(
+        scratch.join("config/reflac/config"),
+        "OUTPUT_ROOT=library\n",
+    )
+    .unwrap();
+
+    let output = reflac(&scratch, &["-p", "./TRACKINFO"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Writing album to library/Album"));
+    assert!(
+        scratch
+            .join("library/Album/01. Artist - One.flac")
+            .is_file()
+    );
+
+    // --output takes precedence, but not alongside the positional argument
+    let output = reflac(&scratch, &["--output", "elsewhere", "-p", "./TRACKINFO"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("elsewhere/Album").is_dir());
+    let output = reflac(&scratch, &["-o", "elsewhere", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn encoding_time_is_estimated_from_earlier_runs() {
+    let scratch = Scratch::new("estimate");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("estimated"));
+    assert!(scratch.join("state/reflac/calibration").is_file());
+
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("  estimated "),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn missing_metaflac_fails_before_encoding() {
+    let scratch = Scratch::new("no-metaflac");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    // Nothing but the fake flac, so no metaflac can be found
+    let bin = common::fake_tools(&scratch.join("bare"));
+    fs::remove_file(bin.join("metaflac")).unwrap();
+    let path = bin.to_str().unwrap();
+    let output = reflac_with_env(&scratch, &["./TRACKINFO", "."], &[("PATH", path)]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("pass --replaygain=off"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!scratch.join("Album").exists());
+
+    // Planning does not need it
+    let output = reflac_with_env(&scratch, &["plan", "./TRACKINFO", "."], &[("PATH", path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Dry run, not encoding."));
+}
+
+#[test]
+fn gain_groups_span_albums() {
+    let scratch = Scratch::new("gain-group");
+    for volume in ["Vol. 1", "Vol. 2"] {
+        write_flac(
+            &scratch.join(format!("{volume}/01. Track.flac")),
+            0.1,
+            &[],
+            None,
+        );
+    }
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --add-replay-gain ] && echo \"$#\" >> gain-calls\nexit 0\n",
+    );
+    let output = reflac(&scratch, &["gain", "--group", "Vol. 1", "Vol. 2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // One call for both files
+    assert_eq!(
+        fs::read_to_string(scratch.join("gain-calls")).unwrap(),
+        "3\n"
+    );
+
+    fs::remove_file(scratch.join("gain-calls")).unwrap();
+    let output = reflac(&scratch, &["gain", "Vol. 1", "Vol. 2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(scratch.join("gain-calls")).unwrap(),
+        "2\n2\n"
+    );
+}
+
+#[test]
+fn replaygain_is_reported() {
+    let scratch = Scratch::new("gain-report");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Loud\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    // metaflac's results, the second track much louder than the others
+    fs::create_dir_all(scratch.join("gained")).unwrap();
+    for (n, title, gain) in [
+        (1, "One", "-6.80"),
+        (2, "Two", "-11.20"),
+        (3, "Three", "-7.10"),
+    ] {
+        let comments = [
+            ("REPLAYGAIN_TRACK_GAIN", format!("{gain} dB")),
+            ("REPLAYGAIN_TRACK_PEAK", String::from("0.98852539")),
+            ("REPLAYGAIN_ALBUM_GAIN", String::from("-7.00 dB")),
+            ("REPLAYGAIN_ALBUM_PEAK", String::from("0.99996948")),
+        ];
+        let comments: Vec<_> = comments.iter().map(|(f, v)| (*f, v.as_str())).collect();
+        fs::write(
+            scratch.join(format!("gained/{n:02}. {title}.flac")),
+            common::flac_bytes(&tone(n), &comments, None),
+        )
+        .unwrap();
+    }
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\"\n\
+         [ \"$1\" = --add-replay-gain ] || exit 0\nshift\n\
+         for f in \"$@\"; do cp \"gained/$(basename \"$f\")\" \"$f\"; done\n",
+    );
+    let output = reflac(
+        &scratch,
+        &[
+            "--gain-outliers",
+            "3",
+            "--report",
+            "./report.json",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#2 is 4.2 dB louder than the album"));
+    assert!(!stderr(&output).contains("#3 is"));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(
+            r#""replaygain":{"track_gain":"-11.20 dB","track_peak":"0.98852539","album_gain":"-7.00 dB","album_peak":"0.99996948"}"#
+        ),
+        "{report}"
+    );
+    assert!(report.contains(r#""category":"loudness""#));
+}
+
+#[test]
+fn shared_covers_are_flagged_and_blocklisted() {
+    let scratch = Scratch::new("shared-cover");
+    album_fixture(&scratch, "");
+    fs::write(scratch.join("src/cover.png"), b"no image").unwrap();
+    let run = |album: &str| {
+        fs::write(
+            scratch.join("TRACKINFO"),
+            format!("INPUT=src\nALBUM={album}\nCOVER=cover.png\nTITLE[1]=One\n"),
+        )
+        .unwrap();
+        let output = reflac(&scratch, &["./TRACKINFO", "."]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        stderr(&output)
+    };
+    for album in ["A", "B", "C"] {
+        assert!(!run(album).contains("may be a placeholder"));
+    }
+    assert!(run("D").contains("already the cover of 3 albums"));
+
+    let provenance = fs::read_to_string(scratch.join("D/reflac-run.toml")).unwrap();
+    let digest = provenance
+        .lines()
+        .find_map(|l| l.strip_prefix("cover_sha256 = "))
+        .unwrap()
+        .trim_matches('"')
+        .to_string();
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(
+        scratch.join("config/reflac/config"),
+        format!("COVER_BLOCKLIST={digest}\n"),
+    )
+    .unwrap();
+    assert!(run("E").contains("it is in COVER_BLOCKLIST"));
+    let provenance = fs::read_to_string(scratch.join("E/reflac-run.toml")).unwrap();
+    assert!(!provenance.contains("cover_sha256"));
+}
+
+#[test]
+fn booklets_become_covers() {
+    let scratch = Scratch::new("booklet");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nCOVER=auto\nTITLE[1]=One\n",
+    );
+    fs::write(scratch.join("src/Digital Booklet.pdf"), b"%PDF-1.4").unwrap();
+    override_tool(
+        &scratch,
+        "pdftoppm",
+        "#!/bin/sh\nfor a; do last=\"$a\"; done\nprintf 'jpeg' > \"$last.jpg\"\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Cover: first page of"));
+    assert_eq!(
+        fs::read(scratch.join("Album/Digital Booklet.pdf")).unwrap(),
+        b"%PDF-1.4"
+    );
+}
+
+#[test]
+fn best_source_is_picked_per_track() {
+    let scratch = Scratch::new("best-source");
+    album_fixture(
+        &scratch,
+        "INPUT=web|src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\n",
+    );
+    // The web rip has a truncated first track
+    write_flac(&scratch.join("web/01 - Track.flac"), 0.1, &[], None);
+    write_flac(&scratch.join("web/02 - Track.flac"), 0.3, &[], None);
+    let output = reflac(
+        &scratch,
+        &[
+            "--best-source",
+            "--source-policy",
+            "duration",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#1: using \"src\""));
+    assert!(stderr(&output).contains("#2: using \"web\""));
+    assert_eq!(
+        encoded_audio(&scratch.join("Album/01. Artist - One.flac")),
+        common::pcm(&tone(1))
+    );
+}
+
+#[test]
+fn rejects_unsafe_archives() {
+    let scratch = Scratch::new("unsafe");
+    write_zip(&scratch.join("evil.zip"), &[("../01.flac", b"x")]);
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=evil.zip\nALBUM=Evil\nTITLE[1]=One\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("unsafe member"));
+    assert!(!scratch.join("01.flac").exists());
+}
+
+#[test]
+fn rejects_archived_symlinks_before_extracting() {
+    let scratch = Scratch::new("symlink");
+    fs::write(scratch.join("evil.7z"), "").unwrap();
+    override_tool(
+        &scratch,
+        "7za",
+        &format!(
+            "#!/bin/sh\n\
+             case \"$1\" in\n\
+             l) printf '%s\\n' ---------- 'Path = 01.flac' 'Attributes = A_ lrwxrwxrwx' \
+             'Symbolic Link = ../../etc/passwd' ;;\n\
+             x) touch \"{}\" ;;\n\
+             esac\n",
+            scratch.join("extracted").display()
+        ),
+    );
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=evil.7z\nALBUM=Evil\nTITLE[1]=One\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("unsafe member \"01.flac\""));
+    assert!(!scratch.join("extracted").exists());
+}
+
+#[test]
+fn rejects_zipped_symlinks() {
+    let scratch = Scratch::new("zip-symlink");
+    let path = scratch.join("evil.zip");
+    write_zip(&path, &[("01.flac", b"/etc/passwd")]);
+    // Mark the member as a symlink made on Unix
+    let mut data = fs::read(&path).unwrap();
+    let entry = data.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+    data[entry + 5] = 3;
+    data[entry + 38..entry + 42].copy_from_slice(&(0o120777u32 << 16).to_le_bytes());
+    fs::write(&path, data).unwrap();
+    override_tool(
+        &scratch,
+        "unzip",
+        &format!(
+            "#!/bin/sh\ntouch \"{}\"\n",
+            scratch.join("unzipped").display()
+        ),
+    );
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=evil.zip\nALBUM=Evil\nTITLE[1]=One\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("unsafe member \"01.flac\""));
+    assert!(!scratch.join("unzipped").exists());
+}
+
+#[test]
+fn dry_run_lists_changes_without_encoding() {
+    let scratch = Scratch::new("dry-run");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Dry\nARTIST=Main\nTITLE[1]=Song (feat. Guest)\n",
+    );
+    let output = reflac(
+        &scratch,
+        &["--feat", "artist", "--dry-run", "./TRACKINFO", "."],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#1 ARTIST: \"Main\" → \"Main feat. Guest\""));
+    assert!(stderr(&output).contains("#1 ← \"01 - Track.flac\""));
+    assert!(stderr(&output).contains("    ARTIST=Main feat. Guest\n"));
+    assert!(!scratch.join("Dry").exists());
+
+    // Archives are listed, not extracted
+    let one = common::flac_bytes(&common::sine(440.0, 0.1), &[], None);
+    write_zip(&scratch.join("album.zip"), &[("Album/01 Foo.flac", &one)]);
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=album.zip\nALBUM=Zipped\nTITLE[1]=Foo\n",
+    )
+    .unwrap();
+    override_tool(&scratch, "flac", "#!/bin/sh\nexit 1\n");
+    let output = reflac(&scratch, &["--dry-run", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = stderr(&output);
+    assert!(log.contains("#1 ← \"01 Foo.flac\""), "{log}");
+    assert!(
+        log.contains("Zipped/01. Foo.flac\"\n    TITLE=Foo\n"),
+        "{log}"
+    );
+    assert!(!scratch.join("Zipped").exists());
+}
+
+#[test]
+fn vinyl_positions_map_to_files() {
+    let scratch = Scratch::new("vinyl");
+    for name in ["A1", "A2", "B1"] {
+        write_flac(&scratch.join(format!("src/{name}.flac")), 0.1, &[], None);
+    }
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=src\nALBUM=Vinyl\nTITLE[B1]=Baz\nTITLE[A1]=Foo\nTITLE[A2]=Bar\n",
+    )
+    .unwrap();
+    let output = reflac(
+        &scratch,
+        &[
+            "--side-numbering",
+            "--file-template",
+            "{position} {title}",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let baz = tags(&scratch.join("Vinyl/B1 Baz.flac"));
+    assert!(baz.contains(&String::from("TRACKNUMBER=1")));
+    assert!(baz.contains(&String::from("TRACKTOTAL=1")));
+    assert!(scratch.join("Vinyl/A2 Bar.flac").is_file());
+}
+
+#[test]
+fn flaky_tools_are_retried() {
+    let scratch = Scratch::new("retry");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Retried\nTITLE[1]=One\n");
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\" && exit 0\n\
+         [ -e failed-once ] && exit 0\n: > failed-once\necho flaky >&2\nexit 1\n",
+    );
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    fs::remove_file(scratch.join("failed-once")).unwrap();
+    fs::remove_dir_all(scratch.join("Retried")).unwrap();
+
+    let output = reflac(
+        &scratch,
+        &[
+            "--retries",
+            "1",
+            "--retry-delay",
+            "0",
+            "--report",
+            "./report.json",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""retries":[{"command":"metaflac","track":null,"attempt":"1","#),
+        "{report}"
+    );
+    assert!(report.contains("flaky"));
+}
+
+#[test]
+fn hung_archive_tools_time_out() {
+    let scratch = Scratch::new("hung-listing");
+    fs::write(scratch.join("album.7z"), "").unwrap();
+    override_tool(&scratch, "7za", "#!/bin/sh\nexec sleep 30\n");
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=album.7z\nALBUM=Hung\nTITLE[1]=One\n",
+    )
+    .unwrap();
+    let started = std::time::Instant::now();
+    let output = reflac(
+        &scratch,
+        &[
+            "--timeout",
+            "1",
+            "--report",
+            "./report.json",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(started.elapsed() < std::time::Duration::from_secs(20));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(report.contains(r#""error_code":"timed-out""#), "{report}");
+}
+
+#[test]
+fn old_encoders_refuse_32_bit_sources() {
+    let scratch = Scratch::new("32-bit");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Wide\nTITLE[1]=One\n");
+    // Claim 32 bits per sample in STREAMINFO
+    let path = scratch.join("src/01 - Track.flac");
+    let mut data = fs::read(&path).unwrap();
+    let mut packed = u64::from_be_bytes(data[18..26].try_into().unwrap());
+    packed = (packed & !(0x1f << 36)) | (31 << 36);
+    data[18..26].copy_from_slice(&packed.to_be_bytes());
+    fs::write(&path, data).unwrap();
+    override_tool(
+        &scratch,
+        "flac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"flac 1.3.4\" && exit 0\nexit 1\n",
+    );
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Your flac 1.3 is too old for 32-bit input"),
+        "{}",
+        stderr(&output)
+    );
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"encoder-too-old""#),
+        "{report}"
+    );
+}
+
+#[test]
+fn mixed_formats_are_flagged() {
+    let scratch = Scratch::new("mixed-formats");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Mixed\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    // Claim 48 kHz in the STREAMINFO of the second track
+    let path = scratch.join("src/02 - Track.flac");
+    let mut data = fs::read(&path).unwrap();
+    let mut packed = u64::from_be_bytes(data[18..26].try_into().unwrap());
+    packed = (packed & !(0xf_ffff << 44)) | (48000 << 44);
+    data[18..26].copy_from_slice(&packed.to_be_bytes());
+    fs::write(&path, data).unwrap();
+
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains(
+            "Tracks of the album differ in format: 44100 Hz/16 bit (#1, #3), 48000 Hz/16 bit (#2)"
+        ),
+        "{}",
+        stderr(&output)
+    );
+
+    fs::remove_dir_all(scratch.join("Mixed")).unwrap();
+    let output = reflac(
+        &scratch,
+        &[
+            "--require-uniform-format",
+            "--report",
+            "./report.json",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(!scratch.join("Mixed").exists());
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"mixed-formats""#),
+        "{report}"
+    );
+}
+
+#[test]
+fn salvaged_tracks_are_reported() {
+    let scratch = Scratch::new("salvage");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Damaged\nTITLE[1]=One\n");
+    // A decoder that conceals one damaged frame of the first track
+    override_tool(
+        &scratch,
+        "flac",
+        "#!/bin/sh\nout=\"\"; last=\"\"; salvage=0\nfor a in \"$@\"; do\n\
+         case \"$a\" in --decode-through-errors) salvage=1;; \
+         --output-name=*) out=\"${a#--output-name=}\";; esac\nlast=\"$a\"\ndone\n\
+         if [ \"$last\" = - ]; then cat > \"$out\"; exit 0; fi\n\
+         case \"$last\" in *01*) [ $salvage = 1 ] && \
+         echo \"$last: *** Got error code 0:FLAC__STREAM_DECODER_ERROR_STATUS_LOST_SYNC\" >&2;; esac\n\
+         cat \"$last\"\n",
+    );
+    let output = reflac(
+        &scratch,
+        &["--salvage", "--report", "./report.json", "./TRACKINFO", "."],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#1: salvaged, 1 damaged frame(s) concealed"));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(report.contains(r#""bad_frames":"1""#), "{report}");
+    let provenance = fs::read_to_string(scratch.join("Damaged/reflac-run.toml")).unwrap();
+    assert!(provenance.contains("bad_frames = 1\n"), "{provenance}");
+}
+
+#[test]
+fn automatic_cover_prefers_front() {
+    let scratch = Scratch::new("auto-cover");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Covered\nCOVER=auto\nTITLE[1]=One\n",
+    );
+    let png = |width: u32, height: u32| {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data
+    };
+    fs::create_dir_all(scratch.join("src/scans")).unwrap();
+    fs::write(scratch.join("src/scans/inlay.png"), png(3000, 3000)).unwrap();
+    fs::write(scratch.join("src/front.png"), png(1000, 1000)).unwrap();
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Cover: front.png (1000×1000, 1 KiB)"));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""covers":["./src/front.png"]"#),
+        "{report}"
+    );
+}
+
+#[test]
+fn extractions_are_cached() {
+    let scratch = Scratch::new("cache");
+    let one = common::flac_bytes(&common::sine(440.0, 0.1), &[], None);
+    write_zip(
+        &scratch.join("album.zip"),
+        &[("Album/01 Foo.flac", one.as_slice())],
+    );
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=album.zip\nALBUM=Cached\nTITLE[1]=Foo\n",
+    )
+    .unwrap();
+    let args = ["--cache", "--cache-dir", "./cache", "./TRACKINFO", "."];
+    let output = reflac(&scratch, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("Using cached extraction"));
+    fs::remove_dir_all(scratch.join("Cached")).unwrap();
+
+    let output = reflac(&scratch, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Using cached extraction of"));
+    assert!(scratch.join("Cached/01. Foo.flac").is_file());
+
+    fs::write(scratch.join("config"), "CACHE_DIR=./cache\n").unwrap();
+    let output = reflac(&scratch, &["cache", "clean", "--config", "./config"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!scratch.join("cache/archives").exists());
+}
+
+#[test]
+fn uncertain_mappings_are_reviewed() {
+    let scratch = Scratch::new("confidence");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Unsure\nTITLE[1]=Track\nTITLE[2]=Something Else\n",
+    );
+    let output = reflac(
+        &scratch,
+        &[
+            "--review",
+            "./review.txt",
+            "--min-confidence",
+            "60",
+            "--report",
+            "./report.json",
+            "./TRACKINFO",
+            ".",
+        ],
+    );
+    assert!(!output.status.success());
+    let review = fs::read_to_string(scratch.join("review.txt")).unwrap();
+    assert_eq!(review, "#2 (0%): Something Else ← ./src/02 - Track.flac\n");
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"low-confidence""#),
+        "{report}"
+    );
+    assert!(!scratch.join("Unsure").exists());
+
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(report.contains(r#""confidence":"100""#), "{report}");
+    assert!(report.contains(r#""confidence":"0""#), "{report}");
+}
+
+#[test]
+fn ab_compares_output_with_source() {
+    let scratch = Scratch::new("ab");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[2]=Two\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = reflac(&scratch, &["ab", "Album", "2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Identical: "));
+
+    // Other audio differs
+    write_flac(
+        &scratch.join("Album/02. Artist - Two.flac"),
+        0.2,
+        &[("TITLE", "New")],
+        None,
+    );
+    let output = reflac(&scratch, &["ab", "Album", "2"]);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("First difference: sample "));
+    assert!(stderr(&output).contains("differs from its source"));
+
+    let output = reflac(&scratch, &["ab", "Album", "9"]);
+    assert!(stderr(&output).contains("Track not found in the album: 9"));
+
+    fs::write(scratch.join("notes.txt"), "not audio").unwrap();
+    let output = reflac(&scratch, &["ab", "Album", "2", "--source", "notes.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Not a FLAC source: notes.txt"));
+
+    // A trailing option without its value is not ignored
+    let output = reflac(&scratch, &["ab", "Album", "2", "--source"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).starts_with("USAGE: "));
+}
+
+#[test]
+fn ab_finds_sources_below_the_base_dir() {
+    let scratch = Scratch::new("ab-base-dir");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Album\nTITLE[1]=One\n");
+    fs::create_dir(scratch.join("rips")).unwrap();
+    fs::rename(scratch.join("src"), scratch.join("rips/src")).unwrap();
+    let output = reflac(&scratch, &["--base-dir", "rips", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = reflac(&scratch, &["ab", "Album", "1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Identical: "));
+}
+
+#[test]
+fn sources_from_this_encoder_are_kept() {
+    let scratch = Scratch::new("kept");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\n",
+    );
+    write_flac(
+        &scratch.join("src/02 - Track.flac"),
+        0.3,
+        &[
+            ("ENCODER", "flac 1.4.3"),
+            (
+                "ENCODERSETTINGS",
+                "--best --exhaustive-model-search --qlp-coeff-precision-search",
+            ),
+        ],
+        None,
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#2 is already optimal, keeping source"));
+    // Only encoded files have tags recorded by the fake encoder
+    assert!(scratch.join("Album/01. Artist - One.flac.tags").exists());
+    assert!(!scratch.join("Album/02. Artist - Two.flac.tags").exists());
+
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["--force-reencode", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("already optimal"));
+    assert!(scratch.join("Album/02. Artist - Two.flac.tags").exists());
+}
+
+#[test]
+fn identical_audio_is_encoded_once() {
+    let scratch = Scratch::new("duplicates");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\nTITLE[2]=Two\n",
+    );
+    // The same audio as the first track, so the same STREAMINFO MD5
+    write_flac(&scratch.join("src/02 - Track.flac"), 0.2, &[], None);
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("#2 has the same audio as #1"));
+    assert!(scratch.join("Album/02. Artist - Two.flac").is_file());
+    assert!(!scratch.join("Album/02. Artist - Two.flac.tags").exists());
+
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["--encode-duplicates", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("Album/02. Artist - Two.flac.tags").exists());
+}
+
+#[test]
+fn libraries_over_quota_are_reported() {
+    let scratch = Scratch::new("quota");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\n",
+    );
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(scratch.join("config/reflac/config"), "QUOTA=1K\n").unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("over its quota of 1 MiB"));
+
+    let output = reflac(&scratch, &["prune-report", "--quota", "1K", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("inefficient\t1 MiB\t"));
+    assert!(stdout(&output).trim_end().ends_with("./Album"));
+}
+
+#[test]
+fn profiles_bundle_settings() {
+    let scratch = Scratch::new("profiles");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nPROFILE=fast\nALBUM=Album\nARTIST=Artist\nTITLE[1]=One\n",
+    );
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(
+        scratch.join("config/reflac/config"),
+        "ENCODER_SETTINGS[fast]=-5\nREPLAYGAIN[fast]=off\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Skipping ReplayGain"));
+    let tags = tags(&scratch.join("Album/01. Artist - One.flac"));
+    assert!(tags.contains(&String::from("ENCODERSETTINGS=-5")));
+
+    fs::write(scratch.join("config/reflac/config"), "").unwrap();
+    fs::remove_dir_all(scratch.join("Album")).unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(stderr(&output).contains("Profile not found in the configuration: fast"));
+}
+
+#[test]
+fn tag_providers_fill_in_tags() {
+    let scratch = Scratch::new("provider");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nPROVIDER=db\nALBUM=Album\nARTIST=Artist\n\
+         TITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    override_tool(
+        &scratch,
+        "tagdb",
+        "#!/bin/sh\ncat > request.json\n\
+         echo '{\"album\":{\"LABEL\":\"In-house\"},\"tracks\":[{\"track\":\"2\",\"fields\":{\"GENRE\":\"Jazz\",\"TITLE\":\"Ignored\"}}]}'\n",
+    );
+    fs::create_dir_all(scratch.join("config/reflac")).unwrap();
+    fs::write(
+        scratch.join("config/reflac/config"),
+        "PROVIDER[db]=tagdb --quick\n",
+    )
+    .unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let request = fs::read_to_string(scratch.join("request.json")).unwrap();
+    assert!(
+        request.starts_with(r#"{"version":"1","tracks":[{"track":"1","fields":{"INPUT":"src","#),
+        "{request}"
+    );
+    let tags = tags(&scratch.join("Album/02. Artist - Two.flac"));
+    assert!(tags.contains(&String::from("GENRE=Jazz")), "{tags:?}");
+    assert!(tags.contains(&String::from("LABEL=In-house")), "{tags:?}");
+    assert!(tags.contains(&String::from("TITLE=Two")), "{tags:?}");
+
+    override_tool(
+        &scratch,
+        "tagdb",
+        "#!/bin/sh\necho 'no such album' >&2\nexit 1\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "out", "-p"]);
+    assert!(
+        stderr(&output).contains("Tag provider db failed: exit status 1: no such album"),
+        "{}",
+        stderr(&output)
+    );
+
+    override_tool(
+        &scratch,
+        "tagdb",
+        "#!/bin/sh\necho '{\"album\":{\"TITLE[3]\":\"Sneaky\"}}'\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "out", "-p"]);
+    assert!(
+        stderr(&output).contains("Tag provider db failed: invalid field name \"TITLE[3]\""),
+        "{}",
+        stderr(&output)
+    );
+
+    override_tool(&scratch, "tagdb", "#!/bin/sh\nexec sleep 30\n");
+    let output = reflac(&scratch, &["--timeout", "1", "./TRACKINFO", "out", "-p"]);
+    assert!(
+        stderr(&output).contains("Tag provider db failed: timed out after 1s"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn jobs_cap_concurrent_encoders() {
+    let scratch = Scratch::new("jobs");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Capped\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    // Records the most encoders seen running at once
+    override_tool(
+        &scratch,
+        "flac",
+        "#!/bin/sh\ncase \"$*\" in *--force-raw-format*) ;; *) exec \"$(dirname \"$0\")/../bin/flac\" \"$@\";; esac\n\
+         touch \"$TMPDIR/running.$$\"; n=$(ls \"$TMPDIR\" | grep -c '^running\\.')\n\
+         echo $n >> \"$TMPDIR/concurrency\"; sleep 0.3\n\
+         \"$(dirname \"$0\")/../bin/flac\" \"$@\"; status=$?; rm \"$TMPDIR/running.$$\"; exit $status\n",
+    );
+    let most = || {
+        let seen = fs::read_to_string(scratch.join("tmp/concurrency")).unwrap();
+        fs::remove_file(scratch.join("tmp/concurrency")).unwrap();
+        seen.lines()
+            .map(|n| n.parse::<usize>().unwrap())
+            .max()
+            .unwrap()
+    };
+    let output = reflac_with_env(
+        &scratch,
+        &["--jobs", "1", "./TRACKINFO", "."],
+        &[("REFLAC_JOBS", "3")],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(most(), 1);
+
+    fs::remove_dir_all(scratch.join("Capped")).unwrap();
+    let output = reflac_with_env(&scratch, &["./TRACKINFO", "."], &[("REFLAC_JOBS", "2")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(most(), 2);
+
+    fs::remove_dir_all(scratch.join("Capped")).unwrap();
+    let output = reflac_with_env(&scratch, &["./TRACKINFO", "."], &[("REFLAC_JOBS", "0")]);
+    assert!(stderr(&output).contains("Invalid value of REFLAC_JOBS: 0"));
+    assert!(
+        !reflac(&scratch, &["--jobs", "0", "./TRACKINFO", "."])
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn writes_are_throttled() {
+    let scratch = Scratch::new("throttle");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Slow\nTITLE[1]=One\nTITLE[2]=Two\nTITLE[3]=Three\n",
+    );
+    let started = std::time::Instant::now();
+    let output = reflac(&scratch, &["--max-write-MBps", "0.5", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The second and third track of about 35 kB each wait for the ones before
+    assert!(started.elapsed() >= std::time::Duration::from_millis(140));
+    for (n, title) in [(1, "One"), (2, "Two"), (3, "Three")] {
+        let path = scratch.join(format!("Slow/{n:02}. {title}.flac"));
+        assert_eq!(encoded_audio(&path), common::pcm(&tone(n)));
+    }
+    let output = reflac(&scratch, &["--max-write-MBps", "0", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn keep_temp_leaves_work_dir() {
+    let scratch = Scratch::new("keep-temp");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Kept\nTITLE[1]=One\n");
+    let output = reflac(&scratch, &["--keep-temp", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let kept: Vec<_> = fs::read_dir(scratch.join("tmp"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    assert_eq!(kept.len(), 1, "{kept:?}");
+    assert!(stderr(&output).contains("Keeping temporary directory"));
+}
+
+#[test]
+fn missing_track_fails() {
+    let scratch = Scratch::new("missing");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Missing\nTITLE[7]=Seven\n");
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Input file not found for track: 7"));
+}
+
+#[test]
+fn missing_album_fails_cleanly() {
+    let scratch = Scratch::new("no-album");
+    for trackinfo in ["INPUT=src\nTITLE[1]=One\n", ""] {
+        album_fixture(&scratch, trackinfo);
+        let output = reflac(
+            &scratch,
+            &[
+                "--keep-temp",
+                "--report",
+                "./report.json",
+                "./TRACKINFO",
+                ".",
+            ],
+        );
+        assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+        assert!(stderr(&output).contains("TRACKINFO has no ALBUM"));
+        let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+        assert!(
+            report.contains(r#""error_code":"missing-field""#),
+            "{report}"
+        );
+        // Planning failed, so no work directory was created
+        let work_dirs = fs::read_dir(scratch.join("tmp"))
+            .unwrap()
+            .flatten()
+            .filter(|e| e.path().is_dir())
+            .count();
+        assert_eq!(work_dirs, 0);
+    }
+}
+
+#[test]
+fn missing_output_dir_is_reported() {
+    let scratch = Scratch::new("no-output");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Lost\nTITLE[1]=One\n");
+    let output = reflac(
+        &scratch,
+        &["--report", "./report.json", "./TRACKINFO", "./nowhere"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"path-does-not-exist""#),
+        "{report}"
+    );
+    // The work directory is cleaned up
+    assert_eq!(fs::read_dir(scratch.join("tmp")).unwrap().count(), 0);
+}
+
+#[test]
+fn report_carries_error_code() {
+    let scratch = Scratch::new("report-error");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Missing\nTITLE[7]=Seven\n");
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(report.contains(r#""status":"failed""#), "{report}");
+    assert!(report.contains(r#""error_code":"input-track-not-found""#));
+    assert!(report.contains(r#""error_context":{"track":"7"}"#));
+}
+
+#[test]
+fn runs_resume_after_encoding() {
+    let scratch = Scratch::new("resume");
+    album_fixture(
+        &scratch,
+        "INPUT=src\nALBUM=Resumed\nTITLE[1]=One\nTITLE[2]=Two\n",
+    );
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\"\n\
+         case \"$*\" in *--add-replay-gain*) echo crashed >&2; exit 1;; esac\nexit 0\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(scratch.join("Resumed/.reflac-checkpoint").is_file());
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(stderr(&output).contains("finish it with --resume"));
+
+    // Nothing is encoded again
+    override_tool(
+        &scratch,
+        "flac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"flac 1.4.3\" && exit 0\nexit 1\n",
+    );
+    fs::remove_file(scratch.join("override/metaflac")).unwrap();
+    let output = reflac(&scratch, &["--resume", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Resuming after encoding ..."));
+    assert!(stderr(&output).contains("Adding ReplayGain ..."));
+    let album = scratch.join("Resumed");
+    assert!(!album.join(".reflac-checkpoint").exists());
+    assert!(album.join("reflac-run.toml").is_file());
+    assert_eq!(
+        encoded_audio(&album.join("02. Two.flac")),
+        common::pcm(&tone(2))
+    );
+    assert_eq!(stdout(&output).lines().count(), 2);
+}
+
+#[test]
+fn resuming_records_the_run_once() {
+    let scratch = Scratch::new("resume-late");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Late\nTITLE[1]=One\n");
+    // Blocks the completed TRACKINFO, so the run dies after writing its
+    // provenance
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\"\n\
+         case \"$*\" in *--add-replay-gain*)\n\
+         for last; do :; done; mkdir \"$(dirname \"$last\")/reflac.trackinfo\";;\n\
+         esac\nexit 0\n",
+    );
+    let output = reflac(&scratch, &["./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    let album = scratch.join("Late");
+    assert!(album.join(".reflac-checkpoint").is_file());
+    assert!(album.join("reflac-run.toml").is_file());
+
+    fs::remove_file(scratch.join("override/metaflac")).unwrap();
+    fs::remove_dir(album.join("reflac.trackinfo")).unwrap();
+    let output = reflac(&scratch, &["--resume", "./TRACKINFO", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("ReplayGain already added"));
+    assert!(!album.join(".reflac-checkpoint").exists());
+    assert!(!album.join("reflac-run-2.toml").exists());
+    let completed = fs::read_to_string(album.join("reflac.trackinfo")).unwrap();
+    assert_eq!(completed.matches("TITLE[1]=One").count(), 1, "{completed}");
+}
+
+#[test]
+fn resuming_needs_the_same_trackinfo() {
+    let scratch = Scratch::new("resume-mismatch");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Resumed\nTITLE[1]=One\n");
+    override_tool(
+        &scratch,
+        "metaflac",
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo \"metaflac 1.4.3\"\n\
+         case \"$*\" in *--add-replay-gain*) exit 1;; esac\nexit 0\n",
+    );
+    assert!(!reflac(&scratch, &["./TRACKINFO", "."]).status.success());
+    fs::write(
+        scratch.join("TRACKINFO"),
+        "INPUT=src\nALBUM=Resumed\nTITLE[1]=Uno\n",
+    )
+    .unwrap();
+    let output = reflac(
+        &scratch,
+        &["--resume", "--report", "./report.json", "./TRACKINFO", "."],
+    );
+    assert!(!output.status.success());
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"checkpoint-mismatch""#),
+        "{report}"
+    );
+}
+
+#[test]
+fn damaged_sources_fail_to_decode() {
+    let scratch = Scratch::new("damaged");
+    album_fixture(&scratch, "INPUT=src\nALBUM=Damaged\nTITLE[2]=Two\n");
+    let path = scratch.join("src/02 - Track.flac");
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 10;
+    data[last] ^= 0x01;
+    fs::write(&path, data).unwrap();
+    let output = reflac(&scratch, &["--report", "./report.json", "./TRACKINFO", "."]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("frame CRC mismatch"),
+        "{}",
+        stderr(&output)
+    );
+    let report = fs::read_to_string(scratch.join("report.json")).unwrap();
+    assert!(
+        report.contains(r#""error_code":"decode-failed""#),
+        "{report}"
+    );
+}
+
+#[test]
+fn exported_trackinfo_round_trips() {
+    let scratch = Scratch::new("export");
+    write_flac(
+        &scratch.join("album/01. One.flac"),
+        0.1,
+        &[
+            ("TITLE", "One"),
+            ("ALBUM", "Album"),
+            ("ARTIST", "Artist"),
+            ("TRACKNUMBER", "1"),
+        ],
+        Some(b"PNG"),
+    );
+    write_flac(
+        &scratch.join("album/02. Two.flac"),
+        0.1,
+        &[
+            ("TITLE", "Two"),
+            ("ALBUM", "Album"),
+            ("ARTIST", "Guest"),
+            ("TRACKNUMBER", "2"),
+        ],
+        Some(b"PNG"),
+    );
+    let output = reflac(&scratch, &["export-trackinfo", "album"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let trackinfo = stdout(&output);
+    assert!(trackinfo.contains("\nALBUM=Album\n"));
+    assert!(trackinfo.contains("COVER=01. One.flac\n"));
+    assert!(trackinfo.contains("ARTIST[2]=Guest\n"));
+    assert!(trackinfo.contains("TITLE[1]=One\n"));
+
+    fs::write(scratch.join("TRACKINFO"), trackinfo).unwrap();
+    fs::create_dir(scratch.join("out")).unwrap();
+    let output = reflac(&scratch, &["./TRACKINFO", "out"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(scratch.join("out/Album/02. Guest - Two.flac").is_file());
+}
+
+#[test]
+fn tag_edits_keep_audio() {
+    let scratch = Scratch::new("tag");
+    let path = scratch.join("album/01. One.flac");
+    write_flac(&path, 0.1, &[("GENRE", "Rock"), ("TITLE", "One")], None);
+    let audio = fs::read(&path).unwrap();
+    let audio = &audio[audio.len() - 1000..];
+
+    let output = reflac(
+        &scratch,
+        &["tag", "set", "--dry-run", "album", "GENRE=Post-Rock"],
+    );
+    assert!(stdout(&output).contains("GENRE: \"Rock\" → \"Post-Rock\""));
+    assert!(fs::read(&path).unwrap().ends_with(audio));
+
+    let output = reflac(&scratch, &["tag", "set", "album", "GENRE=Post-Rock"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = reflac(&scratch, &["tag", "rename", "album", "GENRE", "STYLE"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read(&path).unwrap().ends_with(audio));
+
+    let output = reflac(&scratch, &["export-trackinfo", "album"]);
+    assert!(!stdout(&output).contains("GENRE"));
+    let output = reflac(&scratch, &["tag", "set", "--dry-run", "album", "STYLE=x"]);
+    assert!(stdout(&output).contains("STYLE: \"Post-Rock\" → \"x\""));
+}
+
+#[test]
+fn art_extract_writes_cover() {
+    let scratch = Scratch::new("art");
+    write_flac(&scratch.join("album/01.flac"), 0.1, &[], Some(b"PNG DATA"));
+    let output = reflac(&scratch, &["art", "extract", "album"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output).trim(), "cover.png");
+    assert_eq!(fs::read(scratch.join("cover.png")).unwrap(), b"PNG DATA");
+}
+
+#[test]
+fn subcommands_plan_encode_and_verify() {
+    let scratch = Scratch::new("subcommands");
+    let output = reflac(&scratch, &["--help"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("verify ALBUM_DIR"));
+    let output = reflac(&scratch, &["encode", "-V"]);
+    assert!(stdout(&output).starts_with("reflac "));
+
+    album_fixture(&scratch, "INPUT=src\nALBUM=Sub\nTITLE[1]=Foo\n");
+    let output = reflac(&scratch, &["plan", "-p", "./TRACKINFO", "-o", "out"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Dry run, not encoding."));
+    assert!(!scratch.join("out/Sub").exists());
+
+    let output = reflac(
+        &scratch,
+        &["encode", "-q", "-p", "--output-dir", "out", "./TRACKINFO"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("→"), "{}", stderr(&output));
+    assert!(stderr(&output).starts_with("WARNING: "));
+    let output = reflac(&scratch, &["verify", "out/Sub"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("01. Foo.flac: ok"));
+
+    fs::write(scratch.join("out/Sub/01. Foo.flac"), b"garbage").unwrap();
+    let output = reflac(&scratch, &["verify", "out/Sub"]);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("01. Foo.flac: FAILED"));
+    assert!(stderr(&output).contains("FLAC verification failed"));
+}